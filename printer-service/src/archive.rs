@@ -0,0 +1,437 @@
+//! Copies of printed tickets for the receipt archive. Each printed job's
+//! transcript (the same text `--mock-pretty` shows) is saved to a local
+//! directory and/or POSTed to an HTTP endpoint. This all happens on a
+//! background task with its own retry queue, so archiving never holds up
+//! printing; when it falls too far behind, the oldest copies are dropped.
+
+use std::collections::VecDeque;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use log::{debug, error, info, warn};
+use serde::Serialize;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+use crate::clock;
+use crate::profile::PrinterProfile;
+use crate::transcript;
+
+/// Printed jobs waiting for the archive task, unless `--low-memory` says fewer
+pub const CHANNEL_SIZE: usize = 64;
+/// Copies waiting to be POSTed; the oldest are dropped beyond this
+const MAX_PENDING: usize = 500;
+const POST_TIMEOUT: Duration = Duration::from_secs(15);
+const RETRY_INITIAL: Duration = Duration::from_secs(5);
+const RETRY_MAX: Duration = Duration::from_secs(300);
+
+pub struct ArchiveConfig {
+    pub dir: Option<PathBuf>,
+    pub url: Option<String>,
+    /// Oldest files in `dir` are deleted once it holds more than this
+    pub max_bytes: u64,
+    /// Files in `dir` older than this are deleted
+    pub max_age: Option<Duration>,
+    /// Printed jobs that can wait for the archive task; more are not archived
+    pub queue_size: usize,
+}
+
+/// What is stored locally and POSTed.
+#[derive(Serialize)]
+struct Copy {
+    id: Option<String>,
+    /// RFC 3339
+    printed_at: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    device_id: String,
+    transcript: String,
+}
+
+/// A printed job as handed over by the service.
+struct Printed {
+    id: Option<String>,
+    printed_at: chrono::DateTime<chrono::Utc>,
+    bytes: Vec<u8>,
+}
+
+/// Handle for sending printed jobs to the archive task.
+pub struct Archive {
+    tx: mpsc::Sender<Printed>,
+}
+
+impl Archive {
+    /// Starts the archive task, or returns `None` if there's nowhere to archive to.
+    pub fn start(
+        config: ArchiveConfig,
+        profile: PrinterProfile,
+        device_id: String,
+    ) -> Option<Self> {
+        if config.dir.is_none() && config.url.is_none() {
+            return None;
+        }
+        if let Some(dir) = &config.dir {
+            info!("Archiving printed tickets to {}", dir.display());
+        }
+        if let Some(url) = &config.url {
+            info!("Archiving printed tickets to {}", url);
+        }
+        let (tx, rx) = mpsc::channel(config.queue_size.max(1));
+        tokio::spawn(run(rx, config, profile, device_id));
+        Some(Self { tx })
+    }
+
+    /// Queues a printed job's bytes for archiving. Never waits.
+    pub fn record(&self, id: Option<String>, bytes: &[u8]) {
+        let printed = Printed {
+            id,
+            printed_at: clock::now(),
+            bytes: bytes.to_vec(),
+        };
+        if let Err(e) = self.tx.try_send(printed) {
+            warn!("Archive is behind, not archiving this ticket: {}", e);
+        }
+    }
+}
+
+async fn run(
+    mut rx: mpsc::Receiver<Printed>,
+    config: ArchiveConfig,
+    profile: PrinterProfile,
+    device_id: String,
+) {
+    let client = reqwest::Client::builder()
+        .timeout(POST_TIMEOUT)
+        .build()
+        .expect("failed to build HTTP client");
+    let mut pending: VecDeque<String> = VecDeque::new();
+    let mut backoff = RETRY_INITIAL;
+    let mut retry_at = Instant::now();
+
+    loop {
+        let post_at = Some(retry_at).filter(|_| !pending.is_empty());
+        tokio::select! {
+            printed = rx.recv() => {
+                let Some(printed) = printed else {
+                    return;
+                };
+                let profile = profile.clone();
+                let device_id = device_id.clone();
+                let dir = config.dir.clone();
+                let (max_bytes, max_age) = (config.max_bytes, config.max_age);
+                let body = tokio::task::spawn_blocking(move || {
+                    let copy = Copy {
+                        id: printed.id,
+                        printed_at: printed.printed_at.to_rfc3339(),
+                        device_id,
+                        transcript: transcript::render(
+                            &transcript::decode(&printed.bytes, profile.commands),
+                            &profile,
+                        ),
+                    };
+                    if let Some(dir) = &dir {
+                        save(dir, &copy, &printed.printed_at);
+                        prune(dir, max_bytes, max_age);
+                    }
+                    serde_json::to_string(&copy).expect("archive copy serializes")
+                })
+                .await;
+                match body {
+                    Ok(body) if config.url.is_some() => {
+                        if pending.len() >= MAX_PENDING {
+                            warn!("Archive endpoint unreachable for too long, dropping the oldest unsent copy");
+                            pending.pop_front();
+                        }
+                        pending.push_back(body);
+                    }
+                    Ok(_) => {}
+                    Err(e) => error!("Archiving a ticket failed: {}", e),
+                }
+            }
+            _ = sleep_until_some(post_at) => {
+                let (Some(url), Some(body)) = (&config.url, pending.front()) else {
+                    continue;
+                };
+                let result = client
+                    .post(url)
+                    .header("content-type", "application/json")
+                    .body(body.clone())
+                    .send()
+                    .await
+                    .and_then(|response| response.error_for_status());
+                match result {
+                    Ok(_) => {
+                        debug!("Archived a ticket ({} more waiting)", pending.len() - 1);
+                        pending.pop_front();
+                        backoff = RETRY_INITIAL;
+                    }
+                    Err(e) => {
+                        warn!(
+                            "Failed to archive a ticket ({} waiting), retrying in {:?}: {}",
+                            pending.len(),
+                            backoff,
+                            e
+                        );
+                        retry_at = Instant::now() + backoff;
+                        backoff = (backoff * 2).min(RETRY_MAX);
+                    }
+                }
+            }
+        }
+    }
+}
+
+async fn sleep_until_some(at: Option<Instant>) {
+    match at {
+        Some(at) => tokio::time::sleep_until(at).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Writes the transcript to `<dir>/<time>-<id>.txt`.
+fn save(dir: &Path, copy: &Copy, printed_at: &chrono::DateTime<chrono::Utc>) {
+    if let Err(e) = fs::create_dir_all(dir) {
+        error!(
+            "Failed to create archive directory {}: {}",
+            dir.display(),
+            e
+        );
+        return;
+    }
+    let id: String = copy
+        .id
+        .as_deref()
+        .unwrap_or("noid")
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .take(64)
+        .collect();
+    let name = format!("{}-{}.txt", printed_at.format("%Y%m%dT%H%M%S%.3fZ"), id);
+    let text = format!(
+        "Job: {}\nPrinted: {}\n\n{}",
+        copy.id.as_deref().unwrap_or("-"),
+        copy.printed_at,
+        copy.transcript
+    );
+    let path = dir.join(name);
+    if let Err(e) = fs::write(&path, text) {
+        error!("Failed to archive ticket to {}: {}", path.display(), e);
+    }
+}
+
+/// Deletes archived files past `max_age`, then the oldest until the
+/// directory is within `max_bytes` or only the newest is left. File names
+/// start with the print time, so name order is age order.
+fn prune(dir: &Path, max_bytes: u64, max_age: Option<Duration>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    let mut files: Vec<(PathBuf, u64, SystemTime)> = entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().extension().is_some_and(|ext| ext == "txt"))
+        .filter_map(|entry| {
+            let meta = entry.metadata().ok()?;
+            Some((entry.path(), meta.len(), meta.modified().ok()?))
+        })
+        .collect();
+    files.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let now = SystemTime::now();
+    let mut total: u64 = files.iter().map(|(_, len, _)| len).sum();
+    // The copy just saved is always kept
+    files.pop();
+    for (path, len, modified) in files {
+        let expired = max_age.is_some_and(|age| {
+            now.duration_since(modified)
+                .is_ok_and(|elapsed| elapsed > age)
+        });
+        if !expired && total <= max_bytes {
+            break;
+        }
+        match fs::remove_file(&path) {
+            Ok(()) => total -= len,
+            Err(e) => warn!(
+                "Failed to delete old archive file {}: {}",
+                path.display(),
+                e
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::protocol::Job;
+    use crate::render;
+    use crate::tempdir::TempDir;
+
+    fn profile() -> PrinterProfile {
+        PrinterProfile::find("default").unwrap().clone()
+    }
+
+    fn ticket(text: &str) -> Vec<u8> {
+        render::render_job(&Job::plain(text.to_string()), &profile(), None)
+            .unwrap()
+            .bytes
+    }
+
+    fn config(dir: Option<&Path>, url: Option<String>) -> ArchiveConfig {
+        ArchiveConfig {
+            dir: dir.map(Path::to_path_buf),
+            url,
+            max_bytes: 1 << 20,
+            max_age: None,
+            queue_size: CHANNEL_SIZE,
+        }
+    }
+
+    /// Waits for `done`, failing after `within`.
+    async fn eventually(within: Duration, done: impl Fn() -> bool) {
+        let deadline = Instant::now() + within;
+        while !done() {
+            assert!(Instant::now() < deadline, "timed out");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    fn files(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .map(|entries| {
+                entries
+                    .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                    .collect()
+            })
+            .unwrap_or_default();
+        names.sort();
+        names
+    }
+
+    /// An HTTP server answering each POST with the next of `statuses`, then
+    /// 200. Returns its URL and the bodies of the POSTs it accepted.
+    async fn endpoint(statuses: Vec<u16>) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/archive", listener.local_addr().unwrap());
+        let accepted = Arc::new(Mutex::new(Vec::new()));
+        let bodies = Arc::clone(&accepted);
+        tokio::spawn(async move {
+            let mut statuses = statuses.into_iter();
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = Vec::new();
+                let mut buf = [0; 4096];
+                let body_len = loop {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    assert!(n > 0, "client hung up mid-request");
+                    request.extend_from_slice(&buf[..n]);
+                    if let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                        let head = String::from_utf8_lossy(&request[..end]).to_lowercase();
+                        let len: usize = head
+                            .lines()
+                            .find_map(|line| line.strip_prefix("content-length:"))
+                            .map_or(0, |len| len.trim().parse().unwrap());
+                        break end + 4 + len;
+                    }
+                };
+                while request.len() < body_len {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                }
+                let status = statuses.next().unwrap_or(200);
+                if status == 200 {
+                    let start = request.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+                    let body = String::from_utf8_lossy(&request[start + 4..]).to_string();
+                    bodies.lock().unwrap().push(body);
+                }
+                let response = format!(
+                    "HTTP/1.1 {} X\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    status
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        (url, accepted)
+    }
+
+    #[tokio::test]
+    async fn nowhere_to_archive_to_starts_nothing() {
+        assert!(Archive::start(config(None, None), profile(), String::new()).is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn printed_tickets_are_saved_as_transcripts() {
+        let dir = TempDir::new("archive");
+        let archive_dir = dir.path().join("archive");
+        let archive =
+            Archive::start(config(Some(&archive_dir), None), profile(), String::new()).unwrap();
+        archive.record(Some("order/42".to_string()), &ticket("Flat white x2"));
+        archive.record(None, &ticket("Long black"));
+        eventually(Duration::from_secs(5), || files(&archive_dir).len() == 2).await;
+
+        let names = files(&archive_dir);
+        let order = names
+            .iter()
+            .find(|name| name.ends_with("-order_42.txt"))
+            .unwrap();
+        assert!(names.iter().any(|name| name.ends_with("-noid.txt")));
+        let text = fs::read_to_string(archive_dir.join(order)).unwrap();
+        assert!(text.starts_with("Job: order/42\nPrinted: "), "{}", text);
+        assert!(text.contains("Flat white x2"), "{}", text);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn copies_are_posted_in_order_and_retried() {
+        let (url, accepted) = endpoint(vec![503]).await;
+        let archive =
+            Archive::start(config(None, Some(url)), profile(), "kiosk-7".to_string()).unwrap();
+        archive.record(Some("a1".to_string()), &ticket("First"));
+        archive.record(Some("a2".to_string()), &ticket("Second"));
+        // The first retry is RETRY_INITIAL after the failure
+        eventually(RETRY_INITIAL * 2, || accepted.lock().unwrap().len() == 2).await;
+
+        let bodies = accepted.lock().unwrap();
+        let copies: Vec<serde_json::Value> = bodies
+            .iter()
+            .map(|body| serde_json::from_str(body).unwrap())
+            .collect();
+        assert_eq!(copies[0]["id"], "a1");
+        assert_eq!(copies[1]["id"], "a2");
+        assert_eq!(copies[0]["device_id"], "kiosk-7");
+        assert!(copies[1]["transcript"].as_str().unwrap().contains("Second"));
+        assert!(
+            chrono::DateTime::parse_from_rfc3339(copies[0]["printed_at"].as_str().unwrap()).is_ok()
+        );
+    }
+
+    #[test]
+    fn pruning_keeps_the_newest_within_size_and_age() {
+        let dir = TempDir::new("archive");
+        let old = SystemTime::now() - Duration::from_secs(3600);
+        for name in ["1.txt", "2.txt", "3.txt", "4.txt"] {
+            let path = dir.path().join(name);
+            fs::write(&path, [b'x'; 100]).unwrap();
+            if name < "3" {
+                fs::File::open(&path).unwrap().set_modified(old).unwrap();
+            }
+        }
+        fs::write(dir.path().join("notes.md"), [b'x'; 1000]).unwrap();
+
+        prune(dir.path(), 300, None);
+        assert_eq!(files(dir.path()), ["2.txt", "3.txt", "4.txt", "notes.md"]);
+        prune(dir.path(), 1 << 20, Some(Duration::from_secs(60)));
+        assert_eq!(files(dir.path()), ["3.txt", "4.txt", "notes.md"]);
+        // The newest copy stays whatever the limits
+        prune(dir.path(), 0, Some(Duration::ZERO));
+        assert_eq!(files(dir.path()), ["4.txt", "notes.md"]);
+    }
+}
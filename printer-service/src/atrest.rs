@@ -0,0 +1,315 @@
+//! Optional encryption of the spool, journal and dead letters on disk, since
+//! jobs and journal entries can carry customer names and a kiosk's SD card is
+//! easy to walk off with. Each record is sealed on its own with AES-256-GCM,
+//! so compaction and torn-tail recovery still work a record at a time. Keys
+//! are derived with HKDF from the device key, or from `--at-rest-key-file`,
+//! and numbered: rotating moves new records to the next key and re-encrypts
+//! the old ones, and each record names the key it was sealed with, so a
+//! rotation cut short by a crash loses nothing. Every generation comes from
+//! the same secret, so rotating doesn't help once the secret is out; keys
+//! derived from the device key are only as safe as the card it's on.
+
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{Context, Result, anyhow, bail};
+use aws_lc_rs::aead::{AES_256_GCM, Aad, LessSafeKey, NONCE_LEN, Nonce, UnboundKey};
+use aws_lc_rs::hkdf::{HKDF_SHA256, Salt};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+/// Holds the key generation in use, beside the files it encrypts
+const STATE_FILE: &str = "at-rest.json";
+const SALT: &[u8] = b"printer-service at-rest";
+/// Shortest key file accepted, in bytes
+const MIN_KEY_LEN: usize = 16;
+
+/// One record as stored when encrypted.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Sealed {
+    /// Generation of the key it was sealed with
+    pub key: u32,
+    /// Hex nonce followed by the ciphertext and tag
+    pub sealed: String,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct State {
+    generation: u32,
+}
+
+/// The secret the keys are derived from, and the generation new records are
+/// sealed with.
+#[derive(Clone)]
+pub struct Keys {
+    secret: Arc<[u8]>,
+    generation: u32,
+    state_path: PathBuf,
+}
+
+impl Keys {
+    /// Keys derived from `secret`, at the generation saved in `dir`.
+    pub fn new(secret: &[u8], dir: &Path) -> Result<Self> {
+        let state_path = dir.join(STATE_FILE);
+        let state = match fs::read_to_string(&state_path) {
+            Ok(text) => serde_json::from_str(&text)
+                .with_context(|| format!("Failed to parse {}", state_path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => State::default(),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to read {}", state_path.display()));
+            }
+        };
+        Ok(Self {
+            secret: secret.into(),
+            generation: state.generation,
+            state_path,
+        })
+    }
+
+    /// The same secret, at the generation saved in `dir`, for another
+    /// printer's spool and journal.
+    pub fn for_dir(&self, dir: &Path) -> Result<Self> {
+        Self::new(&self.secret, dir)
+    }
+
+    /// Keys derived from a hex-encoded secret in a file (surrounding
+    /// whitespace ignored), like the signing key file.
+    pub fn from_file(path: &Path, dir: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read at-rest key file {}", path.display()))?;
+        let secret = hex::decode(contents.trim())
+            .with_context(|| format!("At-rest key file {} is not valid hex", path.display()))?;
+        if secret.len() < MIN_KEY_LEN {
+            bail!(
+                "At-rest key file {} holds {} bytes, at least {} are needed",
+                path.display(),
+                secret.len(),
+                MIN_KEY_LEN
+            );
+        }
+        Self::new(&secret, dir)
+    }
+
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+
+    fn key(&self, generation: u32) -> LessSafeKey {
+        let info = format!("key {}", generation);
+        let info = [info.as_bytes()];
+        let prk = Salt::new(HKDF_SHA256, SALT).extract(&self.secret);
+        let okm = prk
+            .expand(&info, &AES_256_GCM)
+            .expect("AES-256 key length is valid for HKDF-SHA256");
+        LessSafeKey::new(UnboundKey::from(okm))
+    }
+
+    /// Seals `plaintext` with the current key. `aad` ties it to where it's
+    /// stored, so a record can't be moved elsewhere and still open.
+    pub fn seal(&self, plaintext: &[u8], aad: &str) -> Sealed {
+        let nonce = rand::rng().random::<[u8; NONCE_LEN]>();
+        let mut in_out = plaintext.to_vec();
+        self.key(self.generation)
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(aad.as_bytes()),
+                &mut in_out,
+            )
+            .expect("AES-GCM sealing cannot fail");
+        Sealed {
+            key: self.generation,
+            sealed: hex::encode([&nonce[..], &in_out].concat()),
+        }
+    }
+
+    /// Opens a record sealed with any generation of these keys.
+    pub fn open(&self, sealed: &Sealed, aad: &str) -> Result<Vec<u8>> {
+        let bytes = hex::decode(&sealed.sealed).context("Encrypted record is not valid hex")?;
+        if bytes.len() < NONCE_LEN {
+            bail!("Encrypted record is too short");
+        }
+        let (nonce, ciphertext) = bytes.split_at(NONCE_LEN);
+        let mut in_out = ciphertext.to_vec();
+        let plaintext = self
+            .key(sealed.key)
+            .open_in_place(
+                Nonce::try_assume_unique_for_key(nonce).expect("nonce has the right length"),
+                Aad::from(aad.as_bytes()),
+                &mut in_out,
+            )
+            .map_err(|_| {
+                anyhow!(
+                    "Failed to decrypt a record sealed with key {} (wrong key?)",
+                    sealed.key
+                )
+            })?;
+        Ok(plaintext.to_vec())
+    }
+
+    /// Moves on to the next key, saving the new generation before anything
+    /// is sealed with it.
+    pub fn rotate(&mut self) -> Result<()> {
+        let state = State {
+            generation: self.generation + 1,
+        };
+        let tmp_path = self.state_path.with_extension("tmp");
+        let mut tmp = File::create(&tmp_path)
+            .with_context(|| format!("Failed to create {}", tmp_path.display()))?;
+        tmp.write_all(serde_json::to_string(&state)?.as_bytes())?;
+        tmp.sync_all()?;
+        fs::rename(&tmp_path, &self.state_path)
+            .with_context(|| format!("Failed to write {}", self.state_path.display()))?;
+        if let Some(dir) = self.state_path.parent() {
+            File::open(dir)?.sync_all()?;
+        }
+        self.generation = state.generation;
+        Ok(())
+    }
+}
+
+/// How the spool, journal and dead letters are stored.
+#[derive(Clone, Default)]
+pub enum AtRest {
+    /// In plaintext; finding encrypted records is an error
+    #[default]
+    Plain,
+    /// Encrypted, with plaintext records encrypted on startup
+    Encrypted(Keys),
+    /// In plaintext, with encrypted records decrypted on startup (`--decrypt-at-rest`)
+    Decrypting(Keys),
+}
+
+impl AtRest {
+    /// The keys new records are sealed with, when encrypting.
+    pub fn sealing(&self) -> Option<&Keys> {
+        match self {
+            AtRest::Encrypted(keys) => Some(keys),
+            _ => None,
+        }
+    }
+
+    /// The keys to open records with, when there are any.
+    pub fn keys(&self) -> Option<&Keys> {
+        match self {
+            AtRest::Plain => None,
+            AtRest::Encrypted(keys) | AtRest::Decrypting(keys) => Some(keys),
+        }
+    }
+
+    /// The same storage for another printer, keeping its key generation in
+    /// `dir`.
+    pub fn for_dir(&self, dir: &Path) -> Result<Self> {
+        Ok(match self {
+            AtRest::Plain => AtRest::Plain,
+            AtRest::Encrypted(keys) => AtRest::Encrypted(keys.for_dir(dir)?),
+            AtRest::Decrypting(keys) => AtRest::Decrypting(keys.for_dir(dir)?),
+        })
+    }
+
+    /// The error for an encrypted record found without the keys to it.
+    pub fn locked(what: &str) -> anyhow::Error {
+        anyhow!(
+            "{} is encrypted; start with --encrypt-at-rest, or --decrypt-at-rest to store it in plaintext again",
+            what
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tempdir::TempDir;
+
+    const SECRET: &[u8] = b"0123456789abcdef0123456789abcdef";
+
+    #[test]
+    fn records_open_only_with_their_key_and_place() {
+        let dir = TempDir::new("atrest");
+        let keys = Keys::new(SECRET, dir.path()).unwrap();
+        let sealed = keys.seal(b"Table 4: Ana", "spool:1");
+        assert_eq!(sealed.key, 0);
+        assert!(!sealed.sealed.contains(&hex::encode("Ana")));
+        assert_eq!(keys.open(&sealed, "spool:1").unwrap(), b"Table 4: Ana");
+        // A fresh nonce every time
+        assert_ne!(keys.seal(b"Table 4: Ana", "spool:1").sealed, sealed.sealed);
+
+        assert!(keys.open(&sealed, "spool:2").is_err());
+        let other = Keys::new(b"another secret, just as long", dir.path()).unwrap();
+        assert!(other.open(&sealed, "spool:1").is_err());
+        let mut tampered = sealed.clone();
+        let last = if tampered.sealed.ends_with('0') {
+            "1"
+        } else {
+            "0"
+        };
+        tampered
+            .sealed
+            .replace_range(tampered.sealed.len() - 1.., last);
+        assert!(keys.open(&tampered, "spool:1").is_err());
+        let short = Sealed {
+            key: 0,
+            sealed: "abcd".to_string(),
+        };
+        assert!(keys.open(&short, "spool:1").is_err());
+    }
+
+    #[test]
+    fn rotating_seals_with_the_next_key_and_still_opens_old_records() {
+        let dir = TempDir::new("atrest");
+        let mut keys = Keys::new(SECRET, dir.path()).unwrap();
+        let old = keys.seal(b"old", "journal");
+        keys.rotate().unwrap();
+        assert_eq!(keys.generation(), 1);
+        let new = keys.seal(b"new", "journal");
+        assert_eq!(new.key, 1);
+        assert_eq!(keys.open(&old, "journal").unwrap(), b"old");
+
+        // The generation is saved beside the files
+        let reopened = Keys::new(SECRET, dir.path()).unwrap();
+        assert_eq!(reopened.generation(), 1);
+        assert_eq!(reopened.open(&new, "journal").unwrap(), b"new");
+        let elsewhere = TempDir::new("atrest");
+        assert_eq!(keys.for_dir(elsewhere.path()).unwrap().generation(), 0);
+    }
+
+    #[test]
+    fn key_files_hold_enough_hex() {
+        let dir = TempDir::new("atrest");
+        let path = dir.path().join("key");
+        fs::write(&path, format!("  {}\n", hex::encode(SECRET))).unwrap();
+        let from_file = Keys::from_file(&path, dir.path()).unwrap();
+        let sealed = Keys::new(SECRET, dir.path()).unwrap().seal(b"x", "a");
+        assert_eq!(from_file.open(&sealed, "a").unwrap(), b"x");
+
+        fs::write(&path, "not hex").unwrap();
+        assert!(Keys::from_file(&path, dir.path()).is_err());
+        fs::write(&path, hex::encode(&SECRET[..MIN_KEY_LEN - 1])).unwrap();
+        let short = Keys::from_file(&path, dir.path()).err().unwrap();
+        assert!(format!("{:#}", short).contains("at least 16"));
+        assert!(Keys::from_file(&dir.path().join("missing"), dir.path()).is_err());
+    }
+
+    #[test]
+    fn a_corrupt_state_file_is_an_error() {
+        let dir = TempDir::new("atrest");
+        fs::write(dir.path().join(STATE_FILE), "{").unwrap();
+        assert!(Keys::new(SECRET, dir.path()).is_err());
+    }
+
+    #[test]
+    fn only_encrypting_storage_seals() {
+        let dir = TempDir::new("atrest");
+        let keys = Keys::new(SECRET, dir.path()).unwrap();
+        assert!(AtRest::Plain.sealing().is_none() && AtRest::Plain.keys().is_none());
+        let encrypted = AtRest::Encrypted(keys.clone());
+        assert!(encrypted.sealing().is_some() && encrypted.keys().is_some());
+        let decrypting = AtRest::Decrypting(keys);
+        assert!(decrypting.sealing().is_none() && decrypting.keys().is_some());
+        assert!(matches!(
+            decrypting.for_dir(dir.path()).unwrap(),
+            AtRest::Decrypting(_)
+        ));
+    }
+}
@@ -0,0 +1,212 @@
+//! `--chaos`: fault injection for release testing, built only with the
+//! `chaos` feature. Wraps the printer driver to fail writes, slow them down
+//! and report the paper as out, and has the service drop its WebSocket
+//! connection on a timer, so recovery can be exercised without unplugging
+//! anything.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+
+use anyhow::{Context, Result, bail};
+use escpos::driver::Driver;
+use escpos::errors::PrinterError;
+use log::warn;
+use rand::Rng;
+
+/// DLE EOT 4: the paper sensor status request sent by `driver::paper_out`
+const PAPER_STATUS_REQUEST: &[u8] = &[0x10, 0x04, 0x04];
+/// Paper sensor status with the paper end bits (5 and 6) set, plus the
+/// bits that are always on
+const PAPER_OUT_STATUS: u8 = 0x72;
+
+/// What to inject, parsed from `--chaos` as comma-separated `key=value`
+/// pairs, e.g. `write_errors=0.05,latency_ms=200,disconnect_secs=300`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ChaosConfig {
+    /// Chance (0-1) of each driver write failing
+    pub write_errors: f64,
+    /// Added before every driver write
+    pub latency: Duration,
+    /// Drop the WebSocket connection this often
+    pub disconnect_every: Option<Duration>,
+    /// Chance (0-1) of a paper status request answering "out of paper"
+    pub paper_out: f64,
+}
+
+/// Parses a `--chaos` value.
+pub fn parse(spec: &str) -> Result<ChaosConfig> {
+    let mut config = ChaosConfig::default();
+    for pair in spec
+        .split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+    {
+        let (key, value) = pair
+            .split_once('=')
+            .with_context(|| format!("Expected key=value, got {:?}", pair))?;
+        let invalid = || format!("Invalid value for {}: {:?}", key, value);
+        match key {
+            "write_errors" => config.write_errors = probability(value).with_context(invalid)?,
+            "paper_out" => config.paper_out = probability(value).with_context(invalid)?,
+            "latency_ms" => {
+                config.latency = Duration::from_millis(value.parse().with_context(invalid)?)
+            }
+            "disconnect_secs" => {
+                let secs: u64 = value.parse().with_context(invalid)?;
+                config.disconnect_every = Some(Duration::from_secs(secs)).filter(|d| !d.is_zero());
+            }
+            _ => bail!(
+                "Unknown chaos setting {:?} (expected write_errors, latency_ms, disconnect_secs or paper_out)",
+                key
+            ),
+        }
+    }
+    Ok(config)
+}
+
+fn probability(value: &str) -> Result<f64> {
+    let p: f64 = value.parse()?;
+    if !(0.0..=1.0).contains(&p) {
+        bail!("must be between 0 and 1");
+    }
+    Ok(p)
+}
+
+/// Driver wrapper injecting the faults in a [`ChaosConfig`].
+#[derive(Clone)]
+pub struct ChaosDriver<D> {
+    inner: D,
+    config: ChaosConfig,
+    /// The last write was a paper status request
+    status_requested: Arc<AtomicBool>,
+    injected: Arc<AtomicU64>,
+}
+
+impl<D: Driver> ChaosDriver<D> {
+    pub fn new(inner: D, config: ChaosConfig) -> Self {
+        Self {
+            inner,
+            config,
+            status_requested: Arc::new(AtomicBool::new(false)),
+            injected: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Faults injected so far (failed writes and fake paper-out answers).
+    pub fn injected(&self) -> u64 {
+        self.injected.load(Ordering::Relaxed)
+    }
+
+    fn roll(p: f64) -> bool {
+        p > 0.0 && rand::rng().random_bool(p)
+    }
+}
+
+impl<D: Driver> Driver for ChaosDriver<D> {
+    fn name(&self) -> String {
+        format!("chaos ({})", self.inner.name())
+    }
+
+    fn write(&self, data: &[u8]) -> escpos::errors::Result<()> {
+        if !self.config.latency.is_zero() {
+            std::thread::sleep(self.config.latency);
+        }
+        self.status_requested
+            .store(data == PAPER_STATUS_REQUEST, Ordering::Relaxed);
+        if Self::roll(self.config.write_errors) {
+            self.injected.fetch_add(1, Ordering::Relaxed);
+            warn!("Chaos: failing a {} byte write", data.len());
+            return Err(PrinterError::Io("chaos: injected write error".to_string()));
+        }
+        self.inner.write(data)
+    }
+
+    fn read(&self, buf: &mut [u8]) -> escpos::errors::Result<usize> {
+        if self.status_requested.swap(false, Ordering::Relaxed)
+            && !buf.is_empty()
+            && Self::roll(self.config.paper_out)
+        {
+            self.injected.fetch_add(1, Ordering::Relaxed);
+            warn!("Chaos: reporting the paper as out");
+            buf[0] = PAPER_OUT_STATUS;
+            return Ok(1);
+        }
+        self.inner.read(buf)
+    }
+
+    fn flush(&self) -> escpos::errors::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::RecordingDriver;
+
+    #[test]
+    fn settings_are_parsed() {
+        assert_eq!(
+            parse(" write_errors=0.05, latency_ms=200,disconnect_secs=300,paper_out=1,").unwrap(),
+            ChaosConfig {
+                write_errors: 0.05,
+                latency: Duration::from_millis(200),
+                disconnect_every: Some(Duration::from_secs(300)),
+                paper_out: 1.0,
+            }
+        );
+        assert_eq!(parse("").unwrap(), ChaosConfig::default());
+        assert_eq!(parse("disconnect_secs=0").unwrap().disconnect_every, None);
+        for bad in [
+            "write_errors",
+            "write_errors=1.5",
+            "paper_out=-0.1",
+            "latency_ms=soon",
+            "jitter=5",
+        ] {
+            assert!(parse(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn certain_faults_always_happen() {
+        let printer = RecordingDriver::default();
+        let failing = ChaosDriver::new(
+            printer.clone(),
+            ChaosConfig {
+                write_errors: 1.0,
+                ..ChaosConfig::default()
+            },
+        );
+        assert!(failing.write(b"ticket").is_err());
+        assert_eq!(printer.written(), 0);
+        assert_eq!(failing.injected(), 1);
+
+        let paper_out = ChaosDriver::new(
+            printer.clone(),
+            ChaosConfig {
+                paper_out: 1.0,
+                ..ChaosConfig::default()
+            },
+        );
+        assert_eq!(crate::driver::paper_out(&paper_out), Some(true));
+        // Only a paper status request is answered
+        paper_out.write(&[0x10, 0x04, 0x01]).unwrap();
+        assert_eq!(paper_out.read(&mut [0; 1]).unwrap(), 0);
+        assert_eq!(paper_out.injected(), 1);
+    }
+
+    #[test]
+    fn with_nothing_to_inject_it_passes_through() {
+        let printer = RecordingDriver::default();
+        let chaos = ChaosDriver::new(printer.clone(), ChaosConfig::default());
+        for _ in 0..100 {
+            chaos.write(b"ok").unwrap();
+        }
+        assert_eq!(crate::driver::paper_out(&chaos), None);
+        assert_eq!(printer.written(), 200 + PAPER_STATUS_REQUEST.len());
+        assert_eq!(chaos.injected(), 0);
+        assert_eq!(chaos.name(), "chaos (recording)");
+    }
+}
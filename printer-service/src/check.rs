@@ -0,0 +1,383 @@
+//! `--check`: validates a unit's setup without starting the service, for
+//! deployment tooling. Loads the config, opens and initializes the printer,
+//! reads its status and connects to the server once, then exits with a code
+//! saying what (if anything) is wrong.
+
+use std::time::Duration;
+
+use anyhow::Result;
+use escpos::driver::Driver;
+use serde::Serialize;
+
+use crate::clock;
+use crate::driver::{self, Readiness};
+use crate::protocol::Job;
+use crate::render;
+use crate::service::{self, ServiceConfig};
+use crate::transport::{self, Transport};
+
+pub const EXIT_OK: i32 = 0;
+/// Also what clap exits with for invalid flags
+pub const EXIT_CONFIG_INVALID: i32 = 2;
+pub const EXIT_PRINTER_UNREACHABLE: i32 = 3;
+pub const EXIT_SERVER_UNREACHABLE: i32 = 4;
+pub const EXIT_AUTH_REJECTED: i32 = 5;
+
+/// How long the printer gets to report itself online
+const READY_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Debug)]
+pub struct Report {
+    pub ok: bool,
+    pub exit_code: i32,
+    pub checks: Vec<Check>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Check {
+    pub name: &'static str,
+    pub ok: bool,
+    pub detail: String,
+}
+
+impl Report {
+    fn new() -> Self {
+        Self {
+            ok: true,
+            exit_code: EXIT_OK,
+            checks: Vec::new(),
+        }
+    }
+
+    /// Records a check. The exit code is that of the first one failing.
+    fn record(&mut self, name: &'static str, result: Result<String, String>, code: i32) {
+        let ok = result.is_ok();
+        if !ok && self.ok {
+            self.ok = false;
+            self.exit_code = code;
+        }
+        self.checks.push(Check {
+            name,
+            ok,
+            detail: result.unwrap_or_else(|e| e),
+        });
+    }
+
+    /// Prints the report to stdout, as JSON or one line per check.
+    pub fn print(&self, json: bool) {
+        if json {
+            println!(
+                "{}",
+                serde_json::to_string(self).expect("check report serializes")
+            );
+            return;
+        }
+        for check in &self.checks {
+            let status = if check.ok { "ok" } else { "FAILED" };
+            println!("{:<8} {:<7} {}", check.name, status, check.detail);
+        }
+        println!(
+            "{}",
+            if self.ok {
+                "All checks passed".to_string()
+            } else {
+                format!("Check failed (exit code {})", self.exit_code)
+            }
+        );
+    }
+}
+
+/// Runs the checks. `config` is the outcome of loading the settings and
+/// working out where jobs come from, and `printer` opens the configured
+/// printer and checks it with [`printer`].
+pub async fn run(
+    config: Result<(ServiceConfig, Transport)>,
+    printer: impl FnOnce(&ServiceConfig) -> Result<String>,
+) -> Report {
+    let mut report = Report::new();
+    let (config, transport) = match config {
+        Ok((config, transport)) => {
+            let detail = match &config.config_path {
+                Some(path) => format!("{} (device {})", path.display(), config.device_id),
+                None => "no config file, flags only".to_string(),
+            };
+            report.record("config", Ok(detail), EXIT_CONFIG_INVALID);
+            (config, transport)
+        }
+        Err(e) => {
+            report.record("config", Err(format!("{:#}", e)), EXIT_CONFIG_INVALID);
+            return report;
+        }
+    };
+
+    let result = tokio::task::block_in_place(|| printer(&config));
+    report.record(
+        "printer",
+        result.map_err(|e| format!("{:#}", e)),
+        EXIT_PRINTER_UNREACHABLE,
+    );
+
+    let Transport::WebSocket(url) = transport else {
+        report.record(
+            "server",
+            Ok("demo mode, no server checked".to_string()),
+            EXIT_SERVER_UNREACHABLE,
+        );
+        return report;
+    };
+    match transport::check_server(&url, config.default_protocol).await {
+        Ok(wire) => report.record(
+            "server",
+            Ok(format!("{} (speaking {:?})", url, wire)),
+            EXIT_SERVER_UNREACHABLE,
+        ),
+        Err(e) if transport::is_auth_rejected(&e) => report.record(
+            "server",
+            Err(format!("{}: authentication rejected: {}", url, e)),
+            EXIT_AUTH_REJECTED,
+        ),
+        Err(e) => report.record(
+            "server",
+            Err(format!("{}: {}", url, e)),
+            EXIT_SERVER_UNREACHABLE,
+        ),
+    }
+    report
+}
+
+/// Initializes the printer and reads its status, printing a short test
+/// ticket too if `print` is set. Returns what was found.
+pub fn printer<D: Driver>(driver: &D, config: &ServiceConfig, print: bool) -> Result<String> {
+    service::init_printer(driver)?;
+    let mut detail = match driver::wait_ready(driver, READY_TIMEOUT)? {
+        Readiness::Online => "online".to_string(),
+        Readiness::NoStatus => "no status reply".to_string(),
+    };
+    if driver::paper_out(driver) == Some(true) {
+        detail.push_str(", out of paper");
+    }
+    if print {
+        let text = format!(
+            "Printer check\n{}\n{}",
+            config.device_id,
+            clock::local_now().format("%Y-%m-%d %H:%M")
+        );
+        let rendered = render::render_job(&Job::plain(text), &config.profile, None)?;
+        driver::write_job(driver, &rendered, &config.profile)?;
+        detail.push_str(", test ticket printed");
+    }
+    Ok(detail)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::sync::Mutex;
+
+    use futures_util::StreamExt;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::profile::PrinterProfile;
+
+    /// Online, with `paper` as its paper sensor reply.
+    struct Printer {
+        paper: u8,
+        written: Mutex<Vec<u8>>,
+        reply: Mutex<Option<u8>>,
+    }
+
+    impl Printer {
+        fn new(paper: u8) -> Self {
+            Self {
+                paper,
+                written: Mutex::new(Vec::new()),
+                reply: Mutex::new(None),
+            }
+        }
+
+        fn contains(&self, text: &str) -> bool {
+            let written = self.written.lock().unwrap();
+            written
+                .windows(text.len())
+                .any(|window| window == text.as_bytes())
+        }
+    }
+
+    impl Driver for Printer {
+        fn name(&self) -> String {
+            "test".to_string()
+        }
+
+        fn write(&self, data: &[u8]) -> escpos::errors::Result<()> {
+            *self.reply.lock().unwrap() = match data {
+                [0x10, 0x04, 0x01] => Some(0x12),
+                [0x10, 0x04, 0x04] => Some(self.paper),
+                _ => None,
+            };
+            self.written.lock().unwrap().extend_from_slice(data);
+            Ok(())
+        }
+
+        fn read(&self, buf: &mut [u8]) -> escpos::errors::Result<usize> {
+            match self.reply.lock().unwrap().take() {
+                Some(byte) => {
+                    buf[0] = byte;
+                    Ok(1)
+                }
+                None => Ok(0),
+            }
+        }
+
+        fn flush(&self) -> escpos::errors::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn config() -> ServiceConfig {
+        ServiceConfig {
+            profile: PrinterProfile::find("default").unwrap().clone(),
+            device_id: "kiosk-7".to_string(),
+            ..ServiceConfig::default()
+        }
+    }
+
+    fn online(_: &ServiceConfig) -> Result<String> {
+        Ok("online".to_string())
+    }
+
+    /// A WebSocket server accepting every connection.
+    async fn server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    if let Ok(mut ws) = tokio_tungstenite::accept_async(stream).await {
+                        while let Some(Ok(_)) = ws.next().await {}
+                    }
+                });
+            }
+        });
+        url
+    }
+
+    /// An HTTP server turning every connection away with `status`.
+    async fn refusing(status: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = [0; 4096];
+                let _ = stream.read(&mut request).await;
+                let response = format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status);
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        url
+    }
+
+    fn results(report: &Report) -> Vec<(&'static str, bool)> {
+        report
+            .checks
+            .iter()
+            .map(|check| (check.name, check.ok))
+            .collect()
+    }
+
+    #[test]
+    fn the_first_failure_sets_the_exit_code() {
+        let mut report = Report::new();
+        report.record("config", Ok("fine".to_string()), EXIT_CONFIG_INVALID);
+        report.record("printer", Err("gone".to_string()), EXIT_PRINTER_UNREACHABLE);
+        report.record("server", Err("gone".to_string()), EXIT_SERVER_UNREACHABLE);
+        assert!(!report.ok);
+        assert_eq!(report.exit_code, EXIT_PRINTER_UNREACHABLE);
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["checks"][1]["detail"], "gone");
+        assert_eq!(json["exit_code"], 3);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_bad_config_stops_the_checks() {
+        let report = run(Err(anyhow::anyhow!("no such profile")), |_| {
+            panic!("the printer shouldn't be opened")
+        })
+        .await;
+        assert_eq!(results(&report), [("config", false)]);
+        assert_eq!(report.exit_code, EXIT_CONFIG_INVALID);
+        assert_eq!(report.checks[0].detail, "no such profile");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn demo_mode_checks_no_server() {
+        let mut with_file = config();
+        with_file.config_path = Some(PathBuf::from("/etc/printer.toml"));
+        let report = run(Ok((with_file, Transport::Embedded)), online).await;
+        assert!(report.ok);
+        assert_eq!(
+            results(&report),
+            [("config", true), ("printer", true), ("server", true)]
+        );
+        assert_eq!(
+            report.checks[0].detail,
+            "/etc/printer.toml (device kiosk-7)"
+        );
+        assert_eq!(report.checks[2].detail, "demo mode, no server checked");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn an_unreachable_printer_still_checks_the_server() {
+        let url = server().await;
+        let report = run(Ok((config(), Transport::WebSocket(url.clone()))), |_| {
+            Err(anyhow::anyhow!("No such device"))
+        })
+        .await;
+        assert_eq!(
+            results(&report),
+            [("config", true), ("printer", false), ("server", true)]
+        );
+        assert_eq!(report.exit_code, EXIT_PRINTER_UNREACHABLE);
+        assert!(report.checks[2].detail.starts_with(&url));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn server_failures_are_told_apart() {
+        let closed = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            format!("ws://{}", listener.local_addr().unwrap())
+        };
+        let report = run(Ok((config(), Transport::WebSocket(closed))), online).await;
+        assert_eq!(report.exit_code, EXIT_SERVER_UNREACHABLE);
+
+        for status in ["401 Unauthorized", "403 Forbidden"] {
+            let url = refusing(status).await;
+            let report = run(Ok((config(), Transport::WebSocket(url))), online).await;
+            assert_eq!(report.exit_code, EXIT_AUTH_REJECTED, "{}", status);
+            assert!(report.checks[2].detail.contains("authentication rejected"));
+        }
+
+        let url = refusing("500 Internal Server Error").await;
+        let report = run(Ok((config(), Transport::WebSocket(url))), online).await;
+        assert_eq!(report.exit_code, EXIT_SERVER_UNREACHABLE);
+    }
+
+    #[test]
+    fn the_printer_check_reports_status_and_can_print() {
+        let printer = Printer::new(0x12);
+        assert_eq!(
+            super::printer(&printer, &config(), false).unwrap(),
+            "online"
+        );
+        assert!(!printer.contains("Printer check"));
+
+        let printer = Printer::new(0x72);
+        assert_eq!(
+            super::printer(&printer, &config(), true).unwrap(),
+            "online, out of paper, test ticket printed"
+        );
+        assert!(printer.contains("Printer check"));
+        assert!(printer.contains("kiosk-7"));
+    }
+}
@@ -0,0 +1,222 @@
+//! Wall-clock time as the server sees it. Devices with a dead RTC battery
+//! boot thinking it's 1970, so the server's time (from its `hello` or a
+//! `time` command) sets an offset applied to everything time-based: TTLs,
+//! the daily report, signature timestamps and printed times. The system
+//! clock itself is never touched.
+
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+
+use chrono::{DateTime, Local, TimeDelta, Utc};
+use log::{info, warn};
+
+/// Skew past which the device clock is reported as wrong
+const SKEW_WARNING_SECS: i64 = 60;
+/// Offsets smaller than this are treated as agreement
+const SKEW_TOLERANCE_SECS: i64 = 2;
+
+/// The device clock and the offset correcting it
+static CLOCK: Clock = Clock::new(Utc::now);
+
+/// Current time, corrected by the server's offset.
+pub fn now() -> DateTime<Utc> {
+    CLOCK.now()
+}
+
+pub fn local_now() -> DateTime<Local> {
+    CLOCK.local_now()
+}
+
+/// Seconds added to the device clock.
+pub fn offset_secs() -> i64 {
+    CLOCK.offset_secs()
+}
+
+/// Whether times are currently being corrected rather than taken from the
+/// device clock as is.
+pub fn is_corrected() -> bool {
+    CLOCK.is_corrected()
+}
+
+/// Takes `server_time` (Unix seconds) as the real time and returns the new
+/// offset.
+pub fn set_server_time(server_time: i64) -> i64 {
+    CLOCK.set_server_time(server_time)
+}
+
+/// A device clock and the offset from the server correcting it. The
+/// service has one, behind the functions above.
+struct Clock<D = fn() -> DateTime<Utc>> {
+    device: D,
+    offset_secs: AtomicI64,
+    corrected: AtomicBool,
+}
+
+impl<D: Fn() -> DateTime<Utc>> Clock<D> {
+    const fn new(device: D) -> Self {
+        Self {
+            device,
+            offset_secs: AtomicI64::new(0),
+            corrected: AtomicBool::new(false),
+        }
+    }
+
+    fn now(&self) -> DateTime<Utc> {
+        (self.device)() + TimeDelta::seconds(self.offset_secs())
+    }
+
+    fn local_now(&self) -> DateTime<Local> {
+        self.now().with_timezone(&Local)
+    }
+
+    fn offset_secs(&self) -> i64 {
+        self.offset_secs.load(Ordering::Relaxed)
+    }
+
+    fn is_corrected(&self) -> bool {
+        self.corrected.load(Ordering::Relaxed)
+    }
+
+    fn set_server_time(&self, server_time: i64) -> i64 {
+        let device_time = (self.device)().timestamp();
+        let mut offset = server_time - device_time;
+        if offset.abs() < SKEW_TOLERANCE_SECS {
+            offset = 0;
+        }
+        if offset.abs() > SKEW_WARNING_SECS {
+            warn!(
+                "Device clock is {}s {} the server's, correcting times by {}s",
+                offset.abs(),
+                if offset > 0 { "behind" } else { "ahead of" },
+                offset
+            );
+        } else if offset != self.offset_secs() {
+            info!("Clock offset from the server: {}s", offset);
+        }
+        self.offset_secs.store(offset, Ordering::Relaxed);
+        self.corrected.store(offset != 0, Ordering::Relaxed);
+        offset
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::protocol::Job;
+
+    /// The real time, as the server sends it
+    const REAL: i64 = 1_760_000_000;
+    const HOURS: i64 = 60 * 60;
+
+    /// A clock whose device time is set by the test.
+    fn device_clock(device: &Arc<AtomicI64>) -> Clock<impl Fn() -> DateTime<Utc>> {
+        let device = Arc::clone(device);
+        Clock::new(move || DateTime::from_timestamp(device.load(Ordering::Relaxed), 0).unwrap())
+    }
+
+    #[test]
+    fn a_device_hours_behind_is_corrected_to_server_time() {
+        let device = Arc::new(AtomicI64::new(REAL - 5 * HOURS));
+        let clock = device_clock(&device);
+        assert_eq!(clock.now().timestamp(), REAL - 5 * HOURS);
+        assert!(!clock.is_corrected());
+
+        assert_eq!(clock.set_server_time(REAL), 5 * HOURS);
+        assert!(clock.is_corrected());
+        assert_eq!(clock.now().timestamp(), REAL);
+        // The offset holds as the device clock runs on
+        device.fetch_add(90, Ordering::Relaxed);
+        assert_eq!(clock.now().timestamp(), REAL + 90);
+        assert_eq!(clock.local_now().timestamp(), REAL + 90);
+    }
+
+    #[test]
+    fn a_device_ahead_is_corrected_back() {
+        let device = Arc::new(AtomicI64::new(REAL + 3 * HOURS));
+        let clock = device_clock(&device);
+        assert_eq!(clock.set_server_time(REAL), -3 * HOURS);
+        assert_eq!(clock.now().timestamp(), REAL);
+        assert!(clock.is_corrected());
+    }
+
+    #[test]
+    fn a_booted_in_1970_device_is_corrected() {
+        let device = Arc::new(AtomicI64::new(12));
+        let clock = device_clock(&device);
+        assert_eq!(clock.set_server_time(REAL), REAL - 12);
+        assert_eq!(clock.now().timestamp(), REAL);
+    }
+
+    #[test]
+    fn skew_within_tolerance_is_agreement() {
+        let device = Arc::new(AtomicI64::new(REAL - 5 * HOURS));
+        let clock = device_clock(&device);
+        clock.set_server_time(REAL);
+        // Once the device clock is set right, the correction goes
+        device.store(REAL + 1, Ordering::Relaxed);
+        assert_eq!(clock.set_server_time(REAL), 0);
+        assert!(!clock.is_corrected());
+        assert_eq!(clock.now().timestamp(), REAL + 1);
+        assert_eq!(clock.set_server_time(REAL + 3), 2);
+    }
+
+    #[test]
+    fn printed_times_are_the_server_time() {
+        let device = Arc::new(AtomicI64::new(REAL - 5 * HOURS));
+        let clock = device_clock(&device);
+        let real = DateTime::from_timestamp(REAL, 0)
+            .unwrap()
+            .with_timezone(&Local);
+        let format = "%Y-%m-%d %H:%M:%S";
+        assert_ne!(
+            clock.local_now().format(format).to_string(),
+            real.format(format).to_string()
+        );
+        clock.set_server_time(REAL);
+        assert_eq!(
+            clock.local_now().format(format).to_string(),
+            real.format(format).to_string()
+        );
+    }
+
+    #[test]
+    fn ttls_count_from_the_corrected_time() {
+        let device = Arc::new(AtomicI64::new(REAL - 5 * HOURS));
+        let clock = device_clock(&device);
+        // The server stamps an expiry in real time
+        let mut stamped = Job::plain("Stamped".to_string());
+        stamped.expires_at = Some(REAL + 60);
+        // Uncorrected, the device wouldn't expire it for five hours
+        device.fetch_add(60, Ordering::Relaxed);
+        assert!(!stamped.is_expired_at(clock.now().timestamp()));
+        device.fetch_sub(60, Ordering::Relaxed);
+
+        clock.set_server_time(REAL);
+        let mut job = Job::plain("Soup".to_string());
+        job.ttl_secs = Some(60);
+        job.apply_ttl_at(300, clock.now().timestamp());
+        assert_eq!(job.expires_at, Some(REAL + 60));
+        device.fetch_add(59, Ordering::Relaxed);
+        assert!(!job.is_expired_at(clock.now().timestamp()));
+        assert!(!stamped.is_expired_at(clock.now().timestamp()));
+        device.fetch_add(1, Ordering::Relaxed);
+        assert!(job.is_expired_at(clock.now().timestamp()));
+        assert!(stamped.is_expired_at(clock.now().timestamp()));
+    }
+
+    #[test]
+    fn the_default_ttl_applies_and_zero_never_expires() {
+        let mut job = Job::plain("Soup".to_string());
+        job.apply_ttl_at(300, REAL);
+        assert_eq!(job.expires_at, Some(REAL + 300));
+        // An expiry already set is kept
+        job.apply_ttl_at(10, REAL + 1000);
+        assert_eq!(job.expires_at, Some(REAL + 300));
+
+        let mut job = Job::plain("Soup".to_string());
+        job.apply_ttl_at(0, REAL);
+        assert_eq!(job.expires_at, None);
+        assert!(!job.is_expired_at(i64::MAX));
+    }
+}
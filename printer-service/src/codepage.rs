@@ -0,0 +1,90 @@
+//! Code page 437, the ESC/POS character set printers start in after init,
+//! and the plain-ASCII subset used on printers that may start in another.
+
+/// Characters 0x80-0xFF of code page 437.
+#[rustfmt::skip]
+const CP437_HIGH: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å',
+    'É', 'æ', 'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ',
+    'á', 'í', 'ó', 'ú', 'ñ', 'Ñ', 'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»',
+    '░', '▒', '▓', '│', '┤', '╡', '╢', '╖', '╕', '╣', '║', '╗', '╝', '╜', '╛', '┐',
+    '└', '┴', '┬', '├', '─', '┼', '╞', '╟', '╚', '╔', '╩', '╦', '╠', '═', '╬', '╧',
+    '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘', '┌', '█', '▄', '▌', '▐', '▀',
+    'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩',
+    '≡', '±', '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²', '■', '\u{A0}',
+];
+
+/// The character set text is encoded in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CodePage {
+    Cp437,
+    /// Printable ASCII only, for printers that might not start in CP437
+    Ascii,
+}
+
+impl CodePage {
+    /// The byte printing `c`, if there is one.
+    pub fn encode(self, c: char) -> Option<u8> {
+        if c.is_ascii() {
+            return (!c.is_ascii_control()).then_some(c as u8);
+        }
+        match self {
+            CodePage::Cp437 => CP437_HIGH
+                .iter()
+                .position(|&high| high == c)
+                .map(|i| 0x80 + i as u8),
+            CodePage::Ascii => None,
+        }
+    }
+}
+
+/// The character CP437 byte `b` prints, for printable bytes.
+pub fn cp437_char(b: u8) -> Option<char> {
+    match b {
+        0x20..=0x7E => Some(b as char),
+        0x80.. => Some(CP437_HIGH[(b - 0x80) as usize]),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn printable_ascii_is_itself_on_both_pages() {
+        for page in [CodePage::Cp437, CodePage::Ascii] {
+            assert_eq!(page.encode('A'), Some(b'A'));
+            assert_eq!(page.encode(' '), Some(b' '));
+            assert_eq!(page.encode('~'), Some(b'~'));
+            // Control characters never reach the printer as text
+            assert_eq!(page.encode('\n'), None);
+            assert_eq!(page.encode('\x1b'), None);
+            assert_eq!(page.encode('\x7f'), None);
+        }
+    }
+
+    #[test]
+    fn cp437_has_accents_and_box_drawing_and_ascii_has_neither() {
+        assert_eq!(CodePage::Cp437.encode('é'), Some(0x82));
+        assert_eq!(CodePage::Cp437.encode('£'), Some(0x9C));
+        assert_eq!(CodePage::Cp437.encode('─'), Some(0xC4));
+        assert_eq!(CodePage::Cp437.encode('\u{A0}'), Some(0xFF));
+        assert_eq!(CodePage::Ascii.encode('é'), None);
+        assert_eq!(CodePage::Ascii.encode('─'), None);
+        // Neither has macrons or CJK
+        assert_eq!(CodePage::Cp437.encode('ā'), None);
+        assert_eq!(CodePage::Cp437.encode('中'), None);
+    }
+
+    #[test]
+    fn every_printable_byte_decodes_back_to_itself() {
+        for b in (0x20..=0x7E).chain(0x80..=0xFF) {
+            let c = cp437_char(b).unwrap();
+            assert_eq!(CodePage::Cp437.encode(c), Some(b), "{:?}", c);
+        }
+        for b in (0x00..0x20).chain([0x7F]) {
+            assert_eq!(cp437_char(b), None, "{:#04x}", b);
+        }
+    }
+}
@@ -0,0 +1,131 @@
+//! Commands whose bytes differ between printer vendors.
+//!
+//! Everything else (text, styles, QR codes) goes through the escpos printer
+//! as usual; the profile's [`CommandSet`] only decides how these few commands
+//! are spelled, so both backends share the rest of the render pipeline.
+
+const ESC: u8 = 0x1B;
+const GS: u8 = 0x1D;
+const LF: u8 = 0x0A;
+const BEL: u8 = 0x07;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandSet {
+    EscPos,
+    /// Star Line Mode (TSP100/TSP650 and friends), where `ESC d` is a cut
+    /// rather than a feed
+    Star,
+}
+
+impl CommandSet {
+    /// Prints the current line and feeds `lines` lines.
+    pub fn feed(self, lines: u8) -> Vec<u8> {
+        match self {
+            CommandSet::EscPos => vec![ESC, b'd', lines],
+            CommandSet::Star => vec![LF; lines as usize],
+        }
+    }
+
+    /// Prints the current line and feeds `dots` dot rows.
+    pub fn feed_dots(self, dots: u8) -> Vec<u8> {
+        match self {
+            CommandSet::EscPos => vec![ESC, b'J', dots],
+            // Star feeds in 1/4 mm steps, two dots at 203 dpi
+            CommandSet::Star => vec![ESC, b'J', dots.div_ceil(2)],
+        }
+    }
+
+    /// Feeds to the cutter and does a full cut.
+    pub fn cut(self) -> Vec<u8> {
+        match self {
+            CommandSet::EscPos => vec![GS, b'V', b'A', 0],
+            CommandSet::Star => vec![ESC, b'd', 2],
+        }
+    }
+
+    /// Pulses the cash drawer on connector 1.
+    pub fn cash_drawer(self) -> Vec<u8> {
+        match self {
+            // Pin 2, 50 ms on, 500 ms off
+            CommandSet::EscPos => vec![ESC, b'p', 0, 25, 250],
+            // Pulse width comes from the printer's memory switch settings
+            CommandSet::Star => vec![BEL],
+        }
+    }
+
+    /// A 1-bit raster image, `width_bytes` bytes (8 dots each) per row, rows
+    /// top to bottom. A short final row is padded with white.
+    pub fn raster(self, width_bytes: usize, data: &[u8]) -> Vec<u8> {
+        let width_bytes = width_bytes.max(1);
+        let rows = data.len().div_ceil(width_bytes);
+        let mut padded = data.to_vec();
+        padded.resize(rows * width_bytes, 0);
+
+        match self {
+            CommandSet::EscPos => {
+                let mut out = vec![GS, b'v', b'0', 0];
+                out.extend_from_slice(&(width_bytes as u16).to_le_bytes());
+                out.extend_from_slice(&(rows as u16).to_le_bytes());
+                out.extend_from_slice(&padded);
+                out
+            }
+            CommandSet::Star => {
+                let mut out = vec![ESC, b'*', b'r', b'A'];
+                for row in padded.chunks(width_bytes) {
+                    out.push(b'b');
+                    out.extend_from_slice(&(width_bytes as u16).to_le_bytes());
+                    out.extend_from_slice(row);
+                }
+                out.extend_from_slice(&[ESC, b'*', b'r', b'B']);
+                out
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn feeds() {
+        assert_eq!(CommandSet::EscPos.feed(3), [ESC, b'd', 3]);
+        assert_eq!(CommandSet::Star.feed(3), [LF, LF, LF]);
+        assert!(CommandSet::Star.feed(0).is_empty());
+        assert_eq!(CommandSet::EscPos.feed_dots(7), [ESC, b'J', 7]);
+        // Star's quarter millimetres, rounded up so it never feeds less
+        assert_eq!(CommandSet::Star.feed_dots(7), [ESC, b'J', 4]);
+        assert_eq!(CommandSet::Star.feed_dots(255), [ESC, b'J', 128]);
+    }
+
+    #[test]
+    fn cut_and_cash_drawer() {
+        assert_eq!(CommandSet::EscPos.cut(), [GS, b'V', b'A', 0]);
+        assert_eq!(CommandSet::Star.cut(), [ESC, b'd', 2]);
+        assert_eq!(CommandSet::EscPos.cash_drawer(), [ESC, b'p', 0, 25, 250]);
+        assert_eq!(CommandSet::Star.cash_drawer(), [BEL]);
+    }
+
+    #[test]
+    fn raster_images() {
+        let data = [0xF0, 0x0F, 0xAA, 0x55, 0xFF];
+        assert_eq!(
+            CommandSet::EscPos.raster(2, &data),
+            [
+                GS, b'v', b'0', 0, 2, 0, 3, 0, 0xF0, 0x0F, 0xAA, 0x55, 0xFF, 0x00
+            ]
+        );
+        assert_eq!(
+            CommandSet::Star.raster(2, &data),
+            [
+                ESC, b'*', b'r', b'A', b'b', 2, 0, 0xF0, 0x0F, b'b', 2, 0, 0xAA, 0x55, b'b', 2, 0,
+                0xFF, 0x00, ESC, b'*', b'r', b'B'
+            ]
+        );
+        // A width of 0 is taken as 1 rather than dividing by it
+        assert_eq!(
+            CommandSet::EscPos.raster(0, &[0x80]),
+            [GS, b'v', b'0', 0, 1, 0, 1, 0, 0x80]
+        );
+    }
+}
@@ -0,0 +1,377 @@
+//! Guards on the server's control commands, so a compromised or buggy server
+//! can't spam destructive ones. Every command has a risk level and a limit
+//! on how many are accepted a minute, which depends on its risk unless set.
+//! Commands at or above `--command-auth` only run when they carry `auth`,
+//! the HMAC with the signing key of a one-time challenge from this device.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use anyhow::{Context, Result, anyhow, bail};
+use log::info;
+use rand::Rng;
+use tokio::time::Instant;
+
+use crate::protocol::ErrorCode;
+use crate::ratelimit::RateLimiter;
+use crate::signing::Signer;
+
+/// How long a challenge can be answered for
+const CHALLENGE_TTL: Duration = Duration::from_secs(60);
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Risk {
+    /// Only reads state
+    Low,
+    /// Holds up or changes printing, but is easily undone
+    Medium,
+    /// Changes config or loses state
+    High,
+}
+
+impl Risk {
+    /// Commands accepted a minute unless `--command-limits` sets it
+    fn default_limit(self) -> u32 {
+        match self {
+            Risk::Low => 60,
+            Risk::Medium => 10,
+            Risk::High => 6,
+        }
+    }
+}
+
+/// Every command, with its risk unless `--command-risk` changes it
+const COMMANDS: &[(&str, Risk)] = &[
+    ("status", Risk::Low),
+    ("report", Risk::Low),
+    ("lookup", Risk::Low),
+    ("time", Risk::Medium),
+    ("pause", Risk::Medium),
+    ("resume", Risk::Medium),
+    ("compact", Risk::Medium),
+    ("flush_cache", Risk::Medium),
+    ("reload", Risk::High),
+    ("maintenance_reset", Risk::High),
+    ("rotate_key", Risk::High),
+];
+
+/// `--command-auth`: the lowest risk that needs authorizing.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandAuth {
+    Off,
+    Low,
+    Medium,
+    High,
+}
+
+impl CommandAuth {
+    pub fn lowest(self) -> Option<Risk> {
+        match self {
+            CommandAuth::Off => None,
+            CommandAuth::Low => Some(Risk::Low),
+            CommandAuth::Medium => Some(Risk::Medium),
+            CommandAuth::High => Some(Risk::High),
+        }
+    }
+}
+
+/// Risk levels and limits changed from the defaults, and what needs authorizing.
+#[derive(Debug, Clone, Default)]
+pub struct CommandPolicy {
+    pub risks: HashMap<String, Risk>,
+    /// Per minute; 0 is unlimited
+    pub limits: HashMap<String, u32>,
+    pub auth: Option<Risk>,
+}
+
+/// Parses `--command-risk`, e.g. `pause=high,lookup=medium`.
+pub fn parse_risks(spec: &str) -> Result<HashMap<String, Risk>> {
+    parse_map(spec, |value| {
+        clap::ValueEnum::from_str(value, true).map_err(|_| anyhow!("expected low, medium or high"))
+    })
+}
+
+/// Parses `--command-limits`, e.g. `status=120,reload=1`.
+pub fn parse_limits(spec: &str) -> Result<HashMap<String, u32>> {
+    parse_map(spec, |value| Ok(value.parse()?))
+}
+
+fn parse_map<T>(spec: &str, parse: impl Fn(&str) -> Result<T>) -> Result<HashMap<String, T>> {
+    let mut map = HashMap::new();
+    for pair in spec
+        .split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+    {
+        let (command, value) = pair
+            .split_once('=')
+            .with_context(|| format!("Expected command=value, got {:?}", pair))?;
+        if !COMMANDS.iter().any(|(name, _)| *name == command) {
+            let names: Vec<&str> = COMMANDS.iter().map(|(name, _)| *name).collect();
+            bail!(
+                "Unknown command {:?} (expected one of {})",
+                command,
+                names.join(", ")
+            );
+        }
+        let value =
+            parse(value).with_context(|| format!("Invalid value for {}: {:?}", command, value))?;
+        map.insert(command.to_string(), value);
+    }
+    Ok(map)
+}
+
+/// Why a command was refused without being run.
+pub struct Refused {
+    pub error: ErrorCode,
+    pub message: String,
+    /// For `UNAUTHORIZED`: what to sign to send the command again
+    pub challenge: Option<String>,
+}
+
+pub struct Control {
+    risks: HashMap<&'static str, Risk>,
+    limiters: HashMap<&'static str, (u32, RateLimiter)>,
+    /// The lowest risk that needs authorizing, and the key that checks it
+    auth: Option<(Risk, Signer)>,
+    /// The challenge handed out for each command and when it expires
+    challenges: HashMap<&'static str, (String, Instant)>,
+}
+
+impl Control {
+    pub fn new(policy: &CommandPolicy, signer: Option<Signer>) -> Self {
+        let mut risks = HashMap::new();
+        let mut limiters = HashMap::new();
+        for &(name, default) in COMMANDS {
+            let risk = policy.risks.get(name).copied().unwrap_or(default);
+            let limit = policy
+                .limits
+                .get(name)
+                .copied()
+                .unwrap_or(risk.default_limit());
+            risks.insert(name, risk);
+            if limit > 0 {
+                limiters.insert(
+                    name,
+                    (limit, RateLimiter::new(limit, limit, Duration::ZERO)),
+                );
+            }
+        }
+        let auth = policy.auth.zip(signer);
+        if let Some((lowest, _)) = &auth {
+            let needing: Vec<&str> = COMMANDS
+                .iter()
+                .map(|(name, _)| *name)
+                .filter(|name| risks[name] >= *lowest)
+                .collect();
+            info!(
+                "Control commands needing authorization: {}",
+                needing.join(", ")
+            );
+        }
+        Self {
+            risks,
+            limiters,
+            auth,
+            challenges: HashMap::new(),
+        }
+    }
+
+    /// Decides whether `command` may run now. Only a command that's let
+    /// through counts towards its limit, so refused attempts can't use up
+    /// the limit before the authorized one arrives.
+    pub fn admit(&mut self, command: &'static str, auth: Option<&str>) -> Result<(), Refused> {
+        let now = Instant::now();
+        if let Some((limit, limiter)) = self.limiters.get_mut(command)
+            && limiter.next_allowed(now) > now
+        {
+            return Err(Refused {
+                error: ErrorCode::RateLimited,
+                message: format!(
+                    "At most {} {} commands are accepted a minute",
+                    limit, command
+                ),
+                challenge: None,
+            });
+        }
+        self.authorize(command, auth, now)?;
+        if let Some((_, limiter)) = self.limiters.get_mut(command) {
+            limiter.record(now);
+        }
+        Ok(())
+    }
+
+    /// Checks the authorization of a command that needs it. A command
+    /// needing authorization gets a fresh challenge whenever it's refused,
+    /// and a challenge is used up by the first attempt to answer it, so a
+    /// captured `auth` can't be replayed.
+    fn authorize(
+        &mut self,
+        command: &'static str,
+        auth: Option<&str>,
+        now: Instant,
+    ) -> Result<(), Refused> {
+        let Some((lowest, signer)) = &self.auth else {
+            return Ok(());
+        };
+        if self.risks.get(command).is_none_or(|risk| risk < lowest) {
+            return Ok(());
+        }
+        let issued = self.challenges.remove(command);
+        if let (Some(auth), Some((challenge, expires))) = (auth, &issued)
+            && now < *expires
+            && signer.verify_challenge(challenge, command, auth)
+        {
+            return Ok(());
+        }
+
+        let challenge = hex::encode(rand::rng().random::<[u8; 16]>());
+        self.challenges
+            .insert(command, (challenge.clone(), now + CHALLENGE_TTL));
+        let message = match auth {
+            Some(_) => format!(
+                "Wrong, expired or reused authorization for {}; sign the new challenge",
+                command
+            ),
+            None => format!(
+                "{} needs authorization: send it again within {} seconds with \"auth\" set to the hex HMAC-SHA256 of \"{}.{}\"",
+                command,
+                CHALLENGE_TTL.as_secs(),
+                challenge,
+                command
+            ),
+        };
+        Err(Refused {
+            error: ErrorCode::Unauthorized,
+            message,
+            challenge: Some(challenge),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hmac::{Hmac, KeyInit, Mac};
+    use sha2::Sha256;
+
+    use super::*;
+
+    const KEY: &[u8] = b"device key";
+
+    fn control(limits: &[(&str, u32)], auth: Option<Risk>) -> Control {
+        let policy = CommandPolicy {
+            risks: HashMap::new(),
+            limits: limits
+                .iter()
+                .map(|(name, limit)| (name.to_string(), *limit))
+                .collect(),
+            auth,
+        };
+        Control::new(&policy, Some(Signer::new(KEY.to_vec(), 300).unwrap()))
+    }
+
+    fn answer(challenge: &str, command: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(KEY).unwrap();
+        mac.update(format!("{}.{}", challenge, command).as_bytes());
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    /// The challenge a refused command was given.
+    fn challenge(refused: Result<(), Refused>) -> String {
+        let refused = refused.expect_err("the command was let through");
+        assert_eq!(refused.error, ErrorCode::Unauthorized);
+        refused.challenge.unwrap()
+    }
+
+    #[test]
+    fn commands_past_their_burst_are_rate_limited() {
+        let mut control = control(&[("status", 3), ("report", 0)], None);
+        for _ in 0..3 {
+            assert!(control.admit("status", None).is_ok());
+        }
+        let refused = control.admit("status", None).unwrap_err();
+        assert_eq!(refused.error, ErrorCode::RateLimited);
+        assert!(refused.challenge.is_none());
+        assert!(refused.message.contains("At most 3 status"));
+        // Each command has its own limit, and 0 is none
+        assert!(control.admit("lookup", None).is_ok());
+        for _ in 0..100 {
+            assert!(control.admit("report", None).is_ok());
+        }
+    }
+
+    #[test]
+    fn only_authorized_attempts_count_towards_the_limit() {
+        let mut control = control(&[("reload", 2)], Some(Risk::High));
+        let mut issued = String::new();
+        for _ in 0..10 {
+            issued = challenge(control.admit("reload", None));
+        }
+        let auth = answer(&issued, "reload");
+        assert!(control.admit("reload", Some(&auth)).is_ok());
+
+        let issued = challenge(control.admit("reload", None));
+        assert!(
+            control
+                .admit("reload", Some(&answer(&issued, "reload")))
+                .is_ok()
+        );
+        let refused = control.admit("reload", None).unwrap_err();
+        assert_eq!(refused.error, ErrorCode::RateLimited);
+    }
+
+    #[test]
+    fn a_challenge_can_only_be_answered_once() {
+        let mut control = control(&[], Some(Risk::Medium));
+        let issued = challenge(control.admit("pause", None));
+        let auth = answer(&issued, "pause");
+        assert!(control.admit("pause", Some(&auth)).is_ok());
+
+        let fresh = challenge(control.admit("pause", Some(&auth)));
+        assert_ne!(fresh, issued);
+        // A wrong answer uses the challenge up too
+        let wrong = answer(&fresh, "resume");
+        challenge(control.admit("pause", Some(&wrong)));
+        assert!(
+            control
+                .admit("pause", Some(&answer(&fresh, "pause")))
+                .is_err()
+        );
+        // Challenges are per command
+        let other = challenge(control.admit("resume", None));
+        assert!(
+            control
+                .admit("pause", Some(&answer(&other, "pause")))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn commands_below_the_auth_risk_run_unchallenged() {
+        let mut control = control(&[], Some(Risk::High));
+        assert!(control.admit("status", None).is_ok());
+        assert!(control.admit("pause", None).is_ok());
+        challenge(control.admit("rotate_key", None));
+
+        let mut control = Control::new(
+            &CommandPolicy {
+                auth: Some(Risk::Low),
+                ..CommandPolicy::default()
+            },
+            None,
+        );
+        // Without a key there's nothing to check authorization with
+        assert!(control.admit("reload", None).is_ok());
+    }
+
+    #[test]
+    fn policies_only_name_known_commands() {
+        let risks = parse_risks("pause=high, lookup=MEDIUM").unwrap();
+        assert_eq!(risks["pause"], Risk::High);
+        assert_eq!(risks["lookup"], Risk::Medium);
+        assert!(parse_risks("pause=extreme").is_err());
+        assert!(parse_risks("print=low").is_err());
+        assert_eq!(parse_limits("status=120,reload=0").unwrap()["reload"], 0);
+        assert!(parse_limits("status").is_err());
+    }
+}
@@ -0,0 +1,377 @@
+//! Jobs that must never be retried automatically (they made the service
+//! panic), kept for someone to look at. With a spool dir they're appended to
+//! `<dir>/dead-letter.jsonl`, one JSON object per line, sealed like the spool
+//! and journal when encrypting at rest. A full file is moved to
+//! `dead-letter.1.jsonl`, dropping the one before, so the two together stay
+//! under twice `MAX_FILE_BYTES`.
+
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::atrest::{AtRest, Keys, Sealed};
+use crate::clock;
+use crate::protocol::Job;
+
+const DEAD_LETTER_FILE: &str = "dead-letter.jsonl";
+const ROTATED_FILE: &str = "dead-letter.1.jsonl";
+/// Size the file is rotated at
+const MAX_FILE_BYTES: u64 = 256 * 1024;
+const AAD: &str = "dead letter";
+/// Ids listed in the `status` reply; the files keep more
+const RECENT: usize = 50;
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    /// RFC 3339
+    time: String,
+    reason: String,
+    job: Job,
+}
+
+/// A line of a dead letter file.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Line {
+    Sealed(Sealed),
+    Entry(Box<Entry>),
+}
+
+pub struct DeadLetters {
+    dir: Option<PathBuf>,
+    at_rest: AtRest,
+    /// Ids of the most recent entries, oldest first
+    recent: VecDeque<String>,
+}
+
+impl DeadLetters {
+    /// Opens the dead letters in `dir`, reading them a line at a time.
+    /// Entries stored the other way than `at_rest` says are rewritten first,
+    /// as the journal's are; only that, or finding sealed entries without the
+    /// keys, can fail.
+    pub fn open(dir: Option<&Path>, at_rest: AtRest) -> Result<Self> {
+        let mut letters = Self {
+            dir: dir.map(Path::to_path_buf),
+            at_rest,
+            recent: VecDeque::new(),
+        };
+        let Some(dir) = dir else {
+            return Ok(letters);
+        };
+        for path in files(dir).iter().filter(|path| path.exists()) {
+            if letters.scan(path)? {
+                let sealing = letters.at_rest.sealing().is_some();
+                let how = if sealing { "Encrypting" } else { "Decrypting" };
+                info!("{} dead letters {}", how, path.display());
+                letters.rewrite(path)?;
+            }
+        }
+        Ok(letters)
+    }
+
+    pub fn add(&mut self, job: &Job, reason: &str) {
+        self.remember(job.id.clone().unwrap_or_default());
+        let Some(dir) = &self.dir else {
+            return;
+        };
+        let path = dir.join(DEAD_LETTER_FILE);
+        if fs::metadata(&path).is_ok_and(|meta| meta.len() >= MAX_FILE_BYTES)
+            && let Err(e) = fs::rename(&path, dir.join(ROTATED_FILE))
+        {
+            error!("Failed to rotate dead letters {}: {}", path.display(), e);
+        }
+        let entry = Entry {
+            time: clock::now().to_rfc3339(),
+            reason: reason.to_string(),
+            job: job.clone(),
+        };
+        let line = self.store(&entry);
+        let result = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .and_then(|mut file| writeln!(file, "{}", line));
+        if let Err(e) = result {
+            error!("Failed to save dead letter {}: {}", path.display(), e);
+        }
+    }
+
+    /// Ids of the most recent dead letters, oldest first.
+    pub fn recent(&self) -> Vec<String> {
+        self.recent.iter().cloned().collect()
+    }
+
+    /// Switches to `keys` and rewrites the files with them.
+    pub fn rotate_key(&mut self, keys: Keys) -> Result<()> {
+        self.at_rest = AtRest::Encrypted(keys);
+        let Some(dir) = &self.dir else {
+            return Ok(());
+        };
+        for path in files(dir).iter().filter(|path| path.exists()) {
+            self.rewrite(path)?;
+        }
+        Ok(())
+    }
+
+    fn remember(&mut self, id: String) {
+        if self.recent.len() >= RECENT {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(id);
+    }
+
+    /// Remembers the ids in `path`, saying whether any entries are stored
+    /// the other way than `at_rest` says. A file that can't be read is
+    /// skipped.
+    fn scan(&mut self, path: &Path) -> Result<bool> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) => {
+                warn!("Failed to read dead letters {}: {}", path.display(), e);
+                return Ok(false);
+            }
+        };
+        let sealing = self.at_rest.sealing().is_some();
+        let mut mismatched = false;
+        for line in BufReader::new(file).lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(e) => {
+                    warn!("Failed to read dead letters {}: {}", path.display(), e);
+                    break;
+                }
+            };
+            // A line cut short by a crash mid-write is skipped
+            let Ok(line) = serde_json::from_str::<Line>(&line) else {
+                continue;
+            };
+            mismatched |= matches!(line, Line::Sealed(_)) != sealing;
+            if let Some(entry) = self.restore(line, path)? {
+                self.remember(entry.job.id.unwrap_or_default());
+            }
+        }
+        Ok(mismatched)
+    }
+    /// An entry as it's written, sealed if encrypting.
+    fn store(&self, entry: &Entry) -> String {
+        let json = serde_json::to_string(entry).expect("dead letter serializes");
+        match self.at_rest.sealing() {
+            Some(keys) => serde_json::to_string(&keys.seal(json.as_bytes(), AAD))
+                .expect("sealed dead letter serializes"),
+            None => json,
+        }
+    }
+
+    /// A line read back into an entry; an error for a sealed one that can't
+    /// be opened.
+    fn restore(&self, line: Line, path: &Path) -> Result<Option<Entry>> {
+        match line {
+            Line::Entry(entry) => Ok(Some(*entry)),
+            Line::Sealed(sealed) => {
+                let keys = self.at_rest.keys().ok_or_else(|| {
+                    AtRest::locked(&format!("Dead letter file {}", path.display()))
+                })?;
+                let json = keys
+                    .open(&sealed, AAD)
+                    .with_context(|| format!("Dead letter file {}", path.display()))?;
+                Ok(serde_json::from_slice(&json).ok())
+            }
+        }
+    }
+
+    /// Rewrites `path` with every readable entry stored as `at_rest` says,
+    /// beside the old file and renamed over it.
+    fn rewrite(&self, path: &Path) -> Result<()> {
+        let file =
+            File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+        let tmp_path = path.with_extension("tmp");
+        let mut tmp = File::create(&tmp_path)
+            .with_context(|| format!("Failed to create {}", tmp_path.display()))?;
+        for line in BufReader::new(file).lines() {
+            let line = line.with_context(|| format!("Failed to read {}", path.display()))?;
+            let Ok(line) = serde_json::from_str::<Line>(&line) else {
+                continue;
+            };
+            if let Some(entry) = self.restore(line, path)? {
+                writeln!(tmp, "{}", self.store(&entry))?;
+            }
+        }
+        tmp.sync_all()?;
+        fs::rename(&tmp_path, path)
+            .with_context(|| format!("Failed to replace {}", path.display()))?;
+        if let Some(dir) = path.parent() {
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+/// The dead letter files in `dir`, oldest first.
+fn files(dir: &Path) -> [PathBuf; 2] {
+    [dir.join(ROTATED_FILE), dir.join(DEAD_LETTER_FILE)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tempdir::TempDir;
+
+    const SECRET: &[u8] = b"0123456789abcdef0123456789abcdef";
+
+    fn job(id: Option<&str>) -> Job {
+        let mut job = Job::plain("Soup".to_string());
+        job.id = id.map(str::to_string);
+        job
+    }
+
+    #[test]
+    fn dead_letters_are_appended_and_read_back() {
+        let dir = TempDir::new("deadletter");
+        let mut letters = DeadLetters::open(Some(dir.path()), AtRest::Plain).unwrap();
+        assert!(letters.recent().is_empty());
+        letters.add(&job(Some("7")), "panicked: out of range");
+        letters.add(&job(None), "panicked: bad font");
+        assert_eq!(letters.recent(), ["7", ""]);
+
+        let text = fs::read_to_string(dir.path().join(DEAD_LETTER_FILE)).unwrap();
+        let entries: Vec<Entry> = text
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].reason, "panicked: out of range");
+        assert_eq!(entries[0].job.text, "Soup");
+        assert!(chrono::DateTime::parse_from_rfc3339(&entries[1].time).is_ok());
+
+        let reopened = DeadLetters::open(Some(dir.path()), AtRest::Plain).unwrap();
+        assert_eq!(reopened.recent(), ["7", ""]);
+    }
+
+    #[test]
+    fn only_the_latest_ids_are_listed() {
+        let dir = TempDir::new("deadletter");
+        let mut letters = DeadLetters::open(Some(dir.path()), AtRest::Plain).unwrap();
+        for n in 0..RECENT + 5 {
+            letters.add(&job(Some(&n.to_string())), "panicked");
+        }
+        let expected: Vec<String> = (5..RECENT + 5).map(|n| n.to_string()).collect();
+        assert_eq!(letters.recent(), expected);
+        // The file keeps them all while it's small
+        let text = fs::read_to_string(dir.path().join(DEAD_LETTER_FILE)).unwrap();
+        assert_eq!(text.lines().count(), RECENT + 5);
+        assert_eq!(
+            DeadLetters::open(Some(dir.path()), AtRest::Plain)
+                .unwrap()
+                .recent(),
+            expected
+        );
+    }
+
+    #[test]
+    fn full_files_are_rotated_and_the_oldest_dropped() {
+        let dir = TempDir::new("deadletter");
+        let mut letters = DeadLetters::open(Some(dir.path()), AtRest::Plain).unwrap();
+        let mut big = job(None);
+        big.text = "x".repeat(10 * 1024);
+        for n in 0..100 {
+            big.id = Some(n.to_string());
+            letters.add(&big, "panicked");
+        }
+        let sizes: Vec<u64> = files(dir.path())
+            .iter()
+            .map(|path| fs::metadata(path).unwrap().len())
+            .collect();
+        assert!(sizes[0] >= MAX_FILE_BYTES, "{:?}", sizes);
+        assert!(sizes.iter().sum::<u64>() < 2 * MAX_FILE_BYTES + 11 * 1024);
+
+        // The oldest entries are gone, and both files are read back
+        let kept: Vec<Entry> = files(dir.path())
+            .iter()
+            .flat_map(|path| {
+                let text = fs::read_to_string(path).unwrap();
+                text.lines()
+                    .map(|line| serde_json::from_str(line).unwrap())
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        assert!(kept.len() < 100 && kept[0].job.id.as_deref() != Some("0"));
+        let reopened = DeadLetters::open(Some(dir.path()), AtRest::Plain).unwrap();
+        let expected: Vec<String> = kept
+            .iter()
+            .rev()
+            .take(RECENT)
+            .rev()
+            .map(|entry| entry.job.id.clone().unwrap())
+            .collect();
+        assert_eq!(reopened.recent(), expected);
+        assert_eq!(expected.last().unwrap(), "99");
+    }
+
+    #[test]
+    fn unreadable_lines_are_skipped() {
+        let dir = TempDir::new("deadletter");
+        let mut letters = DeadLetters::open(Some(dir.path()), AtRest::Plain).unwrap();
+        letters.add(&job(Some("1")), "panicked");
+        let path = dir.path().join(DEAD_LETTER_FILE);
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        writeln!(file, "{{\"time\":\"cut off").unwrap();
+        drop(file);
+        letters.add(&job(Some("2")), "panicked");
+        assert_eq!(
+            DeadLetters::open(Some(dir.path()), AtRest::Plain)
+                .unwrap()
+                .recent(),
+            ["1", "2"]
+        );
+    }
+
+    #[test]
+    fn without_a_spool_dir_ids_are_only_kept_in_memory() {
+        let mut letters = DeadLetters::open(None, AtRest::Plain).unwrap();
+        letters.add(&job(Some("7")), "panicked");
+        assert_eq!(letters.recent(), ["7"]);
+    }
+
+    #[test]
+    fn encrypted_dead_letters_are_sealed_and_migrated_both_ways() {
+        let dir = TempDir::new("deadletter");
+        let mut letters = DeadLetters::open(Some(dir.path()), AtRest::Plain).unwrap();
+        let mut soup = job(Some("7"));
+        soup.text = "Table 4: Ana".to_string();
+        letters.add(&soup, "panicked");
+        let path = dir.path().join(DEAD_LETTER_FILE);
+        let keys = Keys::new(SECRET, dir.path()).unwrap();
+
+        // Plaintext entries are encrypted on opening, and new ones sealed
+        let encrypted = AtRest::Encrypted(keys.clone());
+        let mut letters = DeadLetters::open(Some(dir.path()), encrypted.clone()).unwrap();
+        letters.add(&job(Some("8")), "panicked");
+        let text = fs::read_to_string(&path).unwrap();
+        assert!(!text.contains("Ana") && !text.contains("Soup"), "{}", text);
+        assert_eq!(text.lines().count(), 2);
+        assert_eq!(letters.recent(), ["7", "8"]);
+
+        // Without the keys they're locked
+        assert!(DeadLetters::open(Some(dir.path()), AtRest::Plain).is_err());
+
+        // They follow the key on to the next generation
+        let mut next = keys.clone();
+        next.rotate().unwrap();
+        letters.rotate_key(next.clone()).unwrap();
+        let text = fs::read_to_string(&path).unwrap();
+        let sealed: Vec<Sealed> = text
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert!(sealed.iter().all(|sealed| sealed.key == 1));
+
+        let decrypted = DeadLetters::open(Some(dir.path()), AtRest::Decrypting(next)).unwrap();
+        assert_eq!(decrypted.recent(), ["7", "8"]);
+        assert!(fs::read_to_string(&path).unwrap().contains("Table 4: Ana"));
+    }
+}
@@ -0,0 +1,175 @@
+//! `--demo`: made-up café orders on a timer, for showing the kiosk at trade
+//! shows with no server and usually no printer (`--mock-pretty`). Orders go
+//! in as the JSON a server would send, so they're parsed, filtered, queued,
+//! spooled and rendered like real ones.
+
+use rand::Rng;
+use rand::seq::IndexedRandom;
+use serde_json::Value;
+
+use crate::protocol::{Job, RuleStyle, Segment, TextSegment};
+
+/// Name and price in cents
+const MENU: &[(&str, u32)] = &[
+    ("Flat white", 450),
+    ("Long black", 400),
+    ("Oat latte", 520),
+    ("Chai latte", 500),
+    ("Hot chocolate", 480),
+    ("Iced coffee", 650),
+    ("Fresh orange juice", 700),
+    ("Blueberry muffin", 500),
+    ("Banana bread", 550),
+    ("Ham & cheese toastie", 950),
+    ("Avocado toast", 1650),
+    ("Big breakfast", 2200),
+];
+
+const NAMES: &[&str] = &[
+    "Aroha", "Ben", "Chloe", "Dev", "Emma", "Finn", "Grace", "Hemi", "Isla", "Jack", "Mia", "Noah",
+];
+
+const NOTES: &[&str] = &[
+    "Extra hot please",
+    "No sugar",
+    "Gluten free bread",
+    "Allergy: tree nuts",
+    "Oat milk on all drinks",
+    "Make it decaf",
+];
+
+/// The job message for demo order `n`, laid out for a printer `columns` wide.
+pub fn order(n: u64, columns: usize) -> String {
+    let mut rng = rand::rng();
+    let place = if rng.random_bool(0.5) {
+        format!("Table {}", rng.random_range(1..=20))
+    } else {
+        format!(
+            "Takeaway - {}",
+            NAMES.choose(&mut rng).expect("names are not empty")
+        )
+    };
+
+    let mut lines = Vec::new();
+    let mut total = 0;
+    for _ in 0..rng.random_range(1..=5) {
+        let (name, price) = MENU.choose(&mut rng).expect("menu is not empty");
+        let quantity = rng.random_range(1..=3);
+        total += price * quantity;
+        lines.push(priced(
+            &format!("{}x {}", quantity, name),
+            price * quantity,
+            columns,
+        ));
+    }
+
+    let mut job = Job::plain(String::new());
+    job.id = Some(format!("demo-{}", n));
+    job.kind = Some("receipt".to_string());
+    job.segments = vec![
+        text(format!("Order {}\n{}", 100 + n, place)),
+        Segment::Timestamp {
+            timestamp: "%d/%m/%Y %H:%M".to_string(),
+        },
+        Segment::Rule {
+            rule: RuleStyle::Dash,
+        },
+        text(lines.join("\n")),
+        Segment::Rule {
+            rule: RuleStyle::Dash,
+        },
+        text(priced("Total", total, columns)),
+    ];
+    if rng.random_bool(0.3) {
+        let note = NOTES.choose(&mut rng).expect("notes are not empty");
+        job.segments.push(Segment::Box {
+            segments: vec![text(note.to_string())],
+        });
+    }
+
+    let mut message = serde_json::to_value(&job).expect("job serializes");
+    message["type"] = Value::from("job");
+    message.to_string()
+}
+
+/// `label` with the price right-aligned at `columns`.
+fn priced(label: &str, cents: u32, columns: usize) -> String {
+    let price = format!("{}.{:02}", cents / 100, cents % 100);
+    let width = columns.saturating_sub(price.len() + 1);
+    format!("{:<width$} {}", label, price, width = width)
+}
+
+fn text(text: String) -> Segment {
+    Segment::Text(TextSegment {
+        text,
+        font: None,
+        line_spacing: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::Inbound;
+    use crate::render;
+
+    fn job(message: &str) -> Job {
+        match serde_json::from_str(message).unwrap() {
+            Inbound::Job(job) => job,
+            other => panic!("not a job: {:?}", other),
+        }
+    }
+
+    fn texts(job: &Job) -> Vec<&str> {
+        job.segments
+            .iter()
+            .filter_map(|segment| match segment {
+                Segment::Text(text) => Some(text.text.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The price at the end of a line, in cents.
+    fn cents(line: &str) -> u32 {
+        let (whole, part) = line.rsplit(' ').next().unwrap().split_once('.').unwrap();
+        whole.parse::<u32>().unwrap() * 100 + part.parse::<u32>().unwrap()
+    }
+
+    #[test]
+    fn prices_are_right_aligned() {
+        assert_eq!(priced("Total", 1250, 20), "Total          12.50");
+        assert_eq!(priced("1x Flat white", 5, 20), "1x Flat white   0.05");
+        // Too long for the paper: pushed right, not cut
+        assert_eq!(
+            priced("3x Ham & cheese toastie", 2850, 20),
+            "3x Ham & cheese toastie 28.50"
+        );
+    }
+
+    #[test]
+    fn orders_are_jobs_that_add_up() {
+        let profile = crate::profile::PrinterProfile::find("serial-58mm").unwrap();
+        for n in 1..=50 {
+            let job = job(&order(n, profile.columns));
+            assert_eq!(job.id, Some(format!("demo-{}", n)));
+            assert_eq!(job.kind.as_deref(), Some("receipt"));
+
+            let texts = texts(&job);
+            assert!(texts[0].starts_with(&format!("Order {}\n", 100 + n)));
+            let items: Vec<&str> = texts[1].lines().collect();
+            assert!((1..=5).contains(&items.len()));
+            let total = texts[2];
+            assert!(total.starts_with("Total"));
+            assert_eq!(
+                items.iter().map(|item| cents(item)).sum::<u32>(),
+                cents(total)
+            );
+            for line in items.iter().chain([&total]) {
+                assert_eq!(line.chars().count(), profile.columns, "{:?}", line);
+            }
+
+            render::render_job(&job, profile, None).unwrap();
+        }
+    }
+}
@@ -0,0 +1,450 @@
+//! The `diagnose` subcommand, for installers at a kiosk with no connectivity
+//! and no way to read the logs. Gathers the device id, its addresses, whether
+//! the gateway, DNS and the server answer, the printer profile and the spool
+//! depth, and prints them on a ticket (`--print`), as JSON (`--json`) or as
+//! text. Every network check has a short timeout and they run at the same
+//! time, so the ticket comes out within seconds even when nothing answers.
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::{Context, Result, bail};
+use escpos::utils::JustifyMode;
+use serde::Serialize;
+use tokio::process::Command;
+use tokio::time::timeout;
+
+use crate::clock;
+use crate::commands::CommandSet;
+use crate::config::{self, DeviceConfig};
+use crate::network;
+use crate::provision;
+use crate::render::{Rendered, Ticket};
+use crate::spool;
+use crate::transport;
+
+/// For `ip` and `ping`
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(3);
+const DNS_TIMEOUT: Duration = Duration::from_secs(3);
+const WS_TIMEOUT: Duration = Duration::from_secs(6);
+
+#[derive(clap::Args, Debug)]
+pub struct DiagnoseArgs {
+    /// Device config with the server URL and printer
+    #[arg(long, default_value = config::DEFAULT_CONFIG_PATH)]
+    config: PathBuf,
+
+    /// Server to check instead of the one in the config file
+    #[arg(long)]
+    url: Option<String>,
+
+    /// The service's --spool-dir, to count the jobs waiting in it
+    #[arg(long)]
+    spool_dir: Option<PathBuf>,
+
+    /// Also print the diagnosis on paper
+    #[arg(long)]
+    print: bool,
+
+    /// Print the ticket to the console instead of the printer
+    #[arg(long)]
+    mock: bool,
+
+    /// Write the diagnosis to stdout as JSON
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Diagnosis {
+    pub device_id: String,
+    pub version: &'static str,
+    /// Local time it was taken
+    pub time: String,
+    /// Why the config file couldn't be used, if it couldn't
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub config_error: Option<String>,
+    pub interfaces: Vec<Interface>,
+    pub gateway: Probe,
+    pub dns: Probe,
+    pub websocket: Probe,
+    pub profile: String,
+    /// Jobs accepted but not printed, with `--spool-dir`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub spool_pending: Option<usize>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Interface {
+    pub name: String,
+    pub addresses: Vec<String>,
+}
+
+/// What one check found, or what went wrong.
+#[derive(Serialize, Debug)]
+pub struct Probe {
+    pub ok: bool,
+    pub detail: String,
+}
+
+impl Probe {
+    fn from(result: Result<String>) -> Self {
+        match result {
+            Ok(detail) => Self { ok: true, detail },
+            Err(e) => Self {
+                ok: false,
+                detail: format!("{:#}", e),
+            },
+        }
+    }
+}
+
+pub async fn run(args: &DiagnoseArgs) -> Result<()> {
+    let (device_config, config_error) = match config::load(&args.config) {
+        Ok(device_config) => (device_config, None),
+        Err(e) => (DeviceConfig::default(), Some(format!("{:#}", e))),
+    };
+    let url = args
+        .url
+        .clone()
+        .unwrap_or_else(|| device_config.url.clone());
+
+    let (interfaces, gateway, dns, websocket) =
+        tokio::join!(interfaces(), ping_gateway(), resolve(&url), connect(&url));
+    let diagnosis = Diagnosis {
+        device_id: device_config.device_id.clone(),
+        version: env!("CARGO_PKG_VERSION"),
+        time: clock::local_now().format("%Y-%m-%d %H:%M:%S").to_string(),
+        config_error,
+        interfaces,
+        gateway: Probe::from(gateway),
+        dns: Probe::from(dns),
+        websocket: Probe::from(websocket),
+        profile: provision::printer_profile(&device_config).name.to_string(),
+        spool_pending: match &args.spool_dir {
+            Some(dir) => Some(spool::count_pending(dir)?),
+            None => None,
+        },
+    };
+
+    if args.json {
+        println!(
+            "{}",
+            serde_json::to_string(&diagnosis).expect("diagnosis serializes")
+        );
+    } else {
+        for line in lines(&diagnosis) {
+            println!("{}", line);
+        }
+    }
+    if args.print {
+        let profile = provision::printer_profile(&device_config);
+        let ticket = render_ticket(&diagnosis, profile.commands)?;
+        tokio::task::block_in_place(|| provision::print(&device_config, args.mock, &ticket))?;
+    }
+    Ok(())
+}
+
+/// The diagnosis as short lines, for the terminal and the ticket.
+fn lines(diagnosis: &Diagnosis) -> Vec<String> {
+    let check = |probe: &Probe| {
+        let status = if probe.ok { "ok" } else { "FAILED" };
+        format!("{} - {}", status, probe.detail)
+    };
+    let mut lines = vec![
+        format!("Device: {}", or_none(&diagnosis.device_id)),
+        format!("Version: {}", diagnosis.version),
+        format!("Time: {}", diagnosis.time),
+    ];
+    if let Some(e) = &diagnosis.config_error {
+        lines.push(format!("Config: {}", e));
+    }
+    if diagnosis.interfaces.is_empty() {
+        lines.push("Addresses: none found".to_string());
+    }
+    for interface in &diagnosis.interfaces {
+        lines.push(format!(
+            "{}: {}",
+            interface.name,
+            interface.addresses.join(", ")
+        ));
+    }
+    lines.push(format!("Gateway: {}", check(&diagnosis.gateway)));
+    lines.push(format!("DNS: {}", check(&diagnosis.dns)));
+    lines.push(format!("Server: {}", check(&diagnosis.websocket)));
+    lines.push(format!("Profile: {}", diagnosis.profile));
+    if let Some(pending) = diagnosis.spool_pending {
+        lines.push(format!("Spooled jobs: {}", pending));
+    }
+    lines
+}
+
+fn or_none(value: &str) -> &str {
+    if value.is_empty() { "none" } else { value }
+}
+
+fn render_ticket(diagnosis: &Diagnosis, commands: CommandSet) -> Result<Rendered> {
+    let mut ticket = Ticket::new(commands)?;
+    ticket.printer.justify(JustifyMode::CENTER)?;
+    ticket.printer.bold(true)?;
+    ticket.line("DIAGNOSTICS")?;
+    ticket.printer.bold(false)?;
+    ticket.feed()?;
+    ticket.printer.justify(JustifyMode::LEFT)?;
+    for line in lines(diagnosis) {
+        ticket.line(&line)?;
+    }
+    ticket.finish(false)
+}
+
+/// Addresses per interface from `ip -o addr`, leaving out loopback.
+async fn interfaces() -> Vec<Interface> {
+    let Ok(Ok(output)) = timeout(
+        COMMAND_TIMEOUT,
+        Command::new("ip").args(["-o", "addr", "show"]).output(),
+    )
+    .await
+    else {
+        return Vec::new();
+    };
+    parse_interfaces(&String::from_utf8_lossy(&output.stdout))
+}
+
+fn parse_interfaces(ip_output: &str) -> Vec<Interface> {
+    let mut interfaces: Vec<Interface> = Vec::new();
+    for line in ip_output.lines() {
+        // 2: wlan0    inet 192.168.1.20/24 brd 192.168.1.255 scope global wlan0 ...
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let (Some(name), Some(address)) = (fields.get(1), fields.get(3)) else {
+            continue;
+        };
+        let name = name.trim_end_matches(':');
+        if name == "lo" {
+            continue;
+        }
+        match interfaces.iter_mut().find(|i| i.name == name) {
+            Some(interface) => interface.addresses.push(address.to_string()),
+            None => interfaces.push(Interface {
+                name: name.to_string(),
+                addresses: vec![address.to_string()],
+            }),
+        }
+    }
+    interfaces
+}
+
+/// One ping to the default gateway.
+async fn ping_gateway() -> Result<String> {
+    let gateway = network::default_gateway().context("No default route")?;
+    let output = timeout(
+        COMMAND_TIMEOUT,
+        Command::new("ping")
+            .args(["-c", "1", "-W", "2", &gateway.to_string()])
+            .output(),
+    )
+    .await
+    .with_context(|| format!("{}: no reply within {:?}", gateway, COMMAND_TIMEOUT))?
+    .context("Failed to run ping")?;
+    if !output.status.success() {
+        bail!("{}: no reply", gateway);
+    }
+    Ok(format!("{} replied", gateway))
+}
+
+/// Resolves the server's hostname.
+async fn resolve(url: &str) -> Result<String> {
+    let (host, port) = host_port(url)?;
+    let addresses = timeout(DNS_TIMEOUT, tokio::net::lookup_host((host.as_str(), port)))
+        .await
+        .with_context(|| format!("{}: no answer within {:?}", host, DNS_TIMEOUT))?
+        .with_context(|| format!("{} doesn't resolve", host))?;
+    let addresses: Vec<String> = addresses.map(|address| address.ip().to_string()).collect();
+    Ok(format!("{} is {}", host, addresses.join(", ")))
+}
+
+/// Opens a WebSocket to the server and closes it again. The URL carries the
+/// device's token, so only the host is shown.
+async fn connect(url: &str) -> Result<String> {
+    let (host, port) = host_port(url)?;
+    let result = timeout(WS_TIMEOUT, tokio_tungstenite::connect_async(url))
+        .await
+        .with_context(|| format!("{}:{}: no connection within {:?}", host, port, WS_TIMEOUT))?;
+    match result {
+        Ok((mut stream, _)) => {
+            let _ = stream.close(None).await;
+            Ok(format!("{}:{} connected", host, port))
+        }
+        Err(e) if transport::is_auth_rejected(&e) => {
+            bail!("{}:{}: token rejected ({})", host, port, e)
+        }
+        Err(e) => bail!("{}:{}: {}", host, port, e),
+    }
+}
+
+fn host_port(url: &str) -> Result<(String, u16)> {
+    if url.is_empty() {
+        bail!("No server URL configured");
+    }
+    let url = url::Url::parse(url).context("Invalid server URL")?;
+    let host = url
+        .host_str()
+        .context("Server URL has no host")?
+        .to_string();
+    let port = url
+        .port_or_known_default()
+        .context("Server URL has no port")?;
+    Ok((host, port))
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::StreamExt;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    fn diagnosis() -> Diagnosis {
+        Diagnosis {
+            device_id: String::new(),
+            version: "1.2.3",
+            time: "2026-10-14 09:30:00".to_string(),
+            config_error: Some("Failed to read config".to_string()),
+            interfaces: Vec::new(),
+            gateway: Probe::from(Ok("192.168.1.1 replied".to_string())),
+            dns: Probe::from(Err(anyhow::anyhow!("print.example.com doesn't resolve"))),
+            websocket: Probe::from(Err(
+                anyhow::anyhow!("refused").context("print.example.com:443")
+            )),
+            profile: "default".to_string(),
+            spool_pending: Some(3),
+        }
+    }
+
+    #[test]
+    fn addresses_are_grouped_by_interface_without_loopback() {
+        let output = "\
+1: lo    inet 127.0.0.1/8 scope host lo\\       valid_lft forever preferred_lft forever
+2: wlan0    inet 192.168.1.20/24 brd 192.168.1.255 scope global dynamic wlan0\\       valid_lft 86000sec
+2: wlan0    inet6 fe80::1/64 scope link \\       valid_lft forever preferred_lft forever
+3: eth0    inet 10.0.0.5/8 scope global eth0
+garbage
+";
+        let interfaces = parse_interfaces(output);
+        let found: Vec<(&str, Vec<&str>)> = interfaces
+            .iter()
+            .map(|i| {
+                (
+                    i.name.as_str(),
+                    i.addresses.iter().map(String::as_str).collect(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            found,
+            [
+                ("wlan0", vec!["192.168.1.20/24", "fe80::1/64"]),
+                ("eth0", vec!["10.0.0.5/8"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn the_lines_say_what_failed() {
+        let mut diagnosis = diagnosis();
+        assert_eq!(
+            lines(&diagnosis),
+            [
+                "Device: none",
+                "Version: 1.2.3",
+                "Time: 2026-10-14 09:30:00",
+                "Config: Failed to read config",
+                "Addresses: none found",
+                "Gateway: ok - 192.168.1.1 replied",
+                "DNS: FAILED - print.example.com doesn't resolve",
+                "Server: FAILED - print.example.com:443: refused",
+                "Profile: default",
+                "Spooled jobs: 3",
+            ]
+        );
+        diagnosis.device_id = "kiosk-7".to_string();
+        diagnosis.config_error = None;
+        diagnosis.spool_pending = None;
+        diagnosis.interfaces = vec![Interface {
+            name: "eth0".to_string(),
+            addresses: vec!["10.0.0.5/8".to_string(), "fe80::1/64".to_string()],
+        }];
+        let lines = lines(&diagnosis);
+        assert_eq!(lines[0], "Device: kiosk-7");
+        assert_eq!(lines[3], "eth0: 10.0.0.5/8, fe80::1/64");
+        assert!(
+            !lines
+                .iter()
+                .any(|line| line.starts_with("Config") || line.starts_with("Spooled"))
+        );
+
+        let json = serde_json::to_value(&diagnosis).unwrap();
+        assert!(json.get("config_error").is_none());
+        assert_eq!(json["dns"]["ok"], false);
+
+        let rendered = render_ticket(&diagnosis, CommandSet::EscPos).unwrap();
+        let text = "Server: FAILED";
+        assert!(
+            rendered
+                .bytes
+                .windows(text.len())
+                .any(|w| w == text.as_bytes())
+        );
+    }
+
+    #[test]
+    fn server_urls_need_a_host() {
+        assert_eq!(
+            host_port("wss://print.example.com/ws?token=x").unwrap(),
+            ("print.example.com".to_string(), 443)
+        );
+        assert_eq!(
+            host_port("ws://10.0.0.2:8080").unwrap(),
+            ("10.0.0.2".to_string(), 8080)
+        );
+        for (url, complaint) in [
+            ("", "No server URL configured"),
+            ("not a url", "Invalid server URL"),
+            ("unix:/run/print.sock", "no host"),
+        ] {
+            let error = format!("{:#}", host_port(url).err().unwrap());
+            assert!(error.contains(complaint), "{}: {}", url, error);
+        }
+    }
+
+    #[tokio::test]
+    async fn localhost_resolves() {
+        let found = resolve("ws://localhost:9").await.unwrap();
+        assert!(found.starts_with("localhost is "), "{}", found);
+    }
+
+    #[tokio::test]
+    async fn the_server_check_hides_the_token() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            while let Some(Ok(_)) = ws.next().await {}
+            // The second connection is turned away
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let _ = stream.read(&mut [0; 4096]).await;
+            let _ = stream
+                .write_all(b"HTTP/1.1 401 Unauthorized\r\nContent-Length: 0\r\n\r\n")
+                .await;
+        });
+        let url = format!("ws://{}/ws?token=s3cret", address);
+        assert_eq!(
+            connect(&url).await.unwrap(),
+            format!("{} connected", address)
+        );
+        let rejected = format!("{:#}", connect(&url).await.err().unwrap());
+        assert!(rejected.contains("token rejected"), "{}", rejected);
+        assert!(!rejected.contains("s3cret"), "{}", rejected);
+    }
+}
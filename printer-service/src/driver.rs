@@ -0,0 +1,611 @@
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use escpos::driver::Driver;
+use escpos::errors::PrinterError;
+use log::warn;
+use serialport::{FlowControl, SerialPort};
+
+use crate::clock;
+use crate::commands::CommandSet;
+use crate::png::Png;
+use crate::profile::PrinterProfile;
+use crate::render::Rendered;
+use crate::transcript;
+
+const SERIAL_TIMEOUT: Duration = Duration::from_secs(5);
+const STATUS_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// USB ids of the receipt printer the kiosks ship with.
+pub const USB_VENDOR_ID: u16 = 0x0456;
+pub const USB_PRODUCT_ID: u16 = 0x0808;
+
+/// Summary of one job write, for logging.
+pub struct WriteStats {
+    pub bytes: usize,
+    pub writes: usize,
+    pub elapsed: Duration,
+}
+
+/// Writes a rendered job to the driver, flushing once at the end.
+///
+/// With a profile `chunk_size` of 0 the whole job goes out in a single write.
+/// Otherwise the job is split into `chunk_size` pieces, pausing
+/// `inter_chunk_delay` between them, except that the cut command is always kept
+/// whole in the final write so a failure mid-job can never leave a cut pending.
+pub fn write_job<D: Driver>(
+    driver: &D,
+    job: &Rendered,
+    profile: &PrinterProfile,
+) -> Result<WriteStats> {
+    write_job_with_progress(driver, job, profile, |_, _| {})
+}
+
+/// Like [`write_job`], calling `progress` with the bytes written so far and
+/// the total after every write.
+pub fn write_job_with_progress<D: Driver>(
+    driver: &D,
+    job: &Rendered,
+    profile: &PrinterProfile,
+    mut progress: impl FnMut(usize, usize),
+) -> Result<WriteStats> {
+    let start = Instant::now();
+    let mut written = 0;
+    let mut writes = 0;
+
+    let (body, cut) = job.bytes.split_at(job.cut_offset.min(job.bytes.len()));
+    let chunk_size = match profile.chunk_size {
+        0 => job.bytes.len().max(1),
+        size => size,
+    };
+
+    let mut chunks = body.chunks(chunk_size).peekable();
+    if chunks.peek().is_none() {
+        driver.write(cut)?;
+        writes += 1;
+    }
+    while let Some(chunk) = chunks.next() {
+        if writes > 0 && !profile.inter_chunk_delay.is_zero() {
+            std::thread::sleep(profile.inter_chunk_delay);
+        }
+        if chunks.peek().is_none() {
+            driver.write(&[chunk, cut].concat())?;
+            written += chunk.len() + cut.len();
+        } else {
+            driver.write(chunk)?;
+            written += chunk.len();
+        }
+        writes += 1;
+        progress(written, job.bytes.len());
+    }
+    driver.flush()?;
+
+    Ok(WriteStats {
+        bytes: job.bytes.len(),
+        writes,
+        elapsed: start.elapsed(),
+    })
+}
+
+/// What a printer said when asked whether it's ready.
+pub enum Readiness {
+    Online,
+    /// The printer never answered; it may not support status requests
+    NoStatus,
+}
+
+/// Polls the printer with a real-time status request (DLE EOT 1) until it
+/// reports itself online. Fails if it keeps reporting offline until `timeout`.
+pub fn wait_ready<D: Driver>(driver: &D, timeout: Duration) -> Result<Readiness> {
+    let deadline = Instant::now() + timeout;
+    let mut answered = false;
+
+    while Instant::now() < deadline {
+        driver.write(&[0x10, 0x04, 0x01])?;
+        driver.flush()?;
+        let mut status = [0u8; 1];
+        if let Ok(1) = driver.read(&mut status) {
+            answered = true;
+            // Bit 3 set = offline
+            if status[0] & 0x08 == 0 {
+                return Ok(Readiness::Online);
+            }
+        }
+        std::thread::sleep(STATUS_POLL_INTERVAL);
+    }
+
+    if answered {
+        anyhow::bail!("Printer still offline after {:?}", timeout);
+    }
+    Ok(Readiness::NoStatus)
+}
+
+/// Asks the printer's paper sensor (DLE EOT 4) whether the roll has run out.
+/// `None` if it didn't answer.
+pub fn paper_out<D: Driver>(driver: &D) -> Option<bool> {
+    driver.write(&[0x10, 0x04, 0x04]).ok()?;
+    driver.flush().ok()?;
+    let mut status = [0u8; 1];
+    match driver.read(&mut status) {
+        // Bits 5 and 6: paper end sensor
+        Ok(1) => Some(status[0] & 0x60 == 0x60),
+        _ => None,
+    }
+}
+
+/// Driver for serial (and serial-over-Bluetooth) printers. Unlike the escpos
+/// serial driver this one can turn on XON/XOFF flow control, letting the
+/// printer pause us when its input buffer fills.
+#[derive(Clone)]
+pub struct SerialDriver {
+    path: String,
+    port: Arc<Mutex<Box<dyn SerialPort>>>,
+}
+
+impl SerialDriver {
+    pub fn open(path: &str, baud_rate: u32, xon_xoff: bool) -> Result<Self> {
+        let flow_control = if xon_xoff {
+            FlowControl::Software
+        } else {
+            FlowControl::None
+        };
+        let port = serialport::new(path, baud_rate)
+            .flow_control(flow_control)
+            .timeout(SERIAL_TIMEOUT)
+            .open()?;
+        Ok(Self {
+            path: path.to_owned(),
+            port: Arc::new(Mutex::new(port)),
+        })
+    }
+}
+
+impl Driver for SerialDriver {
+    fn name(&self) -> String {
+        format!("serial ({})", self.path)
+    }
+
+    fn write(&self, data: &[u8]) -> escpos::errors::Result<()> {
+        Ok(self.port.lock()?.write_all(data)?)
+    }
+
+    fn read(&self, buf: &mut [u8]) -> escpos::errors::Result<usize> {
+        match self.port.lock()?.read(buf) {
+            Ok(n) => Ok(n),
+            Err(e) if e.kind() == std::io::ErrorKind::TimedOut => Ok(0),
+            Err(e) => Err(PrinterError::Io(e.to_string())),
+        }
+    }
+
+    fn flush(&self) -> escpos::errors::Result<()> {
+        Ok(self.port.lock()?.flush()?)
+    }
+}
+
+/// Any driver, boxed, so the service needs no type parameter for it.
+pub struct BoxedDriver(Box<dyn Driver + Send>);
+
+impl BoxedDriver {
+    pub fn new(driver: impl Driver + Send + 'static) -> Self {
+        Self(Box::new(driver))
+    }
+}
+
+impl Driver for BoxedDriver {
+    fn name(&self) -> String {
+        self.0.name()
+    }
+
+    fn write(&self, data: &[u8]) -> escpos::errors::Result<()> {
+        self.0.write(data)
+    }
+
+    fn read(&self, buf: &mut [u8]) -> escpos::errors::Result<usize> {
+        self.0.read(buf)
+    }
+
+    fn flush(&self) -> escpos::errors::Result<()> {
+        self.0.flush()
+    }
+}
+
+/// Sees a copy of everything written to the printer, one flush at a time,
+/// without being able to hold up or fail the job.
+pub trait Observer: Send + 'static {
+    fn name(&self) -> String;
+
+    /// Called with the bytes written between two flushes.
+    fn flushed(&mut self, bytes: &[u8]) -> Result<()>;
+}
+
+/// Flushes an observer may fall behind by before it misses some
+const OBSERVER_BACKLOG: usize = 16;
+
+/// A driver that passes everything through to `primary` and hands a copy of
+/// each flush to its observers. Every observer runs on its own thread behind
+/// a bounded queue, so a slow one misses flushes rather than slowing the
+/// printer, and its errors are only logged.
+#[derive(Clone)]
+pub struct TeeDriver<D> {
+    primary: D,
+    buffer: Arc<Mutex<Vec<u8>>>,
+    observers: Vec<(String, SyncSender<Arc<[u8]>>)>,
+}
+
+impl<D: Driver> TeeDriver<D> {
+    pub fn new(primary: D, observers: Vec<Box<dyn Observer>>) -> Self {
+        let observers = observers.into_iter().map(spawn_observer).collect();
+        Self {
+            primary,
+            buffer: Arc::new(Mutex::new(Vec::new())),
+            observers,
+        }
+    }
+}
+
+fn spawn_observer(mut observer: Box<dyn Observer>) -> (String, SyncSender<Arc<[u8]>>) {
+    let name = observer.name();
+    let (tx, rx) = mpsc::sync_channel::<Arc<[u8]>>(OBSERVER_BACKLOG);
+    let thread_name = name.clone();
+    std::thread::spawn(move || {
+        for bytes in rx {
+            if let Err(e) = observer.flushed(&bytes) {
+                warn!("Output observer {} failed: {}", thread_name, e);
+            }
+        }
+    });
+    (name, tx)
+}
+
+impl<D: Driver> Driver for TeeDriver<D> {
+    fn name(&self) -> String {
+        format!("tee ({})", self.primary.name())
+    }
+
+    fn write(&self, data: &[u8]) -> escpos::errors::Result<()> {
+        if !self.observers.is_empty() {
+            self.buffer.lock()?.extend_from_slice(data);
+        }
+        self.primary.write(data)
+    }
+
+    fn read(&self, buf: &mut [u8]) -> escpos::errors::Result<usize> {
+        self.primary.read(buf)
+    }
+
+    fn flush(&self) -> escpos::errors::Result<()> {
+        let result = self.primary.flush();
+
+        let bytes = std::mem::take(&mut *self.buffer.lock()?);
+        if bytes.is_empty() {
+            return result;
+        }
+        let bytes: Arc<[u8]> = bytes.into();
+        for (name, tx) in &self.observers {
+            match tx.try_send(bytes.clone()) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => warn!(
+                    "Output observer {} is behind, skipping {} bytes",
+                    name,
+                    bytes.len()
+                ),
+                Err(TrySendError::Disconnected(_)) => {}
+            }
+        }
+        result
+    }
+}
+
+/// Prints a human-readable transcript of each flush (decoded commands, text
+/// laid out at the paper width). Optionally also saves each transcript to its
+/// own file.
+pub struct PrettyTranscript {
+    profile: PrinterProfile,
+    dir: Option<PathBuf>,
+    count: usize,
+}
+
+impl PrettyTranscript {
+    pub fn new(profile: PrinterProfile, dir: Option<PathBuf>) -> Result<Self> {
+        if let Some(dir) = &dir {
+            std::fs::create_dir_all(dir)?;
+        }
+        Ok(Self {
+            profile,
+            dir,
+            count: 0,
+        })
+    }
+}
+
+impl Observer for PrettyTranscript {
+    fn name(&self) -> String {
+        "pretty transcript".to_string()
+    }
+
+    fn flushed(&mut self, bytes: &[u8]) -> Result<()> {
+        let text = transcript::render(
+            &transcript::decode(bytes, self.profile.commands),
+            &self.profile,
+        );
+        let mut stdout = std::io::stdout().lock();
+        stdout.write_all(text.as_bytes())?;
+        stdout.flush()?;
+
+        if let Some(dir) = &self.dir {
+            let path = dir.join(format!(
+                "{}-{:04}.txt",
+                clock::local_now().format("%Y%m%d-%H%M%S"),
+                self.count
+            ));
+            self.count += 1;
+            if let Err(e) = std::fs::write(&path, &text) {
+                warn!("Failed to save transcript to {}: {}", path.display(), e);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Saves the raster graphics of each flush (rules and images, in the order
+/// they print) as a PNG in `dir`, writing them a band at a time. Text isn't
+/// drawn; the transcript shows that.
+pub struct PngRender {
+    commands: CommandSet,
+    dir: PathBuf,
+    count: usize,
+}
+
+impl PngRender {
+    pub fn new(commands: CommandSet, dir: PathBuf) -> Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self {
+            commands,
+            dir,
+            count: 0,
+        })
+    }
+}
+
+impl Observer for PngRender {
+    fn name(&self) -> String {
+        "PNG render".to_string()
+    }
+
+    fn flushed(&mut self, bytes: &[u8]) -> Result<()> {
+        let rasters = transcript::rasters(bytes, self.commands);
+        if rasters.is_empty() {
+            return Ok(());
+        }
+        let width = rasters
+            .iter()
+            .map(|raster| raster.width_bytes)
+            .max()
+            .unwrap_or(0)
+            * 8;
+        let height = rasters.iter().map(|raster| raster.rows.len()).sum();
+        let path = self.dir.join(format!(
+            "{}-{:04}.png",
+            clock::local_now().format("%Y%m%d-%H%M%S"),
+            self.count
+        ));
+        self.count += 1;
+        let file = std::io::BufWriter::new(std::fs::File::create(&path)?);
+        let mut png = Png::start(file, width, height)?;
+        for raster in &rasters {
+            png.band(&raster.rows)?;
+        }
+        png.finish()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::RecordingDriver;
+    use crate::tempdir::TempDir;
+
+    /// Keeps each write separately, answering status requests with `status`.
+    #[derive(Default)]
+    struct Printer {
+        writes: Mutex<Vec<Vec<u8>>>,
+        status: Option<u8>,
+        flushes: Mutex<usize>,
+    }
+
+    impl Driver for Printer {
+        fn name(&self) -> String {
+            "test".to_string()
+        }
+
+        fn write(&self, data: &[u8]) -> escpos::errors::Result<()> {
+            self.writes.lock().unwrap().push(data.to_vec());
+            Ok(())
+        }
+
+        fn read(&self, buf: &mut [u8]) -> escpos::errors::Result<usize> {
+            match self.status {
+                Some(status) => {
+                    buf[0] = status;
+                    Ok(1)
+                }
+                None => Ok(0),
+            }
+        }
+
+        fn flush(&self) -> escpos::errors::Result<()> {
+            *self.flushes.lock().unwrap() += 1;
+            Ok(())
+        }
+    }
+
+    fn job(body: usize) -> Rendered {
+        let mut bytes = vec![b'x'; body];
+        bytes.extend_from_slice(&[0x1D, b'V', 0]);
+        Rendered {
+            bytes,
+            cut_offset: body,
+            lines: 1,
+        }
+    }
+
+    fn chunked(chunk_size: usize) -> PrinterProfile {
+        PrinterProfile {
+            chunk_size,
+            inter_chunk_delay: Duration::from_millis(1),
+            ..PrinterProfile::find("default").unwrap().clone()
+        }
+    }
+
+    #[test]
+    fn unchunked_jobs_go_out_in_one_write() {
+        let printer = Printer::default();
+        let stats = write_job(&printer, &job(1000), &chunked(0)).unwrap();
+        assert_eq!((stats.bytes, stats.writes), (1003, 1));
+        assert_eq!(printer.writes.lock().unwrap()[0], job(1000).bytes);
+        assert_eq!(*printer.flushes.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn chunks_keep_the_cut_in_the_last_write() {
+        let printer = Printer::default();
+        let mut progress = Vec::new();
+        let stats = write_job_with_progress(&printer, &job(250), &chunked(100), |done, total| {
+            progress.push((done, total))
+        })
+        .unwrap();
+        assert_eq!(stats.writes, 3);
+        let writes = printer.writes.lock().unwrap();
+        let sizes: Vec<usize> = writes.iter().map(Vec::len).collect();
+        assert_eq!(sizes, [100, 100, 53]);
+        assert!(writes[2].ends_with(&[0x1D, b'V', 0]));
+        assert_eq!(progress, [(100, 253), (200, 253), (253, 253)]);
+
+        // A ticket that's only a cut still gets one write
+        let printer = Printer::default();
+        assert_eq!(
+            write_job(&printer, &job(0), &chunked(100)).unwrap().writes,
+            1
+        );
+        assert_eq!(printer.writes.lock().unwrap()[0], [0x1D, b'V', 0]);
+    }
+
+    #[test]
+    fn readiness_comes_from_the_status_reply() {
+        let online = Printer {
+            status: Some(0x12),
+            ..Printer::default()
+        };
+        assert!(matches!(
+            wait_ready(&online, Duration::from_secs(1)).unwrap(),
+            Readiness::Online
+        ));
+        let silent = Printer::default();
+        assert!(matches!(
+            wait_ready(&silent, Duration::from_millis(250)).unwrap(),
+            Readiness::NoStatus
+        ));
+        let offline = Printer {
+            status: Some(0x1A),
+            ..Printer::default()
+        };
+        assert!(wait_ready(&offline, Duration::from_millis(250)).is_err());
+    }
+
+    #[test]
+    fn paper_out_needs_both_sensor_bits() {
+        let paper = |status| {
+            paper_out(&Printer {
+                status,
+                ..Printer::default()
+            })
+        };
+        assert_eq!(paper(Some(0x72)), Some(true));
+        assert_eq!(paper(Some(0x1E)), Some(false));
+        assert_eq!(paper(Some(0x32)), Some(false));
+        assert_eq!(paper(None), None);
+    }
+
+    /// Keeps what it's shown, failing every other time.
+    struct Watcher {
+        seen: Arc<Mutex<Vec<Vec<u8>>>>,
+    }
+
+    impl Observer for Watcher {
+        fn name(&self) -> String {
+            "watcher".to_string()
+        }
+
+        fn flushed(&mut self, bytes: &[u8]) -> Result<()> {
+            let mut seen = self.seen.lock().unwrap();
+            seen.push(bytes.to_vec());
+            if seen.len() % 2 == 1 {
+                anyhow::bail!("flaky");
+            }
+            Ok(())
+        }
+    }
+
+    fn wait_for(done: impl Fn() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !done() {
+            assert!(Instant::now() < deadline, "timed out");
+            std::thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn observers_see_each_flush() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let primary = RecordingDriver::default();
+        let watcher = Watcher {
+            seen: Arc::clone(&seen),
+        };
+        let tee = TeeDriver::new(primary.clone(), vec![Box::new(watcher)]);
+        tee.write(b"one").unwrap();
+        tee.write(b" two").unwrap();
+        tee.flush().unwrap();
+        // Nothing written, nothing passed on
+        tee.flush().unwrap();
+        tee.write(b"three").unwrap();
+        tee.flush().unwrap();
+        wait_for(|| seen.lock().unwrap().len() == 2);
+        assert_eq!(
+            *seen.lock().unwrap(),
+            [b"one two".to_vec(), b"three".to_vec()]
+        );
+        assert_eq!(primary.take(), b"one twothree");
+        assert_eq!(tee.name(), "tee (recording)");
+    }
+
+    #[test]
+    fn rasters_are_saved_as_pngs() {
+        let dir = TempDir::new("driver");
+        let mut render = PngRender::new(CommandSet::EscPos, dir.path().join("png")).unwrap();
+        render.flushed(b"text only\n").unwrap();
+        assert_eq!(
+            std::fs::read_dir(dir.path().join("png")).unwrap().count(),
+            0
+        );
+
+        // GS v 0, 2 bytes wide and 3 rows high
+        let mut image = vec![0x1D, b'v', b'0', 0, 2, 0, 3, 0];
+        image.extend_from_slice(&[0xFF, 0x00, 0x0F, 0xF0, 0x00, 0xFF]);
+        render.flushed(&image).unwrap();
+        let files: Vec<PathBuf> = std::fs::read_dir(dir.path().join("png"))
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(files.len(), 1);
+        assert!(files[0].to_str().unwrap().ends_with("-0000.png"));
+        let png = std::fs::read(&files[0]).unwrap();
+        assert_eq!(png[1..4], *b"PNG");
+        // IHDR's width and height
+        assert_eq!(png[16..24], [0, 0, 0, 16, 0, 0, 0, 3]);
+    }
+}
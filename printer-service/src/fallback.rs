@@ -0,0 +1,382 @@
+//! Glyphs for characters the printer's code page doesn't have (Māori
+//! macrons, Chinese item names, ...), drawn from a BDF bitmap font: the
+//! one given with `--fallback-font`, or else the built-in one, which covers
+//! Latin, Greek and Cyrillic but not CJK. The renderer sends them as user-defined
+//! characters, so they sit on the line's baseline, take the width of whole
+//! character cells and scale with the text size like any other character.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::OnceLock;
+
+use anyhow::{Context, Result, bail};
+use log::info;
+
+static FONT: OnceLock<FallbackFont> = OnceLock::new();
+
+/// DejaVu Sans Mono rendered 24 dots high, font A's cell height, so it
+/// prints unscaled in font A (see `fonts/LICENSE`).
+const BUILT_IN: &[u8] = include_bytes!("../fonts/fallback.bdf");
+
+/// Makes `font` the fallback for everything rendered from now on. Only the
+/// first font installed counts.
+pub fn install(font: FallbackFont) {
+    let _ = FONT.set(font);
+}
+
+pub fn font() -> Option<&'static FallbackFont> {
+    FONT.get()
+}
+
+pub struct FallbackFont {
+    /// Rows above the baseline
+    ascent: i32,
+    /// Rows below it
+    descent: i32,
+    glyphs: HashMap<char, Glyph>,
+}
+
+struct Glyph {
+    /// Horizontal advance in pixels
+    advance: i32,
+    /// Bounding box size and offset from the origin on the baseline
+    width: i32,
+    height: i32,
+    x_offset: i32,
+    y_offset: i32,
+    /// Top row first, leftmost pixel in the high bit of each row's first byte
+    rows: Vec<Vec<u8>>,
+}
+
+impl Glyph {
+    /// Dots across the glyph takes, scaled by `scale`.
+    fn scaled_width(&self, scale: f64) -> usize {
+        ((self.advance as f64 * scale).round() as usize).max(1)
+    }
+
+    fn pixel(&self, x: i32, y: i32) -> bool {
+        if x < 0 || y < 0 || x >= self.width || y >= self.height {
+            return false;
+        }
+        let row = &self.rows[y as usize];
+        row.get(x as usize / 8)
+            .is_some_and(|b| b & (0x80 >> (x % 8)) != 0)
+    }
+}
+
+impl FallbackFont {
+    /// Reads a BDF font, keyed by each glyph's `ENCODING` taken as a Unicode
+    /// code point (as in Unifont and other ISO 10646 BDF fonts).
+    pub fn load(path: &Path) -> Result<Self> {
+        let bytes = fs::read(path)
+            .with_context(|| format!("Failed to read fallback font {}", path.display()))?;
+        let font = Self::from_bdf(&bytes)
+            .with_context(|| format!("Failed to parse fallback font {}", path.display()))?;
+        info!(
+            "Fallback font {}: {} glyphs",
+            path.display(),
+            font.glyphs.len()
+        );
+        Ok(font)
+    }
+
+    /// The font compiled into the binary, used without `--fallback-font`.
+    pub fn built_in() -> Self {
+        let font = Self::from_bdf(BUILT_IN).expect("the built-in fallback font parses");
+        info!("Built-in fallback font: {} glyphs", font.glyphs.len());
+        font
+    }
+
+    fn from_bdf(bytes: &[u8]) -> Result<Self> {
+        Self::parse(std::str::from_utf8(bytes).context("Font isn't text")?)
+    }
+
+    fn parse(text: &str) -> Result<Self> {
+        let mut ascent = None;
+        let mut descent = None;
+        let mut bounding_box = None;
+        let mut glyphs = HashMap::new();
+        let mut lines = text.lines().enumerate();
+
+        while let Some((n, line)) = lines.next() {
+            let mut words = line.split_whitespace();
+            match words.next() {
+                Some("FONT_ASCENT") => ascent = Some(number(words.next(), n)?),
+                Some("FONT_DESCENT") => descent = Some(number(words.next(), n)?),
+                Some("FONTBOUNDINGBOX") => bounding_box = Some(numbers::<4>(words, n)?),
+                Some("STARTCHAR") => {
+                    let mut encoding = None;
+                    let mut advance = None;
+                    let mut bbx = None;
+                    let mut rows = Vec::new();
+                    for (n, line) in lines.by_ref() {
+                        let mut words = line.split_whitespace();
+                        match words.next() {
+                            Some("ENCODING") => encoding = Some(number(words.next(), n)?),
+                            Some("DWIDTH") => advance = Some(number(words.next(), n)?),
+                            Some("BBX") => bbx = Some(numbers::<4>(words, n)?),
+                            Some("BITMAP") => {}
+                            Some("ENDCHAR") => break,
+                            Some(word) if bbx.is_some() => rows
+                                .push(hex::decode(word).with_context(|| {
+                                    format!("Bad bitmap row on line {}", n + 1)
+                                })?),
+                            _ => {}
+                        }
+                    }
+                    let Some([width, height, x_offset, y_offset]) = bbx else {
+                        bail!("Glyph ending before line {} has no BBX", n + 1);
+                    };
+                    // -1 marks glyphs outside the font's encoding
+                    let Some(c) = encoding
+                        .and_then(|code: i32| u32::try_from(code).ok())
+                        .and_then(char::from_u32)
+                    else {
+                        continue;
+                    };
+                    rows.resize(height.max(0) as usize, Vec::new());
+                    glyphs.insert(
+                        c,
+                        Glyph {
+                            advance: advance.unwrap_or(width),
+                            width,
+                            height,
+                            x_offset,
+                            y_offset,
+                            rows,
+                        },
+                    );
+                }
+                _ => {}
+            }
+        }
+
+        // Fonts without FONT_ASCENT/FONT_DESCENT go by their bounding box
+        let [_, box_height, _, box_y] = bounding_box.unwrap_or_default();
+        let ascent = ascent.unwrap_or(box_height + box_y);
+        let descent = descent.unwrap_or(-box_y);
+        if ascent + descent <= 0 {
+            bail!("Font has no height");
+        }
+        if glyphs.is_empty() {
+            bail!("Font has no glyphs");
+        }
+        Ok(Self {
+            ascent,
+            descent,
+            glyphs,
+        })
+    }
+
+    /// Draws `c` scaled to the height of a character cell `cell_width` by
+    /// `cell_height` dots, centred across as many cells as its width takes.
+    /// Each cell comes back as ESC & data: column by column, three bytes of
+    /// eight dots each from the top. `None` if the font hasn't got `c`.
+    pub fn cells(&self, c: char, cell_width: usize, cell_height: usize) -> Option<Vec<Vec<u8>>> {
+        let glyph = self.glyphs.get(&c)?;
+        let scale = self.scale(cell_height);
+        let scaled_width = glyph.scaled_width(scale);
+        let count = scaled_width.div_ceil(cell_width);
+        let pad = (count * cell_width - scaled_width) / 2;
+
+        let mut cells = vec![vec![0u8; cell_width * 3]; count];
+        for y in 0..cell_height.min(24) {
+            // Row of the font's box, then of the glyph's bitmap
+            let font_row = ((y as f64 + 0.5) / scale) as i32;
+            let glyph_row = glyph.y_offset + glyph.height - self.ascent + font_row;
+            for x in 0..scaled_width {
+                let font_column = ((x as f64 + 0.5) / scale) as i32;
+                if glyph.pixel(font_column - glyph.x_offset, glyph_row) {
+                    let dot = x + pad;
+                    let cell = &mut cells[dot / cell_width];
+                    cell[(dot % cell_width) * 3 + y / 8] |= 0x80 >> (y % 8);
+                }
+            }
+        }
+        Some(cells)
+    }
+
+    /// How many character cells `cell_width` by `cell_height` dots drawing
+    /// `c` takes, without drawing it. `None` if the font hasn't got `c`.
+    pub fn cell_count(&self, c: char, cell_width: usize, cell_height: usize) -> Option<usize> {
+        let glyph = self.glyphs.get(&c)?;
+        Some(
+            glyph
+                .scaled_width(self.scale(cell_height))
+                .div_ceil(cell_width),
+        )
+    }
+
+    fn scale(&self, cell_height: usize) -> f64 {
+        cell_height as f64 / (self.ascent + self.descent) as f64
+    }
+}
+
+fn number(word: Option<&str>, line: usize) -> Result<i32> {
+    word.and_then(|w| w.parse().ok())
+        .with_context(|| format!("Expected a number on line {}", line + 1))
+}
+
+fn numbers<'a, const N: usize>(
+    mut words: impl Iterator<Item = &'a str>,
+    line: usize,
+) -> Result<[i32; N]> {
+    let mut values = [0; N];
+    for value in &mut values {
+        *value = number(words.next(), line)?;
+    }
+    Ok(values)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 16 pixel high font: `ā` 8 wide, `中` 16 wide.
+    fn fixture() -> FallbackFont {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/fallback.bdf");
+        FallbackFont::load(&path).unwrap()
+    }
+
+    /// A one-glyph font with the glyph's lines in between.
+    fn bdf(header: &str, glyph: &str) -> String {
+        format!(
+            "STARTFONT 2.1\n{}\nSTARTCHAR x\n{}\nENDCHAR\nENDFONT\n",
+            header, glyph
+        )
+    }
+
+    /// Whether the dot at `x`, `y` of a line of `cells` is black.
+    fn dot(cells: &[Vec<u8>], cell_width: usize, x: usize, y: usize) -> bool {
+        cells[x / cell_width][(x % cell_width) * 3 + y / 8] & (0x80 >> (y % 8)) != 0
+    }
+
+    #[test]
+    fn glyphs_are_keyed_by_code_point() {
+        let font = fixture();
+        assert_eq!((font.ascent, font.descent), (14, 2));
+        assert_eq!(font.glyphs.len(), 2);
+        assert_eq!(font.glyphs[&'ā'].advance, 8);
+        assert_eq!(font.glyphs[&'中'].advance, 16);
+        assert!(font.cells('x', 12, 24).is_none());
+        assert_eq!(font.cell_count('x', 12, 24), None);
+    }
+
+    #[test]
+    fn wide_glyphs_take_two_cells_at_the_printers_scale() {
+        let font = fixture();
+        // 24 dots high is a scale of 1.5, so 12 and 24 dots across
+        assert_eq!(font.cell_count('ā', 12, 24), Some(1));
+        assert_eq!(font.cell_count('中', 12, 24), Some(2));
+        assert_eq!(font.cells('ā', 12, 24).unwrap().len(), 1);
+        let cells = font.cells('中', 12, 24).unwrap();
+        assert_eq!(cells.len(), 2);
+        assert!(cells.iter().all(|cell| cell.len() == 12 * 3));
+        // Font B's 9 by 17 cells: 9 and 17 dots across
+        assert_eq!(font.cell_count('ā', 9, 17), Some(1));
+        assert_eq!(font.cell_count('中', 9, 17), Some(2));
+    }
+
+    #[test]
+    fn glyphs_are_drawn_where_the_bitmap_says() {
+        let font = fixture();
+        let cells = font.cells('中', 12, 24).unwrap();
+        // The stem, bitmap column 7, is scaled to dots 10 and 11 of 24,
+        // from the top row to the one before the blank last row
+        for y in 0..22 {
+            assert!(dot(&cells, 12, 10, y), "stem missing at row {}", y);
+        }
+        assert!(!dot(&cells, 12, 10, 23));
+        // The box's top, bitmap row 3, runs across from column 1 to 13
+        assert!(dot(&cells, 12, 2, 5) && dot(&cells, 12, 20, 5));
+        assert!(!dot(&cells, 12, 0, 5) && !dot(&cells, 12, 23, 5));
+        // Nothing above it outside the stem
+        assert!(!dot(&cells, 12, 2, 0));
+    }
+
+    #[test]
+    fn narrow_glyphs_are_centred_in_their_cells() {
+        // 4 pixels wide and all black, in a 12 dot cell at scale 1
+        let glyph = format!(
+            "ENCODING 65\nDWIDTH 4 0\nBBX 4 24 0 0\nBITMAP\n{}",
+            "F0\n".repeat(24)
+        );
+        let text = bdf("FONT_ASCENT 24\nFONT_DESCENT 0", &glyph);
+        let font = FallbackFont::parse(&text).unwrap();
+        let cells = font.cells('A', 12, 24).unwrap();
+        let black: Vec<usize> = (0..12).filter(|&x| dot(&cells, 12, x, 12)).collect();
+        assert_eq!(black, [4, 5, 6, 7]);
+    }
+
+    #[test]
+    fn a_font_without_ascent_goes_by_its_bounding_box() {
+        let text = bdf(
+            "FONTBOUNDINGBOX 8 10 0 -2",
+            "ENCODING 66\nBBX 8 10 0 -2\nBITMAP\nFF",
+        );
+        let font = FallbackFont::parse(&text).unwrap();
+        assert_eq!((font.ascent, font.descent), (8, 2));
+        // DWIDTH missing: the advance is the box's width
+        assert_eq!(font.glyphs[&'B'].advance, 8);
+        // Rows the bitmap leaves out are blank
+        assert_eq!(font.glyphs[&'B'].rows.len(), 10);
+    }
+
+    #[test]
+    fn glyphs_outside_the_encoding_are_skipped() {
+        let text = bdf(
+            "FONT_ASCENT 8\nFONT_DESCENT 0",
+            "ENCODING -1\nBBX 8 8 0 0\nBITMAP\nFF",
+        )
+        .replace(
+            "ENDFONT",
+            "STARTCHAR y\nENCODING 67\nBBX 8 8 0 0\nBITMAP\nFF\nENDCHAR\nENDFONT",
+        );
+        let font = FallbackFont::parse(&text).unwrap();
+        assert_eq!(font.glyphs.keys().collect::<Vec<_>>(), [&'C']);
+    }
+
+    #[test]
+    fn the_built_in_font_prints_unscaled_in_font_a() {
+        let font = FallbackFont::built_in();
+        assert_eq!(font.ascent + font.descent, 24);
+        for c in ['ā', 'Ō', 'ű', 'ệ', 'Ж', 'λ', '€', '№'] {
+            assert_eq!(font.cell_count(c, 12, 24), Some(1), "{}", c);
+            assert_eq!(font.cell_count(c, 9, 17), Some(1), "{}", c);
+            let cells = font.cells(c, 12, 24).unwrap();
+            assert!(cells[0].iter().any(|&b| b != 0), "{} is blank", c);
+        }
+        // No CJK; that needs a font like Unifont given with --fallback-font
+        assert_eq!(font.cell_count('中', 12, 24), None);
+        // The macron sits above the a, apart from it
+        let cells = font.cells('ā', 12, 24).unwrap();
+        let inked = |y| (0..12).any(|x| dot(&cells, 12, x, y));
+        let rows: Vec<bool> = (0..24).map(inked).collect();
+        let first = rows.iter().position(|&ink| ink).unwrap();
+        let gap = first + rows[first..].iter().position(|&ink| !ink).unwrap();
+        assert!(rows[gap..19].iter().any(|&ink| ink), "{:?}", rows);
+    }
+
+    #[test]
+    fn broken_fonts_are_refused() {
+        let no_glyphs = "STARTFONT 2.1\nFONT_ASCENT 8\nFONT_DESCENT 0\nENDFONT\n";
+        let no_height = bdf("", "ENCODING 65\nBBX 8 8 0 0\nBITMAP\nFF");
+        let no_bbx = bdf("FONT_ASCENT 8\nFONT_DESCENT 0", "ENCODING 65\nBITMAP\nFF");
+        let bad_row = bdf(
+            "FONT_ASCENT 8\nFONT_DESCENT 0",
+            "ENCODING 65\nBBX 8 8 0 0\nBITMAP\nZZ",
+        );
+        let bad_number = bdf("FONT_ASCENT eight", "ENCODING 65\nBBX 8 8 0 0");
+        for (text, error) in [
+            (no_glyphs.to_string(), "no glyphs"),
+            (no_height, "no height"),
+            (no_bbx, "no BBX"),
+            (bad_row, "Bad bitmap row on line 8"),
+            (bad_number, "Expected a number on line 2"),
+        ] {
+            let message = format!("{:#}", FallbackFont::parse(&text).err().unwrap());
+            assert!(message.contains(error), "{:?} for {:?}", message, text);
+        }
+    }
+}
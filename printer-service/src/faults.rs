@@ -0,0 +1,372 @@
+//! Printer faults (cover open, cutter jam, ...) found by polling the
+//! printer's real-time status. Which status bit means what, and what to tell
+//! staff about it in each language, is data: the built-in table is
+//! `faults.json`, and the device config's `faults` entries add to it or
+//! replace built-in entries with the same code.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use anyhow::{Result, bail};
+use escpos::driver::Driver;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+const BUILTIN: &str = include_str!("faults.json");
+/// Messages are looked up in the configured locale, then this one
+const FALLBACK_LOCALE: &str = "en";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct FaultRule {
+    pub code: String,
+    /// DLE EOT request (1-4) whose reply carries the fault
+    pub status: u8,
+    /// Bits that are all set in the reply while the fault is active
+    pub mask: u8,
+    /// The printer still prints while this is active, so its ticket goes out
+    /// straight away rather than once it clears
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub print_while_active: bool,
+    /// By locale, e.g. "en"
+    pub messages: BTreeMap<String, FaultMessage>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct FaultMessage {
+    /// What happened
+    pub title: String,
+    /// What to do about it
+    pub action: String,
+}
+
+impl FaultRule {
+    pub fn validate(&self) -> Result<()> {
+        if !(1..=4).contains(&self.status) {
+            bail!(
+                "Fault {} has status {}, expected 1-4",
+                self.code,
+                self.status
+            );
+        }
+        if self.mask == 0 {
+            bail!("Fault {} has an empty mask", self.code);
+        }
+        Ok(())
+    }
+
+    pub fn message(&self, locale: &str) -> FaultMessage {
+        self.messages
+            .get(locale)
+            .or_else(|| self.messages.get(FALLBACK_LOCALE))
+            .or_else(|| self.messages.values().next())
+            .cloned()
+            .unwrap_or_else(|| FaultMessage {
+                title: format!("Printer fault: {}", self.code),
+                action: "Check the printer".to_string(),
+            })
+    }
+}
+
+/// The built-in table with `extra` merged in.
+pub fn table(extra: &[FaultRule]) -> Vec<FaultRule> {
+    let mut rules: Vec<FaultRule> =
+        serde_json::from_str(BUILTIN).expect("built-in fault table is valid");
+    for rule in extra {
+        match rules.iter_mut().find(|r| r.code == rule.code) {
+            Some(existing) => *existing = rule.clone(),
+            None => rules.push(rule.clone()),
+        }
+    }
+    rules
+}
+
+/// A fault starting or clearing.
+pub struct Transition {
+    pub code: String,
+    pub active: bool,
+    pub message: FaultMessage,
+    /// Print the fault ticket now; at most once per episode
+    pub ticket: bool,
+}
+
+/// Tracks which faults are active between polls.
+pub struct FaultMonitor {
+    rules: Vec<FaultRule>,
+    locale: String,
+    /// Active faults, and whether their episode's ticket was printed yet
+    active: BTreeMap<String, bool>,
+}
+
+impl FaultMonitor {
+    pub fn new(rules: Vec<FaultRule>, locale: String) -> Self {
+        Self {
+            rules,
+            locale,
+            active: BTreeMap::new(),
+        }
+    }
+
+    pub fn reconfigure(&mut self, rules: Vec<FaultRule>) {
+        self.active
+            .retain(|code, _| rules.iter().any(|r| &r.code == code));
+        self.rules = rules;
+    }
+
+    /// Codes of the faults active as of the last poll.
+    pub fn active(&self) -> Vec<String> {
+        self.active.keys().cloned().collect()
+    }
+
+    /// Asks the printer for every status the table uses and returns what
+    /// changed since the last poll. A printer that doesn't answer the first
+    /// request isn't asked the rest, and unanswered statuses change nothing.
+    pub fn poll<D: Driver>(&mut self, driver: &D) -> Vec<Transition> {
+        let requests: BTreeSet<u8> = self.rules.iter().map(|r| r.status).collect();
+        let mut replies = BTreeMap::new();
+        for request in requests {
+            match status(driver, request) {
+                Some(reply) => {
+                    replies.insert(request, reply);
+                }
+                None if replies.is_empty() => return Vec::new(),
+                None => {}
+            }
+        }
+
+        let mut transitions = Vec::new();
+        for rule in &self.rules {
+            let Some(reply) = replies.get(&rule.status) else {
+                continue;
+            };
+            let now_active = reply & rule.mask == rule.mask;
+            let was_active = self.active.contains_key(&rule.code);
+            if now_active == was_active {
+                continue;
+            }
+            let message = rule.message(&self.locale);
+            let ticket = if now_active {
+                warn!("Printer fault: {} ({})", rule.code, message.title);
+                self.active
+                    .insert(rule.code.clone(), rule.print_while_active);
+                rule.print_while_active
+            } else {
+                info!("Printer fault cleared: {}", rule.code);
+                !self.active.remove(&rule.code).unwrap_or(true)
+            };
+            transitions.push(Transition {
+                code: rule.code.clone(),
+                active: now_active,
+                message,
+                ticket,
+            });
+        }
+        transitions
+    }
+}
+
+/// Sends DLE EOT `request` and reads the one-byte reply.
+fn status<D: Driver>(driver: &D, request: u8) -> Option<u8> {
+    driver.write(&[0x10, 0x04, request]).ok()?;
+    driver.flush().ok()?;
+    let mut reply = [0u8; 1];
+    match driver.read(&mut reply) {
+        Ok(1) => Some(reply[0]),
+        _ => None,
+    }
+}
+
+/// Text of the ticket printed for a fault.
+pub fn ticket_text(message: &FaultMessage, code: &str, at: &str) -> String {
+    format!(
+        "{}\n\n{}\n\n{} ({})",
+        message.title, message.action, at, code
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    /// Answers DLE EOT 1-4 with what's set for each, or not at all.
+    #[derive(Default)]
+    struct Printer {
+        replies: Mutex<[Option<u8>; 5]>,
+        asked: Mutex<Vec<u8>>,
+    }
+
+    impl Printer {
+        fn set(&self, request: u8, reply: Option<u8>) {
+            self.replies.lock().unwrap()[request as usize] = reply;
+        }
+    }
+
+    impl Driver for Printer {
+        fn name(&self) -> String {
+            "test".to_string()
+        }
+
+        fn write(&self, data: &[u8]) -> escpos::errors::Result<()> {
+            if let [0x10, 0x04, request] = data {
+                self.asked.lock().unwrap().push(*request);
+            }
+            Ok(())
+        }
+
+        fn read(&self, buf: &mut [u8]) -> escpos::errors::Result<usize> {
+            let request = *self.asked.lock().unwrap().last().unwrap();
+            match self.replies.lock().unwrap()[request as usize] {
+                Some(reply) => {
+                    buf[0] = reply;
+                    Ok(1)
+                }
+                None => Ok(0),
+            }
+        }
+
+        fn flush(&self) -> escpos::errors::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn rule(code: &str, status: u8, mask: u8, locales: &[&str]) -> FaultRule {
+        FaultRule {
+            code: code.to_string(),
+            status,
+            mask,
+            print_while_active: false,
+            messages: locales
+                .iter()
+                .map(|locale| {
+                    let message = FaultMessage {
+                        title: format!("{} title", locale),
+                        action: format!("{} action", locale),
+                    };
+                    (locale.to_string(), message)
+                })
+                .collect(),
+        }
+    }
+
+    fn changes(transitions: &[Transition]) -> Vec<(&str, bool, bool)> {
+        transitions
+            .iter()
+            .map(|t| (t.code.as_str(), t.active, t.ticket))
+            .collect()
+    }
+
+    /// A printer with nothing wrong.
+    fn healthy() -> Printer {
+        let printer = Printer::default();
+        for request in 2..=4 {
+            printer.set(request, Some(0x12));
+        }
+        printer
+    }
+
+    #[test]
+    fn the_built_in_table_is_valid_and_extendable() {
+        let builtin = table(&[]);
+        assert!(builtin.iter().all(|rule| rule.validate().is_ok()));
+        assert!(builtin.iter().all(|rule| rule.messages.contains_key("en")));
+
+        let cover = rule("cover_open", 2, 0x04, &["de"]);
+        let merged = table(&[cover.clone(), rule("drawer_open", 1, 0x04, &["en"])]);
+        assert_eq!(merged.len(), builtin.len() + 1);
+        assert_eq!(merged.iter().find(|r| r.code == "cover_open"), Some(&cover));
+        assert_eq!(merged.last().unwrap().code, "drawer_open");
+    }
+
+    #[test]
+    fn rules_need_a_status_and_a_mask() {
+        assert!(rule("a", 0, 1, &[]).validate().is_err());
+        assert!(rule("a", 5, 1, &[]).validate().is_err());
+        assert!(rule("a", 1, 0, &[]).validate().is_err());
+        assert!(rule("a", 4, 1, &[]).validate().is_ok());
+    }
+
+    #[test]
+    fn messages_fall_back_to_english_then_anything() {
+        let fault = rule("jam", 3, 8, &["en", "fr"]);
+        assert_eq!(fault.message("fr").title, "fr title");
+        assert_eq!(fault.message("de").title, "en title");
+        assert_eq!(rule("jam", 3, 8, &["fr"]).message("de").title, "fr title");
+        let bare = rule("jam", 3, 8, &[]).message("de");
+        assert_eq!(bare.title, "Printer fault: jam");
+        assert_eq!(
+            ticket_text(&fault.message("en"), "jam", "12:00"),
+            "en title\n\nen action\n\n12:00 (jam)"
+        );
+    }
+
+    #[test]
+    fn faults_are_reported_when_they_start_and_clear() {
+        let printer = healthy();
+        let mut monitor = FaultMonitor::new(table(&[]), "en".to_string());
+        assert!(monitor.poll(&printer).is_empty());
+        // Each status once, however many rules use it
+        assert_eq!(*printer.asked.lock().unwrap(), [2, 3, 4]);
+
+        // Cover open, and out of paper with the low paper bits too
+        printer.set(2, Some(0x16));
+        printer.set(4, Some(0x7E));
+        let started = monitor.poll(&printer);
+        assert_eq!(
+            changes(&started),
+            [
+                ("cover_open", true, false),
+                ("paper_out", true, false),
+                ("paper_low", true, true),
+            ]
+        );
+        assert_eq!(started[0].message.title, "The printer cover was open");
+        assert_eq!(monitor.active(), ["cover_open", "paper_low", "paper_out"]);
+        assert!(monitor.poll(&printer).is_empty());
+
+        // A fault whose ticket waited gets it when it clears
+        printer.set(2, Some(0x12));
+        printer.set(4, Some(0x12));
+        assert_eq!(
+            changes(&monitor.poll(&printer)),
+            [
+                ("cover_open", false, true),
+                ("paper_out", false, true),
+                ("paper_low", false, false),
+            ]
+        );
+        assert!(monitor.active().is_empty());
+    }
+
+    #[test]
+    fn unanswered_statuses_change_nothing() {
+        let printer = healthy();
+        let mut monitor = FaultMonitor::new(table(&[]), "en".to_string());
+        printer.set(3, Some(0x12 | 0x08));
+        assert_eq!(
+            changes(&monitor.poll(&printer)),
+            [("cutter_jam", true, false)]
+        );
+
+        printer.set(3, None);
+        assert!(monitor.poll(&printer).is_empty());
+        assert_eq!(monitor.active(), ["cutter_jam"]);
+
+        // Nothing more is asked once the first request goes unanswered
+        printer.set(2, None);
+        printer.asked.lock().unwrap().clear();
+        assert!(monitor.poll(&printer).is_empty());
+        assert_eq!(*printer.asked.lock().unwrap(), [2]);
+    }
+
+    #[test]
+    fn reconfiguring_forgets_faults_no_longer_watched() {
+        let printer = healthy();
+        printer.set(2, Some(0x16));
+        printer.set(3, Some(0x1A));
+        let mut monitor = FaultMonitor::new(table(&[]), "en".to_string());
+        monitor.poll(&printer);
+        assert_eq!(monitor.active(), ["cover_open", "cutter_jam"]);
+        monitor.reconfigure(vec![rule("cutter_jam", 3, 0x08, &["en"])]);
+        assert_eq!(monitor.active(), ["cutter_jam"]);
+    }
+}
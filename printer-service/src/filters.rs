@@ -0,0 +1,421 @@
+//! Per-device job filters from the config file's `filters`: small rewrites
+//! (uppercasing, prefixes, regex replacements, dropping fields, forcing a
+//! priority) applied in
+//! order to every job as it's accepted, before it's spooled or rendered.
+//! Filters only ever look at the job, so the same job always comes out the
+//! same way.
+
+use anyhow::{Context, Result, bail};
+use log::info;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::protocol::{Job, Priority, Segment};
+
+/// One step of the chain, e.g. `{"op":"prefix","field":"text","value":"A-"}`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Filter {
+    /// Uppercases the ticket text, segments included
+    Uppercase,
+    Prefix {
+        field: Field,
+        value: String,
+    },
+    Suffix {
+        field: Field,
+        value: String,
+    },
+    /// Regex find/replace on the ticket text, segments included; `replace`
+    /// can use `$1` or `${name}` for groups
+    Replace {
+        find: String,
+        replace: String,
+    },
+    /// Removes the field, as if the server hadn't sent it
+    DropField {
+        field: Field,
+    },
+    /// Sets the job's priority, whatever the server asked for
+    ForcePriority {
+        priority: Priority,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Field {
+    /// The ticket text before any segments
+    Text,
+    Kind,
+    Segments,
+    Font,
+    LineSpacing,
+    Footer,
+    Compact,
+    Copies,
+    OpenDrawer,
+}
+
+impl Field {
+    /// Whether `prefix` and `suffix` can be used on it.
+    fn is_text(self) -> bool {
+        matches!(self, Field::Text | Field::Kind)
+    }
+}
+
+/// A filter with its regex compiled.
+enum Step {
+    Uppercase,
+    Prefix(Field, String),
+    Suffix(Field, String),
+    Replace(Regex, String),
+    DropField(Field),
+    ForcePriority(Priority),
+}
+
+/// The configured filters, checked and ready to apply.
+pub struct FilterChain {
+    filters: Vec<Filter>,
+    steps: Vec<Step>,
+}
+
+impl FilterChain {
+    /// Checks and compiles `filters`.
+    pub fn new(filters: &[Filter]) -> Result<Self> {
+        let mut steps = Vec::new();
+        for (i, filter) in filters.iter().enumerate() {
+            if let Filter::Prefix { field, .. } | Filter::Suffix { field, .. } = filter
+                && !field.is_text()
+            {
+                bail!(
+                    "Filter {} adds text to {:?}, which isn't a text field",
+                    i + 1,
+                    field
+                );
+            }
+            let step = match filter {
+                Filter::Uppercase => Step::Uppercase,
+                Filter::Prefix { field, value } => Step::Prefix(*field, value.clone()),
+                Filter::Suffix { field, value } => Step::Suffix(*field, value.clone()),
+                Filter::Replace { find, replace } => Step::Replace(
+                    Regex::new(find).with_context(|| {
+                        format!("Filter {} has an invalid regex {:?}", i + 1, find)
+                    })?,
+                    replace.clone(),
+                ),
+                Filter::DropField { field } => Step::DropField(*field),
+                Filter::ForcePriority { priority } => Step::ForcePriority(*priority),
+            };
+            steps.push(step);
+        }
+        Ok(Self {
+            filters: filters.to_vec(),
+            steps,
+        })
+    }
+
+    /// The filters as configured.
+    pub fn filters(&self) -> &[Filter] {
+        &self.filters
+    }
+
+    pub fn apply(&self, job: &mut Job) {
+        for step in &self.steps {
+            match step {
+                Step::Replace(regex, replace) => for_each_text(job, |text| {
+                    *text = regex.replace_all(text, replace.as_str()).into_owned()
+                }),
+                Step::Uppercase => for_each_text(job, |text| *text = text.to_uppercase()),
+                Step::Prefix(field, value) => {
+                    if let Some(text) = text_field(job, *field) {
+                        text.insert_str(0, value);
+                    }
+                }
+                Step::Suffix(field, value) => {
+                    if let Some(text) = text_field(job, *field) {
+                        text.push_str(value);
+                    }
+                }
+                Step::DropField(field) => drop_field(job, *field),
+                Step::ForcePriority(priority) => job.priority = Some(*priority),
+            }
+        }
+    }
+
+    /// A sample receipt as the chain leaves it.
+    pub fn sample(&self) -> Job {
+        let mut sample = Job::plain("Order 42\nSample ticket".to_string());
+        sample.kind = Some("receipt".to_string());
+        self.apply(&mut sample);
+        sample
+    }
+
+    /// Logs the chain and what it makes of a sample ticket, so a surprising
+    /// filter shows up at startup rather than on the first real job.
+    pub fn dry_run(&self) {
+        if self.filters.is_empty() {
+            return;
+        }
+        info!(
+            "Job filters: {}; a sample ticket comes out as {}",
+            serde_json::to_string(&self.filters).expect("filters serialize"),
+            serde_json::to_string(&self.sample()).expect("jobs serialize")
+        );
+    }
+}
+
+fn text_field(job: &mut Job, field: Field) -> Option<&mut String> {
+    match field {
+        Field::Text => Some(&mut job.text),
+        // A job without a kind gets none rather than just the affix
+        Field::Kind => job.kind.as_mut(),
+        _ => None,
+    }
+}
+
+fn drop_field(job: &mut Job, field: Field) {
+    match field {
+        Field::Text => job.text.clear(),
+        Field::Kind => job.kind = None,
+        Field::Segments => job.segments.clear(),
+        Field::Font => job.font = None,
+        Field::LineSpacing => job.line_spacing = None,
+        Field::Footer => job.footer = None,
+        Field::Compact => job.compact = None,
+        Field::Copies => job.copies = None,
+        Field::OpenDrawer => job.open_drawer = false,
+    }
+}
+
+/// Runs `f` on the job's text and every text segment, boxes included.
+fn for_each_text(job: &mut Job, mut f: impl FnMut(&mut String)) {
+    fn walk(segments: &mut [Segment], f: &mut impl FnMut(&mut String)) {
+        for segment in segments {
+            match segment {
+                Segment::Text(text) => f(&mut text.text),
+                Segment::Box { segments: inner } => walk(inner, f),
+                Segment::Rule { .. }
+                | Segment::Spacer { .. }
+                | Segment::Image { .. }
+                | Segment::Timestamp { .. } => {}
+            }
+        }
+    }
+    f(&mut job.text);
+    walk(&mut job.segments, &mut f);
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{Value, json};
+
+    use super::*;
+
+    fn chain(filters: Value) -> Result<FilterChain> {
+        FilterChain::new(&serde_json::from_value::<Vec<Filter>>(filters).unwrap())
+    }
+
+    fn job(job: Value) -> Job {
+        serde_json::from_value(job).unwrap()
+    }
+
+    /// The job after `filters`, as JSON.
+    fn filtered(filters: Value, input: Value) -> Value {
+        let mut input = job(input);
+        chain(filters).unwrap().apply(&mut input);
+        serde_json::to_value(&input).unwrap()
+    }
+
+    fn ticket() -> Value {
+        json!({
+            "text": "Order 42",
+            "kind": "kitchen",
+            "segments": [
+                {"text": "Flat white"},
+                {"rule": "double"},
+                {"box": [{"text": "No sugar"}, {"spacer": 8}]},
+            ],
+        })
+    }
+
+    #[test]
+    fn an_empty_chain_changes_nothing() {
+        let unfiltered = serde_json::to_value(job(ticket())).unwrap();
+        assert_eq!(filtered(json!([]), ticket()), unfiltered);
+        assert!(chain(json!([])).unwrap().filters().is_empty());
+    }
+
+    #[test]
+    fn uppercase_reaches_segments_and_boxes() {
+        let out = filtered(json!([{"op": "uppercase"}]), ticket());
+        assert_eq!(out["text"], "ORDER 42");
+        assert_eq!(out["segments"][0]["text"], "FLAT WHITE");
+        assert_eq!(out["segments"][2]["box"][0]["text"], "NO SUGAR");
+        // Only text changes, not what the ticket is for
+        assert_eq!(out["kind"], "kitchen");
+        assert_eq!(out["segments"][1]["rule"], "double");
+    }
+
+    #[test]
+    fn prefix_and_suffix_go_on_text_fields() {
+        let out = filtered(
+            json!([
+                {"op": "prefix", "field": "text", "value": "B-"},
+                {"op": "suffix", "field": "text", "value": " (bar)"},
+                {"op": "prefix", "field": "kind", "value": "bar-"},
+            ]),
+            ticket(),
+        );
+        assert_eq!(out["text"], "B-Order 42 (bar)");
+        assert_eq!(out["kind"], "bar-kitchen");
+        // Segments are left alone
+        assert_eq!(out["segments"][0]["text"], "Flat white");
+
+        let out = filtered(
+            json!([{"op": "suffix", "field": "kind", "value": "-bar"}]),
+            json!({"text": "No kind"}),
+        );
+        assert!(out.get("kind").is_none(), "{}", out);
+    }
+
+    #[test]
+    fn prefix_and_suffix_refuse_other_fields() {
+        for op in ["prefix", "suffix"] {
+            for field in ["segments", "font", "copies", "open_drawer"] {
+                let error = chain(json!([
+                    {"op": "uppercase"},
+                    {"op": op, "field": field, "value": "x"},
+                ]))
+                .err()
+                .unwrap_or_else(|| panic!("{} on {} was accepted", op, field));
+                assert!(error.to_string().starts_with("Filter 2 "), "{}", error);
+            }
+        }
+    }
+
+    #[test]
+    fn replace_uses_groups_everywhere_text_is() {
+        let out = filtered(
+            json!([{"op": "replace", "find": r"Order (?<n>\d+)", "replace": "#${n}"}]),
+            ticket(),
+        );
+        assert_eq!(out["text"], "#42");
+        let out = filtered(
+            json!([{"op": "replace", "find": "(?i)no (\\w+)", "replace": "NO $1!"}]),
+            ticket(),
+        );
+        assert_eq!(out["segments"][2]["box"][0]["text"], "NO sugar!");
+        // No match, no change
+        let out = filtered(
+            json!([{"op": "replace", "find": "soy", "replace": "oat"}]),
+            ticket(),
+        );
+        assert_eq!(out, serde_json::to_value(job(ticket())).unwrap());
+    }
+
+    #[test]
+    fn replace_refuses_an_invalid_regex() {
+        let error = chain(json!([{"op": "replace", "find": "(unclosed", "replace": ""}]))
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("invalid regex"), "{}", error);
+    }
+
+    #[test]
+    fn drop_field_removes_each_field() {
+        let full = json!({
+            "text": "Order 42",
+            "kind": "receipt",
+            "segments": [{"text": "Flat white"}],
+            "font": "b",
+            "line_spacing": 40,
+            "footer": false,
+            "compact": true,
+            "copies": 2,
+            "open_drawer": true,
+        });
+        for (field, key) in [
+            ("text", "text"),
+            ("kind", "kind"),
+            ("segments", "segments"),
+            ("font", "font"),
+            ("line_spacing", "line_spacing"),
+            ("footer", "footer"),
+            ("compact", "compact"),
+            ("copies", "copies"),
+            ("open_drawer", "open_drawer"),
+        ] {
+            let mut dropped = job(full.clone());
+            chain(json!([{"op": "drop_field", "field": field}]))
+                .unwrap()
+                .apply(&mut dropped);
+            let kept = serde_json::to_value(job(full.clone())).unwrap();
+            let dropped = serde_json::to_value(dropped).unwrap();
+            assert_ne!(dropped[key], kept[key], "{} wasn't dropped", field);
+            let empty = serde_json::to_value(Job::plain(String::new())).unwrap();
+            assert_eq!(
+                dropped.get(key),
+                empty.get(key),
+                "{} isn't as if it weren't sent",
+                field
+            );
+        }
+    }
+
+    #[test]
+    fn force_priority_overrides_the_server() {
+        let high = json!([{"op": "force_priority", "priority": "high"}]);
+        assert_eq!(filtered(high.clone(), ticket())["priority"], "high");
+        let mut low = ticket();
+        low["priority"] = json!("low");
+        assert_eq!(filtered(high, low)["priority"], "high");
+        assert!(
+            serde_json::from_value::<Vec<Filter>>(
+                json!([{"op": "force_priority", "priority": "urgent"}])
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn unknown_filters_and_fields_are_refused() {
+        assert!(serde_json::from_value::<Vec<Filter>>(json!([{"op": "reverse"}])).is_err());
+        assert!(
+            serde_json::from_value::<Vec<Filter>>(json!([{"op": "drop_field", "field": "id"}]))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn filters_run_in_order() {
+        let prefix_first = filtered(
+            json!([
+                {"op": "prefix", "field": "text", "value": "b-"},
+                {"op": "uppercase"},
+            ]),
+            ticket(),
+        );
+        assert_eq!(prefix_first["text"], "B-ORDER 42");
+        let uppercase_first = filtered(
+            json!([
+                {"op": "uppercase"},
+                {"op": "prefix", "field": "text", "value": "b-"},
+                {"op": "replace", "find": "^b-", "replace": "Bar "},
+            ]),
+            ticket(),
+        );
+        assert_eq!(uppercase_first["text"], "Bar ORDER 42");
+    }
+
+    #[test]
+    fn the_chain_keeps_its_filters_as_configured() {
+        let filters = json!([
+            {"op": "uppercase"},
+            {"op": "drop_field", "field": "copies"},
+        ]);
+        let chain = chain(filters.clone()).unwrap();
+        assert_eq!(serde_json::to_value(chain.filters()).unwrap(), filters);
+        chain.dry_run();
+    }
+}
@@ -0,0 +1,282 @@
+//! Promotional footers from the device config, appended to matching jobs
+//! after the body and rotated from one job to the next.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use log::warn;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::protocol::Job;
+
+const STATE_FILE: &str = "footer.json";
+
+/// The `footers` section of the device config.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct FooterConfig {
+    #[serde(default)]
+    pub rotation: Rotation,
+    #[serde(default)]
+    pub items: Vec<Footer>,
+}
+
+impl FooterConfig {
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Rotation {
+    #[default]
+    RoundRobin,
+    Random,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Footer {
+    /// Printed centred, wrapped at the paper width
+    #[serde(default)]
+    pub lines: Vec<String>,
+    /// Printed as a QR code below the lines
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub qr: Option<String>,
+    /// Printed as a Code 39 barcode below the lines
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub barcode: Option<String>,
+    /// Job kinds the footer goes on (empty = every job)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub kinds: Vec<String>,
+}
+
+impl Footer {
+    fn applies_to(&self, job: &Job) -> bool {
+        self.kinds.is_empty()
+            || job
+                .kind
+                .as_ref()
+                .is_some_and(|kind| self.kinds.contains(kind))
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct State {
+    /// Round-robin position, counted over all footered jobs
+    next: usize,
+}
+
+/// Picks the footer for each job and remembers where the rotation is, in
+/// the state dir if there is one so restarts carry on from the same place.
+pub struct Footers {
+    config: FooterConfig,
+    state_path: Option<PathBuf>,
+    next: usize,
+}
+
+impl Footers {
+    pub fn new(config: FooterConfig, state_dir: Option<&Path>) -> Self {
+        let state_path = state_dir.map(|dir| dir.join(STATE_FILE));
+        let next = state_path
+            .as_deref()
+            .filter(|path| path.exists())
+            .and_then(|path| {
+                let state = fs::read_to_string(path)
+                    .ok()
+                    .and_then(|text| serde_json::from_str::<State>(&text).ok());
+                if state.is_none() {
+                    warn!("Ignoring unreadable footer state {}", path.display());
+                }
+                state
+            })
+            .map_or(0, |state| state.next);
+        Self {
+            config,
+            state_path,
+            next,
+        }
+    }
+
+    pub fn reconfigure(&mut self, config: FooterConfig) {
+        self.config = config;
+    }
+
+    /// The footer the next print of `job` would get, without moving the
+    /// rotation on. Random rotation shows the first matching footer.
+    pub fn peek(&self, job: &Job) -> Option<&Footer> {
+        let matching = self.matching(job);
+        if matching.is_empty() {
+            return None;
+        }
+        Some(match self.config.rotation {
+            Rotation::RoundRobin => matching[self.next % matching.len()],
+            Rotation::Random => matching[0],
+        })
+    }
+
+    /// Picks the footer for printing `job` and moves the rotation on.
+    pub fn pick(&mut self, job: &Job) -> Option<Footer> {
+        let matching = self.matching(job);
+        if matching.is_empty() {
+            return None;
+        }
+        let footer = match self.config.rotation {
+            Rotation::RoundRobin => matching[self.next % matching.len()].clone(),
+            Rotation::Random => matching[rand::rng().random_range(0..matching.len())].clone(),
+        };
+        if self.config.rotation == Rotation::RoundRobin {
+            self.next = self.next.wrapping_add(1);
+            self.save();
+        }
+        Some(footer)
+    }
+
+    fn matching(&self, job: &Job) -> Vec<&Footer> {
+        if job.footer == Some(false) {
+            return Vec::new();
+        }
+        self.config
+            .items
+            .iter()
+            .filter(|footer| footer.applies_to(job))
+            .collect()
+    }
+
+    fn save(&self) {
+        let Some(path) = &self.state_path else {
+            return;
+        };
+        let state = State { next: self.next };
+        let text = serde_json::to_string(&state).expect("footer state serializes");
+        if let Err(e) = fs::write(path, text) {
+            warn!("Failed to save footer state to {}: {}", path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::tempdir::TempDir;
+
+    fn config(rotation: &str) -> FooterConfig {
+        serde_json::from_value(json!({
+            "rotation": rotation,
+            "items": [
+                {"lines": ["Loyalty: 10th coffee free"]},
+                {"lines": ["Try our muffins"], "kinds": ["receipt"]},
+                {"lines": ["Rate us"], "qr": "https://example.com/rate"},
+            ],
+        }))
+        .unwrap()
+    }
+
+    fn job(kind: Option<&str>) -> Job {
+        let mut job = Job::plain("Order 42".to_string());
+        job.kind = kind.map(str::to_string);
+        job
+    }
+
+    /// First lines of the footers `count` jobs of `kind` get in turn.
+    fn picks(footers: &mut Footers, kind: Option<&str>, count: usize) -> Vec<String> {
+        (0..count)
+            .map(|_| footers.pick(&job(kind)).unwrap().lines[0].clone())
+            .collect()
+    }
+
+    #[test]
+    fn round_robin_goes_through_the_footers_for_the_kind() {
+        let mut footers = Footers::new(config("round_robin"), None);
+        assert_eq!(
+            picks(&mut footers, Some("receipt"), 4),
+            [
+                "Loyalty: 10th coffee free",
+                "Try our muffins",
+                "Rate us",
+                "Loyalty: 10th coffee free"
+            ]
+        );
+        // Kitchen tickets skip the receipt-only one; the rotation is shared
+        let mut footers = Footers::new(config("round_robin"), None);
+        assert_eq!(
+            picks(&mut footers, Some("kitchen"), 3),
+            [
+                "Loyalty: 10th coffee free",
+                "Rate us",
+                "Loyalty: 10th coffee free"
+            ]
+        );
+        assert_eq!(picks(&mut footers, None, 1), ["Rate us"]);
+    }
+
+    #[test]
+    fn peeking_leaves_the_rotation_where_it_is() {
+        let mut footers = Footers::new(config("round_robin"), None);
+        let receipt = job(Some("receipt"));
+        footers.pick(&receipt);
+        let peeked = footers.peek(&receipt).cloned();
+        assert_eq!(footers.peek(&receipt).cloned(), peeked);
+        assert_eq!(footers.pick(&receipt), peeked);
+    }
+
+    #[test]
+    fn random_rotation_picks_a_matching_footer() {
+        let mut footers = Footers::new(config("random"), None);
+        for _ in 0..20 {
+            let footer = footers.pick(&job(Some("kitchen"))).unwrap();
+            assert_ne!(footer.lines, ["Try our muffins"]);
+        }
+        assert_eq!(
+            footers.peek(&job(None)).unwrap().lines,
+            ["Loyalty: 10th coffee free"]
+        );
+    }
+
+    #[test]
+    fn jobs_can_leave_the_footer_off() {
+        let mut footers = Footers::new(config("round_robin"), None);
+        let mut plain = job(Some("receipt"));
+        plain.footer = Some(false);
+        assert!(footers.pick(&plain).is_none());
+        assert!(footers.peek(&plain).is_none());
+        // and doing so doesn't use up a turn
+        assert_eq!(picks(&mut footers, None, 1), ["Loyalty: 10th coffee free"]);
+
+        let mut none = Footers::new(FooterConfig::default(), None);
+        assert!(FooterConfig::default().is_empty());
+        assert!(none.pick(&job(None)).is_none());
+    }
+
+    #[test]
+    fn the_rotation_carries_on_after_a_restart() {
+        let dir = TempDir::new("footer");
+        let mut footers = Footers::new(config("round_robin"), Some(dir.path()));
+        picks(&mut footers, None, 1);
+        let mut restarted = Footers::new(config("round_robin"), Some(dir.path()));
+        assert_eq!(picks(&mut restarted, None, 1), ["Rate us"]);
+
+        // A broken state file starts the rotation again
+        fs::write(dir.path().join(STATE_FILE), "{").unwrap();
+        let mut broken = Footers::new(config("round_robin"), Some(dir.path()));
+        assert_eq!(
+            picks(&mut broken, Some("receipt"), 1),
+            ["Loyalty: 10th coffee free"]
+        );
+    }
+
+    #[test]
+    fn reconfiguring_keeps_the_position() {
+        let mut footers = Footers::new(config("round_robin"), None);
+        picks(&mut footers, Some("receipt"), 2);
+        let mut fewer = config("round_robin");
+        fewer.items.truncate(1);
+        footers.reconfigure(fewer);
+        assert_eq!(
+            picks(&mut footers, Some("receipt"), 2),
+            ["Loyalty: 10th coffee free"; 2]
+        );
+    }
+}
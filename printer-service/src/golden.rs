@@ -0,0 +1,253 @@
+//! Golden tests: render every fixture job in `fixtures/` and compare the
+//! bytes with the golden output committed next to it, so a rendering change
+//! that alters some style of ticket shows up in review instead of on paper.
+//! Each fixture `<name>.json` has a readable golden `<name>.golden.txt` (the
+//! `--mock-pretty` transcript) and the exact bytes in `<name>.golden.bin`.
+//! `UPDATE_GOLDENS=1 cargo test` rewrites them from the current output.
+
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result, bail};
+use serde::Deserialize;
+
+use crate::footer::Footer;
+use crate::profile::PrinterProfile;
+use crate::protocol::Job;
+use crate::render;
+use crate::service::supported_capabilities;
+use crate::transcript;
+
+/// Unchanged lines shown around each change in a diff
+const CONTEXT: usize = 2;
+/// Bytes shown either side of the first difference in the bytes
+const BYTE_CONTEXT: usize = 8;
+
+/// A fixture file: a job as the server would send it, and what to render it with.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Fixture {
+    /// What the fixture covers, shown when it changes
+    #[serde(default)]
+    description: String,
+    /// Printer profile name
+    #[serde(default = "default_profile")]
+    profile: String,
+    /// Footer to print after the job, as in the device config's `footers`
+    #[serde(default)]
+    footer: Option<Footer>,
+    job: Job,
+}
+
+fn default_profile() -> String {
+    "default".to_string()
+}
+
+/// What rendering a fixture produced.
+struct Output {
+    description: String,
+    transcript: String,
+    bytes: Vec<u8>,
+}
+
+#[test]
+fn fixtures_match_their_goldens() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures");
+    let update = std::env::var("UPDATE_GOLDENS").is_ok_and(|v| v == "1");
+    let mut names: Vec<String> = fs::read_dir(&dir)
+        .unwrap()
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter_map(|name| name.strip_suffix(".json").map(str::to_string))
+        .collect();
+    names.sort();
+    assert!(!names.is_empty(), "No fixtures in {}", dir.display());
+
+    // Used for every fixture's characters outside the code page
+    #[cfg(feature = "fallback-font")]
+    crate::fallback::install(
+        crate::fallback::FallbackFont::load(&dir.join("fallback.bdf")).unwrap(),
+    );
+
+    let mut report = String::new();
+    let mut failed = 0;
+    for name in &names {
+        let output = match render_fixture(&dir.join(format!("{}.json", name))) {
+            Ok(Some(output)) => output,
+            Ok(None) => {
+                println!("skipped {}: needs what this build leaves out", name);
+                continue;
+            }
+            Err(e) => {
+                report.push_str(&format!("INVALID {}: {:#}\n", name, e));
+                failed += 1;
+                continue;
+            }
+        };
+        let text_path = dir.join(format!("{}.golden.txt", name));
+        let bin_path = dir.join(format!("{}.golden.bin", name));
+        if update {
+            fs::write(&text_path, &output.transcript).unwrap();
+            fs::write(&bin_path, &output.bytes).unwrap();
+            println!("updated {}", name);
+            continue;
+        }
+        let (Ok(golden_text), Ok(golden_bytes)) =
+            (fs::read_to_string(&text_path), fs::read(&bin_path))
+        else {
+            report.push_str(&format!("MISSING {}: no golden output\n", name));
+            failed += 1;
+            continue;
+        };
+        if golden_bytes == output.bytes && golden_text == output.transcript {
+            continue;
+        }
+        failed += 1;
+        report.push_str(&format!("CHANGED {}: {}\n", name, output.description));
+        if golden_text == output.transcript {
+            report.push_str("  transcript unchanged, bytes differ:\n");
+        } else {
+            report.push_str(&diff(&golden_text, &output.transcript));
+        }
+        if golden_bytes != output.bytes {
+            report.push_str(&byte_difference(&golden_bytes, &output.bytes));
+        }
+    }
+    assert!(
+        failed == 0,
+        "{} of {} fixtures don't match their goldens; if the change is intended, \
+         run with UPDATE_GOLDENS=1 and commit the new goldens\n{}",
+        failed,
+        names.len(),
+        report
+    );
+}
+
+/// Checks a fixture and renders its job with the recording driver. `None`
+/// if the job needs a capability this build leaves out.
+fn render_fixture(path: &Path) -> Result<Option<Output>> {
+    let text =
+        fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let fixture: Fixture = serde_json::from_str(&text).context("Not a valid fixture")?;
+    if let Some(field) = fixture.job.unknown.keys().next() {
+        bail!("Job has unknown field {:?}", field);
+    }
+    let Some(profile) = PrinterProfile::find(&fixture.profile) else {
+        bail!("No printer profile {:?}", fixture.profile);
+    };
+    let missing = fixture
+        .job
+        .missing_capabilities(&supported_capabilities(profile, None));
+    if missing == ["unicode"] && !cfg!(feature = "fallback-font") {
+        return Ok(None);
+    }
+    if !missing.is_empty() {
+        bail!(
+            "Job requires unsupported capabilities {}",
+            missing.join(", ")
+        );
+    }
+    let rendered = render::render_job(&fixture.job, profile, fixture.footer.as_ref())?;
+    Ok(Some(Output {
+        description: fixture.description,
+        transcript: transcript::render(
+            &transcript::decode(&rendered.bytes, profile.commands),
+            profile,
+        ),
+        bytes: rendered.bytes,
+    }))
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Change {
+    Same,
+    Removed,
+    Added,
+}
+
+/// Line diff from `old` to `new`, with `-` for golden lines that went away
+/// and `+` for new ones, and a few unchanged lines around each change.
+fn diff(old: &str, new: &str) -> String {
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+
+    // Longest common subsequence lengths of every pair of suffixes
+    let mut common = vec![vec![0u32; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i][j] = if old[i] == new[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+    let mut lines = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            lines.push((Change::Same, old[i], i + 1));
+            i += 1;
+            j += 1;
+        } else if j < new.len() && (i == old.len() || common[i][j + 1] >= common[i + 1][j]) {
+            lines.push((Change::Added, new[j], i + 1));
+            j += 1;
+        } else {
+            lines.push((Change::Removed, old[i], i + 1));
+            i += 1;
+        }
+    }
+
+    let near_change = |at: usize| {
+        let from = at.saturating_sub(CONTEXT);
+        let to = (at + CONTEXT + 1).min(lines.len());
+        lines[from..to]
+            .iter()
+            .any(|(change, ..)| *change != Change::Same)
+    };
+    let mut out = String::new();
+    let mut skipped = true;
+    for (at, (change, line, old_line)) in lines.iter().enumerate() {
+        if !near_change(at) {
+            skipped = true;
+            continue;
+        }
+        if skipped {
+            out.push_str(&format!("  @@ golden line {} @@\n", old_line));
+            skipped = false;
+        }
+        let mark = match change {
+            Change::Same => ' ',
+            Change::Removed => '-',
+            Change::Added => '+',
+        };
+        out.push_str(&format!("  {} {}\n", mark, line));
+    }
+    out
+}
+
+/// Where the bytes first differ, with a few bytes of each around it.
+fn byte_difference(old: &[u8], new: &[u8]) -> String {
+    let at = old
+        .iter()
+        .zip(new)
+        .position(|(a, b)| a != b)
+        .unwrap_or(old.len().min(new.len()));
+    let window = |bytes: &[u8]| {
+        let from = at.saturating_sub(BYTE_CONTEXT);
+        let to = (at + BYTE_CONTEXT).min(bytes.len());
+        bytes[from..to]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<Vec<_>>()
+            .join(" ")
+    };
+    format!(
+        "  bytes: {} golden, {} now, first difference at offset {}\n  golden: {}\n  now:    {}\n",
+        old.len(),
+        new.len(),
+        at,
+        window(old),
+        window(new)
+    )
+}
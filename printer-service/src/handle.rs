@@ -0,0 +1,481 @@
+//! The service as a library: [`PrinterService::builder`] starts it on the
+//! current tokio runtime, and the handle it returns submits jobs, asks for
+//! the status and shuts it down. Besides its main printer, a service can
+//! print on other [`Target`]s, each with its own queue and task.
+
+use std::collections::HashSet;
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::{Context, Result, anyhow, bail};
+use escpos::driver::Driver;
+use tokio::runtime::{Handle, RuntimeFlavor};
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+
+use crate::driver::BoxedDriver;
+use crate::outbox::Outbox;
+use crate::profile::PrinterProfile;
+use crate::protocol::{AckStatus, ErrorCode, Job, Outbound, Status, Timing};
+use crate::resources;
+use crate::service::{Service, ServiceConfig};
+use crate::transport::{Call, DEFAULT_TARGET, Printer, Printers, Router, Transport};
+
+pub(crate) type StatusCallback = Arc<dyn Fn(&Status) + Send + Sync>;
+
+type Reconnect = Box<dyn Fn() -> Result<BoxedDriver> + Send>;
+
+/// How a job given to [`PrinterService::submit`] ended. More outcomes may
+/// be added, so matches need a catch-all arm.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub enum JobOutcome {
+    Printed(Timing),
+    /// Refused before it was queued, e.g. for a capability the printer lacks
+    Rejected {
+        error: ErrorCode,
+        message: String,
+    },
+    /// The printer failed to print it
+    Failed {
+        error: ErrorCode,
+        message: String,
+    },
+    /// It reached the front of the queue after its `expires_at`
+    Expired,
+    /// The service stopped before the job was printed. It's still in the
+    /// spool, if there is one, and is printed when the service next starts.
+    Stopped,
+}
+
+impl JobOutcome {
+    /// The outcome an ack reports, if it's a final one.
+    pub(crate) fn from_ack(ack: &Outbound) -> Option<Self> {
+        let Outbound::Ack {
+            status,
+            error,
+            message,
+            timing,
+            ..
+        } = ack
+        else {
+            return None;
+        };
+        let error = error.unwrap_or(ErrorCode::InternalError);
+        let message = message.clone().unwrap_or_default();
+        match status {
+            AckStatus::Accepted | AckStatus::Printing => None,
+            AckStatus::Printed => Some(Self::Printed(timing.unwrap_or_default())),
+            AckStatus::Failed => Some(Self::Failed { error, message }),
+            AckStatus::Rejected => Some(Self::Rejected { error, message }),
+            AckStatus::Expired => Some(Self::Expired),
+        }
+    }
+}
+
+/// A running printer service. Dropping it shuts the service down without
+/// waiting for it; [`shutdown`](Self::shutdown) waits.
+pub struct PrinterService {
+    calls: mpsc::UnboundedSender<Call>,
+    task: JoinHandle<Result<()>>,
+}
+
+impl PrinterService {
+    pub fn builder() -> PrinterServiceBuilder {
+        PrinterServiceBuilder::default()
+    }
+
+    /// Queues a job, resolving once it has been printed or has failed. Jobs
+    /// without an id are given one. The job is queued when this is called,
+    /// not when the future is first polled.
+    pub fn submit(&self, job: Job) -> impl Future<Output = JobOutcome> + Send + use<> {
+        let (done, outcome) = oneshot::channel();
+        let _ = self.calls.send(Call::Submit {
+            job: Box::new(job),
+            done,
+        });
+        async move { outcome.await.unwrap_or(JobOutcome::Stopped) }
+    }
+
+    /// What the main printer is doing right now.
+    pub async fn status(&self) -> Result<Status> {
+        self.target_status(None).await
+    }
+
+    /// What the printer added as the [`Target`] called `name` is doing.
+    pub async fn printer_status(&self, name: &str) -> Result<Status> {
+        self.target_status(Some(name.to_string())).await
+    }
+
+    async fn target_status(&self, target: Option<String>) -> Result<Status> {
+        let name = target.clone();
+        let (done, status) = oneshot::channel();
+        self.calls
+            .send(Call::Status { target, done })
+            .map_err(|_| anyhow!("Printer service has stopped"))?;
+        status.await.map_err(|_| match name {
+            Some(name) => anyhow!("Printer {} has stopped or doesn't exist", name),
+            None => anyhow!("Printer service has stopped"),
+        })
+    }
+
+    /// Stops one printer once the job it's printing, if any, is done,
+    /// leaving the others running. The service stops with the last one.
+    pub async fn shutdown_printer(&self, name: &str) -> Result<()> {
+        let (done, stopped) = oneshot::channel();
+        self.calls
+            .send(Call::Stop {
+                target: name.to_string(),
+                done,
+            })
+            .map_err(|_| anyhow!("Printer service has stopped"))?;
+        stopped
+            .await
+            .map_err(|_| anyhow!("Printer service has stopped"))?
+    }
+
+    /// Stops the service once the jobs being printed, if any, are done.
+    /// Jobs still queued are left in the spool.
+    pub async fn shutdown(self) -> Result<()> {
+        let _ = self.calls.send(Call::Shutdown);
+        joined(self.task.await)
+    }
+
+    /// Waits for the service to stop by itself, which it only does when
+    /// every printer is gone for good.
+    pub async fn wait(self) -> Result<()> {
+        let Self { calls, task } = self;
+        let result = joined(task.await);
+        drop(calls);
+        result
+    }
+}
+
+fn joined(joined: Result<Result<()>, tokio::task::JoinError>) -> Result<()> {
+    joined.unwrap_or_else(|e| Err(anyhow!("Printer service failed: {}", e)))
+}
+
+/// Another printer for a [`PrinterService`], besides the one given to
+/// [`PrinterServiceBuilder::driver`]. Jobs and commands reach it by naming
+/// it as their `target`; it has its own queue, rate limit and spool, so it
+/// keeps printing while another printer is jammed.
+pub struct Target {
+    name: String,
+    driver: BoxedDriver,
+    reconnect: Option<Reconnect>,
+    profile: Option<PrinterProfile>,
+}
+
+impl Target {
+    pub fn new(name: impl Into<String>, driver: impl Driver + Send + 'static) -> Self {
+        Self {
+            name: name.into(),
+            driver: BoxedDriver::new(driver),
+            reconnect: None,
+            profile: None,
+        }
+    }
+
+    /// The printer's profile, if it isn't the same model as the main one.
+    pub fn profile(mut self, profile: PrinterProfile) -> Self {
+        self.profile = Some(profile);
+        self
+    }
+
+    /// Like [`PrinterServiceBuilder::reconnect`], for this printer.
+    pub fn reconnect<D, F>(mut self, reconnect: F) -> Self
+    where
+        D: Driver + Send + 'static,
+        F: Fn() -> Result<D> + Send + 'static,
+    {
+        self.reconnect = Some(Box::new(move || reconnect().map(BoxedDriver::new)));
+        self
+    }
+
+    /// The main printer's config with this printer's profile, and its own
+    /// directories under the main ones.
+    fn config(&self, main: &ServiceConfig) -> Result<ServiceConfig> {
+        let own = |dir: &Option<PathBuf>| dir.as_ref().map(|dir| dir.join(&self.name));
+        let mut config = main.clone();
+        if let Some(profile) = &self.profile {
+            config.profile = profile.clone();
+        }
+        // What was probed is the main printer
+        config.printer = None;
+        config.spool_dir = own(&main.spool_dir);
+        config.state_dir = own(&main.state_dir);
+        config.archive_dir = own(&main.archive_dir);
+        if let Some(dir) = config.state_dir.as_deref().or(config.spool_dir.as_deref()) {
+            config.at_rest = main.at_rest.for_dir(dir)?;
+        }
+        Ok(config)
+    }
+}
+
+/// Sets up a [`PrinterService`]. Only the driver has to be given.
+#[derive(Default)]
+pub struct PrinterServiceBuilder {
+    config: ServiceConfig,
+    driver: Option<BoxedDriver>,
+    reconnect: Option<Reconnect>,
+    targets: Vec<Target>,
+    transport: Option<Transport>,
+    on_status: Option<StatusCallback>,
+}
+
+impl PrinterServiceBuilder {
+    pub fn config(mut self, config: ServiceConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// What to print on.
+    pub fn driver(mut self, driver: impl Driver + Send + 'static) -> Self {
+        self.driver = Some(BoxedDriver::new(driver));
+        self
+    }
+
+    /// How to open the printer again after it goes away. Without this, the
+    /// service stops after too many failed writes.
+    pub fn reconnect<D, F>(mut self, reconnect: F) -> Self
+    where
+        D: Driver + Send + 'static,
+        F: Fn() -> Result<D> + Send + 'static,
+    {
+        self.reconnect = Some(Box::new(move || reconnect().map(BoxedDriver::new)));
+        self
+    }
+
+    /// Adds another printer. With any, every frame sent to the server names
+    /// the printer it's from, the main one as `default`.
+    pub fn target(mut self, target: Target) -> Self {
+        self.targets.push(target);
+        self
+    }
+
+    /// Where jobs come from besides `submit`. Defaults to
+    /// [`Transport::Embedded`], which is only `submit`.
+    pub fn transport(mut self, transport: Transport) -> Self {
+        self.transport = Some(transport);
+        self
+    }
+
+    /// Called with the new status whenever a job finishes, printing pauses
+    /// or resumes, or the printer reports a fault. It runs on the printer's
+    /// task, so it should be quick.
+    pub fn on_status(mut self, on_status: impl Fn(&Status) + Send + Sync + 'static) -> Self {
+        self.on_status = Some(Arc::new(on_status));
+        self
+    }
+
+    /// Initializes the printer, opens the spool and starts the service on
+    /// the current tokio runtime, which must be a multi-threaded one.
+    pub fn build(self) -> Result<PrinterService> {
+        let runtime = Handle::try_current()
+            .map_err(|_| anyhow!("The printer service needs a tokio runtime"))?;
+        if runtime.runtime_flavor() != RuntimeFlavor::MultiThread {
+            bail!("The printer service needs a multi-threaded tokio runtime");
+        }
+        let driver = self
+            .driver
+            .ok_or_else(|| anyhow!("The printer service needs a driver"))?;
+        let transport = self.transport.unwrap_or(Transport::Embedded);
+        let config = Arc::new(self.config);
+
+        let several = check_names(&self.targets)?;
+        let server = transport != Transport::Embedded;
+        let outbox = Arc::new(Outbox::new(config.outbox_size));
+        // Every printer is set up before any starts, so one that can't be
+        // leaves nothing running
+        let mut services = vec![(
+            DEFAULT_TARGET.to_string(),
+            Service::new(
+                Arc::clone(&config),
+                driver,
+                self.reconnect,
+                Arc::clone(&outbox),
+                server,
+                several.then(|| DEFAULT_TARGET.into()),
+                self.on_status.clone(),
+            )?,
+        )];
+        for target in self.targets {
+            let service = Service::new(
+                Arc::new(target.config(&config)?),
+                target.driver,
+                target.reconnect,
+                Arc::clone(&outbox),
+                server,
+                Some(target.name.as_str().into()),
+                self.on_status.clone(),
+            )
+            .with_context(|| format!("Printer {}", target.name))?;
+            services.push((target.name, service));
+        }
+        let printers = services
+            .into_iter()
+            .map(|(name, service)| Printer::spawn(&name, service))
+            .collect();
+        // An embedding program has its own idea of what SIGHUP means
+        let hangup = match transport {
+            Transport::Embedded => None,
+            _ => Some(signal(SignalKind::hangup())?),
+        };
+        let (calls, inbox) = mpsc::unbounded_channel();
+        let router = Router::new(config, Printers::new(printers), outbox, inbox, hangup);
+        Ok(PrinterService {
+            calls,
+            task: tokio::spawn(router.run(transport)),
+        })
+    }
+}
+
+/// Checks the extra printers' names, saying whether there are any. Each
+/// name is a directory in the main printer's spool, state and archive dirs,
+/// so it can't leave them or take the name of something already there.
+fn check_names(targets: &[Target]) -> Result<bool> {
+    let mut seen = HashSet::new();
+    for target in targets {
+        let name = target.name.as_str();
+        let allowed = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '-';
+        if name.is_empty() || !name.chars().all(allowed) {
+            bail!(
+                "Printer name {:?} can only use letters, digits, _ and -",
+                name
+            );
+        }
+        if name == DEFAULT_TARGET || name == resources::CACHE_DIR {
+            bail!("A printer can't be called {:?}", name);
+        }
+        if !seen.insert(name) {
+            bail!("There are two printers called {}", name);
+        }
+    }
+    Ok(!targets.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::atrest::{AtRest, Keys};
+    use crate::probe::Detected;
+    use crate::render::RecordingDriver;
+    use crate::tempdir::TempDir;
+
+    fn outcome(ack: Outbound) -> Option<JobOutcome> {
+        JobOutcome::from_ack(&ack)
+    }
+
+    #[test]
+    fn only_final_acks_are_outcomes() {
+        let id = Some("a1".to_string());
+        assert!(outcome(Outbound::ack(id.clone(), AckStatus::Accepted)).is_none());
+        assert!(outcome(Outbound::ack(id.clone(), AckStatus::Printing)).is_none());
+        assert!(
+            outcome(Outbound::Progress {
+                id: id.clone(),
+                percent: 50
+            })
+            .is_none()
+        );
+
+        let timing = Timing {
+            write_ms: 120,
+            ..Timing::default()
+        };
+        let Some(JobOutcome::Printed(timing)) = outcome(Outbound::printed(id.clone(), timing))
+        else {
+            panic!("not printed");
+        };
+        assert_eq!(timing.write_ms, 120);
+        assert!(matches!(
+            outcome(Outbound::ack(id.clone(), AckStatus::Expired)),
+            Some(JobOutcome::Expired)
+        ));
+
+        let failed = Outbound::error_ack(
+            id.clone(),
+            AckStatus::Failed,
+            ErrorCode::PrintFailed,
+            "Paper out",
+        );
+        let Some(JobOutcome::Failed { error, message }) = outcome(failed) else {
+            panic!("not failed");
+        };
+        assert_eq!(
+            (error, message.as_str()),
+            (ErrorCode::PrintFailed, "Paper out")
+        );
+        // A refusal without a code still says something went wrong
+        let Some(JobOutcome::Rejected { error, message }) =
+            outcome(Outbound::ack(id, AckStatus::Rejected))
+        else {
+            panic!("not rejected");
+        };
+        assert_eq!((error, message.as_str()), (ErrorCode::InternalError, ""));
+    }
+
+    #[test]
+    fn targets_keep_their_files_under_the_main_printers() {
+        let dir = TempDir::new("handle");
+        let main = ServiceConfig {
+            spool_dir: Some(dir.path().join("spool")),
+            state_dir: Some(dir.path().join("state")),
+            archive_dir: None,
+            printer: Some(Detected::default()),
+            at_rest: AtRest::Encrypted(Keys::new(b"0123456789abcdef", dir.path()).unwrap()),
+            ..ServiceConfig::default()
+        };
+        let kitchen = Target::new("kitchen", RecordingDriver::default())
+            .profile(PrinterProfile::find("serial-58mm").unwrap().clone());
+        let config = kitchen.config(&main).unwrap();
+        assert_eq!(config.spool_dir, Some(dir.path().join("spool/kitchen")));
+        assert_eq!(config.state_dir, Some(dir.path().join("state/kitchen")));
+        assert_eq!(config.archive_dir, None);
+        assert!(config.printer.is_none());
+        assert_eq!(config.profile.name, "serial-58mm");
+        assert!(config.at_rest.sealing().is_some());
+
+        let bar = Target::new("bar", RecordingDriver::default());
+        assert_eq!(bar.config(&main).unwrap().profile.name, main.profile.name);
+    }
+
+    #[test]
+    fn printer_names_must_be_unique_safe_and_not_taken() {
+        let target = |name: &str| Target::new(name, RecordingDriver::default());
+        assert!(!check_names(&[]).unwrap());
+        assert!(check_names(&[target("bar"), target("kitchen_2"), target("Patio-B")]).unwrap());
+        for names in [
+            vec![""],
+            vec![DEFAULT_TARGET],
+            vec!["bar", "bar"],
+            vec!["../bar"],
+            vec!["bar/kitchen"],
+            vec!["bar.1"],
+            vec!["resources"],
+        ] {
+            let targets: Vec<Target> = names.iter().map(|name| target(name)).collect();
+            assert!(check_names(&targets).is_err(), "{:?}", names);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_stopped_printer_has_no_status() {
+        let service = PrinterService::builder()
+            .driver(RecordingDriver::default())
+            .target(Target::new("kitchen", RecordingDriver::default()))
+            .build()
+            .unwrap();
+        assert!(service.printer_status("kitchen").await.is_ok());
+        assert!(service.printer_status("bar").await.is_err());
+        service.shutdown_printer("kitchen").await.unwrap();
+        let error = service.printer_status("kitchen").await.err().unwrap();
+        assert!(error.to_string().contains("kitchen"), "{}", error);
+        assert!(service.status().await.is_ok());
+        // The last printer stopping stops the service
+        service.shutdown_printer(DEFAULT_TARGET).await.unwrap();
+        service.wait().await.unwrap();
+    }
+}
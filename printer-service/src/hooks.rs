@@ -0,0 +1,297 @@
+//! Site-specific commands run after a job finishes, configured in the device
+//! config file. Hooks run in the background and their outcome never changes
+//! what gets printed or acked; failures are only logged and counted.
+
+use std::process::Stdio;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+use tokio::sync::Semaphore;
+
+use crate::protocol::ErrorCode;
+
+/// Hook commands, each run with `sh -c` and given `JOB_ID`, `STATUS`,
+/// `ERROR_CODE` and `DEVICE_ID` in its environment.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct HookConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on_printed: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on_failed: Option<String>,
+    /// Runs (as well as `on_failed`) when a failed job left the printer reporting no paper
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on_paper_out: Option<String>,
+    /// Hooks allowed to run at once; events beyond this are skipped
+    #[serde(default = "default_max_concurrent")]
+    pub max_concurrent: usize,
+    /// Hooks still running after this long are killed
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+fn default_max_concurrent() -> usize {
+    4
+}
+
+fn default_timeout_secs() -> u64 {
+    10
+}
+
+impl Default for HookConfig {
+    fn default() -> Self {
+        Self {
+            on_printed: None,
+            on_failed: None,
+            on_paper_out: None,
+            max_concurrent: default_max_concurrent(),
+            timeout_secs: default_timeout_secs(),
+        }
+    }
+}
+
+impl HookConfig {
+    pub fn is_empty(&self) -> bool {
+        self.on_printed.is_none() && self.on_failed.is_none() && self.on_paper_out.is_none()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum HookEvent {
+    Printed,
+    Failed,
+    PaperOut,
+}
+
+pub struct Hooks {
+    config: HookConfig,
+    device_id: String,
+    slots: Arc<Semaphore>,
+    failures: Arc<AtomicU64>,
+}
+
+impl Hooks {
+    pub fn new(config: HookConfig, device_id: String) -> Self {
+        let slots = Arc::new(Semaphore::new(config.max_concurrent.max(1)));
+        Self {
+            config,
+            device_id,
+            slots,
+            failures: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Swaps in new hook commands. Hooks already running finish under the old
+    /// limits; the failure count carries over.
+    pub fn reconfigure(&mut self, config: HookConfig) {
+        self.slots = Arc::new(Semaphore::new(config.max_concurrent.max(1)));
+        self.config = config;
+    }
+
+    /// Hooks that failed, timed out or were skipped since startup.
+    pub fn failures(&self) -> u64 {
+        self.failures.load(Ordering::Relaxed)
+    }
+
+    /// Starts the hook for `event`, if one is configured. Returns immediately.
+    pub fn fire(&self, event: HookEvent, job_id: Option<&str>, error: Option<ErrorCode>) {
+        let command = match event {
+            HookEvent::Printed => &self.config.on_printed,
+            HookEvent::Failed => &self.config.on_failed,
+            HookEvent::PaperOut => &self.config.on_paper_out,
+        };
+        let Some(command) = command.clone() else {
+            return;
+        };
+        let Ok(permit) = self.slots.clone().try_acquire_owned() else {
+            warn!(
+                "{} hooks already running, skipping {:?} hook for job {:?}",
+                self.config.max_concurrent, event, job_id
+            );
+            self.failures.fetch_add(1, Ordering::Relaxed);
+            return;
+        };
+
+        let status = match event {
+            HookEvent::Printed => "printed",
+            HookEvent::Failed | HookEvent::PaperOut => "failed",
+        };
+        let mut child = match Command::new("sh")
+            .arg("-c")
+            .arg(&command)
+            .env("JOB_ID", job_id.unwrap_or_default())
+            .env("STATUS", status)
+            .env("ERROR_CODE", error.map(|e| e.as_str()).unwrap_or_default())
+            .env("DEVICE_ID", &self.device_id)
+            .stdin(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()
+        {
+            Ok(child) => child,
+            Err(e) => {
+                warn!("Failed to start {:?} hook: {}", event, e);
+                self.failures.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+        };
+
+        let timeout = Duration::from_secs(self.config.timeout_secs);
+        let failures = self.failures.clone();
+        tokio::spawn(async move {
+            let _permit = permit;
+            let failed = match tokio::time::timeout(timeout, child.wait()).await {
+                Ok(Ok(exit)) if exit.success() => {
+                    debug!("{:?} hook finished", event);
+                    false
+                }
+                Ok(Ok(exit)) => {
+                    warn!("{:?} hook exited with {}", event, exit);
+                    true
+                }
+                Ok(Err(e)) => {
+                    warn!("Failed to wait for {:?} hook: {}", event, e);
+                    true
+                }
+                Err(_) => {
+                    warn!(
+                        "{:?} hook still running after {:?}, killing it",
+                        event, timeout
+                    );
+                    let _ = child.kill().await;
+                    true
+                }
+            };
+            if failed {
+                failures.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+    use std::time::Instant;
+
+    use super::*;
+    use crate::tempdir::TempDir;
+
+    /// Waits for `done`, failing after a few seconds.
+    async fn eventually(done: impl Fn() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !done() {
+            assert!(Instant::now() < deadline, "timed out");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    /// A command writing its environment to `name` in `dir`.
+    fn dump_env(dir: &Path, name: &str) -> Option<String> {
+        Some(format!(
+            "echo \"$JOB_ID|$STATUS|$ERROR_CODE|$DEVICE_ID\" > {}.tmp && mv {0}.tmp {0}",
+            dir.join(name).display()
+        ))
+    }
+
+    fn read(dir: &Path, name: &str) -> Option<String> {
+        fs::read_to_string(dir.join(name))
+            .ok()
+            .map(|text| text.trim().to_string())
+    }
+
+    #[test]
+    fn unset_fields_take_their_defaults() {
+        let config: HookConfig = serde_json::from_str(r#"{"on_printed": "true"}"#).unwrap();
+        assert_eq!(config.max_concurrent, 4);
+        assert_eq!(config.timeout_secs, 10);
+        assert!(!config.is_empty());
+        assert!(HookConfig::default().is_empty());
+    }
+
+    #[tokio::test]
+    async fn each_event_runs_its_command_with_the_job_in_its_environment() {
+        let dir = TempDir::new("hooks");
+        let hooks = Hooks::new(
+            HookConfig {
+                on_printed: dump_env(dir.path(), "printed"),
+                on_failed: dump_env(dir.path(), "failed"),
+                on_paper_out: dump_env(dir.path(), "paper"),
+                ..HookConfig::default()
+            },
+            "kiosk-7".to_string(),
+        );
+        hooks.fire(HookEvent::Printed, Some("a1"), None);
+        hooks.fire(HookEvent::Failed, Some("b2"), Some(ErrorCode::PrintFailed));
+        hooks.fire(HookEvent::PaperOut, None, Some(ErrorCode::PrintFailed));
+        eventually(|| {
+            ["printed", "failed", "paper"]
+                .iter()
+                .all(|n| read(dir.path(), n).is_some())
+        })
+        .await;
+        assert_eq!(read(dir.path(), "printed").unwrap(), "a1|printed||kiosk-7");
+        assert_eq!(
+            read(dir.path(), "failed").unwrap(),
+            "b2|failed|PRINT_FAILED|kiosk-7"
+        );
+        assert_eq!(
+            read(dir.path(), "paper").unwrap(),
+            "|failed|PRINT_FAILED|kiosk-7"
+        );
+        assert_eq!(hooks.failures(), 0);
+    }
+
+    #[tokio::test]
+    async fn events_without_a_command_do_nothing() {
+        let hooks = Hooks::new(HookConfig::default(), "kiosk-7".to_string());
+        hooks.fire(HookEvent::Failed, Some("a1"), Some(ErrorCode::PrintFailed));
+        assert_eq!(hooks.failures(), 0);
+    }
+
+    #[tokio::test]
+    async fn failing_hooks_are_counted() {
+        let hooks = Hooks::new(
+            HookConfig {
+                on_failed: Some("exit 3".to_string()),
+                ..HookConfig::default()
+            },
+            "kiosk-7".to_string(),
+        );
+        hooks.fire(HookEvent::Failed, Some("a1"), None);
+        hooks.fire(HookEvent::Failed, Some("a2"), None);
+        eventually(|| hooks.failures() == 2).await;
+    }
+
+    #[tokio::test]
+    async fn hooks_past_the_limit_are_skipped_and_slow_ones_killed() {
+        let dir = TempDir::new("hooks");
+        let mut hooks = Hooks::new(
+            HookConfig {
+                on_printed: Some("sleep 30".to_string()),
+                max_concurrent: 1,
+                timeout_secs: 1,
+                ..HookConfig::default()
+            },
+            "kiosk-7".to_string(),
+        );
+        let started = Instant::now();
+        hooks.fire(HookEvent::Printed, Some("a1"), None);
+        hooks.fire(HookEvent::Printed, Some("a2"), None);
+        assert_eq!(hooks.failures(), 1);
+        eventually(|| hooks.failures() == 2).await;
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        // New limits apply straight away, and the count carries over
+        hooks.reconfigure(HookConfig {
+            on_printed: dump_env(dir.path(), "printed"),
+            ..HookConfig::default()
+        });
+        hooks.fire(HookEvent::Printed, Some("a3"), None);
+        eventually(|| read(dir.path(), "printed").is_some()).await;
+        assert_eq!(hooks.failures(), 2);
+    }
+}
@@ -0,0 +1,322 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, anyhow};
+use aws_lc_rs::rand::SystemRandom;
+use aws_lc_rs::signature::{Ed25519KeyPair, KeyPair};
+use escpos::utils::JustifyMode;
+use log::{info, warn};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::commands::CommandSet;
+use crate::config::{self, DeviceConfig};
+use crate::provision;
+use crate::render::{Rendered, Ticket};
+
+pub const DEFAULT_STATE_DIR: &str = "/var/lib/printer-service";
+const IDENTITY_FILE: &str = "identity.json";
+
+#[derive(clap::Args, Debug)]
+pub struct IdentityArgs {
+    /// Directory holding the device identity
+    #[arg(long, default_value = DEFAULT_STATE_DIR)]
+    state_dir: PathBuf,
+
+    /// Device config naming the printer for --ticket
+    #[arg(long, default_value = config::DEFAULT_CONFIG_PATH)]
+    config: PathBuf,
+
+    /// Also print the identity on paper
+    #[arg(long)]
+    ticket: bool,
+
+    /// Print the ticket to the console instead of the printer
+    #[arg(long)]
+    mock: bool,
+
+    /// Throw away the existing identity and generate a new one. The server
+    /// will no longer recognise the device until it is enrolled again.
+    #[arg(long)]
+    force: bool,
+}
+
+/// The device's stable id and Ed25519 keypair, generated on first start.
+pub struct Identity {
+    pub device_id: String,
+    key_pair: Ed25519KeyPair,
+    /// The private key as stored
+    pkcs8: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct IdentityFile {
+    device_id: String,
+    /// Hex-encoded PKCS#8 Ed25519 private key
+    private_key: String,
+}
+
+impl Identity {
+    /// Loads the identity from `dir`, generating and saving one if there is
+    /// none yet. An unreadable identity is an error rather than a reason to
+    /// make a new one, which would orphan the enrolled device.
+    pub fn load_or_create(dir: &Path) -> Result<Self> {
+        let path = dir.join(IDENTITY_FILE);
+        if path.exists() {
+            return load(&path);
+        }
+        let identity = generate()?;
+        save(&path, &identity)?;
+        info!(
+            "Generated device identity {} in {}",
+            identity.device_id,
+            path.display()
+        );
+        Ok(identity)
+    }
+
+    /// Loads the identity from `dir` if there is one, without generating it.
+    pub fn load_existing(dir: &Path) -> Result<Option<Self>> {
+        let path = dir.join(IDENTITY_FILE);
+        if !path.exists() {
+            return Ok(None);
+        }
+        load(&path).map(Some)
+    }
+
+    /// What the at-rest encryption keys are derived from without a key file.
+    pub fn at_rest_secret(&self) -> &[u8] {
+        &self.pkcs8
+    }
+
+    /// Hex-encoded public key, as sent in the hello frame.
+    pub fn public_key(&self) -> String {
+        hex::encode(self.key_pair.public_key().as_ref())
+    }
+
+    /// Short SHA-256 fingerprint of the public key for comparing by eye.
+    pub fn fingerprint(&self) -> String {
+        let digest = Sha256::digest(self.key_pair.public_key().as_ref());
+        digest[..16]
+            .chunks(2)
+            .map(hex::encode)
+            .collect::<Vec<_>>()
+            .join(":")
+    }
+}
+
+/// Shows the device identity, generating it if needed (or if `--force`).
+pub fn run(args: &IdentityArgs) -> Result<()> {
+    let path = args.state_dir.join(IDENTITY_FILE);
+    let identity = if args.force {
+        if path.exists() {
+            warn!("Replacing device identity in {}", path.display());
+        }
+        let identity = generate()?;
+        save(&path, &identity)?;
+        identity
+    } else {
+        Identity::load_or_create(&args.state_dir)?
+    };
+
+    println!("Device ID:   {}", identity.device_id);
+    println!("Public key:  {}", identity.public_key());
+    println!("Fingerprint: {}", identity.fingerprint());
+
+    if args.ticket {
+        let device_config = if args.config.exists() {
+            config::load(&args.config)?
+        } else {
+            DeviceConfig::default()
+        };
+        let profile = provision::printer_profile(&device_config);
+        provision::print(
+            &device_config,
+            args.mock,
+            &render_ticket(&identity, profile.commands)?,
+        )?;
+    }
+    Ok(())
+}
+
+fn render_ticket(identity: &Identity, commands: CommandSet) -> Result<Rendered> {
+    let mut ticket = Ticket::new(commands)?;
+    ticket.printer.justify(JustifyMode::CENTER)?;
+    ticket.printer.bold(true)?;
+    ticket.line("DEVICE IDENTITY")?;
+    ticket.printer.bold(false)?;
+    ticket.feed()?;
+    ticket.printer.qrcode(&identity.public_key())?;
+    ticket.feed()?;
+    ticket.printer.justify(JustifyMode::LEFT)?;
+    ticket.line(&format!("Device: {}", identity.device_id))?;
+    ticket.line("Key fingerprint:")?;
+    ticket.line(&identity.fingerprint())?;
+    ticket.finish(false)
+}
+
+fn generate() -> Result<Identity> {
+    let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+        .map_err(|_| anyhow!("Failed to generate device key"))?;
+    from_parts(
+        hex::encode(rand::rng().random::<[u8; 6]>()),
+        pkcs8.as_ref().to_vec(),
+    )
+}
+
+fn from_parts(device_id: String, pkcs8: Vec<u8>) -> Result<Identity> {
+    let key_pair = Ed25519KeyPair::from_pkcs8(&pkcs8)
+        .map_err(|_| anyhow!("Device key is not a valid Ed25519 key"))?;
+    Ok(Identity {
+        device_id,
+        key_pair,
+        pkcs8,
+    })
+}
+
+fn load(path: &Path) -> Result<Identity> {
+    let text = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read device identity {}", path.display()))?;
+    let file: IdentityFile = serde_json::from_str(&text)
+        .with_context(|| format!("Failed to parse device identity {}", path.display()))?;
+    let pkcs8 = hex::decode(&file.private_key)
+        .with_context(|| format!("Device key in {} is not valid hex", path.display()))?;
+    from_parts(file.device_id, pkcs8).with_context(|| {
+        format!(
+            "Device identity {} is unusable; regenerate it with `identity --force`",
+            path.display()
+        )
+    })
+}
+
+/// Writes the identity atomically and readable only by its owner.
+fn save(path: &Path, identity: &Identity) -> Result<()> {
+    let dir = path.parent().expect("identity path has a directory");
+    fs::create_dir_all(dir)
+        .with_context(|| format!("Failed to create state directory {}", dir.display()))?;
+
+    let file = IdentityFile {
+        device_id: identity.device_id.clone(),
+        private_key: hex::encode(&identity.pkcs8),
+    };
+    let tmp_path = path.with_extension("tmp");
+    let mut tmp = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp_path)
+        .with_context(|| format!("Failed to create {}", tmp_path.display()))?;
+    tmp.write_all(serde_json::to_string_pretty(&file)?.as_bytes())?;
+    tmp.write_all(b"\n")?;
+    tmp.sync_all()?;
+    fs::rename(&tmp_path, path)
+        .with_context(|| format!("Failed to write device identity {}", path.display()))?;
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+    use crate::tempdir::TempDir;
+
+    #[test]
+    fn the_identity_is_generated_once_and_kept_private() {
+        let dir = TempDir::new("identity");
+        let state_dir = dir.path().join("state");
+        assert!(Identity::load_existing(&state_dir).unwrap().is_none());
+        let created = Identity::load_or_create(&state_dir).unwrap();
+        assert_eq!(created.device_id.len(), 12);
+        assert_eq!(created.public_key().len(), 64);
+
+        let path = state_dir.join(IDENTITY_FILE);
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert!(!path.with_extension("tmp").exists());
+
+        let loaded = Identity::load_or_create(&state_dir).unwrap();
+        assert_eq!(loaded.device_id, created.device_id);
+        assert_eq!(loaded.public_key(), created.public_key());
+        assert_eq!(loaded.at_rest_secret(), created.at_rest_secret());
+        let existing = Identity::load_existing(&state_dir).unwrap().unwrap();
+        assert_eq!(existing.fingerprint(), created.fingerprint());
+    }
+
+    #[test]
+    fn an_unreadable_identity_is_left_alone() {
+        let dir = TempDir::new("identity");
+        let path = dir.path().join(IDENTITY_FILE);
+        for contents in [
+            "{",
+            r#"{"device_id": "a1", "private_key": "zz"}"#,
+            r#"{"device_id": "a1", "private_key": "00ff"}"#,
+        ] {
+            fs::write(&path, contents).unwrap();
+            assert!(
+                Identity::load_or_create(dir.path()).is_err(),
+                "{}",
+                contents
+            );
+            assert!(Identity::load_existing(dir.path()).is_err(), "{}", contents);
+            assert_eq!(fs::read_to_string(&path).unwrap(), contents);
+        }
+        let unusable = Identity::load_or_create(dir.path()).err().unwrap();
+        assert!(format!("{:#}", unusable).contains("identity --force"));
+    }
+
+    #[test]
+    fn fingerprints_are_eight_groups_of_hex() {
+        let identity = generate().unwrap();
+        let fingerprint = identity.fingerprint();
+        let groups: Vec<&str> = fingerprint.split(':').collect();
+        assert_eq!(groups.len(), 8);
+        assert!(
+            groups
+                .iter()
+                .all(|g| g.len() == 4 && g.chars().all(|c| c.is_ascii_hexdigit()))
+        );
+        assert_ne!(generate().unwrap().fingerprint(), fingerprint);
+    }
+
+    #[test]
+    fn forcing_replaces_the_identity() {
+        let dir = TempDir::new("identity");
+        let before = Identity::load_or_create(dir.path()).unwrap();
+        let args = IdentityArgs {
+            state_dir: dir.path().to_path_buf(),
+            config: dir.path().join("none.toml"),
+            ticket: false,
+            mock: true,
+            force: true,
+        };
+        run(&args).unwrap();
+        let after = Identity::load_existing(dir.path()).unwrap().unwrap();
+        assert_ne!(after.public_key(), before.public_key());
+    }
+
+    #[test]
+    fn the_ticket_shows_the_id_and_fingerprint() {
+        let identity = generate().unwrap();
+        let rendered = render_ticket(&identity, CommandSet::EscPos).unwrap();
+        for text in [
+            identity.device_id.clone(),
+            identity.fingerprint(),
+            identity.public_key(),
+        ] {
+            assert!(
+                rendered
+                    .bytes
+                    .windows(text.len())
+                    .any(|w| w == text.as_bytes()),
+                "{}",
+                text
+            );
+        }
+    }
+}
@@ -0,0 +1,712 @@
+//! Durable record of how every job from the server ended, for settling
+//! "it never printed" disputes after the fact, and of every control command
+//! it sent. With a state dir each final ack and command result is appended
+//! to `<dir>/journal.jsonl` before it's sent, one JSON object per line with
+//! no job content. The file is rotated to `journal.1.jsonl`,
+//! `journal.2.jsonl`, ... as it fills, and the oldest rotated file is
+//! deleted, keeping the total near `--journal-max-kb`. Encrypted at rest,
+//! each line is instead a sealed entry, `{"key":n,"sealed":"<hex>"}`.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::atrest::{AtRest, Keys, Sealed};
+use crate::clock;
+use crate::identity::{self, DEFAULT_STATE_DIR};
+use crate::protocol::{AckStatus, ErrorCode, Outbound};
+
+const JOURNAL_FILE: &str = "journal.jsonl";
+/// Additional data sealed entries are tied to
+const AAD: &str = "journal";
+/// Rotated files kept besides the current one; `journal.1.jsonl` is the newest
+const ROTATED_FILES: usize = 3;
+/// Most entries a lookup returns; the most recent matches win
+pub const MAX_RESULTS: usize = 200;
+
+#[derive(clap::Args, Debug)]
+pub struct JournalArgs {
+    /// Directory holding the journal
+    #[arg(long, default_value = DEFAULT_STATE_DIR)]
+    state_dir: PathBuf,
+
+    /// Only entries for this job id (leaving out control commands)
+    #[arg(long)]
+    id: Option<String>,
+
+    /// Only jobs finished at or after this time (RFC 3339, e.g. 2026-03-01T09:00:00+13:00)
+    #[arg(long, value_parser = parse_time)]
+    since: Option<DateTime<Utc>>,
+
+    /// Only jobs finished at or before this time (RFC 3339)
+    #[arg(long, value_parser = parse_time)]
+    until: Option<DateTime<Utc>>,
+
+    /// Print one JSON object per entry instead of a table
+    #[arg(long)]
+    json: bool,
+
+    /// Key file the journal was encrypted with, if not the device key
+    #[arg(long)]
+    at_rest_key_file: Option<PathBuf>,
+}
+
+fn parse_time(s: &str) -> Result<DateTime<Utc>, String> {
+    DateTime::parse_from_rfc3339(s)
+        .map(|time| time.with_timezone(&Utc))
+        .map_err(|e| format!("expected an RFC 3339 time: {}", e))
+}
+
+/// Prints the journal entries matching the flags.
+pub fn run(args: &JournalArgs) -> Result<()> {
+    let query = Query {
+        id: args.id.clone(),
+        since: args.since,
+        until: args.until,
+    };
+    let keys = match &args.at_rest_key_file {
+        Some(path) => Some(Keys::from_file(path, &args.state_dir)?),
+        None => identity::Identity::load_existing(&args.state_dir)?
+            .map(|identity| Keys::new(identity.at_rest_secret(), &args.state_dir))
+            .transpose()?,
+    };
+    let found = lookup(&args.state_dir, &query, keys.as_ref())?;
+    if found.truncated {
+        eprintln!(
+            "More than {} entries match, showing the most recent",
+            MAX_RESULTS
+        );
+    }
+    for entry in &found.entries {
+        if args.json {
+            println!(
+                "{}",
+                serde_json::to_string(entry).expect("journal entry serializes")
+            );
+            continue;
+        }
+        let status = match entry.error {
+            Some(code) => format!("{:?} {}", entry.status, code.as_str()).to_lowercase(),
+            None => format!("{:?}", entry.status).to_lowercase(),
+        };
+        println!(
+            "{}  {:<28} {:<24} attempts {}  tenant {}  received {}",
+            entry.finished_at,
+            match &entry.command {
+                Some(command) => format!("[{}]", command),
+                None => entry.id.clone().unwrap_or_else(|| "-".to_string()),
+            },
+            status,
+            entry.attempts,
+            entry.tenant.as_deref().unwrap_or("-"),
+            entry.received_at,
+        );
+    }
+    if found.entries.is_empty() {
+        eprintln!("No matching entries in {}", args.state_dir.display());
+    }
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Entry {
+    pub id: Option<String>,
+    /// Set for control commands, which have no id. Their status is
+    /// `accepted` when they ran, `failed` when they ran but didn't succeed
+    /// and `rejected` when they were refused without running.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    /// RFC 3339
+    pub received_at: String,
+    pub finished_at: String,
+    pub status: AckStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<ErrorCode>,
+    /// Writes to the printer, counting the retry after a reconnect
+    pub attempts: u32,
+}
+
+/// Which entries a lookup returns. Times are compared with `finished_at`.
+#[derive(Debug, Default)]
+pub struct Query {
+    pub id: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+}
+
+impl Query {
+    fn matches(&self, entry: &Entry) -> bool {
+        if self.id.is_some() && entry.id != self.id {
+            return false;
+        }
+        if self.since.is_none() && self.until.is_none() {
+            return true;
+        }
+        let Ok(finished) = DateTime::parse_from_rfc3339(&entry.finished_at) else {
+            return false;
+        };
+        self.since.is_none_or(|since| finished >= since)
+            && self.until.is_none_or(|until| finished <= until)
+    }
+}
+
+/// Matching entries, oldest first.
+#[derive(Serialize, Debug, Clone)]
+pub struct Lookup {
+    pub entries: Vec<Entry>,
+    /// More entries matched than `MAX_RESULTS`; only the most recent are listed
+    pub truncated: bool,
+}
+
+/// A line of the journal file.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Line {
+    Sealed(Sealed),
+    Entry(Entry),
+}
+
+pub struct Journal {
+    dir: Option<PathBuf>,
+    at_rest: AtRest,
+    /// Size each file is rotated at
+    max_file_bytes: u64,
+    file: Option<File>,
+    len: u64,
+}
+
+impl Journal {
+    /// Opens the journal in `dir`, or a journal that records nothing without one.
+    /// Files holding entries stored the other way than `at_rest` says are
+    /// rewritten first, encrypting or decrypting them; only that can fail.
+    pub fn open(dir: Option<&Path>, max_bytes: u64, at_rest: AtRest) -> Result<Self> {
+        if let Some(dir) = dir {
+            for path in files(dir).iter().filter(|path| path.exists()) {
+                migrate(path, &at_rest)?;
+            }
+        }
+        let mut journal = Self {
+            dir: dir.map(Path::to_path_buf),
+            at_rest,
+            max_file_bytes: (max_bytes / (ROTATED_FILES as u64 + 1)).max(1),
+            file: None,
+            len: 0,
+        };
+        if let Some(dir) = dir {
+            info!(
+                "Journalling job outcomes to {}",
+                dir.join(JOURNAL_FILE).display()
+            );
+            if let Err(e) = journal.open_file() {
+                error!("Failed to open job journal in {}: {:#}", dir.display(), e);
+            }
+        }
+        Ok(journal)
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.dir.is_some()
+    }
+
+    /// Records a job's final ack. Other frames are ignored. Synced to disk
+    /// before returning, so the entry survives a crash right after the ack
+    /// goes out.
+    pub fn record(
+        &mut self,
+        tenant: Option<&str>,
+        received_at: DateTime<Utc>,
+        attempts: u32,
+        ack: &Outbound,
+    ) {
+        let Outbound::Ack {
+            id, status, error, ..
+        } = ack
+        else {
+            return;
+        };
+        self.write(Entry {
+            id: id.clone(),
+            command: None,
+            tenant: tenant.map(str::to_string),
+            received_at: received_at.to_rfc3339_opts(SecondsFormat::Millis, true),
+            finished_at: clock::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            status: *status,
+            error: *error,
+            attempts,
+        });
+    }
+
+    /// Records a control command and the reply it got, synced to disk like
+    /// job outcomes.
+    pub fn record_command(&mut self, command: &str, received_at: DateTime<Utc>, reply: &Outbound) {
+        let (status, error) = match reply {
+            Outbound::CommandResult {
+                error: Some(code), ..
+            } => (AckStatus::Rejected, Some(*code)),
+            Outbound::CommandResult { ok: false, .. } => (AckStatus::Failed, None),
+            _ => (AckStatus::Accepted, None),
+        };
+        self.write(Entry {
+            id: None,
+            command: Some(command.to_string()),
+            tenant: None,
+            received_at: received_at.to_rfc3339_opts(SecondsFormat::Millis, true),
+            finished_at: clock::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            status,
+            error,
+            attempts: 0,
+        });
+    }
+
+    fn write(&mut self, entry: Entry) {
+        if self.dir.is_none() {
+            return;
+        }
+        let json = serde_json::to_string(&entry).expect("journal entry serializes");
+        let line = store(&json, &self.at_rest) + "\n";
+        if let Err(e) = self.append(line.as_bytes()) {
+            match &entry.command {
+                Some(command) => error!("Failed to journal the {} command: {:#}", command, e),
+                None => error!(
+                    "Failed to journal the outcome of job {:?}: {:#}",
+                    entry.id, e
+                ),
+            }
+            // Reopened for the next entry
+            self.file = None;
+        }
+    }
+
+    fn append(&mut self, line: &[u8]) -> Result<()> {
+        if self.len > 0 && self.len + line.len() as u64 > self.max_file_bytes {
+            self.rotate()?;
+        }
+        if self.file.is_none() {
+            self.open_file()?;
+        }
+        let file = self.file.as_mut().expect("journal file was just opened");
+        file.write_all(line)?;
+        file.sync_data()?;
+        self.len += line.len() as u64;
+        Ok(())
+    }
+
+    fn open_file(&mut self) -> Result<()> {
+        let dir = self.dir.as_deref().expect("journal has a directory");
+        fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join(JOURNAL_FILE))?;
+        self.len = file.metadata()?.len();
+        self.file = Some(file);
+        Ok(())
+    }
+
+    /// Shifts every file one place older, dropping the oldest, and starts a
+    /// new current file. The newest entries are never the ones dropped.
+    fn rotate(&mut self) -> Result<()> {
+        let dir = self.dir.clone().expect("journal has a directory");
+        self.file = None;
+        let oldest = rotated_path(&dir, ROTATED_FILES);
+        if oldest.exists() {
+            fs::remove_file(&oldest)
+                .with_context(|| format!("Failed to delete {}", oldest.display()))?;
+        }
+        for n in (1..ROTATED_FILES).rev() {
+            let from = rotated_path(&dir, n);
+            if from.exists() {
+                fs::rename(&from, rotated_path(&dir, n + 1))?;
+            }
+        }
+        fs::rename(dir.join(JOURNAL_FILE), rotated_path(&dir, 1))?;
+        File::open(&dir)?.sync_all()?;
+        self.open_file()
+    }
+
+    /// Switches to `keys` and rewrites every file with them.
+    pub fn rotate_key(&mut self, keys: Keys) -> Result<()> {
+        self.at_rest = AtRest::Encrypted(keys);
+        let Some(dir) = self.dir.clone() else {
+            return Ok(());
+        };
+        self.file = None;
+        for path in files(&dir).iter().filter(|path| path.exists()) {
+            rewrite(path, &self.at_rest)?;
+        }
+        self.open_file()
+    }
+
+    /// Finds the entries matching `query`.
+    pub fn lookup(&self, query: &Query) -> Result<Lookup> {
+        match &self.dir {
+            Some(dir) => lookup(dir, query, self.at_rest.keys()),
+            None => Ok(Lookup {
+                entries: Vec::new(),
+                truncated: false,
+            }),
+        }
+    }
+}
+
+fn rotated_path(dir: &Path, n: usize) -> PathBuf {
+    dir.join(format!("journal.{}.jsonl", n))
+}
+
+/// Every journal file, oldest first.
+fn files(dir: &Path) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = (1..=ROTATED_FILES)
+        .rev()
+        .map(|n| rotated_path(dir, n))
+        .collect();
+    files.push(dir.join(JOURNAL_FILE));
+    files
+}
+
+/// An entry's JSON as it's written, sealed if encrypting.
+fn store(json: &str, at_rest: &AtRest) -> String {
+    match at_rest.sealing() {
+        Some(keys) => serde_json::to_string(&keys.seal(json.as_bytes(), AAD))
+            .expect("sealed entry serializes"),
+        None => json.to_string(),
+    }
+}
+
+/// Reads a line back into an entry. `None` for a line cut short by a crash
+/// mid-write; an error for a sealed one that can't be opened.
+fn restore(line: &str, keys: Option<&Keys>, path: &Path) -> Result<Option<Entry>> {
+    match serde_json::from_str(line) {
+        Ok(Line::Entry(entry)) => Ok(Some(entry)),
+        Ok(Line::Sealed(sealed)) => {
+            let keys =
+                keys.ok_or_else(|| AtRest::locked(&format!("Journal {}", path.display())))?;
+            let json = keys
+                .open(&sealed, AAD)
+                .with_context(|| format!("Journal {}", path.display()))?;
+            Ok(serde_json::from_slice(&json).ok())
+        }
+        Err(_) => Ok(None),
+    }
+}
+
+/// Rewrites `path` if any of its entries are stored the other way than
+/// `at_rest` says.
+fn migrate(path: &Path, at_rest: &AtRest) -> Result<()> {
+    let text =
+        fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let sealing = at_rest.sealing().is_some();
+    let mismatched = text
+        .lines()
+        .filter_map(|line| serde_json::from_str::<Line>(line).ok())
+        .any(|line| matches!(line, Line::Sealed(_)) != sealing);
+    if !mismatched {
+        return Ok(());
+    }
+    if at_rest.keys().is_none() {
+        return Err(AtRest::locked(&format!("Journal {}", path.display())));
+    }
+    let how = if sealing { "Encrypting" } else { "Decrypting" };
+    info!("{} journal {}", how, path.display());
+    rewrite(path, at_rest)
+}
+
+/// Rewrites `path` with every readable entry stored as `at_rest` says. The
+/// new file is written beside the old one and renamed over it.
+fn rewrite(path: &Path, at_rest: &AtRest) -> Result<()> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let tmp_path = path.with_extension("tmp");
+    let mut tmp = File::create(&tmp_path)
+        .with_context(|| format!("Failed to create {}", tmp_path.display()))?;
+    for line in BufReader::new(file).lines() {
+        let line = line.with_context(|| format!("Failed to read {}", path.display()))?;
+        if let Some(entry) = restore(&line, at_rest.keys(), path)? {
+            let json = serde_json::to_string(&entry).expect("journal entry serializes");
+            tmp.write_all((store(&json, at_rest) + "\n").as_bytes())?;
+        }
+    }
+    tmp.sync_all()?;
+    fs::rename(&tmp_path, path).with_context(|| format!("Failed to replace {}", path.display()))?;
+    if let Some(dir) = path.parent() {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Reads the journal in `dir` a line at a time, oldest file first, keeping
+/// only the last `MAX_RESULTS` matches in memory. Sealed entries need `keys`.
+pub fn lookup(dir: &Path, query: &Query, keys: Option<&Keys>) -> Result<Lookup> {
+    let files = files(dir);
+    let mut entries = std::collections::VecDeque::new();
+    let mut truncated = false;
+    for path in files.iter().filter(|path| path.exists()) {
+        let file =
+            File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+        for line in BufReader::new(file).lines() {
+            let line = line.with_context(|| format!("Failed to read {}", path.display()))?;
+            // A line cut short by a crash mid-write is skipped
+            let Some(entry) = restore(&line, keys, path)? else {
+                warn!("Skipping unreadable journal line in {}", path.display());
+                continue;
+            };
+            if !query.matches(&entry) {
+                continue;
+            }
+            if entries.len() == MAX_RESULTS {
+                entries.pop_front();
+                truncated = true;
+            }
+            entries.push_back(entry);
+        }
+    }
+    Ok(Lookup {
+        entries: entries.into(),
+        truncated,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tempdir::TempDir;
+
+    const SECRET: &[u8] = b"0123456789abcdef";
+
+    fn received() -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339("2026-03-01T09:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    fn printed(journal: &mut Journal, id: &str) {
+        let ack = Outbound::ack(Some(id.to_string()), AckStatus::Printed);
+        journal.record(Some("cafe"), received(), 1, &ack);
+    }
+
+    fn by_id(id: &str) -> Query {
+        Query {
+            id: Some(id.to_string()),
+            ..Query::default()
+        }
+    }
+
+    fn ids(lookup: &Lookup) -> Vec<&str> {
+        lookup
+            .entries
+            .iter()
+            .map(|entry| entry.id.as_deref().or(entry.command.as_deref()).unwrap())
+            .collect()
+    }
+
+    fn encrypted(dir: &Path) -> AtRest {
+        AtRest::Encrypted(Keys::new(SECRET, dir).unwrap())
+    }
+
+    #[test]
+    fn outcomes_and_commands_are_journalled() {
+        let dir = TempDir::new("journal");
+        let mut journal = Journal::open(Some(dir.path()), 1 << 20, AtRest::Plain).unwrap();
+        assert!(journal.enabled());
+        printed(&mut journal, "a1");
+        let failed = Outbound::error_ack(
+            Some("a2".to_string()),
+            AckStatus::Failed,
+            ErrorCode::PrintFailed,
+            "Paper out",
+        );
+        journal.record(None, received(), 2, &failed);
+        journal.record(
+            None,
+            received(),
+            1,
+            &Outbound::Progress {
+                id: None,
+                percent: 5,
+            },
+        );
+        journal.record_command(
+            "pause",
+            received(),
+            &Outbound::command_result("pause", true, ""),
+        );
+        journal.record_command(
+            "cut",
+            received(),
+            &Outbound::command_result("cut", false, "Jammed"),
+        );
+        journal.record_command(
+            "reset",
+            received(),
+            &Outbound::command_refused("reset", ErrorCode::Unauthorized, "Sign it", None),
+        );
+
+        let all = journal.lookup(&Query::default()).unwrap();
+        assert_eq!(ids(&all), ["a1", "a2", "pause", "cut", "reset"]);
+        let statuses: Vec<(AckStatus, Option<ErrorCode>)> =
+            all.entries.iter().map(|e| (e.status, e.error)).collect();
+        assert_eq!(
+            statuses,
+            [
+                (AckStatus::Printed, None),
+                (AckStatus::Failed, Some(ErrorCode::PrintFailed)),
+                (AckStatus::Accepted, None),
+                (AckStatus::Failed, None),
+                (AckStatus::Rejected, Some(ErrorCode::Unauthorized)),
+            ]
+        );
+        assert_eq!(all.entries[0].tenant.as_deref(), Some("cafe"));
+        assert_eq!(all.entries[0].received_at, "2026-03-01T09:00:00.000Z");
+        assert_eq!(all.entries[1].attempts, 2);
+
+        let a2 = journal.lookup(&by_id("a2")).unwrap();
+        assert_eq!(ids(&a2), ["a2"]);
+        // No job content is kept
+        let text = fs::read_to_string(dir.path().join(JOURNAL_FILE)).unwrap();
+        assert!(!text.contains("Paper out") && !text.contains("Jammed"));
+    }
+
+    #[test]
+    fn without_a_directory_nothing_is_kept() {
+        let mut journal = Journal::open(None, 1 << 20, AtRest::Plain).unwrap();
+        assert!(!journal.enabled());
+        printed(&mut journal, "a1");
+        assert!(
+            journal
+                .lookup(&Query::default())
+                .unwrap()
+                .entries
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn times_are_matched_against_when_jobs_finished() {
+        let entry = |finished_at: &str| Entry {
+            id: Some("a1".to_string()),
+            command: None,
+            tenant: None,
+            received_at: String::new(),
+            finished_at: finished_at.to_string(),
+            status: AckStatus::Printed,
+            error: None,
+            attempts: 1,
+        };
+        let query = Query {
+            id: None,
+            since: Some(parse_time("2026-03-01T09:00:00+13:00").unwrap()),
+            until: Some(parse_time("2026-03-01T10:00:00+13:00").unwrap()),
+        };
+        assert!(query.matches(&entry("2026-02-28T20:00:00Z")));
+        assert!(query.matches(&entry("2026-02-28T21:00:00.000Z")));
+        assert!(!query.matches(&entry("2026-02-28T21:00:00.001Z")));
+        assert!(!query.matches(&entry("2026-02-28T19:59:59Z")));
+        assert!(!query.matches(&entry("yesterday")));
+        assert!(Query::default().matches(&entry("yesterday")));
+        assert!(parse_time("2026-03-01").is_err());
+    }
+
+    #[test]
+    fn full_files_are_rotated_and_the_oldest_dropped() {
+        let dir = TempDir::new("journal");
+        let mut journal = Journal::open(Some(dir.path()), 4 * 1024, AtRest::Plain).unwrap();
+        for n in 0..200 {
+            printed(&mut journal, &format!("job-{}", n));
+        }
+        let files: Vec<PathBuf> = files(dir.path())
+            .into_iter()
+            .filter(|p| p.exists())
+            .collect();
+        assert_eq!(files.len(), ROTATED_FILES + 1);
+        let total: u64 = files.iter().map(|p| fs::metadata(p).unwrap().len()).sum();
+        assert!(total <= 4 * 1024, "{} bytes", total);
+
+        let found = journal.lookup(&Query::default()).unwrap();
+        assert_eq!(*ids(&found).last().unwrap(), "job-199");
+        assert!(journal.lookup(&by_id("job-0")).unwrap().entries.is_empty());
+        // Oldest first across the files
+        let numbers: Vec<usize> = ids(&found)
+            .iter()
+            .map(|id| id["job-".len()..].parse().unwrap())
+            .collect();
+        assert!(numbers.windows(2).all(|w| w[0] + 1 == w[1]));
+    }
+
+    #[test]
+    fn only_the_most_recent_matches_are_returned() {
+        let dir = TempDir::new("journal");
+        let mut journal = Journal::open(Some(dir.path()), 1 << 20, AtRest::Plain).unwrap();
+        for n in 0..MAX_RESULTS + 5 {
+            printed(&mut journal, &format!("job-{}", n));
+        }
+        let found = journal.lookup(&Query::default()).unwrap();
+        assert!(found.truncated);
+        assert_eq!(found.entries.len(), MAX_RESULTS);
+        assert_eq!(ids(&found)[0], "job-5");
+    }
+
+    #[test]
+    fn a_line_torn_by_a_crash_is_skipped() {
+        let dir = TempDir::new("journal");
+        let mut journal = Journal::open(Some(dir.path()), 1 << 20, AtRest::Plain).unwrap();
+        printed(&mut journal, "a1");
+        drop(journal);
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(dir.path().join(JOURNAL_FILE))
+            .unwrap();
+        file.write_all(b"{\"id\":\"a2\",\"recei").unwrap();
+        file.write_all(b"\n").unwrap();
+
+        let mut journal = Journal::open(Some(dir.path()), 1 << 20, AtRest::Plain).unwrap();
+        printed(&mut journal, "a3");
+        assert_eq!(
+            ids(&journal.lookup(&Query::default()).unwrap()),
+            ["a1", "a3"]
+        );
+    }
+
+    #[test]
+    fn encrypted_journals_are_sealed_and_migrated_both_ways() {
+        let dir = TempDir::new("journal");
+        let mut journal = Journal::open(Some(dir.path()), 1 << 20, AtRest::Plain).unwrap();
+        printed(&mut journal, "plain");
+        drop(journal);
+
+        // Plain entries are encrypted on opening
+        let mut journal = Journal::open(Some(dir.path()), 1 << 20, encrypted(dir.path())).unwrap();
+        printed(&mut journal, "sealed");
+        let text = fs::read_to_string(dir.path().join(JOURNAL_FILE)).unwrap();
+        assert!(!text.contains("plain") && !text.contains("cafe"));
+        assert!(text.lines().all(|line| line.starts_with("{\"key\":0,")));
+        assert_eq!(
+            ids(&journal.lookup(&Query::default()).unwrap()),
+            ["plain", "sealed"]
+        );
+
+        // Without the keys it's locked
+        assert!(Journal::open(Some(dir.path()), 1 << 20, AtRest::Plain).is_err());
+        assert!(lookup(dir.path(), &Query::default(), None).is_err());
+
+        // A new key rewrites every entry with it
+        let mut keys = Keys::new(SECRET, dir.path()).unwrap();
+        keys.rotate().unwrap();
+        journal.rotate_key(keys.clone()).unwrap();
+        let text = fs::read_to_string(dir.path().join(JOURNAL_FILE)).unwrap();
+        assert!(text.lines().all(|line| line.starts_with("{\"key\":1,")));
+        drop(journal);
+
+        let journal = Journal::open(Some(dir.path()), 1 << 20, AtRest::Decrypting(keys)).unwrap();
+        let text = fs::read_to_string(dir.path().join(JOURNAL_FILE)).unwrap();
+        assert!(text.contains("\"id\":\"plain\"") && text.contains("\"id\":\"sealed\""));
+        assert_eq!(
+            ids(&journal.lookup(&Query::default()).unwrap()),
+            ["plain", "sealed"]
+        );
+    }
+}
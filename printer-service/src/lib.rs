@@ -0,0 +1,114 @@
+//! A receipt printer service: takes jobs from a WebSocket server, or from
+//! the program embedding it, and prints them on an ESC/POS (or Star)
+//! printer, spooling them so none are lost across restarts.
+//!
+//! ```no_run
+//! use escpos::driver::ConsoleDriver;
+//! use printer_service::{JobOutcome, PrintJob, PrinterService};
+//!
+//! # async fn run() -> anyhow::Result<()> {
+//! let service = PrinterService::builder()
+//!     .driver(ConsoleDriver::open(false))
+//!     .on_status(|status| println!("{} jobs queued", status.queue_depth))
+//!     .build()?;
+//! match service.submit(PrintJob::plain("Hello".to_string())).await {
+//!     JobOutcome::Printed(timing) => println!("Printed in {} ms", timing.total_ms),
+//!     outcome => println!("Not printed: {:?}", outcome),
+//! }
+//! service.shutdown().await
+//! # }
+//! ```
+//!
+//! The service blocks on the printer from its tasks, so it needs a
+//! multi-threaded tokio runtime.
+
+// What the `printer-service` binary uses besides the API below. These
+// aren't a stable API.
+#[doc(hidden)]
+pub mod archive;
+#[doc(hidden)]
+pub mod atrest;
+#[cfg(feature = "chaos")]
+#[doc(hidden)]
+pub mod chaos;
+#[doc(hidden)]
+pub mod check;
+#[doc(hidden)]
+pub mod clock;
+mod codepage;
+mod commands;
+#[doc(hidden)]
+pub mod config;
+#[doc(hidden)]
+pub mod control;
+#[cfg(test)]
+mod counting;
+mod deadletter;
+mod demo;
+#[doc(hidden)]
+pub mod diagnose;
+#[doc(hidden)]
+pub mod driver;
+#[cfg(feature = "fallback-font")]
+#[doc(hidden)]
+pub mod fallback;
+mod faults;
+mod filters;
+mod footer;
+#[cfg(test)]
+mod golden;
+mod handle;
+#[doc(hidden)]
+pub mod hooks;
+#[doc(hidden)]
+pub mod identity;
+#[doc(hidden)]
+pub mod journal;
+mod maintenance;
+#[doc(hidden)]
+pub mod memory;
+mod metrics;
+mod network;
+mod outbox;
+mod pacing;
+#[doc(hidden)]
+pub mod panics;
+mod png;
+#[doc(hidden)]
+pub mod preflight;
+#[doc(hidden)]
+pub mod probe;
+#[doc(hidden)]
+pub mod profile;
+mod protocol;
+#[doc(hidden)]
+pub mod provision;
+mod ratelimit;
+mod render;
+#[doc(hidden)]
+pub mod rendercache;
+#[doc(hidden)]
+pub mod report;
+mod resources;
+mod service;
+#[doc(hidden)]
+pub mod signing;
+#[doc(hidden)]
+pub mod soak;
+mod spool;
+#[cfg(test)]
+mod tempdir;
+mod transcript;
+mod transport;
+
+pub use escpos::driver::Driver;
+
+pub use crate::commands::CommandSet;
+pub use crate::handle::{JobOutcome, PrinterService, PrinterServiceBuilder, Target};
+pub use crate::profile::{Font, PrinterProfile};
+pub use crate::protocol::{
+    Accessibility, ErrorCode, Job as PrintJob, Preflight, PreflightDecision, Priority,
+    ResourceData, ResourceRef, RuleStyle, Segment, Status, TextSegment, Timing, WireProtocol,
+};
+pub use crate::service::ServiceConfig;
+pub use crate::transport::{DEFAULT_TARGET, Transport};
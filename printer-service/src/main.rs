@@ -1,35 +1,80 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
 use std::time::Duration;
 
-use anyhow::Result;
-use chrono::Local;
-use clap::Parser;
+use anyhow::{Context, Result, anyhow, bail};
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand};
 use env_logger::Env;
-use escpos::driver::{ConsoleDriver, NativeUsbDriver, NetworkDriver};
-use escpos::printer::Printer;
-use escpos::utils::Protocol;
-use futures_util::StreamExt;
-use log::{error, info, warn};
+use escpos::driver::{ConsoleDriver, Driver, NativeUsbDriver, NetworkDriver};
+use log::{LevelFilter, info, warn};
 use nusb::MaybeFuture;
-use rand::Rng;
-use tokio_tungstenite::tungstenite::client::IntoClientRequest;
-use tokio_tungstenite::{connect_async, tungstenite::protocol::Message};
 
-const MAX_CONSECUTIVE_PRINT_FAILURES: u32 = 5;
-const WS_BACKOFF_INITIAL: Duration = Duration::from_secs(1);
-const WS_BACKOFF_MAX: Duration = Duration::from_secs(30);
-const WS_READ_TIMEOUT: Duration = Duration::from_secs(90);
+use printer_service::atrest::{AtRest, Keys};
+#[cfg(feature = "chaos")]
+use printer_service::chaos;
+use printer_service::config::{DeviceConfig, RateLimitConfig};
+use printer_service::control::{CommandPolicy, Risk};
+use printer_service::driver::{
+    Observer, PngRender, PrettyTranscript, SerialDriver, TeeDriver, USB_PRODUCT_ID, USB_VENDOR_ID,
+};
+#[cfg(feature = "fallback-font")]
+use printer_service::fallback;
+use printer_service::hooks::HookConfig;
+use printer_service::panics::PanicPolicy;
+use printer_service::signing::Signer;
+use printer_service::{
+    CommandSet, Font, PrinterProfile, PrinterService, ServiceConfig, Target, Transport,
+    WireProtocol,
+};
+use printer_service::{
+    archive, check, clock, config, control, diagnose, identity, journal, memory, panics, preflight,
+    probe, profile, provision, rendercache, report, soak,
+};
+use url::Url;
 
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, subcommand_negates_reqs = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Cmd>,
+
     /// Websocket URL to connect to
-    #[arg(short, long)]
-    url: String,
+    #[arg(short, long, required_unless_present_any = ["config", "demo"])]
+    url: Option<String>,
+
+    /// Demo mode for trade shows: print made-up orders on a timer instead of connecting to a server; refuses to start with a server URL
+    #[arg(long, conflicts_with = "url")]
+    demo: bool,
+
+    /// Seconds between demo orders
+    #[arg(long, default_value_t = 20, requires = "demo", value_parser = clap::value_parser!(u64).range(1..))]
+    demo_interval_secs: u64,
+
+    /// Wire protocol to speak when the server picks neither offered subprotocol (flatos-print.v2, flatos-print.v1)
+    #[arg(long, value_enum, default_value_t = WireProtocol::V2)]
+    default_protocol: WireProtocol,
+
+    /// Device config file written by `provision`; command-line flags override it
+    #[arg(long)]
+    config: Option<PathBuf>,
 
     /// Run in mock mode (print to console)
     #[arg(short, long)]
     mock: bool,
 
+    /// Mock mode with a readable transcript (decoded commands, text at paper width) instead of raw bytes
+    #[arg(long)]
+    mock_pretty: bool,
+
+    /// Also save each --mock-pretty transcript to a file in this directory
+    #[arg(long, requires = "mock_pretty")]
+    mock_pretty_dir: Option<PathBuf>,
+
+    /// Also save the rules and images of each --mock-pretty flush as a PNG in this directory
+    #[arg(long, requires = "mock_pretty")]
+    mock_png_dir: Option<PathBuf>,
+
     /// Network printer IP address
     #[arg(long)]
     ip: Option<String>,
@@ -37,33 +82,540 @@ struct Args {
     /// Network printer port
     #[arg(long, default_value_t = 9100)]
     port: u16,
+
+    /// File containing the hex-encoded per-device HMAC key used to sign outbound frames
+    #[arg(long)]
+    signing_key_file: Option<String>,
+
+    /// Reject jobs and commands that don't carry a valid signature (requires --signing-key-file)
+    #[arg(long, requires = "signing_key_file")]
+    require_signed_jobs: bool,
+
+    /// Tenant (venue) id of this device; jobs for another tenant are rejected
+    #[arg(long)]
+    tenant: Option<String>,
+
+    /// With --tenant, also accept jobs that don't name a tenant
+    #[arg(long)]
+    allow_untagged_jobs: bool,
+
+    /// Lowest risk of control command that only runs when authorized by an HMAC challenge-response with the signing key (default high with --signing-key-file)
+    #[arg(long, value_enum, requires = "signing_key_file")]
+    command_auth: Option<control::CommandAuth>,
+
+    /// Change control command risk levels, e.g. pause=high,lookup=medium
+    #[arg(long, value_parser = control::parse_risks)]
+    command_risk: Option<HashMap<String, control::Risk>>,
+
+    /// Most of each control command accepted a minute, e.g. status=120,reload=1 (0 = unlimited; default 60 for low risk, 10 medium, 6 high)
+    #[arg(long, value_parser = control::parse_limits)]
+    command_limits: Option<HashMap<String, u32>>,
+
+    /// Maximum allowed difference in seconds between a signature timestamp and the local clock
+    #[arg(long, default_value_t = 300)]
+    max_clock_skew_secs: i64,
+
+    /// Serial printer device path (e.g. /dev/ttyUSB0 or /dev/rfcomm0)
+    #[arg(long)]
+    serial: Option<String>,
+
+    /// Serial printer baud rate
+    #[arg(long, default_value_t = 9600)]
+    baud: u32,
+
+    /// Enable XON/XOFF software flow control on the serial port
+    #[arg(long)]
+    xon_xoff: bool,
+
+    /// Printer profile
+    #[arg(long, default_value = "default", value_parser = clap::builder::PossibleValuesParser::new(profile::names()))]
+    profile: String,
+
+    /// Ask the printer for its model and features at startup and adjust the profile to match
+    #[arg(long)]
+    probe_printer: bool,
+
+    /// Save paper: font B, tight line spacing, no blank lines, spacers or rules, one feed before the cut. Jobs can override it with "compact"
+    #[arg(long)]
+    compact: bool,
+
+    /// Large type for low-vision staff: double-height font A text with wider line spacing, ignoring --compact and font B. Jobs can override it with "accessibility"
+    #[arg(long)]
+    large_print: bool,
+
+    /// BDF bitmap font (e.g. GNU Unifont) to draw characters missing from the printer's code page with, instead of the built-in Latin, Greek and Cyrillic one
+    #[cfg(feature = "fallback-font")]
+    #[arg(long)]
+    fallback_font: Option<PathBuf>,
+
+    /// Font used unless a job picks another, overriding the profile
+    #[arg(long, value_enum)]
+    font: Option<Font>,
+
+    /// Line spacing in dots, overriding the profile (default: the printer's own)
+    #[arg(long)]
+    line_spacing: Option<u8>,
+
+    /// Split each job into writes of at most this many bytes, overriding the profile (0 = one write per job)
+    #[arg(long)]
+    write_chunk_size: Option<usize>,
+
+    /// Pause between chunked writes in milliseconds, overriding the profile
+    #[arg(long)]
+    inter_chunk_delay_ms: Option<u64>,
+
+    /// Printer receive buffer in bytes, overriding the profile; larger jobs are paced or refused before sending (0 = unknown)
+    #[arg(long)]
+    receive_buffer_bytes: Option<usize>,
+
+    /// Bytes a second the printer prints through, overriding the profile; used to estimate print time and pace jobs (0 = unknown)
+    #[arg(long)]
+    throughput_bytes_per_sec: Option<usize>,
+
+    /// Refuse jobs estimated to take longer than this many seconds to print, overriding the profile (0 = no limit)
+    #[arg(long)]
+    max_print_secs: Option<u64>,
+
+    /// Send raster images in bands of at most this many dot rows, overriding the profile (0 = whole images)
+    #[arg(long)]
+    raster_band_rows: Option<usize>,
+
+    /// Directory for the persistent job spool; jobs received but not yet printed are replayed on startup
+    #[arg(long)]
+    spool_dir: Option<PathBuf>,
+
+    /// Compact the spool once it holds more than this many completed records
+    #[arg(long, default_value_t = 1000)]
+    spool_compact_threshold: usize,
+
+    /// Limit how many jobs per minute are sent to the printer once the burst allowance is used (0 = unlimited)
+    #[arg(long, default_value_t = 0)]
+    max_jobs_per_minute: u32,
+
+    /// Number of jobs that may print back-to-back before --max-jobs-per-minute applies
+    #[arg(long, default_value_t = 1)]
+    rate_limit_burst: u32,
+
+    /// Minimum pause in milliseconds between the end of one job and the start of the next
+    #[arg(long, default_value_t = 0)]
+    min_gap_ms: u64,
+
+    /// Close the printer connection after each job so other systems sharing the printer can use it
+    #[arg(long)]
+    release_printer_between_jobs: bool,
+
+    /// Print a daily report ticket at this local time (HH:MM)
+    #[arg(long, value_parser = report::parse_time)]
+    daily_report: Option<chrono::NaiveTime>,
+
+    /// Poll the printer's status this often while idle and report faults (cover open, cutter jam, ...) to the server (0 = never; ESC/POS printers only)
+    #[arg(long, default_value_t = 0)]
+    status_poll_secs: u64,
+
+    /// With --status-poll-secs, print a ticket for each fault saying what happened and what to do
+    #[arg(long)]
+    fault_tickets: bool,
+
+    /// Language of fault messages and tickets; falls back to English where the fault table has no translation
+    #[arg(long, default_value = "en")]
+    locale: String,
+
+    /// Save a transcript of every printed ticket (except jobs marked "sensitive") in this directory
+    #[arg(long)]
+    archive_dir: Option<PathBuf>,
+
+    /// POST a JSON copy of every printed ticket (except jobs marked "sensitive") to this URL, retrying in the background
+    #[arg(long)]
+    archive_url: Option<String>,
+
+    /// Delete the oldest files in --archive-dir once it holds more than this many megabytes
+    #[arg(long, default_value_t = 100)]
+    archive_max_mb: u64,
+
+    /// Delete files in --archive-dir older than this many days (0 = only the size limit applies)
+    #[arg(long, default_value_t = 0)]
+    archive_max_days: u64,
+
+    /// Bearer token sent when fetching resources jobs refer to with `image_ref`, only to --resource-origin
+    #[arg(long)]
+    resource_token: Option<String>,
+
+    /// The only origin (https://host[:port]) --resource-token is sent to; the server's host over https by default
+    #[arg(long)]
+    resource_origin: Option<String>,
+
+    /// Delete the least recently used resources in <state dir>/resources once it holds more than this many megabytes
+    #[arg(long, default_value_t = 50)]
+    resource_cache_mb: u64,
+
+    /// Keep up to this many kilobytes of rendered tickets in memory, so jobs that repeat aren't rendered again (0 = off; default 1024, 0 with --low-memory)
+    #[arg(long)]
+    render_cache_kb: Option<usize>,
+
+    /// Size cap of the job outcome journal in <state dir>, rotated files included; the oldest entries are dropped beyond this
+    #[arg(long, default_value_t = 4096)]
+    journal_max_kb: u64,
+
+    /// At startup, wait up to this many seconds for the server (and the --ip printer) to be reachable before connecting (0 = don't wait)
+    #[arg(long, default_value_t = 60)]
+    network_wait_secs: u64,
+
+    /// Check the config, the printer and the server connection once, print a summary and exit (0 ok, 2 config invalid, 3 printer unreachable, 4 server unreachable, 5 auth rejected)
+    #[arg(long)]
+    check: bool,
+
+    /// Print the device config in effect (the --config file with any command-line overrides, tokens hidden) as JSON and exit
+    #[arg(long, conflicts_with = "check")]
+    print_config: bool,
+
+    /// With --check, also print a short test ticket
+    #[arg(long, requires = "check")]
+    check_print: bool,
+
+    /// With --check, print the summary as JSON
+    #[arg(long, requires = "check")]
+    json: bool,
+
+    /// After a job panics (it's failed, the printer reset and the job set aside): go on with the next job, or exit
+    #[arg(long = "panic", value_enum, default_value_t = PanicPolicy::Continue)]
+    panic_policy: PanicPolicy,
+
+    /// Put the printer into low-power mode after this many idle minutes (profiles with a sleep command only)
+    #[arg(long)]
+    sleep_after_mins: Option<u64>,
+
+    /// Seconds after receipt a job without its own TTL expires unprinted (0 = never)
+    #[arg(long, default_value_t = 0)]
+    default_job_ttl: u64,
+
+    /// Most lines a job may print, all copies included (0 = unlimited)
+    #[arg(long, default_value_t = 1000)]
+    max_job_lines: usize,
+
+    /// Most bytes a job may send to the printer, all copies included (0 = unlimited)
+    #[arg(long, default_value_t = 1024 * 1024)]
+    max_job_bytes: usize,
+
+    /// Print the first --max-job-lines lines of an oversized job with a truncation notice instead of failing it
+    #[arg(long)]
+    truncate_oversize: bool,
+
+    /// Largest incoming WebSocket message in bytes; bigger ones are rejected with an error ack
+    #[arg(long, default_value_t = 4 * 1024 * 1024)]
+    max_message_size: usize,
+
+    /// Reconnect if no complete WebSocket message (including pings) arrives for this many seconds
+    #[arg(long, default_value_t = 90)]
+    ws_read_timeout_secs: u64,
+
+    /// Directory for the device identity, generated on first start (see the identity subcommand)
+    #[arg(long)]
+    state_dir: Option<PathBuf>,
+
+    /// Encrypt the spool, journal and dead letters on disk, encrypting any plaintext records on startup. Without --at-rest-key-file the key is derived from the device key, which is on the same card: that only stops the files being read on their own, not by someone with the whole card, and rotate_key doesn't help once the card is out
+    #[arg(long, conflicts_with = "decrypt_at_rest")]
+    encrypt_at_rest: bool,
+
+    /// Store an encrypted spool and journal in plaintext again, decrypting them on startup
+    #[arg(long)]
+    decrypt_at_rest: bool,
+
+    /// File containing the hex-encoded secret to derive the at-rest encryption keys from, instead of the device key; keep it off the SD card
+    #[arg(long)]
+    at_rest_key_file: Option<PathBuf>,
+
+    /// Maximum acks and replies kept for resending after a WebSocket reconnect; the oldest are dropped beyond this
+    #[arg(long, default_value_t = 256)]
+    outbox_size: usize,
+
+    /// Use less memory, for boards that share 64 MB with a UI: cap rendered tickets held in the queue, shrink the outbox, archive queue and raster bands, and turn on the RSS self-check
+    #[arg(long)]
+    low_memory: bool,
+
+    /// Drop tickets rendered ahead of time while RSS is over this many megabytes (0 = off; 24 with --low-memory)
+    #[arg(long)]
+    rss_limit_mb: Option<u64>,
+
+    /// Log a one-line network summary (interface, Wi-Fi signal, link speed, errors, ping round trip) on every reconnect
+    #[arg(long)]
+    net_diagnostics: bool,
+
+    /// Inject faults for testing recovery: comma-separated write_errors=P, latency_ms=N, disconnect_secs=N, paper_out=P
+    #[cfg(feature = "chaos")]
+    #[arg(long, value_parser = chaos::parse)]
+    chaos: Option<chaos::ChaosConfig>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Cmd {
+    /// Validate a provisioning file, write the device config and print a confirmation ticket
+    Provision(provision::ProvisionArgs),
+    /// Show the device id and public key fingerprint for enrollment, generating them on first use
+    Identity(identity::IdentityArgs),
+    /// Print synthetic jobs to a discarding driver at a steady rate and report queue, latency and memory statistics
+    Soak(soak::SoakArgs),
+    /// Look up job outcomes in the journal by job id or time range
+    Journal(journal::JournalArgs),
+    /// Check addresses, gateway, DNS and the server, and show or print what was found
+    Diagnose(diagnose::DiagnoseArgs),
+}
+
+impl Args {
+    /// Applies the profile override flags.
+    fn customize_profile(&self, mut profile: PrinterProfile) -> PrinterProfile {
+        if self.compact {
+            profile.compact = true;
+        }
+        if self.large_print {
+            profile.large_print = true;
+        }
+        if let Some(font) = self.font {
+            profile.font = font;
+        }
+        if let Some(dots) = self.line_spacing {
+            profile.line_spacing = Some(dots);
+        }
+        if let Some(chunk_size) = self.write_chunk_size {
+            profile.chunk_size = chunk_size;
+        }
+        if let Some(delay_ms) = self.inter_chunk_delay_ms {
+            profile.inter_chunk_delay = Duration::from_millis(delay_ms);
+        }
+        if let Some(bytes) = self.receive_buffer_bytes {
+            profile.receive_buffer = bytes;
+        }
+        if let Some(throughput) = self.throughput_bytes_per_sec {
+            profile.throughput = throughput;
+        }
+        if let Some(secs) = self.max_print_secs {
+            profile.max_print_time = Duration::from_secs(secs);
+        }
+        if let Some(rows) = self.raster_band_rows {
+            profile.raster_band_rows = rows;
+        } else if self.low_memory {
+            profile.raster_band_rows = memory::band_rows(profile.raster_band_rows);
+        }
+        profile
+    }
+
+    /// Fills in settings from the device config that weren't given on the command line.
+    fn apply_config(&mut self, config: DeviceConfig, matches: &ArgMatches) {
+        let from_cli = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+
+        if self.url.is_none() {
+            self.url = Some(config.url);
+        }
+        if self.tenant.is_none() {
+            self.tenant = config.tenant;
+        }
+        if self.at_rest_key_file.is_none() {
+            self.at_rest_key_file = config.at_rest_key_file;
+        }
+        if !from_cli("ip") && !from_cli("serial") {
+            self.ip = config.ip;
+            self.serial = config.serial;
+        }
+        if let Some(port) = config.port
+            && !from_cli("port")
+        {
+            self.port = port;
+        }
+        if let Some(baud) = config.baud
+            && !from_cli("baud")
+        {
+            self.baud = baud;
+        }
+        if let Some(profile) = config.profile
+            && !from_cli("profile")
+        {
+            self.profile = profile;
+        }
+        if let Some(rate_limit) = config.rate_limit
+            && !self.rate_limit_from_cli(matches)
+        {
+            self.max_jobs_per_minute = rate_limit.max_jobs_per_minute;
+            self.rate_limit_burst = rate_limit.burst;
+            self.min_gap_ms = rate_limit.min_gap_ms;
+        }
+    }
+
+    /// `config` with the settings that `apply_config` fills in replaced by
+    /// the ones in effect.
+    fn resolved_config(&self, config: DeviceConfig) -> DeviceConfig {
+        DeviceConfig {
+            url: self.url.clone().unwrap_or_default(),
+            tenant: self.tenant.clone(),
+            at_rest_key_file: self.at_rest_key_file.clone(),
+            ip: self.ip.clone(),
+            port: Some(self.port),
+            serial: self.serial.clone(),
+            baud: Some(self.baud),
+            profile: Some(self.profile.clone()),
+            rate_limit: Some(RateLimitConfig {
+                max_jobs_per_minute: self.max_jobs_per_minute,
+                burst: self.rate_limit_burst,
+                min_gap_ms: self.min_gap_ms,
+            }),
+            ..config
+        }
+    }
+
+    fn rate_limit_from_cli(&self, matches: &ArgMatches) -> bool {
+        ["max_jobs_per_minute", "rate_limit_burst", "min_gap_ms"]
+            .iter()
+            .any(|id| matches.value_source(id) == Some(ValueSource::CommandLine))
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    rustls::crypto::aws_lc_rs::default_provider().install_default().unwrap();
+    rustls::crypto::aws_lc_rs::default_provider()
+        .install_default()
+        .unwrap();
     env_logger::Builder::from_env(Env::default().default_filter_or("debug")).init();
-    let args = Args::parse();
+    panics::install_hook();
+    let default_log_level = log::max_level();
+    let matches = Args::command().get_matches();
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+
+    if let Some(Cmd::Provision(provision_args)) = &args.command {
+        return provision::run(provision_args);
+    }
+    if let Some(Cmd::Identity(identity_args)) = &args.command {
+        return identity::run(identity_args);
+    }
+    if let Some(Cmd::Soak(soak_args)) = &args.command {
+        return tokio::task::block_in_place(|| soak::run(soak_args));
+    }
+    if let Some(Cmd::Journal(journal_args)) = &args.command {
+        return journal::run(journal_args);
+    }
+    if let Some(Cmd::Diagnose(diagnose_args)) = &args.command {
+        return diagnose::run(diagnose_args).await;
+    }
+
+    if args.print_config {
+        let loaded = match &args.config {
+            Some(path) => config::load(path)?,
+            None => DeviceConfig::default(),
+        };
+        args.apply_config(loaded.clone(), &matches);
+        println!("{}", config::dump(&args.resolved_config(loaded))?);
+        return Ok(());
+    }
+
+    if args.check {
+        let config = service_config(&mut args, &matches, default_log_level)
+            .map(|(config, transport, _)| (config, transport));
+        let report = check::run(config, |config| check_printer(&args, config)).await;
+        report.print(args.json);
+        std::process::exit(report.exit_code);
+    }
 
     info!("Starting printer service for LicheeRV Nano...");
 
-    info!("Target Websocket URL: {}", args.url);
+    let (mut config, transport, profile_explicit) =
+        service_config(&mut args, &matches, default_log_level)?;
+    #[cfg(feature = "chaos")]
+    if let Some(faults) = &args.chaos {
+        config.force_disconnect_every = faults.disconnect_every;
+    }
+
+    if args.network_wait_secs > 0
+        && let Transport::WebSocket(url) = &transport
+    {
+        let printer = args
+            .ip
+            .as_deref()
+            .filter(|_| !args.mock && !args.mock_pretty)
+            .map(|ip| (ip, args.port));
+        preflight::wait_for_network(url, printer, Duration::from_secs(args.network_wait_secs))
+            .await?;
+    }
 
-    if args.mock {
+    let targets = targets(&config, &args)?;
+    if args.probe_printer && (args.mock || args.mock_pretty) {
+        warn!("Nothing to probe in mock mode, ignoring --probe-printer");
+    }
+    if args.mock_pretty {
+        info!("Mode: MOCK (Pretty transcript)");
+        let transcript =
+            PrettyTranscript::new(config.profile.clone(), args.mock_pretty_dir.clone())?;
+        let mut observers: Vec<Box<dyn Observer>> = vec![Box::new(transcript)];
+        if let Some(dir) = &args.mock_png_dir {
+            observers.push(Box::new(PngRender::new(
+                config.profile.commands,
+                dir.clone(),
+            )?));
+        }
+        let driver = TeeDriver::new(ConsoleDriver::open(false), observers);
+        start(
+            driver,
+            config,
+            transport,
+            None::<fn() -> Result<TeeDriver<ConsoleDriver>>>,
+            targets,
+            &args,
+        )
+        .await?;
+    } else if args.mock {
         info!("Mode: MOCK (Console)");
         let driver = ConsoleDriver::open(true);
-        run_service(driver, &args.url, None::<fn() -> Result<ConsoleDriver>>).await?;
-    } else if let Some(ip) = args.ip {
+        start(
+            driver,
+            config,
+            transport,
+            None::<fn() -> Result<ConsoleDriver>>,
+            targets,
+            &args,
+        )
+        .await?;
+    } else if let Some(ip) = args.ip.clone() {
         info!("Mode: NETWORK ({}:{})", ip, args.port);
         spawn_daily_reset(&ip);
         let driver = NetworkDriver::open(&ip, args.port, Some(Duration::from_secs(1)))?;
+        probe_printer(&driver, &mut config, &args, profile_explicit);
         let reconnect_ip = ip.clone();
         let reconnect_port = args.port;
-        run_service(driver, &args.url, Some(move || {
-            info!("Reconnecting to printer at {}:{}...", reconnect_ip, reconnect_port);
-            NetworkDriver::open(&reconnect_ip, reconnect_port, Some(Duration::from_secs(1)))
-                .map_err(|e| anyhow::anyhow!(e))
-        })).await?;
+        start(
+            driver,
+            config,
+            transport,
+            Some(move || {
+                info!(
+                    "Reconnecting to printer at {}:{}...",
+                    reconnect_ip, reconnect_port
+                );
+                NetworkDriver::open(&reconnect_ip, reconnect_port, Some(Duration::from_secs(1)))
+                    .map_err(|e| anyhow::anyhow!(e))
+            }),
+            targets,
+            &args,
+        )
+        .await?;
+    } else if let Some(path) = args.serial.clone() {
+        info!(
+            "Mode: SERIAL ({} @ {} baud{})",
+            path,
+            args.baud,
+            if args.xon_xoff { ", XON/XOFF" } else { "" }
+        );
+        let driver = SerialDriver::open(&path, args.baud, args.xon_xoff)?;
+        probe_printer(&driver, &mut config, &args, profile_explicit);
+        let baud = args.baud;
+        let xon_xoff = args.xon_xoff;
+        start(
+            driver,
+            config,
+            transport,
+            Some(move || {
+                info!("Reconnecting to serial printer at {}...", path);
+                SerialDriver::open(&path, baud, xon_xoff)
+            }),
+            targets,
+            &args,
+        )
+        .await?;
     } else {
         info!("Mode: USB");
         for device in nusb::list_devices().wait().unwrap() {
@@ -78,156 +630,489 @@ async fn main() -> Result<()> {
                 device.serial_number().unwrap_or_default(),
             );
         }
-        let driver = NativeUsbDriver::open(0x0456, 0x0808)?;
-        run_service(driver, &args.url, Some(|| {
-            info!("Reconnecting to USB printer at 0456:0808...");
-            NativeUsbDriver::open(0x0456, 0x0808).map_err(|e| anyhow::anyhow!(e))
-        })).await?;
+        let driver = NativeUsbDriver::open(USB_VENDOR_ID, USB_PRODUCT_ID)?;
+        probe_printer(&driver, &mut config, &args, profile_explicit);
+        start(
+            driver,
+            config,
+            transport,
+            Some(|| {
+                info!("Reconnecting to USB printer at 0456:0808...");
+                NativeUsbDriver::open(USB_VENDOR_ID, USB_PRODUCT_ID).map_err(|e| anyhow::anyhow!(e))
+            }),
+            targets,
+            &args,
+        )
+        .await?;
     }
 
     Ok(())
 }
 
-async fn run_service<D, F>(driver: D, url: &str, reconnect: Option<F>) -> Result<()>
-where
-    D: escpos::driver::Driver + Send + 'static,
-    F: Fn() -> Result<D>,
-{
-    let mut printer = Printer::new(driver, Protocol::default(), None);
+/// Loads the config file and identity and works out the service settings
+/// and where jobs come from, along with whether the profile was picked
+/// explicitly.
+fn service_config(
+    args: &mut Args,
+    matches: &ArgMatches,
+    default_log_level: LevelFilter,
+) -> Result<(ServiceConfig, Transport, bool)> {
+    #[cfg(feature = "fallback-font")]
+    fallback::install(match &args.fallback_font {
+        Some(path) => fallback::FallbackFont::load(path)?,
+        None => fallback::FallbackFont::built_in(),
+    });
+    let mut hooks = HookConfig::default();
+    let mut device_id = String::new();
+    let mut loaded_config = None;
+    if let Some(path) = args.config.clone() {
+        let device_config = config::load(&path)?;
+        info!(
+            "Device {} (config {})",
+            device_config.device_id,
+            path.display()
+        );
+        if let Some(level) = device_config.log_level() {
+            log::set_max_level(level);
+        }
+        hooks = device_config.hooks.clone();
+        device_id = device_config.device_id.clone();
+        loaded_config = Some(device_config.clone());
+        args.apply_config(device_config, matches);
+    }
+    let mut public_key = None;
+    let mut identity = None;
+    if let Some(dir) = &args.state_dir {
+        let loaded = identity::Identity::load_or_create(dir)?;
+        info!(
+            "Device identity {} (key {})",
+            loaded.device_id,
+            loaded.fingerprint()
+        );
+        if device_id.is_empty() {
+            device_id = loaded.device_id.clone();
+        }
+        public_key = Some(loaded.public_key());
+        identity = Some(loaded);
+    }
+    let at_rest = at_rest(args, identity.as_ref())?;
+    let transport = if args.demo {
+        // A config file can give one too; a demo must never reach a real server
+        if args.url.as_deref().is_some_and(|url| !url.is_empty()) {
+            bail!("--demo can't be used with a config file that gives a server URL");
+        }
+        Transport::Demo(Duration::from_secs(args.demo_interval_secs))
+    } else {
+        let url = args
+            .url
+            .clone()
+            .context("--url is required unless the config file gives one")?;
+        info!("Target Websocket URL: {}", url);
+        Transport::WebSocket(url)
+    };
 
-    match printer.init() {
-        Ok(_) => info!("Printer initialized."),
-        Err(e) => {
-            error!("Failed to initialize printer: {}", e);
-            return Err(anyhow::anyhow!("Printer init failed"));
+    let signer = match &args.signing_key_file {
+        Some(path) => {
+            info!("Signing outbound frames with key from {}", path);
+            Some(Signer::from_file(path, args.max_clock_skew_secs)?)
         }
+        None => None,
+    };
+    let profile_explicit = matches.value_source("profile") == Some(ValueSource::CommandLine)
+        || loaded_config.as_ref().is_some_and(|c| c.profile.is_some());
+    let printer_profile = args.customize_profile(
+        PrinterProfile::find(&args.profile)
+            .expect("profile names are validated by clap")
+            .clone(),
+    );
+    log_profile(&printer_profile);
+    if args.low_memory {
+        info!(
+            "Low-memory mode: raster bands of {} rows, at most {} KB of tickets rendered ahead",
+            printer_profile.raster_band_rows,
+            memory::LOW_MEMORY_RENDERED_BYTES / 1024
+        );
     }
 
-    let mut consecutive_print_failures: u32 = 0;
-    let mut ws_backoff = WS_BACKOFF_INITIAL;
-
-    loop {
-        info!("Connecting to WebSocket...");
-        match connect_async(url.into_client_request()?).await {
-            Ok((ws_stream, _)) => {
-                info!("Connected!");
-                ws_backoff = WS_BACKOFF_INITIAL;
-
-                let (_write, mut read) = ws_stream.split();
-
-                loop {
-                    match tokio::time::timeout(WS_READ_TIMEOUT, read.next()).await {
-                        Ok(Some(message)) => match message {
-                            Ok(msg) => {
-                                if let Message::Text(text) = msg {
-                                    info!("Received: {}", text);
-                                    match print_ticket(&mut printer, &text) {
-                                        Ok(_) => {
-                                            info!("Printed ticket.");
-                                            consecutive_print_failures = 0;
-                                        }
-                                        Err(e) => {
-                                            consecutive_print_failures += 1;
-                                            error!(
-                                                "Print failed ({}/{}): {}",
-                                                consecutive_print_failures,
-                                                MAX_CONSECUTIVE_PRINT_FAILURES,
-                                                e
-                                            );
-
-                                            // Attempt to reconnect the printer driver and retry the job once
-                                            if let Some(ref reconnect_fn) = reconnect {
-                                                if reconnect_and_retry(&mut printer, reconnect_fn, &text) {
-                                                    consecutive_print_failures = 0;
-                                                }
-                                            }
-
-                                            if consecutive_print_failures
-                                                >= MAX_CONSECUTIVE_PRINT_FAILURES
-                                            {
-                                                error!(
-                                                    "Printer appears disconnected after {} consecutive failures, exiting for restart",
-                                                    consecutive_print_failures
-                                                );
-                                                return Err(anyhow::anyhow!(
-                                                    "Printer disconnected"
-                                                ));
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                            Err(e) => {
-                                error!("WebSocket error: {}", e);
-                                break;
-                            }
-                        },
-                        Ok(None) => {
-                            info!("WebSocket stream ended.");
-                            break;
-                        }
-                        Err(_) => {
-                            warn!(
-                                "No WebSocket message received in {:?}, reconnecting...",
-                                WS_READ_TIMEOUT
-                            );
-                            break;
-                        }
-                    }
-                }
-            }
-            Err(e) => match &e {
-                tokio_tungstenite::tungstenite::Error::Http(response)
-                    if response.status() == 401 =>
-                {
-                    error!("WebSocket authentication rejected (HTTP 401) — check the token in the ws-url");
-                }
-                _ => {
-                    error!("WebSocket connect failed: {}", e);
-                }
+    let sleep_after = match args.sleep_after_mins {
+        Some(_) if printer_profile.sleep.is_none() => {
+            warn!(
+                "Profile {} has no sleep command, ignoring --sleep-after-mins",
+                printer_profile.name
+            );
+            None
+        }
+        Some(mins) => Some(Duration::from_secs(mins * 60)),
+        None => None,
+    };
+
+    let status_poll = match args.status_poll_secs {
+        0 => None,
+        _ if args.mock || args.mock_pretty => {
+            warn!("No printer status in mock mode, ignoring --status-poll-secs");
+            None
+        }
+        _ if printer_profile.commands != CommandSet::EscPos => {
+            warn!(
+                "Profile {} can't report its status, ignoring --status-poll-secs",
+                printer_profile.name
+            );
+            None
+        }
+        secs => Some(Duration::from_secs(secs)),
+    };
+
+    let config = ServiceConfig {
+        default_protocol: args.default_protocol,
+        signer,
+        require_signed_jobs: args.require_signed_jobs,
+        tenant: args.tenant.clone(),
+        allow_untagged_jobs: args.allow_untagged_jobs,
+        profile: printer_profile,
+        spool_dir: args.spool_dir.clone(),
+        spool_compact_threshold: args.spool_compact_threshold,
+        max_jobs_per_minute: args.max_jobs_per_minute,
+        rate_limit_burst: args.rate_limit_burst,
+        min_gap: Duration::from_millis(args.min_gap_ms),
+        release_printer_between_jobs: args.release_printer_between_jobs,
+        outbox_size: match matches.value_source("outbox_size") {
+            Some(ValueSource::CommandLine) => args.outbox_size,
+            _ if args.low_memory => memory::LOW_MEMORY_OUTBOX_SIZE,
+            _ => args.outbox_size,
+        },
+        daily_report: args.daily_report,
+        default_job_ttl_secs: args.default_job_ttl,
+        sleep_after,
+        max_job_lines: args.max_job_lines,
+        max_job_bytes: args.max_job_bytes,
+        truncate_oversize: args.truncate_oversize,
+        max_message_size: args.max_message_size,
+        ws_read_timeout: Duration::from_secs(args.ws_read_timeout_secs),
+        hooks,
+        device_id,
+        public_key,
+        state_dir: args.state_dir.clone(),
+        config_path: args.config.clone(),
+        loaded_config,
+        rate_limit_from_cli: args.rate_limit_from_cli(matches),
+        default_log_level,
+        printer: None,
+        status_poll,
+        fault_tickets: args.fault_tickets,
+        locale: args.locale.clone(),
+        archive_dir: args.archive_dir.clone(),
+        archive_url: args.archive_url.clone(),
+        archive_max_bytes: args.archive_max_mb * 1024 * 1024,
+        archive_max_age: Some(Duration::from_secs(args.archive_max_days * 24 * 60 * 60))
+            .filter(|age| !age.is_zero()),
+        archive_queue_size: if args.low_memory {
+            memory::LOW_MEMORY_ARCHIVE_QUEUE
+        } else {
+            archive::CHANNEL_SIZE
+        },
+        panic_policy: args.panic_policy,
+        force_disconnect_every: None,
+        resource_token: args.resource_token.clone(),
+        resource_origin: resource_origin(args)?,
+        resource_cache_bytes: args.resource_cache_mb * 1024 * 1024,
+        render_cache_bytes: match args.render_cache_kb {
+            Some(kb) => kb * 1024,
+            None if args.low_memory => 0,
+            None => rendercache::DEFAULT_KB * 1024,
+        },
+        journal_max_bytes: args.journal_max_kb * 1024,
+        max_rendered_bytes: if args.low_memory {
+            memory::LOW_MEMORY_RENDERED_BYTES
+        } else {
+            0
+        },
+        rss_limit_mb: match args.rss_limit_mb {
+            Some(0) => None,
+            Some(mb) => Some(mb),
+            None => Some(memory::LOW_MEMORY_RSS_LIMIT_MB).filter(|_| args.low_memory),
+        },
+        command_policy: CommandPolicy {
+            risks: args.command_risk.clone().unwrap_or_default(),
+            limits: args.command_limits.clone().unwrap_or_default(),
+            auth: match args.command_auth {
+                Some(auth) => auth.lowest(),
+                None => Some(Risk::High).filter(|_| args.signing_key_file.is_some()),
             },
+        },
+        net_diagnostics: args.net_diagnostics,
+        at_rest,
+    };
+    Ok((config, transport, profile_explicit))
+}
+
+/// Where `--resource-token` may be sent: `--resource-origin`, or the
+/// server's host over https.
+fn resource_origin(args: &Args) -> Result<Option<String>> {
+    if args.resource_token.is_none() {
+        return Ok(None);
+    }
+    let url = match (&args.resource_origin, args.url.as_deref()) {
+        (Some(origin), _) => {
+            Url::parse(origin).with_context(|| format!("Invalid --resource-origin {:?}", origin))?
+        }
+        (None, Some(server)) if !server.is_empty() => {
+            let mut url = Url::parse(server).context("Invalid server URL")?;
+            url.set_scheme("https")
+                .map_err(|_| anyhow!("Server URL {} has no host for resources", server))?;
+            url
         }
+        (None, _) => bail!("--resource-token needs --resource-origin or a server URL"),
+    };
+    if url.scheme() != "https" || url.host_str().is_none() {
+        bail!("--resource-origin must be an https:// URL, got {}", url);
+    }
+    let origin = url.origin().ascii_serialization();
+    info!("Sending the resource token to {} only", origin);
+    Ok(Some(origin))
+}
 
-        let jitter = rand::rng().random_range(0..=1000);
-        let sleep_dur = ws_backoff + Duration::from_millis(jitter);
-        info!("Reconnecting in {:?}...", sleep_dur);
-        tokio::time::sleep(sleep_dur).await;
-        ws_backoff = (ws_backoff * 2).min(WS_BACKOFF_MAX);
+/// Works out from `--encrypt-at-rest` and `--decrypt-at-rest` how the spool,
+/// journal and dead letters are stored, and the keys for them.
+fn at_rest(args: &Args, identity: Option<&identity::Identity>) -> Result<AtRest> {
+    if !args.encrypt_at_rest && !args.decrypt_at_rest {
+        return Ok(AtRest::Plain);
     }
+    // The key generation is kept beside what it encrypts
+    let dir = args
+        .state_dir
+        .as_ref()
+        .or(args.spool_dir.as_ref())
+        .context("--encrypt-at-rest and --decrypt-at-rest need --spool-dir or --state-dir")?;
+    let keys = match (&args.at_rest_key_file, identity) {
+        (Some(path), _) => {
+            info!("At-rest encryption keys derived from {}", path.display());
+            Keys::from_file(path, dir)?
+        }
+        (None, Some(identity)) => {
+            if args.encrypt_at_rest {
+                warn!(
+                    "At-rest encryption keys derived from the device key in {}, on the same storage as what they encrypt; use --at-rest-key-file with a secret kept elsewhere",
+                    dir.display()
+                );
+            }
+            Keys::new(identity.at_rest_secret(), dir)?
+        }
+        (None, None) => bail!(
+            "--encrypt-at-rest and --decrypt-at-rest need --at-rest-key-file or the device key in --state-dir"
+        ),
+    };
+    if args.decrypt_at_rest {
+        info!(
+            "Storing the spool, journal and dead letters in plaintext, decrypting any encrypted records"
+        );
+        return Ok(AtRest::Decrypting(keys));
+    }
+    info!(
+        "Encrypting the spool, journal and dead letters at rest (key {})",
+        keys.generation()
+    );
+    if let Some(dir) = &args.archive_dir {
+        warn!("Receipt archive {} is not encrypted at rest", dir.display());
+    }
+    Ok(AtRest::Encrypted(keys))
 }
 
-/// Reconnects the printer driver and retries printing the payload once.
-/// Returns true only if the retry actually printed successfully.
-fn reconnect_and_retry<D, F>(printer: &mut Printer<D>, reconnect_fn: &F, text: &str) -> bool
+/// Runs the service until the printer is gone for good, behind the fault
+/// injector if `--chaos` was given.
+#[cfg(feature = "chaos")]
+async fn start<D, F>(
+    driver: D,
+    config: ServiceConfig,
+    transport: Transport,
+    reconnect: Option<F>,
+    targets: Vec<Target>,
+    args: &Args,
+) -> Result<()>
 where
-    D: escpos::driver::Driver,
-    F: Fn() -> Result<D>,
+    D: Driver + Send + 'static,
+    F: Fn() -> Result<D> + Send + 'static,
 {
-    let new_driver = match reconnect_fn() {
-        Ok(driver) => driver,
-        Err(e) => {
-            warn!("Printer reconnect failed: {}", e);
-            return false;
-        }
+    let Some(faults) = args.chaos.clone() else {
+        return run(driver, config, transport, reconnect, targets).await;
+    };
+    warn!("CHAOS: injecting faults: {:?}", faults);
+    let wrap = faults.clone();
+    let reconnect = reconnect
+        .map(|open| move || open().map(|driver| chaos::ChaosDriver::new(driver, wrap.clone())));
+    run(
+        chaos::ChaosDriver::new(driver, faults),
+        config,
+        transport,
+        reconnect,
+        targets,
+    )
+    .await
+}
+
+#[cfg(not(feature = "chaos"))]
+async fn start<D, F>(
+    driver: D,
+    config: ServiceConfig,
+    transport: Transport,
+    reconnect: Option<F>,
+    targets: Vec<Target>,
+    _args: &Args,
+) -> Result<()>
+where
+    D: Driver + Send + 'static,
+    F: Fn() -> Result<D> + Send + 'static,
+{
+    run(driver, config, transport, reconnect, targets).await
+}
+
+async fn run<D, F>(
+    driver: D,
+    config: ServiceConfig,
+    transport: Transport,
+    reconnect: Option<F>,
+    targets: Vec<Target>,
+) -> Result<()>
+where
+    D: Driver + Send + 'static,
+    F: Fn() -> Result<D> + Send + 'static,
+{
+    let mut builder = PrinterService::builder()
+        .config(config)
+        .driver(driver)
+        .transport(transport);
+    if let Some(reconnect) = reconnect {
+        builder = builder.reconnect(reconnect);
+    }
+    for target in targets {
+        builder = builder.target(target);
+    }
+    builder.build()?.wait().await
+}
+
+/// Opens the config file's extra printers, or stands in the console for
+/// them in mock mode.
+fn targets(config: &ServiceConfig, args: &Args) -> Result<Vec<Target>> {
+    let Some(device) = &config.loaded_config else {
+        return Ok(Vec::new());
     };
+    let mut targets = Vec::new();
+    for printer in &device.printers {
+        let target = if args.mock || args.mock_pretty {
+            info!("Printer {}: MOCK (Console)", printer.name);
+            Target::new(&printer.name, ConsoleDriver::open(true))
+        } else if let Some(ip) = printer.ip.clone() {
+            info!(
+                "Printer {}: NETWORK ({}:{})",
+                printer.name, ip, printer.port
+            );
+            let port = printer.port;
+            let driver = NetworkDriver::open(&ip, port, Some(Duration::from_secs(1)))
+                .with_context(|| format!("Failed to open printer {}", printer.name))?;
+            Target::new(&printer.name, driver).reconnect(move || {
+                info!("Reconnecting to printer at {}:{}...", ip, port);
+                NetworkDriver::open(&ip, port, Some(Duration::from_secs(1)))
+                    .map_err(|e| anyhow::anyhow!(e))
+            })
+        } else if let Some(path) = printer.serial.clone() {
+            info!(
+                "Printer {}: SERIAL ({} @ {} baud)",
+                printer.name, path, printer.baud
+            );
+            let (baud, xon_xoff) = (printer.baud, printer.xon_xoff);
+            let driver = SerialDriver::open(&path, baud, xon_xoff)
+                .with_context(|| format!("Failed to open printer {}", printer.name))?;
+            Target::new(&printer.name, driver).reconnect(move || {
+                info!("Reconnecting to serial printer at {}...", path);
+                SerialDriver::open(&path, baud, xon_xoff)
+            })
+        } else {
+            bail!(
+                "Printer {} has neither an ip nor a serial port",
+                printer.name
+            );
+        };
+        targets.push(match &printer.profile {
+            Some(name) => {
+                let profile = PrinterProfile::find(name)
+                    .with_context(|| format!("Unknown profile {:?}", name))?;
+                target.profile(profile.clone())
+            }
+            None => target,
+        });
+    }
+    Ok(targets)
+}
 
-    *printer = Printer::new(new_driver, Protocol::default(), None);
-    if let Err(e) = printer.init() {
-        warn!("Printer reconnected but init failed: {}", e);
-        return false;
+fn log_profile(profile: &PrinterProfile) {
+    info!(
+        "Printer profile: {} (font {:?}, {} columns, chunk size {}, inter-chunk delay {:?})",
+        profile.name,
+        profile.font,
+        profile.columns_for(profile.font),
+        profile.chunk_size,
+        profile.inter_chunk_delay
+    );
+    if profile.receive_buffer > 0 || profile.throughput > 0 || !profile.max_print_time.is_zero() {
+        info!(
+            "Job size check: receive buffer {} bytes, throughput {} bytes/s, max print time {}s (0 = unknown or no limit)",
+            profile.receive_buffer,
+            profile.throughput,
+            profile.max_print_time.as_secs()
+        );
     }
+}
 
-    info!("Printer reconnected, retrying print...");
-    match print_ticket(printer, text) {
-        Ok(_) => {
-            info!("Printed ticket after reconnect.");
-            true
-        }
-        Err(e) => {
-            warn!("Retry after reconnect failed: {}", e);
-            false
-        }
+/// Opens the configured printer for `--check` and checks it.
+fn check_printer(args: &Args, config: &ServiceConfig) -> Result<String> {
+    if args.mock || args.mock_pretty {
+        return Ok("mock mode, no printer checked".to_string());
+    }
+    if let Some(ip) = &args.ip {
+        let driver = NetworkDriver::open(ip, args.port, Some(Duration::from_secs(1)))
+            .with_context(|| format!("Failed to connect to {}:{}", ip, args.port))?;
+        let detail = check::printer(&driver, config, args.check_print)?;
+        return Ok(format!("network {}:{}, {}", ip, args.port, detail));
     }
+    if let Some(path) = &args.serial {
+        let driver = SerialDriver::open(path, args.baud, args.xon_xoff)
+            .with_context(|| format!("Failed to open {}", path))?;
+        let detail = check::printer(&driver, config, args.check_print)?;
+        return Ok(format!("serial {}, {}", path, detail));
+    }
+    let driver = NativeUsbDriver::open(USB_VENDOR_ID, USB_PRODUCT_ID).with_context(|| {
+        format!(
+            "Failed to open USB printer {:04x}:{:04x}",
+            USB_VENDOR_ID, USB_PRODUCT_ID
+        )
+    })?;
+    let detail = check::printer(&driver, config, args.check_print)?;
+    Ok(format!(
+        "USB {:04x}:{:04x}, {}",
+        USB_VENDOR_ID, USB_PRODUCT_ID, detail
+    ))
+}
+
+/// With `--probe-printer`, asks the printer what it is and adjusts the
+/// profile. Override flags still win over a newly picked profile.
+fn probe_printer<D: Driver>(
+    driver: &D,
+    config: &mut ServiceConfig,
+    args: &Args,
+    profile_explicit: bool,
+) {
+    if !args.probe_printer {
+        return;
+    }
+    let detected = probe::probe(driver, config.profile.commands);
+    info!("Probed printer: {}", probe::summary(&detected));
+    let refined = probe::refine(&detected, &config.profile, profile_explicit);
+    config.profile = if refined.name == config.profile.name {
+        refined
+    } else {
+        args.customize_profile(refined)
+    };
+    log_profile(&config.profile);
+    config.printer = Some(detected);
 }
 
 /// Spawns a background task that calls /reset_srv on the network printer at 12pm daily.
@@ -242,7 +1127,7 @@ fn spawn_daily_reset(ip: &str) {
             .expect("failed to build HTTP client");
 
         loop {
-            let now = Local::now();
+            let now = clock::local_now();
             let today_noon = now
                 .date_naive()
                 .and_hms_opt(12, 0, 0)
@@ -256,7 +1141,9 @@ fn spawn_daily_reset(ip: &str) {
                 today_noon + chrono::Duration::days(1)
             };
 
-            let delay = (next_noon - now).to_std().unwrap_or(Duration::from_secs(60));
+            let delay = (next_noon - now)
+                .to_std()
+                .unwrap_or(Duration::from_secs(60));
             info!("Next printer reset in {:?}", delay);
             tokio::time::sleep(delay).await;
 
@@ -271,17 +1158,3 @@ fn spawn_daily_reset(ip: &str) {
         }
     });
 }
-
-fn print_ticket<D>(printer: &mut Printer<D>, text: &str) -> Result<()>
-where
-    D: escpos::driver::Driver,
-{
-    printer.init()?;
-    printer.smoothing(true)?;
-    printer.writeln(text)?;
-    printer.feed()?;
-    printer.feed()?;
-    printer.print_cut()?;
-
-    Ok(())
-}
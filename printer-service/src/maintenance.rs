@@ -0,0 +1,378 @@
+//! Lifetime counters for the printer mechanism (cuts, lines, paper, hours
+//! powered on), so a worn cutter or head is replaced before it fails mid
+//! service. With a state dir they're saved in `<dir>/maintenance.json` after
+//! every job and survive restarts; `maintenance_reset` starts them again
+//! when the mechanism is replaced.
+
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use log::warn;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+use crate::clock;
+use crate::report::{CUT_MM, LINE_MM};
+
+const MAINTENANCE_FILE: &str = "maintenance.json";
+/// How long a `maintenance_reset` confirmation token stays valid
+const CONFIRM_TTL: Duration = Duration::from_secs(300);
+
+/// Wear limits from the device config; a counter past its limit makes
+/// maintenance due.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct MaintenanceConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_cuts: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_lines: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_paper_m: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_power_on_hours: Option<f64>,
+    /// Print a maintenance-due notice, at most once a day
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub notice: bool,
+}
+
+impl MaintenanceConfig {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// What's saved between runs.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct Counters {
+    /// Unix time of the last reset (or of the first run)
+    since: i64,
+    cuts: u64,
+    lines: u64,
+    paper_mm: f64,
+    power_on_secs: u64,
+    /// Local day (`YYYY-MM-DD`) the last maintenance-due notice was printed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_notice: Option<String>,
+}
+
+/// The counters as sent in heartbeats and `status`.
+#[derive(Serialize, Debug, Clone)]
+pub struct MaintenanceState {
+    /// Unix time the counters were last reset
+    pub since: i64,
+    pub cuts: u64,
+    pub lines: u64,
+    /// Estimated from lines fed and cuts
+    pub paper_m: f64,
+    /// Hours the service has been running, standing in for the printer's
+    pub power_on_hours: f64,
+    /// Limits reached, e.g. `cuts 50012/50000`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub due: Vec<String>,
+}
+
+pub struct Maintenance {
+    path: Option<PathBuf>,
+    config: MaintenanceConfig,
+    counters: Counters,
+    /// Power-on time is counted up to here
+    counted_to: Instant,
+    /// Token a `maintenance_reset` has to echo back, and until when
+    pending_reset: Option<(String, Instant)>,
+}
+
+impl Maintenance {
+    pub fn open(dir: Option<&Path>, config: MaintenanceConfig) -> Self {
+        let path = dir.map(|dir| dir.join(MAINTENANCE_FILE));
+        let counters = path
+            .as_deref()
+            .filter(|path| path.exists())
+            .and_then(|path| match load(path) {
+                Ok(counters) => Some(counters),
+                Err(e) => {
+                    warn!("Ignoring unreadable maintenance counters: {:#}", e);
+                    None
+                }
+            })
+            .unwrap_or_else(|| Counters {
+                since: clock::now().timestamp(),
+                ..Default::default()
+            });
+        Self {
+            path,
+            config,
+            counters,
+            counted_to: Instant::now(),
+            pending_reset: None,
+        }
+    }
+
+    pub fn reconfigure(&mut self, config: MaintenanceConfig) {
+        self.config = config;
+    }
+
+    /// Records a printed ticket that fed `lines` lines and was cut `cuts` times.
+    pub fn record_job(&mut self, lines: usize, cuts: usize) {
+        self.counters.lines += lines as u64;
+        self.counters.cuts += cuts as u64;
+        self.counters.paper_mm += lines as f64 * LINE_MM + cuts as f64 * CUT_MM;
+        self.count_time();
+        self.save();
+    }
+
+    /// Counts power-on time since the last call and saves it.
+    pub fn tick(&mut self) {
+        self.count_time();
+        self.save();
+    }
+
+    pub fn state(&mut self) -> MaintenanceState {
+        self.count_time();
+        let c = &self.counters;
+        MaintenanceState {
+            since: c.since,
+            cuts: c.cuts,
+            lines: c.lines,
+            paper_m: (c.paper_mm / 10.0).round() / 100.0,
+            power_on_hours: (c.power_on_secs as f64 / 360.0).round() / 10.0,
+            due: self.due(),
+        }
+    }
+
+    /// Limits reached, as `name value/limit`.
+    pub fn due(&self) -> Vec<String> {
+        let c = &self.counters;
+        let hours = c.power_on_secs as f64 / 3600.0;
+        let paper_m = c.paper_mm / 1000.0;
+        let mut due = Vec::new();
+        if let Some(max) = self.config.max_cuts.filter(|max| c.cuts >= *max) {
+            due.push(format!("cuts {}/{}", c.cuts, max));
+        }
+        if let Some(max) = self.config.max_lines.filter(|max| c.lines >= *max) {
+            due.push(format!("lines {}/{}", c.lines, max));
+        }
+        if let Some(max) = self.config.max_paper_m.filter(|max| paper_m >= *max) {
+            due.push(format!("paper {:.0}/{} m", paper_m, max));
+        }
+        if let Some(max) = self.config.max_power_on_hours.filter(|max| hours >= *max) {
+            due.push(format!("power-on {:.0}/{} h", hours, max));
+        }
+        due
+    }
+
+    /// Text of the maintenance-due notice, if maintenance is due, notices
+    /// are on and none was printed today. Marks today's notice as printed.
+    pub fn take_notice(&mut self) -> Option<String> {
+        let today = clock::local_now().format("%Y-%m-%d").to_string();
+        if !self.config.notice || self.counters.last_notice.as_ref() == Some(&today) {
+            return None;
+        }
+        let due = self.due();
+        if due.is_empty() {
+            return None;
+        }
+        self.counters.last_notice = Some(today);
+        self.save();
+        Some(format!(
+            "MAINTENANCE DUE\n\n{}\n\nReplace the worn parts, then reset the counters.",
+            due.join("\n")
+        ))
+    }
+
+    /// First step of a reset: a token the reset has to be confirmed with.
+    pub fn reset_token(&mut self) -> String {
+        let token = format!("{:08x}", rand::rng().random::<u32>());
+        self.pending_reset = Some((token.clone(), Instant::now() + CONFIRM_TTL));
+        token
+    }
+
+    /// Resets the counters if `token` is the one last handed out and still valid.
+    pub fn reset(&mut self, token: &str) -> bool {
+        match self.pending_reset.take() {
+            Some((expected, until)) if expected == token && Instant::now() < until => {
+                self.counters = Counters {
+                    since: clock::now().timestamp(),
+                    ..Default::default()
+                };
+                self.counted_to = Instant::now();
+                self.save();
+                true
+            }
+            pending => {
+                self.pending_reset = pending;
+                false
+            }
+        }
+    }
+
+    fn count_time(&mut self) {
+        let now = Instant::now();
+        let secs = now.duration_since(self.counted_to).as_secs();
+        self.counters.power_on_secs += secs;
+        self.counted_to += Duration::from_secs(secs);
+    }
+
+    fn save(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        if let Err(e) = save(path, &self.counters) {
+            warn!(
+                "Failed to save maintenance counters to {}: {:#}",
+                path.display(),
+                e
+            );
+        }
+    }
+}
+
+fn load(path: &Path) -> Result<Counters> {
+    let text = fs::read_to_string(path)?;
+    serde_json::from_str(&text).with_context(|| format!("Failed to parse {}", path.display()))
+}
+
+fn save(path: &Path, counters: &Counters) -> Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    let mut tmp = File::create(&tmp_path)?;
+    tmp.write_all(&serde_json::to_vec(counters)?)?;
+    tmp.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tempdir::TempDir;
+
+    fn limits() -> MaintenanceConfig {
+        MaintenanceConfig {
+            max_cuts: Some(3),
+            max_lines: Some(1000),
+            max_paper_m: Some(0.5),
+            max_power_on_hours: Some(2.0),
+            notice: true,
+        }
+    }
+
+    /// Pretends the service has been running `secs` longer.
+    fn run_for(maintenance: &mut Maintenance, secs: u64) {
+        maintenance.counted_to -= Duration::from_secs(secs);
+    }
+
+    #[test]
+    fn jobs_and_time_are_counted() {
+        let mut maintenance = Maintenance::open(None, MaintenanceConfig::default());
+        maintenance.record_job(100, 1);
+        maintenance.record_job(20, 2);
+        run_for(&mut maintenance, 5400);
+        let state = maintenance.state();
+        assert_eq!((state.cuts, state.lines), (3, 120));
+        // 120 lines and 3 cuts of paper is about half a metre
+        assert_eq!(state.paper_m, 0.55);
+        assert_eq!(state.power_on_hours, 1.5);
+        assert!(state.due.is_empty());
+        assert!(MaintenanceConfig::default().is_empty());
+    }
+
+    #[test]
+    fn limits_reached_make_maintenance_due() {
+        let mut maintenance = Maintenance::open(None, limits());
+        maintenance.record_job(100, 2);
+        assert!(maintenance.due().is_empty());
+        maintenance.record_job(30, 1);
+        run_for(&mut maintenance, 7200);
+        assert_eq!(
+            maintenance.state().due,
+            ["cuts 3/3", "paper 1/0.5 m", "power-on 2/2 h"]
+        );
+        maintenance.reconfigure(MaintenanceConfig {
+            max_lines: Some(130),
+            ..MaintenanceConfig::default()
+        });
+        assert_eq!(maintenance.due(), ["lines 130/130"]);
+    }
+
+    #[test]
+    fn the_notice_is_printed_once_a_day_when_due() {
+        let mut maintenance = Maintenance::open(None, limits());
+        assert_eq!(maintenance.take_notice(), None);
+        maintenance.record_job(0, 3);
+        let notice = maintenance.take_notice().unwrap();
+        assert!(
+            notice.starts_with("MAINTENANCE DUE\n\ncuts 3/3\n"),
+            "{}",
+            notice
+        );
+        assert_eq!(maintenance.take_notice(), None);
+
+        let mut quiet = Maintenance::open(
+            None,
+            MaintenanceConfig {
+                notice: false,
+                ..limits()
+            },
+        );
+        quiet.record_job(0, 3);
+        assert_eq!(quiet.take_notice(), None);
+    }
+
+    #[test]
+    fn counters_survive_a_restart() {
+        let dir = TempDir::new("maintenance");
+        let mut maintenance = Maintenance::open(Some(dir.path()), limits());
+        maintenance.record_job(40, 3);
+        run_for(&mut maintenance, 3600);
+        maintenance.tick();
+        maintenance.take_notice().unwrap();
+        let since = maintenance.state().since;
+        drop(maintenance);
+
+        let mut reopened = Maintenance::open(Some(dir.path()), limits());
+        let state = reopened.state();
+        assert_eq!((state.since, state.cuts, state.lines), (since, 3, 40));
+        assert_eq!(state.power_on_hours, 1.0);
+        // Including that today's notice was printed
+        assert_eq!(reopened.take_notice(), None);
+
+        fs::write(dir.path().join(MAINTENANCE_FILE), "[]").unwrap();
+        let fresh = Maintenance::open(Some(dir.path()), limits()).state();
+        assert_eq!((fresh.cuts, fresh.lines), (0, 0));
+    }
+
+    #[test]
+    fn resets_need_the_latest_unexpired_token() {
+        let dir = TempDir::new("maintenance");
+        let mut maintenance = Maintenance::open(Some(dir.path()), limits());
+        maintenance.record_job(10, 1);
+        assert!(!maintenance.reset("00000000"));
+
+        let first = maintenance.reset_token();
+        let token = maintenance.reset_token();
+        assert!(!maintenance.reset(&first) || first == token);
+        assert_eq!(maintenance.state().cuts, 1);
+        // A wrong guess doesn't use the token up
+        assert!(!maintenance.reset("not it"));
+        assert!(maintenance.reset(&token));
+        assert_eq!(maintenance.state().cuts, 0);
+        assert!(!maintenance.reset(&token));
+        drop(maintenance);
+        assert_eq!(
+            Maintenance::open(Some(dir.path()), limits()).state().lines,
+            0
+        );
+
+        let mut expired = Maintenance::open(None, limits());
+        expired.record_job(10, 1);
+        let token = expired.reset_token();
+        if let Some((_, until)) = &mut expired.pending_reset {
+            *until -= CONFIRM_TTL;
+        }
+        assert!(!expired.reset(&token));
+        assert_eq!(expired.state().cuts, 1);
+    }
+}
@@ -0,0 +1,114 @@
+//! `--low-memory`: tighter limits for the 64 MB LicheeRV Nano, where the
+//! service shares RAM with the kiosk UI, and the RSS self-check that sheds
+//! rendered jobs when memory use crosses `--rss-limit-mb`.
+
+use log::{info, warn};
+
+/// Rendered bytes the queue may hold for jobs rendered ahead of their turn
+pub const LOW_MEMORY_RENDERED_BYTES: usize = 256 * 1024;
+/// Undelivered frames kept for resending, unless `--outbox-size` is given
+pub const LOW_MEMORY_OUTBOX_SIZE: usize = 32;
+/// Printed jobs waiting for the archive task
+pub const LOW_MEMORY_ARCHIVE_QUEUE: usize = 4;
+/// Raster band height, unless `--raster-band-rows` is given
+const LOW_MEMORY_BAND_ROWS: usize = 32;
+/// RSS self-check threshold, unless `--rss-limit-mb` is given
+pub const LOW_MEMORY_RSS_LIMIT_MB: u64 = 24;
+
+/// The raster band height to use under `--low-memory` instead of `rows`.
+pub fn band_rows(rows: usize) -> usize {
+    match rows {
+        0 => LOW_MEMORY_BAND_ROWS,
+        rows => rows.min(LOW_MEMORY_BAND_ROWS),
+    }
+}
+
+/// A memory figure from `/proc/self/status` in kB (`VmRSS` now, `VmHWM`
+/// the peak). `None` off Linux.
+pub fn rss_kb(field: &str) -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix(field)?.strip_prefix(':'))
+        .and_then(|value| value.trim().trim_end_matches("kB").trim().parse().ok())
+}
+
+/// Compares RSS with a limit, logging when it crosses it either way.
+pub struct RssCheck {
+    limit_kb: u64,
+    over: bool,
+}
+
+impl RssCheck {
+    pub fn new(limit_mb: u64) -> Self {
+        info!("Shedding rendered jobs above {} MB RSS", limit_mb);
+        Self {
+            limit_kb: limit_mb * 1024,
+            over: false,
+        }
+    }
+
+    /// Reads RSS and returns whether it's over the limit.
+    pub fn check(&mut self) -> bool {
+        let Some(rss) = rss_kb("VmRSS") else {
+            return false;
+        };
+        let over = rss > self.limit_kb;
+        if over && !self.over {
+            warn!(
+                "RSS {} kB is over the {} kB limit, shedding rendered jobs",
+                rss, self.limit_kb
+            );
+        } else if !over && self.over {
+            info!(
+                "RSS {} kB is back under the {} kB limit",
+                rss, self.limit_kb
+            );
+        }
+        self.over = over;
+        over
+    }
+
+    /// Whether RSS was over the limit at the last check.
+    pub fn over(&self) -> bool {
+        self.over
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn low_memory_bands_are_at_most_32_rows() {
+        assert_eq!(band_rows(0), 32);
+        assert_eq!(band_rows(256), 32);
+        assert_eq!(band_rows(64), 32);
+        assert_eq!(band_rows(16), 16);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn rss_is_read_from_proc() {
+        let rss = rss_kb("VmRSS").unwrap();
+        let peak = rss_kb("VmHWM").unwrap();
+        assert!(rss > 0 && peak >= rss, "{} {}", rss, peak);
+        // A field name that's only a prefix of one isn't taken for it
+        assert_eq!(rss_kb("VmRS"), None);
+        assert_eq!(rss_kb("NoSuchField"), None);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn the_check_says_when_rss_is_over_the_limit() {
+        let mut never = RssCheck::new(u64::MAX / 1024);
+        assert!(!never.check());
+        assert!(!never.over());
+
+        let mut always = RssCheck::new(0);
+        assert!(!always.over());
+        assert!(always.check());
+        assert!(always.over());
+        assert!(always.check());
+    }
+}
@@ -0,0 +1,244 @@
+use std::time::Duration;
+
+use serde::Serialize;
+use serde::ser::{SerializeMap, Serializer};
+
+/// Bucket upper bounds in milliseconds for job timings.
+const TIMING_BOUNDS_MS: &[u64] = &[50, 100, 250, 500, 1000, 2500, 5000, 10000, 30000];
+
+/// Cumulative histogram of durations, serialized Prometheus-style as
+/// `{"le_50": n, ..., "le_inf": n, "sum_ms": n}`.
+#[derive(Debug, Clone)]
+pub struct Histogram {
+    counts: Vec<u64>,
+    total: u64,
+    sum_ms: u64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            counts: vec![0; TIMING_BOUNDS_MS.len()],
+            total: 0,
+            sum_ms: 0,
+        }
+    }
+}
+
+impl Histogram {
+    pub fn record(&mut self, duration: Duration) {
+        let ms = duration.as_millis() as u64;
+        for (count, bound) in self.counts.iter_mut().zip(TIMING_BOUNDS_MS) {
+            if ms <= *bound {
+                *count += 1;
+            }
+        }
+        self.total += 1;
+        self.sum_ms += ms;
+    }
+}
+
+impl Serialize for Histogram {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.counts.len() + 2))?;
+        for (count, bound) in self.counts.iter().zip(TIMING_BOUNDS_MS) {
+            map.serialize_entry(&format!("le_{}", bound), count)?;
+        }
+        map.serialize_entry("le_inf", &self.total)?;
+        map.serialize_entry("sum_ms", &self.sum_ms)?;
+        map.end()
+    }
+}
+
+/// Histograms of the timings reported in printed acks.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct JobTimings {
+    pub queued: Histogram,
+    pub render: Histogram,
+    pub write: Histogram,
+    pub total: Histogram,
+}
+
+/// Weight of the newest sample in the rolling averages
+const ROLLING_WEIGHT: f64 = 0.2;
+/// Assumed until the first job prints: roughly a receipt printer at full speed
+const DEFAULT_MS_PER_LINE: f64 = 10.0;
+const DEFAULT_LINES_PER_JOB: f64 = 30.0;
+
+/// Exponentially weighted moving average.
+#[derive(Debug, Clone, Copy)]
+pub struct Rolling {
+    value: f64,
+    samples: u64,
+}
+
+impl Rolling {
+    pub fn new(initial: f64) -> Self {
+        Self {
+            value: initial,
+            samples: 0,
+        }
+    }
+
+    pub fn record(&mut self, sample: f64) {
+        self.value = if self.samples == 0 {
+            sample
+        } else {
+            self.value + ROLLING_WEIGHT * (sample - self.value)
+        };
+        self.samples += 1;
+    }
+
+    pub fn value(&self) -> f64 {
+        self.value
+    }
+
+    pub fn samples(&self) -> u64 {
+        self.samples
+    }
+}
+
+/// How fast printed jobs have been going to the printer, for estimating how
+/// long a new one would take.
+#[derive(Debug, Clone, Copy)]
+pub struct WriteRate {
+    ms_per_line: Rolling,
+    lines_per_job: Rolling,
+}
+
+impl Default for WriteRate {
+    fn default() -> Self {
+        Self {
+            ms_per_line: Rolling::new(DEFAULT_MS_PER_LINE),
+            lines_per_job: Rolling::new(DEFAULT_LINES_PER_JOB),
+        }
+    }
+}
+
+impl WriteRate {
+    pub fn record(&mut self, lines: usize, write: Duration) {
+        if lines == 0 {
+            return;
+        }
+        self.ms_per_line
+            .record(write.as_secs_f64() * 1000.0 / lines as f64);
+        self.lines_per_job.record(lines as f64);
+    }
+
+    /// Expected write time of a typical job.
+    pub fn job_time(&self) -> Duration {
+        Duration::from_secs_f64(self.ms_per_line.value() * self.lines_per_job.value() / 1000.0)
+    }
+
+    /// Jobs the averages are based on (0 = still the defaults).
+    pub fn samples(&self) -> u64 {
+        self.ms_per_line.samples()
+    }
+
+    pub fn ms_per_line(&self) -> f64 {
+        self.ms_per_line.value()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A write rate after `jobs` jobs of `lines` lines at `ms_per_line`.
+    fn history(rate: &mut WriteRate, jobs: usize, lines: usize, ms_per_line: u64) {
+        for _ in 0..jobs {
+            rate.record(lines, Duration::from_millis(lines as u64 * ms_per_line));
+        }
+    }
+
+    fn ms(duration: Duration) -> f64 {
+        duration.as_secs_f64() * 1000.0
+    }
+
+    #[test]
+    fn histograms_count_each_timing_in_every_bucket_it_fits() {
+        let mut histogram = Histogram::default();
+        for ms in [10, 50, 51, 700, 60_000] {
+            histogram.record(Duration::from_millis(ms));
+        }
+        let json = serde_json::to_value(&histogram).unwrap();
+        assert_eq!(json["le_50"], 2);
+        assert_eq!(json["le_100"], 3);
+        assert_eq!(json["le_500"], 3);
+        assert_eq!(json["le_1000"], 4);
+        assert_eq!(json["le_30000"], 4);
+        assert_eq!(json["le_inf"], 5);
+        assert_eq!(json["sum_ms"], 60_811);
+    }
+
+    #[test]
+    fn the_first_sample_replaces_the_default() {
+        let mut rolling = Rolling::new(10.0);
+        assert_eq!((rolling.value(), rolling.samples()), (10.0, 0));
+        rolling.record(4.0);
+        assert_eq!((rolling.value(), rolling.samples()), (4.0, 1));
+        rolling.record(14.0);
+        assert_eq!(rolling.value(), 6.0);
+    }
+
+    #[test]
+    fn with_no_history_a_job_is_a_30_line_receipt_at_full_speed() {
+        let rate = WriteRate::default();
+        assert_eq!(rate.samples(), 0);
+        assert_eq!(rate.ms_per_line(), DEFAULT_MS_PER_LINE);
+        assert_eq!(rate.job_time(), Duration::from_millis(300));
+    }
+
+    #[test]
+    fn a_steady_history_gives_its_own_job_time() {
+        let mut rate = WriteRate::default();
+        history(&mut rate, 20, 40, 5);
+        assert_eq!(rate.samples(), 20);
+        assert_eq!(rate.ms_per_line(), 5.0);
+        assert!((ms(rate.job_time()) - 200.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn the_estimate_follows_a_printer_slowing_down() {
+        let mut rate = WriteRate::default();
+        history(&mut rate, 20, 40, 5);
+        let mut last = ms(rate.job_time());
+        // Paper running low, say: every line now takes four times as long
+        for _ in 0..30 {
+            history(&mut rate, 1, 40, 20);
+            let now = ms(rate.job_time());
+            assert!(now > last, "{} after {}", now, last);
+            last = now;
+        }
+        assert!((last - 800.0).abs() < 1.0, "{}", last);
+        // Recent jobs count for more than old ones: three fast jobs after
+        // fifty slow ones take it about halfway, where a plain mean would
+        // barely move
+        let mut rate = WriteRate::default();
+        history(&mut rate, 50, 40, 20);
+        history(&mut rate, 3, 40, 5);
+        assert!(
+            (rate.ms_per_line() - 12.68).abs() < 0.01,
+            "{}",
+            rate.ms_per_line()
+        );
+    }
+
+    #[test]
+    fn job_length_is_averaged_too() {
+        let mut rate = WriteRate::default();
+        history(&mut rate, 10, 10, 10);
+        assert!((ms(rate.job_time()) - 100.0).abs() < 0.01);
+        history(&mut rate, 20, 100, 10);
+        assert!(ms(rate.job_time()) > 980.0, "{:?}", rate.job_time());
+    }
+
+    #[test]
+    fn jobs_without_lines_are_left_out() {
+        let mut rate = WriteRate::default();
+        history(&mut rate, 5, 40, 5);
+        rate.record(0, Duration::from_secs(5));
+        assert_eq!(rate.samples(), 5);
+        assert_eq!(rate.ms_per_line(), 5.0);
+    }
+}
@@ -0,0 +1,235 @@
+//! Network quality for heartbeats and `status`, since a flaky kiosk Wi-Fi
+//! looks a lot like a broken printer from the server's side. Reads the
+//! interface carrying the default route from `/proc` and `/sys`, plus the
+//! transmit bitrate from `iw` on wireless ones. Everything is best effort:
+//! off Linux, or with nothing to read, there's simply nothing to report.
+
+use std::fs;
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::process::Command;
+use std::time::Duration;
+
+use serde::Serialize;
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct NetworkStats {
+    pub interface: String,
+    /// Signal level in dBm, on wireless interfaces
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signal_dbm: Option<i32>,
+    /// Transmit bitrate on wireless interfaces, link speed on wired ones
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub link_mbps: Option<f64>,
+    /// Counters since the interface came up
+    pub rx_errors: u64,
+    pub tx_errors: u64,
+    pub rx_dropped: u64,
+    pub tx_dropped: u64,
+    /// Round trip of the last WebSocket ping
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rtt_ms: Option<u64>,
+}
+
+impl NetworkStats {
+    /// One line for the log.
+    pub fn summary(&self) -> String {
+        let mut parts = vec![self.interface.clone()];
+        if let Some(dbm) = self.signal_dbm {
+            parts.push(format!("signal {} dBm", dbm));
+        }
+        if let Some(mbps) = self.link_mbps {
+            parts.push(format!("{} Mbit/s", mbps));
+        }
+        parts.push(format!(
+            "errors rx {} tx {}, dropped rx {} tx {}",
+            self.rx_errors, self.tx_errors, self.rx_dropped, self.tx_dropped
+        ));
+        if let Some(rtt) = self.rtt_ms {
+            parts.push(format!("rtt {} ms", rtt));
+        }
+        parts.join(", ")
+    }
+}
+
+/// Reads the stats of the default route's interface, with `rtt` from the
+/// last ping. `None` when there's no default route or no `/proc` to read.
+pub fn stats(rtt: Option<Duration>) -> Option<NetworkStats> {
+    let interface = default_interface()?;
+    let counter = |name: &str| {
+        fs::read_to_string(format!("/sys/class/net/{}/statistics/{}", interface, name))
+            .ok()
+            .and_then(|value| value.trim().parse().ok())
+            .unwrap_or(0)
+    };
+    let wireless = Path::new(&format!("/sys/class/net/{}/wireless", interface)).exists();
+    let (signal_dbm, link_mbps) = if wireless {
+        (signal_dbm(&interface), tx_bitrate(&interface))
+    } else {
+        // Reads as -1 or fails when there's no link
+        let speed = fs::read_to_string(format!("/sys/class/net/{}/speed", interface))
+            .ok()
+            .and_then(|speed| speed.trim().parse::<f64>().ok())
+            .filter(|speed| *speed > 0.0);
+        (None, speed)
+    };
+    Some(NetworkStats {
+        signal_dbm,
+        link_mbps,
+        rx_errors: counter("rx_errors"),
+        tx_errors: counter("tx_errors"),
+        rx_dropped: counter("rx_dropped"),
+        tx_dropped: counter("tx_dropped"),
+        rtt_ms: rtt.map(|rtt| rtt.as_millis() as u64),
+        interface,
+    })
+}
+
+/// The interface of the first default route in `/proc/net/route`.
+fn default_interface() -> Option<String> {
+    default_route().map(|(interface, _)| interface)
+}
+
+/// The gateway of the first default route, if it has one.
+pub fn default_gateway() -> Option<Ipv4Addr> {
+    default_route().and_then(|(_, gateway)| gateway)
+}
+
+fn default_route() -> Option<(String, Option<Ipv4Addr>)> {
+    parse_default_route(&fs::read_to_string("/proc/net/route").ok()?)
+}
+
+fn parse_default_route(routes: &str) -> Option<(String, Option<Ipv4Addr>)> {
+    routes.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let flags = u16::from_str_radix(fields.get(3)?, 16).ok()?;
+        // RTF_UP
+        if fields.get(1) != Some(&"00000000") || flags & 0x1 == 0 {
+            return None;
+        }
+        // In host byte order, so little-endian here
+        let gateway = u32::from_str_radix(fields.get(2)?, 16)
+            .ok()
+            .filter(|gateway| *gateway != 0)
+            .map(|gateway| Ipv4Addr::from(gateway.to_le_bytes()));
+        Some((fields[0].to_string(), gateway))
+    })
+}
+
+/// The signal level column of `/proc/net/wireless`.
+fn signal_dbm(interface: &str) -> Option<i32> {
+    parse_signal_dbm(&fs::read_to_string("/proc/net/wireless").ok()?, interface)
+}
+
+fn parse_signal_dbm(wireless: &str, interface: &str) -> Option<i32> {
+    let line = wireless
+        .lines()
+        .find(|line| line.trim_start().starts_with(&format!("{}:", interface)))?;
+    let level: f64 = line
+        .split_whitespace()
+        .nth(3)?
+        .trim_end_matches('.')
+        .parse()
+        .ok()?;
+    // Some drivers report it as an unsigned byte
+    let level = level as i32;
+    Some(if level > 0 { level - 256 } else { level })
+}
+
+/// `tx bitrate` from `iw dev <interface> link`, if `iw` is installed.
+fn tx_bitrate(interface: &str) -> Option<f64> {
+    let output = Command::new("iw")
+        .args(["dev", interface, "link"])
+        .output()
+        .ok()?;
+    parse_tx_bitrate(&String::from_utf8_lossy(&output.stdout))
+}
+
+fn parse_tx_bitrate(link: &str) -> Option<f64> {
+    link.lines().find_map(|line| {
+        line.trim()
+            .strip_prefix("tx bitrate:")?
+            .split_whitespace()
+            .next()?
+            .parse()
+            .ok()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROUTES: &str = "\
+Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT
+wlan0\t0000A8C0\t00000000\t0001\t0\t0\t600\t00FFFFFF\t0\t0\t0
+eth0\t00000000\t0101A8C0\t0002\t0\t0\t100\t00000000\t0\t0\t0
+wlan0\t00000000\t0100A8C0\t0003\t0\t0\t600\t00000000\t0\t0\t0
+";
+
+    #[test]
+    fn the_default_route_is_the_first_one_up() {
+        // eth0's default route isn't up
+        assert_eq!(
+            parse_default_route(ROUTES),
+            Some(("wlan0".to_string(), Some(Ipv4Addr::new(192, 168, 0, 1))))
+        );
+        let header = ROUTES.lines().next().unwrap();
+        assert_eq!(parse_default_route(header), None);
+        let on_link = "header\nppp0\t00000000\t00000000\t0001\t0\t0\t0\t00000000\t0\t0\t0";
+        assert_eq!(
+            parse_default_route(on_link),
+            Some(("ppp0".to_string(), None))
+        );
+    }
+
+    #[test]
+    fn signal_is_read_as_dbm() {
+        let wireless = "\
+Inter-| sta-|   Quality        |   Discarded packets               | Missed | WE
+ face | tus | link level noise |  nwid  crypt   frag  retry   misc | beacon | 22
+ wlan0: 0000   54.  -56.  -256        0      0      0      0     12        0
+ wlan1: 0000   40.  200.  0           0      0      0      0      0        0
+";
+        assert_eq!(parse_signal_dbm(wireless, "wlan0"), Some(-56));
+        // As an unsigned byte
+        assert_eq!(parse_signal_dbm(wireless, "wlan1"), Some(-56));
+        assert_eq!(parse_signal_dbm(wireless, "wlan"), None);
+        assert_eq!(parse_signal_dbm(wireless, "eth0"), None);
+    }
+
+    #[test]
+    fn the_bitrate_comes_from_iw() {
+        let link = "\
+Connected to 00:11:22:33:44:55 (on wlan0)
+\tSSID: kiosk
+\tsignal: -56 dBm
+\trx bitrate: 130.0 MBit/s MCS 15
+\ttx bitrate: 72.2 MBit/s MCS 7 short GI
+";
+        assert_eq!(parse_tx_bitrate(link), Some(72.2));
+        assert_eq!(parse_tx_bitrate("Not connected."), None);
+    }
+
+    #[test]
+    fn the_summary_leaves_out_what_is_unknown() {
+        let mut stats = NetworkStats {
+            interface: "eth0".to_string(),
+            rx_errors: 1,
+            tx_dropped: 4,
+            ..Default::default()
+        };
+        assert_eq!(stats.summary(), "eth0, errors rx 1 tx 0, dropped rx 0 tx 4");
+        stats.interface = "wlan0".to_string();
+        stats.signal_dbm = Some(-61);
+        stats.link_mbps = Some(72.2);
+        stats.rtt_ms = Some(35);
+        assert_eq!(
+            stats.summary(),
+            "wlan0, signal -61 dBm, 72.2 Mbit/s, errors rx 1 tx 0, dropped rx 0 tx 4, rtt 35 ms"
+        );
+        let json = serde_json::to_value(NetworkStats::default()).unwrap();
+        assert!(json.get("signal_dbm").is_none());
+        assert_eq!(json["rx_errors"], 0);
+    }
+}
@@ -0,0 +1,379 @@
+//! Every outbound frame goes through the outbox and is sent by a single
+//! writer task, so frames reach the server in the order they were queued.
+//! Each transmitted frame is stamped with the next sequence number; the
+//! counter runs for the life of the process, so the server can spot lost
+//! frames as gaps, across reconnects too. With several printers, each
+//! frame names the printer it's from.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use futures_util::{Sink, SinkExt};
+use log::{debug, warn};
+use tokio::sync::Notify;
+use tokio_tungstenite::tungstenite::protocol::Message;
+
+use crate::protocol::{AckStatus, Outbound};
+use crate::signing::Signer;
+
+pub struct Outbox {
+    state: Mutex<State>,
+    /// Wakes the writer when a frame is queued
+    queued: Notify,
+}
+
+struct State {
+    /// Frames not yet sent, each with the number it was queued under
+    frames: VecDeque<Entry>,
+    capacity: usize,
+    queued_total: u64,
+    /// Frames dropped because the outbox was full
+    dropped: u64,
+    /// Sequence number of the next frame sent
+    next_seq: u64,
+    /// Payload of a WebSocket ping to send ahead of the next frame
+    ping: Option<Vec<u8>>,
+}
+
+struct Entry {
+    queued: u64,
+    /// The printer the frame is from, when there are several
+    target: Option<Arc<str>>,
+    frame: Outbound,
+}
+
+impl Outbox {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            state: Mutex::new(State {
+                frames: VecDeque::new(),
+                capacity: capacity.max(1),
+                queued_total: 0,
+                dropped: 0,
+                next_seq: 1,
+                ping: None,
+            }),
+            queued: Notify::new(),
+        }
+    }
+
+    /// Queues a frame from printer `target` behind everything already
+    /// waiting, dropping the oldest frame if the outbox is full.
+    pub fn push_from(&self, target: Option<Arc<str>>, frame: Outbound) {
+        self.insert(target, frame, false);
+    }
+
+    /// Queues a frame ahead of everything already waiting. Used for the hello,
+    /// which has to open every connection.
+    pub fn push_front(&self, frame: Outbound) {
+        self.insert(None, frame, true);
+    }
+
+    fn insert(&self, target: Option<Arc<str>>, frame: Outbound, front: bool) {
+        let mut state = self.state.lock().unwrap();
+        if state.frames.len() >= state.capacity {
+            state.frames.pop_front();
+            state.dropped += 1;
+            warn!(
+                "Outbox full, dropped oldest undelivered frame ({} dropped so far)",
+                state.dropped
+            );
+        }
+        state.queued_total += 1;
+        let entry = Entry {
+            queued: state.queued_total,
+            target,
+            frame,
+        };
+        if front {
+            state.frames.push_front(entry);
+        } else {
+            state.frames.push_back(entry);
+        }
+        drop(state);
+        self.queued.notify_one();
+    }
+
+    /// Has the writer send a WebSocket ping with `payload` before any other
+    /// frame. Pings aren't numbered and aren't resent after a reconnect.
+    pub fn ping(&self, payload: Vec<u8>) {
+        self.state.lock().unwrap().ping = Some(payload);
+        self.queued.notify_one();
+    }
+
+    /// Drops unsent frames that only mean something on the connection they
+    /// were queued for: hellos, heartbeats, `printing` acks and `progress`,
+    /// and the ping.
+    pub fn discard_live(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.ping = None;
+        state.frames.retain(|entry| {
+            !matches!(
+                entry.frame,
+                Outbound::Hello { .. }
+                    | Outbound::Heartbeat { .. }
+                    | Outbound::Progress { .. }
+                    | Outbound::Ack {
+                        status: AckStatus::Printing,
+                        ..
+                    }
+            )
+        });
+    }
+
+    /// Frames waiting to be sent.
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.state.lock().unwrap().dropped
+    }
+
+    /// Sends queued frames in order until sending fails, waiting for more
+    /// when the outbox is empty. A frame leaves the outbox only once it has
+    /// been sent, so whatever is left is resent on the next connection.
+    pub async fn write_to<S>(&self, sink: &mut S, signer: Option<&Signer>) -> Result<(), S::Error>
+    where
+        S: Sink<Message> + Unpin,
+    {
+        loop {
+            let ping = self.state.lock().unwrap().ping.take();
+            if let Some(payload) = ping {
+                sink.send(Message::Ping(payload.into())).await?;
+                continue;
+            }
+            let front = {
+                let state = self.state.lock().unwrap();
+                state.frames.front().map(|entry| {
+                    (
+                        entry.queued,
+                        entry.target.clone(),
+                        entry.frame.clone(),
+                        state.next_seq,
+                    )
+                })
+            };
+            let Some((queued, target, frame, seq)) = front else {
+                self.queued.notified().await;
+                continue;
+            };
+
+            let text = frame.encode(seq, target.as_deref(), signer);
+            sink.send(Message::text(text)).await?;
+            debug!("Sent #{} {}", seq, frame.describe());
+
+            let mut state = self.state.lock().unwrap();
+            state.next_seq += 1;
+            // The frame may have been dropped for space while it was being sent
+            if state
+                .frames
+                .front()
+                .is_some_and(|entry| entry.queued == queued)
+            {
+                state.frames.pop_front();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use std::time::Duration;
+
+    use serde_json::Value;
+
+    use super::*;
+
+    /// Keeps what's sent to it, failing once it's taken `capacity` messages.
+    struct Connection {
+        sent: Vec<Message>,
+        capacity: usize,
+    }
+
+    impl Connection {
+        fn new(capacity: usize) -> Self {
+            Self {
+                sent: Vec::new(),
+                capacity,
+            }
+        }
+
+        /// The text frames sent, parsed.
+        fn frames(&self) -> Vec<Value> {
+            self.sent
+                .iter()
+                .filter_map(|message| match message {
+                    Message::Text(text) => Some(serde_json::from_str(text).unwrap()),
+                    _ => None,
+                })
+                .collect()
+        }
+    }
+
+    impl Sink<Message> for Connection {
+        type Error = &'static str;
+
+        fn poll_ready(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn start_send(mut self: Pin<&mut Self>, message: Message) -> Result<(), Self::Error> {
+            if self.sent.len() >= self.capacity {
+                return Err("connection lost");
+            }
+            self.sent.push(message);
+            Ok(())
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn ack(id: &str) -> Outbound {
+        Outbound::ack(Some(id.to_string()), AckStatus::Printed)
+    }
+
+    /// Writes to `connection` until it fails or the outbox has been empty
+    /// for a moment.
+    async fn write(outbox: &Outbox, connection: &mut Connection) -> Option<&'static str> {
+        tokio::time::timeout(Duration::from_millis(50), outbox.write_to(connection, None))
+            .await
+            .ok()
+            .map(|result| result.unwrap_err())
+    }
+
+    fn fields(frames: &[Value], name: &str) -> Vec<Value> {
+        frames.iter().map(|frame| frame[name].clone()).collect()
+    }
+
+    #[tokio::test]
+    async fn frames_go_out_in_order_numbered_from_one() {
+        let outbox = Outbox::new(10);
+        outbox.push_from(None, ack("a"));
+        outbox.push_from(Some("bar".into()), ack("b"));
+        outbox.push_front(ack("hello"));
+        assert_eq!(outbox.len(), 3);
+        let mut connection = Connection::new(100);
+        assert_eq!(write(&outbox, &mut connection).await, None);
+        let frames = connection.frames();
+        assert_eq!(fields(&frames, "id"), ["hello", "a", "b"]);
+        assert_eq!(fields(&frames, "seq"), [1, 2, 3]);
+        assert_eq!(
+            fields(&frames, "target"),
+            [Value::Null, Value::Null, "bar".into()]
+        );
+        assert!(outbox.is_empty());
+    }
+
+    #[tokio::test]
+    async fn unsent_frames_are_resent_and_numbering_carries_on() {
+        let outbox = Outbox::new(10);
+        for id in ["a", "b", "c"] {
+            outbox.push_from(None, ack(id));
+        }
+        let mut dropped = Connection::new(1);
+        assert_eq!(write(&outbox, &mut dropped).await, Some("connection lost"));
+        assert_eq!(fields(&dropped.frames(), "id"), ["a"]);
+        assert_eq!(outbox.len(), 2);
+
+        let mut reconnected = Connection::new(100);
+        assert_eq!(write(&outbox, &mut reconnected).await, None);
+        let frames = reconnected.frames();
+        assert_eq!(fields(&frames, "id"), ["b", "c"]);
+        assert_eq!(fields(&frames, "seq"), [2, 3]);
+    }
+
+    #[tokio::test]
+    async fn a_writer_waits_for_frames_queued_later() {
+        let outbox = Arc::new(Outbox::new(10));
+        let writer = tokio::spawn({
+            let outbox = Arc::clone(&outbox);
+            async move {
+                let mut connection = Connection::new(1);
+                let _ = outbox.write_to(&mut connection, None).await;
+                connection.frames()
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        outbox.push_from(None, ack("late"));
+        outbox.push_from(None, ack("later"));
+        let frames = tokio::time::timeout(Duration::from_secs(5), writer)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(fields(&frames, "id"), ["late"]);
+    }
+
+    #[test]
+    fn a_full_outbox_drops_the_oldest_frame() {
+        let outbox = Outbox::new(2);
+        for id in ["a", "b", "c", "d"] {
+            outbox.push_from(None, ack(id));
+        }
+        assert_eq!(outbox.len(), 2);
+        assert_eq!(outbox.dropped(), 2);
+        let ids: Vec<_> = outbox
+            .state
+            .lock()
+            .unwrap()
+            .frames
+            .iter()
+            .map(|entry| entry.frame.describe())
+            .collect();
+        assert_eq!(
+            ids,
+            [
+                r#"Printed ack for job Some("c")"#,
+                r#"Printed ack for job Some("d")"#
+            ]
+        );
+        // Never less than one
+        assert_eq!(Outbox::new(0).state.lock().unwrap().capacity, 1);
+    }
+
+    #[tokio::test]
+    async fn pings_go_ahead_of_frames_and_only_live_frames_are_discarded() {
+        let outbox = Outbox::new(10);
+        outbox.push_from(None, ack("kept"));
+        outbox.push_from(
+            None,
+            Outbound::ack(Some("p".to_string()), AckStatus::Printing),
+        );
+        outbox.push_from(
+            None,
+            Outbound::Progress {
+                id: Some("p".to_string()),
+                percent: 50,
+            },
+        );
+        outbox.push_from(
+            None,
+            Outbound::ack(Some("q".to_string()), AckStatus::Accepted),
+        );
+        outbox.ping(b"beat".to_vec());
+        outbox.discard_live();
+        assert_eq!(outbox.len(), 2);
+
+        outbox.ping(b"beat".to_vec());
+        let mut connection = Connection::new(100);
+        write(&outbox, &mut connection).await;
+        assert!(matches!(&connection.sent[0], Message::Ping(payload) if payload[..] == *b"beat"));
+        let frames = connection.frames();
+        assert_eq!(fields(&frames, "id"), ["kept", "q"]);
+        assert_eq!(fields(&frames, "status"), ["printed", "accepted"]);
+        // Pings take no sequence number
+        assert_eq!(fields(&frames, "seq"), [1, 2]);
+    }
+}
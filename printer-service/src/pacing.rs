@@ -0,0 +1,305 @@
+//! Size check run on every rendered job before any of it is sent. Some
+//! printers silently drop data once their receive buffer overflows, which
+//! shows up as a half-printed logo rather than an error. From the profile's
+//! buffer size and throughput, a job that would overflow the buffer is sent
+//! in chunks paced to the printer's speed. A job that can't be paced, or that
+//! would take longer to print than the profile allows, fails instead.
+
+use std::borrow::Cow;
+use std::time::Duration;
+
+use crate::profile::PrinterProfile;
+use crate::protocol::{Preflight, PreflightDecision};
+
+/// What the check decided for one job.
+pub struct Plan {
+    pub bytes: usize,
+    /// Print time at the profile's throughput, if it has one
+    pub estimate: Option<Duration>,
+    pub decision: Decision,
+}
+
+pub enum Decision {
+    /// Sent as the profile says
+    Send,
+    /// Over the receive buffer, so sent in buffer-sized chunks with a pause
+    /// for each to print
+    Paced { chunk_size: usize, delay: Duration },
+    /// Not sent, with the reason
+    Refused(String),
+}
+
+/// Checks a job of `bytes` against `profile`'s figures.
+pub fn plan(bytes: usize, profile: &PrinterProfile) -> Plan {
+    let estimate = (profile.throughput > 0).then(|| print_time(bytes, profile.throughput));
+    let decision = decide(bytes, estimate, profile);
+    Plan {
+        bytes,
+        estimate,
+        decision,
+    }
+}
+
+fn decide(bytes: usize, estimate: Option<Duration>, profile: &PrinterProfile) -> Decision {
+    if let Some(estimate) = estimate
+        && !profile.max_print_time.is_zero()
+        && estimate > profile.max_print_time
+    {
+        return Decision::Refused(format!(
+            "Job is {} bytes, about {:.1}s to print, over the profile's limit of {:?}",
+            bytes,
+            estimate.as_secs_f64(),
+            profile.max_print_time
+        ));
+    }
+
+    let buffer = profile.receive_buffer;
+    if buffer == 0 || bytes <= buffer {
+        return Decision::Send;
+    }
+    // The profile's own chunking already keeps each write within the buffer
+    if (1..=buffer).contains(&profile.chunk_size) && !profile.inter_chunk_delay.is_zero() {
+        return Decision::Send;
+    }
+    if profile.throughput == 0 {
+        return Decision::Refused(format!(
+            "Job is {} bytes, over the printer's {} byte receive buffer, and the profile has no throughput to pace it by",
+            bytes, buffer
+        ));
+    }
+    // Half the buffer at a time, each given the time it takes to print, so
+    // the buffer never holds more than two chunks
+    let chunk_size = (buffer / 2).max(1);
+    Decision::Paced {
+        chunk_size,
+        delay: print_time(chunk_size, profile.throughput),
+    }
+}
+
+fn print_time(bytes: usize, throughput: usize) -> Duration {
+    Duration::from_secs_f64(bytes as f64 / throughput as f64)
+}
+
+impl Plan {
+    /// One line for the log.
+    pub fn summary(&self) -> String {
+        let estimate = match self.estimate {
+            Some(estimate) => format!(", about {:.1}s to print", estimate.as_secs_f64()),
+            None => String::new(),
+        };
+        match &self.decision {
+            Decision::Send => format!("{} bytes{}; sending", self.bytes, estimate),
+            Decision::Paced { chunk_size, delay } => format!(
+                "{} bytes{}, over the receive buffer; sending {} byte chunks every {:?}",
+                self.bytes, estimate, chunk_size, delay
+            ),
+            // The reason already gives the size
+            Decision::Refused(message) => format!("refused: {}", message),
+        }
+    }
+
+    /// The profile to write the job with.
+    pub fn profile<'a>(&self, profile: &'a PrinterProfile) -> Cow<'a, PrinterProfile> {
+        match self.decision {
+            Decision::Paced { chunk_size, delay } => Cow::Owned(PrinterProfile {
+                chunk_size,
+                inter_chunk_delay: delay,
+                ..profile.clone()
+            }),
+            _ => Cow::Borrowed(profile),
+        }
+    }
+
+    /// What goes in the ack.
+    pub fn preflight(&self) -> Preflight {
+        Preflight {
+            bytes: self.bytes,
+            estimated_ms: self.estimate.map(|estimate| estimate.as_millis() as u64),
+            decision: match self.decision {
+                Decision::Send => PreflightDecision::Send,
+                Decision::Paced { .. } => PreflightDecision::Paced,
+                Decision::Refused(_) => PreflightDecision::Refused,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::Instant;
+
+    use escpos::driver::Driver;
+
+    use super::*;
+    use crate::driver;
+    use crate::render::Rendered;
+
+    const CUT: [u8; 3] = [0x1D, b'V', 0];
+
+    fn profile(receive_buffer: usize, throughput: usize) -> PrinterProfile {
+        PrinterProfile {
+            chunk_size: 0,
+            inter_chunk_delay: Duration::ZERO,
+            receive_buffer,
+            throughput,
+            max_print_time: Duration::ZERO,
+            ..PrinterProfile::find("default").unwrap().clone()
+        }
+    }
+
+    /// When each write came, and what it was
+    type Writes = Arc<Mutex<Vec<(Instant, Vec<u8>)>>>;
+
+    /// A printer with a small receive buffer that prints `throughput` bytes a
+    /// second, over a link that takes a while with each write.
+    #[derive(Clone)]
+    struct SlowPrinter {
+        writes: Writes,
+    }
+
+    impl SlowPrinter {
+        const LATENCY: Duration = Duration::from_millis(2);
+
+        fn new() -> Self {
+            Self {
+                writes: Arc::new(Mutex::new(Vec::new())),
+            }
+        }
+    }
+
+    impl Driver for SlowPrinter {
+        fn name(&self) -> String {
+            "slow".to_string()
+        }
+
+        fn write(&self, data: &[u8]) -> escpos::errors::Result<()> {
+            self.writes
+                .lock()
+                .unwrap()
+                .push((Instant::now(), data.to_vec()));
+            std::thread::sleep(Self::LATENCY);
+            Ok(())
+        }
+
+        fn read(&self, _buf: &mut [u8]) -> escpos::errors::Result<usize> {
+            Ok(0)
+        }
+
+        fn flush(&self) -> escpos::errors::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn job(len: usize) -> Rendered {
+        let mut bytes: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+        let cut_offset = bytes.len();
+        bytes.extend_from_slice(&CUT);
+        Rendered {
+            bytes,
+            cut_offset,
+            lines: 0,
+        }
+    }
+
+    #[test]
+    fn jobs_that_fit_the_buffer_are_sent_as_they_are() {
+        let profile = profile(1000, 20_000);
+        let plan = plan(1000, &profile);
+        assert!(matches!(plan.decision, Decision::Send));
+        assert_eq!(plan.estimate, Some(Duration::from_millis(50)));
+        assert_eq!(plan.profile(&profile).chunk_size, 0);
+
+        // Nothing known about the printer, nothing checked
+        let plan = super::plan(1_000_000, &self::profile(0, 0));
+        assert!(matches!(plan.decision, Decision::Send));
+        assert_eq!(plan.estimate, None);
+    }
+
+    #[test]
+    fn jobs_over_the_buffer_are_paced_to_the_printer() {
+        let profile = profile(1000, 20_000);
+        let plan = plan(1001, &profile);
+        let Decision::Paced { chunk_size, delay } = plan.decision else {
+            panic!("{}", plan.summary());
+        };
+        assert_eq!(chunk_size, 500);
+        assert_eq!(delay, Duration::from_millis(25));
+        let paced = plan.profile(&profile);
+        assert_eq!(paced.chunk_size, 500);
+        assert_eq!(paced.inter_chunk_delay, delay);
+
+        // Chunks the profile already keeps within the buffer will do
+        let chunked = PrinterProfile {
+            chunk_size: 1000,
+            inter_chunk_delay: Duration::from_millis(10),
+            ..profile
+        };
+        assert!(matches!(
+            super::plan(5000, &chunked).decision,
+            Decision::Send
+        ));
+    }
+
+    #[test]
+    fn jobs_that_cant_be_paced_or_take_too_long_are_refused() {
+        let plan = plan(1001, &profile(1000, 0));
+        assert!(matches!(plan.decision, Decision::Refused(_)));
+        assert_eq!(plan.preflight().decision, PreflightDecision::Refused);
+
+        let slow = PrinterProfile {
+            max_print_time: Duration::from_secs(1),
+            ..profile(0, 1000)
+        };
+        assert!(matches!(super::plan(1000, &slow).decision, Decision::Send));
+        let Decision::Refused(message) = super::plan(1001, &slow).decision else {
+            panic!("a job over the time limit was sent");
+        };
+        assert!(message.contains("1001 bytes"), "{}", message);
+    }
+
+    #[test]
+    fn paced_jobs_never_outrun_the_printer() {
+        let (buffer, throughput) = (1000, 20_000);
+        let profile = profile(buffer, throughput);
+        let job = job(10_000);
+        let plan = plan(job.bytes.len(), &profile);
+        assert!(matches!(plan.decision, Decision::Paced { .. }));
+
+        let printer = SlowPrinter::new();
+        let stats = driver::write_job(&printer, &job, &plan.profile(&profile)).unwrap();
+        let writes = printer.writes.lock().unwrap();
+        assert_eq!(stats.writes, writes.len());
+        // The cut rides along with the last chunk
+        assert_eq!(writes.len(), 10_000 / 500);
+
+        // Everything arrives once, in order, with the cut in the last write
+        let sent: Vec<u8> = writes.iter().flat_map(|(_, data)| data.clone()).collect();
+        assert_eq!(sent, job.bytes);
+        assert!(writes.last().unwrap().1.ends_with(&CUT));
+        for (_, data) in &writes[..writes.len() - 1] {
+            assert!(data.len() <= 500);
+        }
+
+        // What's waiting in the printer, with it printing since the first
+        // write, never goes over its buffer
+        let start = writes[0].0;
+        let mut received = 0;
+        for (at, data) in writes.iter() {
+            received += data.len();
+            let printed = at.duration_since(start).as_secs_f64() * throughput as f64;
+            let waiting = received as f64 - printed;
+            assert!(
+                waiting <= buffer as f64,
+                "{} bytes waiting after {:?}",
+                waiting,
+                at.duration_since(start)
+            );
+        }
+        // So the whole job took at least as long as printing all but the
+        // last chunk
+        let elapsed = writes.last().unwrap().0.duration_since(start);
+        let rate = (received - writes.last().unwrap().1.len()) as f64 / elapsed.as_secs_f64();
+        assert!(rate <= throughput as f64, "{} bytes a second", rate);
+    }
+}
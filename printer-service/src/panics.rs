@@ -0,0 +1,109 @@
+//! Panics while printing a job are logged with the job's id and caught by
+//! the job loop, which resets the printer, fails and dead-letters the job,
+//! then carries on or exits as `--panic` says.
+
+use std::any::Any;
+use std::cell::RefCell;
+
+use log::error;
+
+thread_local! {
+    /// Id of the job this thread is working on, for the panic log
+    static CURRENT_JOB: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// What to do after a job panics.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PanicPolicy {
+    /// Fail the job and go on with the next one
+    #[default]
+    Continue,
+    /// Fail the job, reset the printer and exit
+    Abort,
+}
+
+/// Logs panics through the logger, naming the job being worked on.
+pub fn install_hook() {
+    std::panic::set_hook(Box::new(|info| {
+        let job = CURRENT_JOB.with(|job| job.borrow().clone());
+        let location = info
+            .location()
+            .map_or(String::new(), |at| format!(" at {}", at));
+        error!(
+            "PANIC while working on job {}{}: {}",
+            job.as_deref().unwrap_or("(none)"),
+            location,
+            message(info.payload())
+        );
+    }));
+}
+
+/// Marks this thread as working on a job until the returned guard drops.
+pub fn working_on(id: Option<&str>) -> JobScope {
+    let id = id.map_or("(no id)".to_string(), str::to_string);
+    CURRENT_JOB.with(|job| *job.borrow_mut() = Some(id));
+    JobScope
+}
+
+pub struct JobScope;
+
+impl Drop for JobScope {
+    fn drop(&mut self) {
+        CURRENT_JOB.with(|job| *job.borrow_mut() = None);
+    }
+}
+
+/// The message a panic was raised with.
+pub fn message(payload: &(dyn Any + Send)) -> String {
+    if let Some(text) = payload.downcast_ref::<&str>() {
+        text.to_string()
+    } else if let Some(text) = payload.downcast_ref::<String>() {
+        text.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::panic::catch_unwind;
+
+    use super::*;
+
+    fn current() -> Option<String> {
+        CURRENT_JOB.with(|job| job.borrow().clone())
+    }
+
+    #[test]
+    fn the_job_is_named_until_its_scope_ends() {
+        assert_eq!(current(), None);
+        {
+            let _scope = working_on(Some("order-7"));
+            assert_eq!(current().as_deref(), Some("order-7"));
+        }
+        assert_eq!(current(), None);
+        let scope = working_on(None);
+        assert_eq!(current().as_deref(), Some("(no id)"));
+        drop(scope);
+        assert_eq!(current(), None);
+    }
+
+    #[test]
+    fn jobs_on_other_threads_are_their_own() {
+        let _scope = working_on(Some("here"));
+        std::thread::spawn(|| assert_eq!(current(), None))
+            .join()
+            .unwrap();
+        assert_eq!(current().as_deref(), Some("here"));
+    }
+
+    #[test]
+    fn panic_messages_are_recovered() {
+        let literal = catch_unwind(|| panic!("out of paper")).unwrap_err();
+        assert_eq!(message(&*literal), "out of paper");
+        let formatted = catch_unwind(|| panic!("job {} broke", 7)).unwrap_err();
+        assert_eq!(message(&*formatted), "job 7 broke");
+        let other = catch_unwind(|| std::panic::panic_any(42)).unwrap_err();
+        assert_eq!(message(&*other), "unknown panic");
+    }
+}
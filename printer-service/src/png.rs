@@ -0,0 +1,226 @@
+//! Writes 1-bit PNGs a band of rows at a time, so an image is never held
+//! whole. The pixels are stored uncompressed: these are previews of tickets
+//! for checking by eye, not files to keep.
+
+use std::io::{self, Write};
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+/// Most bytes one stored deflate block holds
+const STORED_BLOCK: usize = 0xFFFF;
+const ADLER_MOD: u32 = 65521;
+
+/// A PNG being written. Rows are 1 bit a dot with 1 meaning black, as in
+/// printer raster data; rows narrower than the image are padded with white.
+pub struct Png<W: Write> {
+    out: W,
+    row_bytes: usize,
+    rows_left: usize,
+    adler: (u32, u32),
+}
+
+impl<W: Write> Png<W> {
+    /// Writes the header of a `width` by `height` dot image.
+    pub fn start(mut out: W, width: usize, height: usize) -> io::Result<Self> {
+        let too_big = |_| io::Error::new(io::ErrorKind::InvalidInput, "Image is too big for a PNG");
+        let mut header = Vec::with_capacity(13);
+        header.extend_from_slice(&u32::try_from(width).map_err(too_big)?.to_be_bytes());
+        header.extend_from_slice(&u32::try_from(height).map_err(too_big)?.to_be_bytes());
+        // 1 bit greyscale, deflate, no interlacing
+        header.extend_from_slice(&[1, 0, 0, 0, 0]);
+        out.write_all(&SIGNATURE)?;
+        chunk(&mut out, b"IHDR", &header)?;
+        // The zlib header: deflate, no dictionary
+        chunk(&mut out, b"IDAT", &[0x78, 0x01])?;
+        Ok(Self {
+            out,
+            row_bytes: width.div_ceil(8),
+            rows_left: height,
+            adler: (1, 0),
+        })
+    }
+
+    /// Writes the next rows as one chunk. Rows past the image's height are
+    /// left out.
+    pub fn band(&mut self, rows: &[&[u8]]) -> io::Result<()> {
+        let rows = &rows[..rows.len().min(self.rows_left)];
+        self.rows_left -= rows.len();
+        // Each line is a filter byte then the row
+        let line = 1 + self.row_bytes;
+        let per_block = (STORED_BLOCK / line).max(1);
+        let blocks = rows.len().div_ceil(per_block);
+        let len = rows.len() * line + blocks * 5;
+        let mut writer = ChunkWriter::start(&mut self.out, b"IDAT", len)?;
+        let mut scratch = vec![0; line];
+        for block in rows.chunks(per_block) {
+            let block_len = (block.len() * line) as u16;
+            writer.write(&[0])?;
+            writer.write(&block_len.to_le_bytes())?;
+            writer.write(&(!block_len).to_le_bytes())?;
+            for row in block {
+                // PNG greyscale 1 is white
+                scratch.fill(0xFF);
+                scratch[0] = 0;
+                for (to, from) in scratch[1..].iter_mut().zip(row.iter()) {
+                    *to = !from;
+                }
+                self.adler = adler32(self.adler, &scratch);
+                writer.write(&scratch)?;
+            }
+        }
+        writer.finish()
+    }
+
+    /// Ends the image, with white rows for any not written.
+    pub fn finish(mut self) -> io::Result<W> {
+        let blank = vec![0; self.row_bytes];
+        while self.rows_left > 0 {
+            let rows = vec![blank.as_slice(); self.rows_left.min(256)];
+            self.band(&rows)?;
+        }
+        let (a, b) = self.adler;
+        let mut end = vec![1, 0, 0, 0xFF, 0xFF];
+        end.extend_from_slice(&((b << 16) | a).to_be_bytes());
+        chunk(&mut self.out, b"IDAT", &end)?;
+        chunk(&mut self.out, b"IEND", &[])?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+/// Writes a chunk whose data is written piecemeal.
+struct ChunkWriter<'w, W: Write> {
+    out: &'w mut W,
+    crc: crc32fast::Hasher,
+}
+
+impl<'w, W: Write> ChunkWriter<'w, W> {
+    fn start(out: &'w mut W, kind: &[u8; 4], len: usize) -> io::Result<Self> {
+        out.write_all(&(len as u32).to_be_bytes())?;
+        out.write_all(kind)?;
+        let mut crc = crc32fast::Hasher::new();
+        crc.update(kind);
+        Ok(Self { out, crc })
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.crc.update(data);
+        self.out.write_all(data)
+    }
+
+    fn finish(self) -> io::Result<()> {
+        self.out.write_all(&self.crc.finalize().to_be_bytes())
+    }
+}
+
+fn chunk<W: Write>(out: &mut W, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    let mut writer = ChunkWriter::start(out, kind, data.len())?;
+    writer.write(data)?;
+    writer.finish()
+}
+
+fn adler32((mut a, mut b): (u32, u32), data: &[u8]) -> (u32, u32) {
+    for &byte in data {
+        a = (a + byte as u32) % ADLER_MOD;
+        b = (b + a) % ADLER_MOD;
+    }
+    (a, b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The chunks of a PNG, checking each one's CRC.
+    fn chunks(png: &[u8]) -> Vec<([u8; 4], Vec<u8>)> {
+        assert_eq!(png[..8], SIGNATURE);
+        let mut chunks = Vec::new();
+        let mut rest = &png[8..];
+        while !rest.is_empty() {
+            let len = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+            let kind: [u8; 4] = rest[4..8].try_into().unwrap();
+            let data = rest[8..8 + len].to_vec();
+            let crc = u32::from_be_bytes(rest[8 + len..12 + len].try_into().unwrap());
+            assert_eq!(crc, crc32fast::hash(&rest[4..8 + len]), "{:?}", kind);
+            chunks.push((kind, data));
+            rest = &rest[12 + len..];
+        }
+        chunks
+    }
+
+    /// Inflates a zlib stream of stored blocks, checking its Adler-32.
+    fn inflate_stored(stream: &[u8]) -> Vec<u8> {
+        assert_eq!((stream[0] as u16 * 256 + stream[1] as u16) % 31, 0);
+        let mut out = Vec::new();
+        let mut rest = &stream[2..];
+        loop {
+            let last = rest[0] & 1 == 1;
+            assert_eq!(rest[0] >> 1, 0, "not a stored block");
+            let len = u16::from_le_bytes([rest[1], rest[2]]);
+            assert_eq!(!len, u16::from_le_bytes([rest[3], rest[4]]));
+            out.extend_from_slice(&rest[5..5 + len as usize]);
+            rest = &rest[5 + len as usize..];
+            if last {
+                break;
+            }
+        }
+        let (a, b) = adler32((1, 0), &out);
+        assert_eq!(rest, ((b << 16) | a).to_be_bytes());
+        out
+    }
+
+    fn decode(png: &[u8]) -> (u32, u32, Vec<u8>) {
+        let chunks = chunks(png);
+        let (kind, header) = &chunks[0];
+        assert_eq!(kind, b"IHDR");
+        assert_eq!(header[8..], [1, 0, 0, 0, 0]);
+        assert_eq!(chunks.last().unwrap().0, *b"IEND");
+        let stream: Vec<u8> = chunks
+            .iter()
+            .filter(|(kind, _)| kind == b"IDAT")
+            .flat_map(|(_, data)| data.clone())
+            .collect();
+        let width = u32::from_be_bytes(header[..4].try_into().unwrap());
+        let height = u32::from_be_bytes(header[4..8].try_into().unwrap());
+        (width, height, inflate_stored(&stream))
+    }
+
+    #[test]
+    fn rows_are_written_band_by_band() {
+        let mut png = Png::start(Vec::new(), 12, 3).unwrap();
+        png.band(&[&[0xF0, 0x10], &[0x00, 0x00]]).unwrap();
+        png.band(&[&[0xFF]]).unwrap();
+        let (width, height, lines) = decode(&png.finish().unwrap());
+        assert_eq!((width, height), (12, 3));
+        // A filter byte then the row inverted; the short row is padded white
+        assert_eq!(lines, [0, 0x0F, 0xEF, 0, 0xFF, 0xFF, 0, 0x00, 0xFF]);
+    }
+
+    #[test]
+    fn missing_rows_are_white_and_extra_ones_dropped() {
+        let png = Png::start(Vec::new(), 8, 2).unwrap();
+        let (_, _, lines) = decode(&png.finish().unwrap());
+        assert_eq!(lines, [0, 0xFF, 0, 0xFF]);
+
+        let mut png = Png::start(Vec::new(), 8, 1).unwrap();
+        png.band(&[&[0x01], &[0x02]]).unwrap();
+        let (_, _, lines) = decode(&png.finish().unwrap());
+        assert_eq!(lines, [0, 0xFE]);
+    }
+
+    #[test]
+    fn bands_over_a_stored_block_are_split() {
+        // 576 dots is 73 bytes a line, so 897 lines a block
+        let row = [0xAA; 72];
+        let rows = vec![&row[..]; 2000];
+        let mut png = Png::start(Vec::new(), 576, rows.len()).unwrap();
+        png.band(&rows).unwrap();
+        let (_, height, lines) = decode(&png.finish().unwrap());
+        assert_eq!(height, 2000);
+        assert_eq!(lines.len(), 2000 * 73);
+        assert!(
+            lines
+                .chunks(73)
+                .all(|line| line[0] == 0 && line[1..] == [0x55; 72])
+        );
+    }
+}
@@ -0,0 +1,200 @@
+//! Startup wait for the network. On boot the service can start before Wi-Fi
+//! is associated; rather than burning its first connect attempts (and, for a
+//! network printer, failing to open it), it waits until the server's
+//! hostname resolves and accepts a TCP connection, and the printer (with
+//! `--ip`) does too.
+
+use std::time::Duration;
+
+use anyhow::{Context, Result, bail};
+use log::{info, warn};
+use tokio::net::{TcpStream, lookup_host};
+use tokio::time::Instant;
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(2);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+/// How often a still-missing host is logged again
+const LOG_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Waits up to `timeout` for the server at `url`, and `printer` if given, to
+/// be reachable. Gives up on the server with a warning, as the connect loop
+/// retries anyway, but fails if the printer still can't be reached.
+pub async fn wait_for_network(
+    url: &str,
+    printer: Option<(&str, u16)>,
+    timeout: Duration,
+) -> Result<()> {
+    let url = url::Url::parse(url).with_context(|| format!("Invalid URL {:?}", url))?;
+    let host = url.host_str().context("URL has no host")?.to_string();
+    let port = url.port_or_known_default().context("URL has no port")?;
+
+    let started = Instant::now();
+    let deadline = started + timeout;
+    let mut logged_at: Option<Instant> = None;
+    loop {
+        let server = reachable(&host, port).await.err();
+        let printer_missing = match printer {
+            Some((ip, printer_port)) => reachable(ip, printer_port).await.err(),
+            None => None,
+        };
+        if server.is_none() && printer_missing.is_none() {
+            if logged_at.is_some() {
+                info!("Network is up after {:?}", started.elapsed());
+            }
+            return Ok(());
+        }
+
+        let now = Instant::now();
+        if now >= deadline {
+            if let (Some(e), Some((ip, printer_port))) = (printer_missing, printer) {
+                bail!(
+                    "Printer {}:{} still unreachable after {:?}: {}",
+                    ip,
+                    printer_port,
+                    timeout,
+                    e
+                );
+            }
+            if let Some(e) = server {
+                warn!(
+                    "Server {}:{} still unreachable after {:?}, carrying on: {}",
+                    host, port, timeout, e
+                );
+            }
+            return Ok(());
+        }
+        if logged_at.is_none_or(|at| now - at >= LOG_INTERVAL) {
+            if let Some(e) = &server {
+                info!(
+                    "Waiting for network: server {}:{} unreachable: {}",
+                    host, port, e
+                );
+            }
+            if let (Some(e), Some((ip, printer_port))) = (&printer_missing, printer) {
+                info!(
+                    "Waiting for network: printer {}:{} unreachable: {}",
+                    ip, printer_port, e
+                );
+            }
+            logged_at = Some(now);
+        }
+        tokio::time::sleep(CHECK_INTERVAL.min(deadline - now)).await;
+    }
+}
+
+/// Resolves `host` and opens (then drops) a TCP connection to it.
+async fn reachable(host: &str, port: u16) -> Result<()> {
+    let addrs: Vec<_> = lookup_host((host, port))
+        .await
+        .with_context(|| format!("Failed to resolve {}", host))?
+        .collect();
+    let mut last_error = None;
+    for addr in addrs {
+        match tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(addr)).await {
+            Ok(Ok(_)) => return Ok(()),
+            Ok(Err(e)) => last_error = Some(anyhow::Error::from(e)),
+            Err(_) => last_error = Some(anyhow::anyhow!("connect to {} timed out", addr)),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| anyhow::anyhow!("{} has no addresses", host)))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    /// A port nothing is listening on.
+    async fn closed_port() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap().port()
+    }
+
+    async fn listening() -> (TcpListener, u16) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        (listener, port)
+    }
+
+    const SHORT: Duration = Duration::from_millis(200);
+
+    #[tokio::test]
+    async fn reachable_hosts_need_no_wait() {
+        let (_server, server_port) = listening().await;
+        let (_printer, printer_port) = listening().await;
+        let url = format!("ws://127.0.0.1:{}/print", server_port);
+        let started = Instant::now();
+        wait_for_network(
+            &url,
+            Some(("127.0.0.1", printer_port)),
+            Duration::from_secs(10),
+        )
+        .await
+        .unwrap();
+        assert!(started.elapsed() < CHECK_INTERVAL);
+    }
+
+    #[tokio::test]
+    async fn an_unreachable_server_is_only_warned_about() {
+        let url = format!("ws://127.0.0.1:{}", closed_port().await);
+        let started = Instant::now();
+        wait_for_network(&url, None, SHORT).await.unwrap();
+        assert!(started.elapsed() >= SHORT);
+    }
+
+    #[tokio::test]
+    async fn an_unreachable_printer_fails_startup() {
+        let (_server, server_port) = listening().await;
+        let printer_port = closed_port().await;
+        let url = format!("ws://127.0.0.1:{}", server_port);
+        let error = wait_for_network(&url, Some(("127.0.0.1", printer_port)), SHORT)
+            .await
+            .err()
+            .unwrap();
+        let message = error.to_string();
+        assert!(
+            message.starts_with(&format!(
+                "Printer 127.0.0.1:{} still unreachable",
+                printer_port
+            )),
+            "{}",
+            message
+        );
+    }
+
+    #[tokio::test]
+    async fn a_server_coming_up_ends_the_wait() {
+        let port = closed_port().await;
+        let url = format!("ws://127.0.0.1:{}", port);
+        let up = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            TcpListener::bind(("127.0.0.1", port)).await.unwrap()
+        });
+        let started = Instant::now();
+        wait_for_network(&url, None, Duration::from_secs(10))
+            .await
+            .unwrap();
+        // Found at the check after it came up, well before the timeout
+        assert!(
+            started.elapsed() < CHECK_INTERVAL * 2,
+            "{:?}",
+            started.elapsed()
+        );
+        drop(up.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn urls_need_a_host() {
+        let error = wait_for_network("not a url", None, SHORT)
+            .await
+            .err()
+            .unwrap();
+        assert!(error.to_string().starts_with("Invalid URL"), "{}", error);
+        let error = wait_for_network("unix:/run/print.sock", None, SHORT)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(error.to_string(), "URL has no host");
+    }
+}
@@ -0,0 +1,296 @@
+//! `--probe-printer`: asks the printer what it is at startup and adjusts the
+//! profile to match. Only ESC/POS printers are interrogated, and only with
+//! commands that print nothing on printers that understand them; a printer
+//! that misreads the feature probes prints at most a few stray characters.
+
+use escpos::driver::Driver;
+use log::{info, warn};
+use serde::Serialize;
+
+use crate::commands::CommandSet;
+use crate::driver;
+use crate::profile::PrinterProfile;
+
+/// Model names (from GS I 67) that pick a built-in profile when none was
+/// chosen explicitly.
+const KNOWN_MODELS: &[(&str, &str)] = &[
+    ("TM-T20", "default"),
+    ("TM-T82", "default"),
+    ("TM-T88", "default"),
+    ("TM-m30", "default"),
+    ("MTP-II", "serial-58mm"),
+    ("PT-210", "serial-58mm"),
+];
+
+/// What the printer said about itself. Fields are `None` where it didn't answer.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct Detected {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model_id: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub autocutter: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub paper_out: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub qr: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub raster: Option<bool>,
+}
+
+/// Interrogates the printer. Each question waits at most the driver's read
+/// timeout, and a printer that doesn't answer the first is asked nothing else.
+pub fn probe<D: Driver>(driver: &D, commands: CommandSet) -> Detected {
+    let mut detected = Detected::default();
+    if commands != CommandSet::EscPos {
+        warn!("Printer probing needs an ESC/POS printer, skipping it");
+        return detected;
+    }
+
+    // GS I 1: model id
+    let mut byte = [0u8; 1];
+    if query(driver, &[0x1D, b'I', 1], &mut byte) != Some(1) {
+        warn!("Printer didn't answer the model id request, keeping the configured profile");
+        return detected;
+    }
+    detected.model_id = Some(byte[0]);
+
+    // GS I 2: type id, bit 1 = autocutter fitted
+    if query(driver, &[0x1D, b'I', 2], &mut byte) == Some(1) {
+        detected.autocutter = Some(byte[0] & 0x02 != 0);
+    }
+
+    // GS I 67: model name, as "_<name>\0"
+    let mut name = [0u8; 32];
+    if let Some(n) = query(driver, &[0x1D, b'I', 67], &mut name) {
+        let text = String::from_utf8_lossy(&name[..n]);
+        let text = text.trim_start_matches('_').trim_end_matches('\0').trim();
+        if !text.is_empty() {
+            detected.model_name = Some(text.to_string());
+        }
+    }
+
+    detected.paper_out = driver::paper_out(driver);
+
+    // GS ( k <set QR module size 3>: configures the next QR code, prints nothing
+    detected.qr = accepts(driver, &[0x1D, b'(', b'k', 3, 0, 49, 67, 3]);
+    // GS v 0: a 1x1 blank raster image, which advances the paper by one dot
+    detected.raster = accepts(driver, &[0x1D, b'v', b'0', 0, 1, 0, 1, 0, 0x00]);
+
+    // Clear anything a confused printer is still holding on to
+    let _ = driver.write(&[0x1B, b'@']);
+    let _ = driver.flush();
+    detected
+}
+
+/// Picks the profile for what was detected: the known model's profile if
+/// `explicit` is false, refined by the feature probes either way.
+pub fn refine(detected: &Detected, configured: &PrinterProfile, explicit: bool) -> PrinterProfile {
+    let mut refined = configured.clone();
+    if !explicit
+        && let Some(name) = &detected.model_name
+        && let Some((_, profile_name)) = KNOWN_MODELS.iter().find(|(model, _)| name.contains(model))
+        && let Some(profile) = PrinterProfile::find(profile_name)
+        && profile.name != configured.name
+    {
+        info!("Detected {}, using profile {}", name, profile.name);
+        refined = PrinterProfile {
+            font: configured.font,
+            line_spacing: configured.line_spacing,
+            ..profile.clone()
+        };
+    }
+    if refined.graphics && detected.raster == Some(false) {
+        info!("Printer rejected raster graphics, drawing rules and boxes in ASCII");
+        refined.graphics = false;
+    }
+    refined
+}
+
+/// One-line summary for the log.
+pub fn summary(detected: &Detected) -> String {
+    let known = |value: Option<bool>| match value {
+        Some(true) => "yes",
+        Some(false) => "no",
+        None => "unknown",
+    };
+    format!(
+        "model {} (id {}), autocutter {}, paper out {}, QR {}, raster {}",
+        detected.model_name.as_deref().unwrap_or("unknown"),
+        detected
+            .model_id
+            .map_or("unknown".to_string(), |id| id.to_string()),
+        known(detected.autocutter),
+        known(detected.paper_out),
+        known(detected.qr),
+        known(detected.raster),
+    )
+}
+
+/// Sends `command` and reads one reply into `buf`. `None` if nothing came back.
+fn query<D: Driver>(driver: &D, command: &[u8], buf: &mut [u8]) -> Option<usize> {
+    driver.write(command).ok()?;
+    driver.flush().ok()?;
+    match driver.read(buf) {
+        Ok(n) if n > 0 => Some(n),
+        _ => None,
+    }
+}
+
+/// Sends `command` and then asks for the error status (DLE EOT 3). The
+/// command is taken as supported if the printer answers with no error bits
+/// set; a printer that swallowed the status request while parsing a command
+/// it doesn't know counts as not supporting it.
+fn accepts<D: Driver>(driver: &D, command: &[u8]) -> Option<bool> {
+    driver.write(command).ok()?;
+    let mut status = [0u8; 1];
+    // Bits 3, 5 and 6: cutter, unrecoverable and auto-recoverable errors
+    Some(query(driver, &[0x10, 0x04, 0x03], &mut status) == Some(1) && status[0] & 0x68 == 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::profile::Font;
+
+    type Answers = fn(previous: &[u8], command: &[u8]) -> Option<Vec<u8>>;
+
+    /// Answers each command with what `answers` says, given the command
+    /// before it too.
+    struct Printer {
+        answers: Answers,
+        written: Mutex<Vec<Vec<u8>>>,
+        reply: Mutex<Vec<u8>>,
+    }
+
+    impl Printer {
+        fn new(answers: Answers) -> Self {
+            Self {
+                answers,
+                written: Mutex::new(Vec::new()),
+                reply: Mutex::new(Vec::new()),
+            }
+        }
+    }
+
+    impl Driver for Printer {
+        fn name(&self) -> String {
+            "scripted".to_string()
+        }
+
+        fn write(&self, data: &[u8]) -> escpos::errors::Result<()> {
+            let mut written = self.written.lock().unwrap();
+            let previous = written.last().cloned().unwrap_or_default();
+            *self.reply.lock().unwrap() = (self.answers)(&previous, data).unwrap_or_default();
+            written.push(data.to_vec());
+            Ok(())
+        }
+
+        fn read(&self, buf: &mut [u8]) -> escpos::errors::Result<usize> {
+            let reply = std::mem::take(&mut *self.reply.lock().unwrap());
+            let n = reply.len().min(buf.len());
+            buf[..n].copy_from_slice(&reply[..n]);
+            Ok(n)
+        }
+
+        fn flush(&self) -> escpos::errors::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// A TM-T88 with a cutter and paper, taking QR codes but not raster images.
+    fn tm_t88(previous: &[u8], command: &[u8]) -> Option<Vec<u8>> {
+        match command {
+            [0x1D, b'I', 1] => Some(vec![0x20]),
+            [0x1D, b'I', 2] => Some(vec![0x02]),
+            [0x1D, b'I', 67] => Some(b"_TM-T88V\0".to_vec()),
+            [0x10, 0x04, 0x04] => Some(vec![0x12]),
+            [0x10, 0x04, 0x03] if previous.starts_with(&[0x1D, b'v']) => Some(vec![0x40]),
+            [0x10, 0x04, 0x03] => Some(vec![0x12]),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn an_answering_printer_is_described() {
+        let printer = Printer::new(tm_t88);
+        let detected = probe(&printer, CommandSet::EscPos);
+        assert_eq!(detected.model_id, Some(0x20));
+        assert_eq!(detected.model_name.as_deref(), Some("TM-T88V"));
+        assert_eq!(detected.autocutter, Some(true));
+        assert_eq!(detected.paper_out, Some(false));
+        assert_eq!(detected.qr, Some(true));
+        assert_eq!(detected.raster, Some(false));
+        assert_eq!(
+            summary(&detected),
+            "model TM-T88V (id 32), autocutter yes, paper out no, QR yes, raster no"
+        );
+        // Ends by resetting the printer
+        assert_eq!(
+            printer.written.lock().unwrap().last().unwrap(),
+            &[0x1B, b'@']
+        );
+    }
+
+    #[test]
+    fn a_silent_printer_is_asked_once() {
+        let printer = Printer::new(|_, _| None);
+        let detected = probe(&printer, CommandSet::EscPos);
+        assert!(detected.model_id.is_none() && detected.qr.is_none());
+        assert_eq!(printer.written.lock().unwrap().len(), 1);
+        assert_eq!(
+            summary(&detected),
+            "model unknown (id unknown), autocutter unknown, paper out unknown, QR unknown, raster unknown"
+        );
+        assert_eq!(serde_json::to_string(&detected).unwrap(), "{}");
+    }
+
+    #[test]
+    fn only_esc_pos_printers_are_probed() {
+        let printer = Printer::new(tm_t88);
+        let detected = probe(&printer, CommandSet::Star);
+        assert!(detected.model_id.is_none());
+        assert!(printer.written.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn a_known_model_picks_its_profile_unless_one_was_chosen() {
+        let detected = Detected {
+            model_name: Some("PT-210_7A3F".to_string()),
+            raster: Some(true),
+            ..Default::default()
+        };
+        let mut configured = PrinterProfile::find("default").unwrap().clone();
+        configured.font = Font::B;
+        configured.line_spacing = Some(40);
+        let refined = refine(&detected, &configured, false);
+        assert_eq!(refined.name, "serial-58mm");
+        assert_eq!(refined.columns, 32);
+        assert_eq!((refined.font, refined.line_spacing), (Font::B, Some(40)));
+
+        let kept = refine(&detected, &configured, true);
+        assert_eq!(kept.name, "default");
+        assert!(kept.graphics);
+
+        let unknown = Detected {
+            model_name: Some("XP-80".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(refine(&unknown, &configured, false).name, "default");
+    }
+
+    #[test]
+    fn rejected_raster_turns_graphics_off() {
+        let configured = PrinterProfile::find("default").unwrap();
+        let rejected = Detected {
+            raster: Some(false),
+            ..Default::default()
+        };
+        assert!(!refine(&rejected, configured, true).graphics);
+        // Not knowing isn't rejecting
+        assert!(refine(&Detected::default(), configured, true).graphics);
+    }
+}
@@ -0,0 +1,205 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::commands::CommandSet;
+
+/// Printer-resident fonts, selected with ESC M.
+#[derive(Serialize, Deserialize, clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Font {
+    #[default]
+    A,
+    /// Narrower font, fitting more characters per line
+    B,
+}
+
+/// Settings that depend on the printer model rather than on how it's
+/// connected. Start from one of the built-in profiles with
+/// [`PrinterProfile::find`] and change what differs.
+#[derive(Debug, Clone)]
+pub struct PrinterProfile {
+    pub name: &'static str,
+    /// Spelling of the vendor-specific commands (feed, cut, cash drawer, raster)
+    pub commands: CommandSet,
+    /// Characters per line in font A at normal size
+    pub columns: usize,
+    /// Characters per line in font B at normal size
+    pub font_b_columns: usize,
+    /// Font used unless a job or segment picks another
+    pub font: Font,
+    /// Line spacing in dots (`None` = the printer's default)
+    pub line_spacing: Option<u8>,
+    /// Maximum bytes per driver write (0 = the whole job in one write)
+    pub chunk_size: usize,
+    /// Pause between chunks so printers with small input buffers can keep up
+    pub inter_chunk_delay: Duration,
+    /// Bytes the printer takes in before it has to print some, past which it
+    /// may drop data (0 = unknown)
+    pub receive_buffer: usize,
+    /// Bytes a second the printer gets through while printing (0 = unknown)
+    pub throughput: usize,
+    /// Longest a job may be estimated to take to print (zero = no limit)
+    pub max_print_time: Duration,
+    /// Raster images and the CP437 line-drawing characters work; without them
+    /// rules and boxes are drawn in ASCII
+    pub graphics: bool,
+    /// Dot rows per raster command; taller images are sent as a stack of
+    /// bands so the printer never has to buffer a whole image (0 = one
+    /// command per image)
+    pub raster_band_rows: usize,
+    /// Low-power mode commands, if the printer has one
+    pub sleep: Option<SleepCommands>,
+    /// Render jobs with the paper-saving spacing unless they say otherwise
+    pub compact: bool,
+    /// Render jobs in large type unless they say otherwise
+    pub large_print: bool,
+}
+
+/// How to put a printer into low-power mode and get it back out.
+#[derive(Debug, Clone)]
+pub struct SleepCommands {
+    /// Sent once the printer has been idle long enough
+    pub sleep: &'static [u8],
+    /// Sent before the next job; the printer may swallow these while it wakes
+    pub wake: &'static [u8],
+    /// Pause after the wake bytes before the printer accepts commands again
+    pub settle: Duration,
+}
+
+/// Built-in profiles, selectable with `--profile`.
+pub const PROFILES: &[PrinterProfile] = &[
+    PrinterProfile {
+        name: "default",
+        commands: CommandSet::EscPos,
+        columns: 48,
+        font_b_columns: 64,
+        font: Font::A,
+        line_spacing: None,
+        chunk_size: 0,
+        inter_chunk_delay: Duration::ZERO,
+        receive_buffer: 0,
+        throughput: 0,
+        max_print_time: Duration::ZERO,
+        graphics: true,
+        raster_band_rows: 256,
+        sleep: None,
+        compact: false,
+        large_print: false,
+    },
+    // Cheap 58mm serial/Bluetooth printers drop bytes past a few hundred bytes of buffer
+    PrinterProfile {
+        name: "serial-58mm",
+        commands: CommandSet::EscPos,
+        columns: 32,
+        font_b_columns: 42,
+        font: Font::A,
+        line_spacing: None,
+        chunk_size: 256,
+        inter_chunk_delay: Duration::from_millis(40),
+        receive_buffer: 0,
+        throughput: 0,
+        max_print_time: Duration::ZERO,
+        // Clones often start up in a Chinese code page and ignore GS v 0
+        graphics: false,
+        raster_band_rows: 64,
+        // ESC 8 sets a 1 second sleep timeout; they drop the first bytes while
+        // waking, so wake them with NULs
+        sleep: Some(SleepCommands {
+            sleep: &[0x1B, b'8', 1, 0],
+            wake: &[0; 8],
+            settle: Duration::from_millis(500),
+        }),
+        compact: false,
+        large_print: false,
+    },
+    // Star TSP100/TSP650 in Star Line Mode
+    PrinterProfile {
+        name: "star-tsp",
+        commands: CommandSet::Star,
+        columns: 48,
+        font_b_columns: 64,
+        font: Font::A,
+        line_spacing: None,
+        chunk_size: 0,
+        inter_chunk_delay: Duration::ZERO,
+        receive_buffer: 0,
+        throughput: 0,
+        max_print_time: Duration::ZERO,
+        graphics: true,
+        raster_band_rows: 256,
+        sleep: None,
+        compact: false,
+        large_print: false,
+    },
+];
+
+impl PrinterProfile {
+    /// The built-in profile called `name`, as given to `--profile`.
+    pub fn find(name: &str) -> Option<&'static PrinterProfile> {
+        PROFILES.iter().find(|p| p.name == name)
+    }
+
+    /// Characters per line in `font` at normal size.
+    pub fn columns_for(&self, font: Font) -> usize {
+        match font {
+            Font::A => self.columns,
+            Font::B => self.font_b_columns,
+        }
+    }
+}
+
+pub fn names() -> Vec<&'static str> {
+    PROFILES.iter().map(|p| p.name).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn profiles_are_found_by_name() {
+        assert_eq!(names(), ["default", "serial-58mm", "star-tsp"]);
+        for name in names() {
+            assert_eq!(PrinterProfile::find(name).unwrap().name, name);
+        }
+        assert!(PrinterProfile::find("Default").is_none());
+        assert!(PrinterProfile::find("").is_none());
+    }
+
+    #[test]
+    fn columns_follow_the_font() {
+        let serial = PrinterProfile::find("serial-58mm").unwrap();
+        assert_eq!(serial.columns_for(Font::A), 32);
+        assert_eq!(serial.columns_for(Font::B), 42);
+        let star = PrinterProfile::find("star-tsp").unwrap();
+        assert_eq!(star.commands, CommandSet::Star);
+        assert_eq!(star.columns_for(Font::default()), 48);
+    }
+
+    #[test]
+    fn built_in_profiles_are_consistent() {
+        for profile in PROFILES {
+            // Font B is the narrower one
+            assert!(profile.font_b_columns > profile.columns, "{}", profile.name);
+            // Chunks are paced, and paced writes come in chunks
+            assert_eq!(
+                profile.chunk_size == 0,
+                profile.inter_chunk_delay.is_zero(),
+                "{}",
+                profile.name
+            );
+            assert!(profile.raster_band_rows > 0, "{}", profile.name);
+            if let Some(sleep) = &profile.sleep {
+                assert!(!sleep.sleep.is_empty() && !sleep.wake.is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn fonts_are_named_in_lowercase() {
+        assert_eq!(serde_json::to_string(&Font::B).unwrap(), r#""b""#);
+        assert_eq!(serde_json::from_str::<Font>(r#""a""#).unwrap(), Font::A);
+        assert!(serde_json::from_str::<Font>(r#""c""#).is_err());
+    }
+}
@@ -0,0 +1,1067 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use log::debug;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::clock;
+use crate::filters::Filter;
+use crate::journal::Lookup;
+use crate::maintenance::MaintenanceState;
+use crate::metrics::JobTimings;
+use crate::network::NetworkStats;
+use crate::probe::Detected;
+use crate::profile::Font;
+use crate::rendercache::RenderCacheStats;
+use crate::report::Counters;
+use crate::resources::ResourceStats;
+use crate::signing::{Envelope, Signer};
+
+/// Version of the message schema spoken by this build. Bump when fields change meaning.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Wire protocols, negotiated as WebSocket subprotocols.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WireProtocol {
+    /// Every message is a plain-text ticket; nothing is sent back
+    V1,
+    /// JSON jobs and commands, with acks, heartbeats and the rest
+    V2,
+}
+
+impl WireProtocol {
+    /// Offered to the server, most preferred first.
+    pub const OFFERED: [WireProtocol; 2] = [WireProtocol::V2, WireProtocol::V1];
+
+    pub fn subprotocol(self) -> &'static str {
+        match self {
+            WireProtocol::V1 => "flatos-print.v1",
+            WireProtocol::V2 => "flatos-print.v2",
+        }
+    }
+
+    pub fn from_subprotocol(name: &str) -> Option<Self> {
+        Self::OFFERED.into_iter().find(|p| p.subprotocol() == name)
+    }
+}
+
+/// Messages the server can send us. Anything that isn't a JSON object with a
+/// `type` field is treated as a legacy plain-text ticket.
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Inbound {
+    Job(Job),
+    /// One job for several printers, each acking it separately
+    Fanout(Fanout),
+    Command(CommandFrame),
+    /// Render a job and send back its transcript instead of printing it
+    Preview {
+        job: Job,
+    },
+    /// The server's reply to our hello; `time` is its clock in Unix seconds
+    Hello {
+        #[serde(default)]
+        time: Option<i64>,
+    },
+}
+
+/// A control command and the authorization it carries, if any.
+#[derive(Deserialize, Debug)]
+pub struct CommandFrame {
+    #[serde(flatten)]
+    pub command: Command,
+    /// Hex HMAC-SHA256 of `<challenge>.<command>`, for commands that need
+    /// authorizing (see `control`)
+    #[serde(default)]
+    pub auth: Option<String>,
+    /// The printer the command is for; every printer when not given
+    #[serde(default)]
+    pub target: Option<String>,
+}
+
+/// `{"type":"fanout","targets":[...],"job":{...}}`: the job is printed on
+/// each of `targets`, or on every printer when that's empty.
+#[derive(Deserialize, Debug)]
+pub struct Fanout {
+    #[serde(default)]
+    pub targets: Vec<String>,
+    pub job: Job,
+}
+
+/// Control commands, sent as `{"type":"command","command":"<name>",...}`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Command {
+    /// Rewrite the spool without its completed records
+    Compact,
+    /// Drop every ticket in the render cache
+    FlushCache,
+    /// Send the daily report counters for the current period
+    Report,
+    /// Report queue depth and rate-limit state
+    Status,
+    /// Re-read the config file and apply what can change without a restart
+    Reload,
+    /// Hold printing (jobs are still accepted and spooled) until `resume`,
+    /// or at most `max_secs`
+    Pause {
+        #[serde(default)]
+        reason: Option<String>,
+        #[serde(default)]
+        max_secs: Option<u64>,
+    },
+    Resume,
+    /// The server's clock in Unix seconds, to correct ours by
+    Time {
+        time: i64,
+    },
+    /// Reset the maintenance counters after replacing the mechanism. Without
+    /// `confirm` the reply carries a token to send back in it.
+    MaintenanceReset {
+        #[serde(default)]
+        confirm: Option<String>,
+    },
+    /// Move the spool and journal encryption on to a new key, re-encrypting
+    /// what's stored
+    RotateKey,
+    /// Look up job outcomes in the journal by id and/or a range of Unix
+    /// times they finished in
+    Lookup {
+        #[serde(default)]
+        id: Option<String>,
+        #[serde(default)]
+        since: Option<i64>,
+        #[serde(default)]
+        until: Option<i64>,
+    },
+}
+
+impl Command {
+    /// The command's name on the wire.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Compact => "compact",
+            Command::FlushCache => "flush_cache",
+            Command::Report => "report",
+            Command::Status => "status",
+            Command::Reload => "reload",
+            Command::Pause { .. } => "pause",
+            Command::Resume => "resume",
+            Command::Time { .. } => "time",
+            Command::MaintenanceReset { .. } => "maintenance_reset",
+            Command::Lookup { .. } => "lookup",
+            Command::RotateKey => "rotate_key",
+        }
+    }
+}
+
+/// A ticket to print, as the server sends it in a `job` message (exported
+/// as `PrintJob`). Everything but `text` is optional; [`Job::plain`] makes
+/// one from text alone.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Job {
+    /// Used in acks and to spot a job sent twice
+    pub id: Option<String>,
+    #[serde(default)]
+    pub text: String,
+    /// Font for the whole job, overriding the printer profile
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub font: Option<Font>,
+    /// Line spacing in dots for the whole job, overriding the printer profile
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub line_spacing: Option<u8>,
+    /// Blocks printed after `text`: text with its own font and spacing, rules,
+    /// spacers and boxes
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub segments: Vec<Segment>,
+    /// Kick the cash drawer after the cut
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub open_drawer: bool,
+    /// Send a `printing` ack when writing starts and `progress` frames while it goes on
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub progress: bool,
+    /// What the ticket is for (e.g. `receipt`, `kitchen`); picks which footers apply
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kind: Option<String>,
+    /// `false` leaves off the configured footer
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub footer: Option<bool>,
+    /// Use (or, with `false`, don't use) the paper-saving compact spacing,
+    /// overriding the profile
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compact: Option<bool>,
+    /// `large` prints the ticket in large type, `standard` doesn't,
+    /// overriding `--large-print`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub accessibility: Option<Accessibility>,
+    /// Keep the ticket out of the receipt archive
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub sensitive: bool,
+    /// Print the ticket this many times (default 1)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub copies: Option<u32>,
+    /// Where the job goes in the queue: ahead of any queued jobs of lower
+    /// priority (default `normal`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub priority: Option<Priority>,
+    /// Unix time after which the job is acked `expired` instead of printed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<i64>,
+    /// Seconds after receipt the job expires (0 = never); turned into
+    /// `expires_at` when the job is accepted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl_secs: Option<u64>,
+    /// Tenant the job is meant for, checked against the device's
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    /// The printer to print on, by name; the default printer when not given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    /// Schema version the server built this job against
+    pub schema_version: Option<u32>,
+    /// Capabilities the job needs; it's rejected rather than partially printed if any are missing
+    #[serde(default)]
+    pub requires: Vec<String>,
+    /// Fields from newer schema versions we don't understand
+    #[serde(flatten)]
+    pub unknown: BTreeMap<String, Value>,
+}
+
+/// One block of a job, told apart by which field it has.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum Segment {
+    /// A horizontal line across the paper (or the box it's in)
+    Rule {
+        rule: RuleStyle,
+    },
+    /// Blank space of this many dot rows
+    Spacer {
+        spacer: u8,
+    },
+    /// Segments drawn inside a box
+    Box {
+        #[serde(rename = "box")]
+        segments: Vec<Segment>,
+    },
+    /// A PBM or PGM image fetched by URL rather than sent inline
+    Image {
+        image_ref: ResourceRef,
+    },
+    /// The local time when the ticket prints, in strftime format (e.g.
+    /// `%d/%m/%Y %H:%M`), in the job's font
+    Timestamp {
+        timestamp: String,
+    },
+    Text(TextSegment),
+}
+
+/// A resource kept outside the job, checked against its SHA-256 once fetched.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ResourceRef {
+    pub url: String,
+    /// Hex SHA-256 of the resource's bytes
+    pub sha256: String,
+    /// The fetched bytes, once resolved
+    #[serde(skip)]
+    pub data: Option<ResourceData>,
+}
+
+/// Fetched resource bytes, shown by length only when debug-printed.
+#[derive(Clone)]
+pub struct ResourceData(pub Arc<[u8]>);
+
+impl From<Arc<[u8]>> for ResourceData {
+    fn from(data: Arc<[u8]>) -> Self {
+        Self(data)
+    }
+}
+
+impl std::fmt::Debug for ResourceData {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<{} bytes>", self.0.len())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleStyle {
+    /// `-----`
+    Dash,
+    /// `=====`
+    Double,
+    /// `░░░░░`, or `.....` on printers without graphics
+    Shade,
+    /// A solid bit-image line, or `-----` on printers without graphics
+    Solid,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Accessibility {
+    Standard,
+    /// Double-height font A text with at least `LARGE_LINE_SPACING` dots
+    /// between lines, whatever the job or compact mode ask for
+    Large,
+}
+
+/// How urgently a job should print, from its `priority`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+/// A block of text with its own font or line spacing.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TextSegment {
+    pub text: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub font: Option<Font>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub line_spacing: Option<u8>,
+}
+
+impl Job {
+    /// A legacy plain-text ticket.
+    pub fn plain(text: String) -> Self {
+        Job {
+            id: None,
+            text,
+            font: None,
+            line_spacing: None,
+            segments: Vec::new(),
+            open_drawer: false,
+            progress: false,
+            kind: None,
+            footer: None,
+            compact: None,
+            accessibility: None,
+            sensitive: false,
+            copies: None,
+            priority: None,
+            expires_at: None,
+            ttl_secs: None,
+            tenant: None,
+            target: None,
+            schema_version: None,
+            requires: Vec::new(),
+            unknown: BTreeMap::new(),
+        }
+    }
+
+    /// Sets `expires_at` from the job's `ttl_secs`, or `default_ttl_secs` if it
+    /// has neither, counting from now.
+    pub fn apply_ttl(&mut self, default_ttl_secs: u64) {
+        self.apply_ttl_at(default_ttl_secs, clock::now().timestamp());
+    }
+
+    /// `apply_ttl` counting from `now` (Unix seconds).
+    pub fn apply_ttl_at(&mut self, default_ttl_secs: u64, now: i64) {
+        if self.expires_at.is_some() {
+            return;
+        }
+        let ttl = self.ttl_secs.unwrap_or(default_ttl_secs);
+        if ttl > 0 {
+            self.expires_at = Some(now.saturating_add_unsigned(ttl));
+        }
+    }
+
+    pub fn copies(&self) -> usize {
+        self.copies.unwrap_or(1).max(1) as usize
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.is_expired_at(clock::now().timestamp())
+    }
+
+    /// Whether the job had expired by `now` (Unix seconds).
+    pub fn is_expired_at(&self, now: i64) -> bool {
+        self.expires_at.is_some_and(|at| now >= at)
+    }
+
+    /// Returns the required capabilities this device doesn't have.
+    pub fn missing_capabilities(&self, supported: &[Capability]) -> Vec<String> {
+        self.requires
+            .iter()
+            .filter(|name| !supported.iter().any(|cap| cap.name() == name.as_str()))
+            .cloned()
+            .collect()
+    }
+}
+
+/// Features a device can advertise in its hello frame and a job can require.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Capability {
+    Text,
+    /// Font A/B and line spacing, per job or segment
+    Fonts,
+    /// Rule, spacer and box segments
+    Layout,
+    /// Image segments fetched by `image_ref`
+    Images,
+    /// Timestamp segments
+    Timestamps,
+    /// Footer QR codes printed as symbols rather than as their text
+    Qr,
+    /// Characters outside the code page drawn from the built-in fallback font
+    Unicode,
+}
+
+impl Capability {
+    pub fn name(self) -> &'static str {
+        match self {
+            Capability::Text => "text",
+            Capability::Fonts => "fonts",
+            Capability::Layout => "layout",
+            Capability::Images => "images",
+            Capability::Timestamps => "timestamps",
+            Capability::Qr => "qr",
+            Capability::Unicode => "unicode",
+        }
+    }
+}
+
+/// Frames we send back to the server.
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Outbound {
+    Hello {
+        version: &'static str,
+        /// The device clock in Unix seconds, uncorrected
+        time: i64,
+        capabilities: Vec<Capability>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        device_id: Option<String>,
+        /// Hex Ed25519 public key from the device identity, for the server to pin
+        #[serde(skip_serializing_if = "Option::is_none")]
+        public_key: Option<String>,
+        /// Tenant the device is configured for, so the server can check its routing
+        #[serde(skip_serializing_if = "Option::is_none")]
+        tenant: Option<String>,
+        /// Printer profile in use
+        profile: &'static str,
+        /// What the printer reported about itself, with `--probe-printer`
+        #[serde(skip_serializing_if = "Option::is_none")]
+        printer: Option<Detected>,
+        /// The other printers jobs can be sent to, when there are any
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        targets: Vec<TargetInfo>,
+    },
+    Ack {
+        id: Option<String>,
+        status: AckStatus,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<ErrorCode>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        message: Option<String>,
+        /// How long the job took, on `printed` acks and ones refused by the size check
+        #[serde(skip_serializing_if = "Option::is_none")]
+        timing: Option<Timing>,
+    },
+    /// Share of a job's bytes written so far, for jobs that asked for `progress`
+    Progress { id: Option<String>, percent: u8 },
+    Heartbeat {
+        uptime_secs: u64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        paused: Option<PauseState>,
+        estimate: Estimate,
+        maintenance: MaintenanceState,
+        /// The network interface to the server, where it can be read
+        #[serde(skip_serializing_if = "Option::is_none")]
+        network: Option<NetworkStats>,
+    },
+    CommandResult {
+        command: &'static str,
+        ok: bool,
+        /// Set when the command was refused without being run
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<ErrorCode>,
+        message: String,
+        /// With `UNAUTHORIZED`: what to sign to send the command again
+        #[serde(skip_serializing_if = "Option::is_none")]
+        challenge: Option<String>,
+    },
+    /// Reply to a `preview` message
+    Preview {
+        id: Option<String>,
+        /// Characters per line in font A
+        columns: usize,
+        transcript: String,
+    },
+    /// Reply to the `report` command
+    Report {
+        /// The report as it would be printed
+        text: String,
+        uptime_secs: u64,
+        #[serde(flatten)]
+        counters: Counters,
+    },
+    /// Reply to the `status` command; boxed to keep the other frames small
+    Status(Box<Status>),
+    /// Reply to the `lookup` command
+    Lookup {
+        #[serde(flatten)]
+        found: Lookup,
+    },
+    /// A printer fault starting or clearing, found by `--status-poll-secs`
+    PrinterFault {
+        code: String,
+        active: bool,
+        /// What happened and what to do, in the configured locale
+        title: String,
+        action: String,
+    },
+}
+
+/// A printer other than the default one, as the hello frame lists it.
+#[derive(Serialize, Debug, Clone)]
+pub struct TargetInfo {
+    pub name: String,
+    pub profile: &'static str,
+    pub capabilities: Vec<Capability>,
+}
+
+/// What a printer's service is doing: its queue, the printer, and counters
+/// since startup. Sent for the `status` command and returned by
+/// `PrinterService::status`.
+#[derive(Serialize, Debug, Clone)]
+pub struct Status {
+    /// Which printer this is, when there are several
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    pub uptime_secs: u64,
+    pub queue_depth: usize,
+    /// How long the next queued job is being held back by the rate limit
+    pub rate_limit_delay_ms: u64,
+    pub printer_connected: bool,
+    /// Frames waiting to be resent after a reconnect
+    pub undelivered_frames: usize,
+    /// Frames dropped because too many were waiting
+    pub dropped_frames: u64,
+    pub previews: u64,
+    pub printer_asleep: bool,
+    /// Completed sleep/wake cycles since startup
+    pub sleep_cycles: u64,
+    /// Hooks that failed, timed out or were skipped
+    pub hook_failures: u64,
+    /// Histograms of the timings in printed acks
+    pub timings: JobTimings,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub paused: Option<PauseState>,
+    /// Seconds added to the device clock to match the server's
+    pub clock_offset_secs: i64,
+    pub estimate: Estimate,
+    /// Codes of the printer faults active as of the last status poll
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub printer_faults: Vec<String>,
+    /// Ids of recent jobs set aside after they made the service panic
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub dead_letters: Vec<String>,
+    pub maintenance: MaintenanceState,
+    /// The config file's job filters, in the order they're applied
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub filters: Vec<Filter>,
+    /// Resource cache hits and misses since startup
+    pub resources: ResourceStats,
+    /// Render cache hits and misses since startup, and what it holds
+    pub render_cache: RenderCacheStats,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub network: Option<NetworkStats>,
+}
+
+/// Roughly how long a job sent now would take to finish printing, from the
+/// queue, the rate limit and recent write speeds. An estimate, not a promise.
+#[derive(Serialize, Debug, Clone)]
+pub struct Estimate {
+    pub time_to_print_ms: u64,
+    /// Recent average write time per rendered line
+    pub ms_per_line: f64,
+    /// Printed jobs the averages come from (0 = built-in defaults)
+    pub samples: u64,
+}
+
+/// Why printing is paused and when it resumes by itself.
+#[derive(Serialize, Debug, Clone)]
+pub struct PauseState {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    pub resumes_in_secs: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AckStatus {
+    /// Queued for printing; a `printed` or `failed` ack follows
+    Accepted,
+    /// Started writing to the printer; only sent for jobs that asked for `progress`
+    Printing,
+    Printed,
+    Failed,
+    Rejected,
+    /// Reached the front of the queue after its `expires_at`, so not printed
+    Expired,
+}
+
+/// Where a printed job's time went, in milliseconds.
+#[derive(Serialize, Debug, Clone, Copy, Default)]
+pub struct Timing {
+    /// Waiting in the queue
+    pub queued_ms: u64,
+    pub render_ms: u64,
+    /// Writing to the printer, including any reconnect and retry
+    pub write_ms: u64,
+    /// From acceptance to the last byte written
+    pub total_ms: u64,
+    /// The size check before anything was sent
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preflight: Option<Preflight>,
+}
+
+/// A job's size and estimated print time, and what was done about them.
+#[derive(Serialize, Debug, Clone, Copy)]
+pub struct Preflight {
+    pub bytes: usize,
+    /// From the profile's throughput, when it has one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub estimated_ms: Option<u64>,
+    pub decision: PreflightDecision,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PreflightDecision {
+    Send,
+    /// Sent in chunks paced to the printer, as it would overflow its receive buffer
+    Paced,
+    /// Failed with `JOB_TOO_LARGE` before any of it was sent
+    Refused,
+}
+
+/// Why a job or command didn't go through, in acks and command results.
+/// New codes are added as the protocol grows.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+#[non_exhaustive]
+pub enum ErrorCode {
+    /// Writing to the printer failed, or the printer went away
+    PrintFailed,
+    /// The message wasn't a valid job, or the job couldn't be rendered
+    InvalidJob,
+    /// Unsigned job or command with `--require-signed-jobs`
+    UnsignedJob,
+    /// The message's signature didn't verify
+    BadSignature,
+    /// The job requires a capability this device doesn't have
+    UnsupportedFeature,
+    /// The message was over `--max-message-size`
+    MessageTooLarge,
+    /// The job renders to more than the output budget or the printer can take
+    JobTooLarge,
+    /// The job is for another tenant, or names none where one is required
+    TenantMismatch,
+    /// A resource the job refers to couldn't be fetched or didn't match its hash
+    ResourceFetch,
+    /// The service hit a bug handling the job
+    InternalError,
+    /// A control command arrived more often than its limit allows
+    RateLimited,
+    /// A control command needing authorization came without a valid one
+    Unauthorized,
+    /// The job or command names a printer this device doesn't have
+    UnknownTarget,
+}
+
+impl ErrorCode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorCode::PrintFailed => "PRINT_FAILED",
+            ErrorCode::InvalidJob => "INVALID_JOB",
+            ErrorCode::UnsignedJob => "UNSIGNED_JOB",
+            ErrorCode::BadSignature => "BAD_SIGNATURE",
+            ErrorCode::UnsupportedFeature => "UNSUPPORTED_FEATURE",
+            ErrorCode::MessageTooLarge => "MESSAGE_TOO_LARGE",
+            ErrorCode::JobTooLarge => "JOB_TOO_LARGE",
+            ErrorCode::TenantMismatch => "TENANT_MISMATCH",
+            ErrorCode::ResourceFetch => "RESOURCE_FETCH",
+            ErrorCode::InternalError => "INTERNAL_ERROR",
+            ErrorCode::RateLimited => "RATE_LIMITED",
+            ErrorCode::Unauthorized => "UNAUTHORIZED",
+            ErrorCode::UnknownTarget => "UNKNOWN_TARGET",
+        }
+    }
+}
+
+/// Outbound frame as it goes on the wire, stamped with our schema version,
+/// its sequence number and the printer it's from.
+#[derive(Serialize)]
+struct Versioned<'a> {
+    schema_version: u32,
+    seq: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    target: Option<&'a str>,
+    #[serde(flatten)]
+    frame: &'a Outbound,
+}
+
+impl Outbound {
+    pub fn ack(id: Option<String>, status: AckStatus) -> Self {
+        Outbound::Ack {
+            id,
+            status,
+            error: None,
+            message: None,
+            timing: None,
+        }
+    }
+
+    pub fn printed(id: Option<String>, timing: Timing) -> Self {
+        Outbound::Ack {
+            id,
+            status: AckStatus::Printed,
+            error: None,
+            message: None,
+            timing: Some(timing),
+        }
+    }
+
+    pub fn error_ack(
+        id: Option<String>,
+        status: AckStatus,
+        error: ErrorCode,
+        message: impl Into<String>,
+    ) -> Self {
+        Outbound::Ack {
+            id,
+            status,
+            error: Some(error),
+            message: Some(message.into()),
+            timing: None,
+        }
+    }
+
+    /// Adds `timing` to an ack.
+    pub fn with_timing(mut self, timing: Timing) -> Self {
+        if let Outbound::Ack { timing: slot, .. } = &mut self {
+            *slot = Some(timing);
+        }
+        self
+    }
+
+    pub fn command_result(command: &'static str, ok: bool, message: impl Into<String>) -> Self {
+        Outbound::CommandResult {
+            command,
+            ok,
+            error: None,
+            message: message.into(),
+            challenge: None,
+        }
+    }
+
+    /// Reply to a command refused without being run.
+    pub fn command_refused(
+        command: &'static str,
+        error: ErrorCode,
+        message: impl Into<String>,
+        challenge: Option<String>,
+    ) -> Self {
+        Outbound::CommandResult {
+            command,
+            ok: false,
+            error: Some(error),
+            message: message.into(),
+            challenge,
+        }
+    }
+
+    /// Short description for logs.
+    pub fn describe(&self) -> String {
+        match self {
+            Outbound::Hello { .. } => "hello".to_string(),
+            Outbound::Ack {
+                id, status, error, ..
+            } => match error {
+                Some(code) => format!("{:?} ack for job {:?} ({})", status, id, code.as_str()),
+                None => format!("{:?} ack for job {:?}", status, id),
+            },
+            Outbound::Progress { id, percent } => format!("progress {}% for job {:?}", percent, id),
+            Outbound::Heartbeat { .. } => "heartbeat".to_string(),
+            Outbound::CommandResult {
+                command,
+                error: Some(code),
+                ..
+            } => format!("{} refused ({})", command, code.as_str()),
+            Outbound::CommandResult { command, ok, .. } => {
+                format!("{} result (ok: {})", command, ok)
+            }
+            Outbound::Preview { id, .. } => format!("preview of job {:?}", id),
+            Outbound::Report { .. } => "report".to_string(),
+            Outbound::Status(_) => "status".to_string(),
+            Outbound::Lookup { found } => format!("lookup ({} entries)", found.entries.len()),
+            Outbound::PrinterFault { code, active, .. } => {
+                format!("printer fault {} (active: {})", code, active)
+            }
+        }
+    }
+
+    /// Serializes the frame as number `seq`, from printer `target` when there
+    /// are several, wrapping it in a signed envelope when a signer is
+    /// configured.
+    pub fn encode(&self, seq: u64, target: Option<&str>, signer: Option<&Signer>) -> String {
+        let versioned = Versioned {
+            schema_version: SCHEMA_VERSION,
+            seq,
+            target,
+            frame: self,
+        };
+        let payload = serde_json::to_string(&versioned).expect("frame serialization cannot fail");
+        match signer {
+            Some(signer) => signer.sign(payload),
+            None => payload,
+        }
+    }
+}
+
+/// Result of decoding one inbound text frame.
+#[derive(Debug)]
+pub enum Decoded {
+    Job(Job),
+    Fanout(Fanout),
+    Command(CommandFrame),
+    Preview(Job),
+    ServerHello {
+        time: Option<i64>,
+    },
+    Rejected {
+        id: Option<String>,
+        /// The tenant the job was for, when it could be read
+        tenant: Option<String>,
+        error: ErrorCode,
+        message: String,
+    },
+    /// A command turned away before it could be run
+    Refused {
+        command: &'static str,
+        error: ErrorCode,
+        message: String,
+    },
+}
+
+/// Decodes an inbound text frame, verifying its signature if it carries one.
+/// With `require_signed`, jobs and commands that aren't signed are turned
+/// away.
+pub fn decode(text: &str, signer: Option<&Signer>, require_signed: bool) -> Decoded {
+    let (body, signed) = match serde_json::from_str::<Envelope>(text) {
+        Ok(envelope) => {
+            if let Some(signer) = signer
+                && let Err(e) = signer.verify(&envelope)
+            {
+                return Decoded::Rejected {
+                    id: peek_id(&envelope.payload),
+                    tenant: None,
+                    error: ErrorCode::BadSignature,
+                    message: e.to_string(),
+                };
+            }
+            (envelope.payload, signer.is_some())
+        }
+        Err(_) => (text.to_owned(), false),
+    };
+    let unsigned = require_signed && !signed;
+
+    let is_typed = serde_json::from_str::<Value>(&body)
+        .map(|v| v.get("type").is_some())
+        .unwrap_or(false);
+    if !is_typed {
+        if unsigned {
+            return unsigned_job(None, None);
+        }
+        return Decoded::Job(Job::plain(body));
+    }
+
+    match serde_json::from_str::<Inbound>(&body) {
+        Ok(Inbound::Job(job)) if unsigned => unsigned_job(job.id, job.tenant),
+        Ok(Inbound::Job(job)) => {
+            if !job.unknown.is_empty() {
+                let fields: Vec<&str> = job.unknown.keys().map(String::as_str).collect();
+                debug!(
+                    "Ignoring unknown job fields {:?} (job schema {:?}, ours {})",
+                    fields, job.schema_version, SCHEMA_VERSION
+                );
+            }
+            Decoded::Job(job)
+        }
+        Ok(Inbound::Fanout(Fanout { job, .. })) if unsigned => unsigned_job(job.id, job.tenant),
+        Ok(Inbound::Fanout(fanout)) => Decoded::Fanout(fanout),
+        Ok(Inbound::Command(frame)) if unsigned => Decoded::Refused {
+            command: frame.command.name(),
+            error: ErrorCode::UnsignedJob,
+            message: "Command is not signed".to_string(),
+        },
+        Ok(Inbound::Command(frame)) => Decoded::Command(frame),
+        Ok(Inbound::Preview { job }) => Decoded::Preview(job),
+        Ok(Inbound::Hello { time }) => Decoded::ServerHello { time },
+        Err(e) => Decoded::Rejected {
+            id: peek_id(&body),
+            tenant: None,
+            error: ErrorCode::InvalidJob,
+            message: e.to_string(),
+        },
+    }
+}
+
+fn unsigned_job(id: Option<String>, tenant: Option<String>) -> Decoded {
+    Decoded::Rejected {
+        id,
+        tenant,
+        error: ErrorCode::UnsignedJob,
+        message: "Job is not signed".to_string(),
+    }
+}
+
+/// Best-effort extraction of a job id from a payload we otherwise couldn't accept.
+fn peek_id(body: &str) -> Option<String> {
+    serde_json::from_str::<Value>(body)
+        .ok()?
+        .get("id")?
+        .as_str()
+        .map(str::to_owned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fanout_jobs_decode_with_their_targets() {
+        let text =
+            r#"{"type":"fanout","targets":["kitchen","bar"],"job":{"id":"7","text":"Soup"}}"#;
+        match decode(text, None, false) {
+            Decoded::Fanout(fanout) => {
+                assert_eq!(fanout.targets, ["kitchen", "bar"]);
+                assert_eq!(fanout.job.id.as_deref(), Some("7"));
+                assert_eq!(fanout.job.text, "Soup");
+            }
+            _ => panic!("not decoded as a fanout"),
+        }
+        let text = r#"{"type":"fanout","job":{"text":"Everywhere"}}"#;
+        assert!(matches!(
+            decode(text, None, false),
+            Decoded::Fanout(fanout) if fanout.targets.is_empty()
+        ));
+    }
+
+    #[test]
+    fn jobs_and_commands_name_their_printer() {
+        let text = r#"{"type":"job","target":"kitchen","text":"Soup"}"#;
+        match decode(text, None, false) {
+            Decoded::Job(job) => assert_eq!(job.target.as_deref(), Some("kitchen")),
+            _ => panic!("not decoded as a job"),
+        }
+        let text = r#"{"type":"command","command":"resume","target":"bar"}"#;
+        match decode(text, None, false) {
+            Decoded::Command(frame) => assert_eq!(frame.target.as_deref(), Some("bar")),
+            _ => panic!("not decoded as a command"),
+        }
+    }
+
+    #[test]
+    fn unsigned_jobs_and_commands_are_turned_away_when_signing_is_required() {
+        let signer = Signer::new(b"device key".to_vec(), 300).unwrap();
+        let job = r#"{"type":"job","id":"7","tenant":"cafe","text":"Soup"}"#;
+        match decode(job, Some(&signer), true) {
+            Decoded::Rejected {
+                id, tenant, error, ..
+            } => {
+                assert_eq!(id.as_deref(), Some("7"));
+                assert_eq!(tenant.as_deref(), Some("cafe"));
+                assert_eq!(error, ErrorCode::UnsignedJob);
+            }
+            other => panic!("unsigned job decoded as {:?}", other),
+        }
+        let fanout = r#"{"type":"fanout","job":{"id":"8","text":"Soup"}}"#;
+        assert!(matches!(
+            decode(fanout, Some(&signer), true),
+            Decoded::Rejected {
+                error: ErrorCode::UnsignedJob,
+                ..
+            }
+        ));
+        assert!(matches!(
+            decode("Plain ticket", Some(&signer), true),
+            Decoded::Rejected {
+                error: ErrorCode::UnsignedJob,
+                ..
+            }
+        ));
+        let command = r#"{"type":"command","command":"resume"}"#;
+        match decode(command, Some(&signer), true) {
+            Decoded::Refused { command, error, .. } => {
+                assert_eq!(command, "resume");
+                assert_eq!(error, ErrorCode::UnsignedJob);
+            }
+            other => panic!("unsigned command decoded as {:?}", other),
+        }
+        // The server's hello needn't be signed
+        assert!(matches!(
+            decode(r#"{"type":"hello","time":5}"#, Some(&signer), true),
+            Decoded::ServerHello { time: Some(5) }
+        ));
+
+        assert!(matches!(
+            decode(&signer.sign(job.to_string()), Some(&signer), true),
+            Decoded::Job(_)
+        ));
+        assert!(matches!(
+            decode(&signer.sign(command.to_string()), Some(&signer), true),
+            Decoded::Command(_)
+        ));
+        assert!(matches!(
+            decode(command, Some(&signer), false),
+            Decoded::Command(_)
+        ));
+    }
+
+    #[test]
+    fn replayed_and_tampered_frames_are_rejected() {
+        let signer = Signer::new(b"device key".to_vec(), 300).unwrap();
+        let signed = signer.sign(r#"{"type":"command","command":"pause"}"#.to_string());
+        assert!(matches!(
+            decode(&signed, Some(&signer), true),
+            Decoded::Command(_)
+        ));
+        assert!(matches!(
+            decode(&signed, Some(&signer), true),
+            Decoded::Rejected {
+                error: ErrorCode::BadSignature,
+                ..
+            }
+        ));
+
+        let tampered = signer
+            .sign(r#"{"type":"job","id":"9","text":"1 coffee"}"#.to_string())
+            .replace("1 coffee", "9 coffees");
+        match decode(&tampered, Some(&signer), false) {
+            Decoded::Rejected { id, error, .. } => {
+                assert_eq!(id.as_deref(), Some("9"));
+                assert_eq!(error, ErrorCode::BadSignature);
+            }
+            other => panic!("tampered job decoded as {:?}", other),
+        }
+    }
+
+    #[test]
+    fn frames_carry_the_printer_only_when_there_are_several() {
+        let ack = Outbound::ack(Some("7".to_string()), AckStatus::Printed);
+        let alone: Value = serde_json::from_str(&ack.encode(1, None, None)).unwrap();
+        assert!(alone.get("target").is_none());
+        let named: Value = serde_json::from_str(&ack.encode(2, Some("bar"), None)).unwrap();
+        assert_eq!(named["target"], "bar");
+        assert_eq!(named["seq"], 2);
+    }
+}
@@ -0,0 +1,549 @@
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use escpos::driver::{ConsoleDriver, Driver, NativeUsbDriver, NetworkDriver};
+use escpos::utils::JustifyMode;
+use log::{error, info};
+use rand::Rng;
+use serde_json::{Map, Value};
+use url::Url;
+
+use crate::config::{self, CONFIG_VERSION, DeviceConfig};
+use crate::driver::{self, SerialDriver, USB_PRODUCT_ID, USB_VENDOR_ID};
+use crate::profile::{self, PrinterProfile};
+use crate::render::{Rendered, Ticket};
+
+const KNOWN_FIELDS: &[&str] = &[
+    "url",
+    "token",
+    "device_id",
+    "enrollment_url",
+    "ip",
+    "port",
+    "serial",
+    "baud",
+    "profile",
+];
+
+#[derive(clap::Args, Debug)]
+pub struct ProvisionArgs {
+    /// Provisioning JSON file, e.g. on a USB stick (reads stdin if omitted or "-")
+    input: Option<PathBuf>,
+
+    /// Where to write the device config
+    #[arg(long, default_value = config::DEFAULT_CONFIG_PATH)]
+    config: PathBuf,
+
+    /// Print tickets to the console instead of the printer
+    #[arg(long)]
+    mock: bool,
+
+    /// Also print validation errors on paper
+    #[arg(long)]
+    print_errors: bool,
+
+    /// Don't print the confirmation ticket
+    #[arg(long)]
+    no_ticket: bool,
+}
+
+/// A provisioning field that failed validation.
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+/// Reads and validates a provisioning file, writes the device config and prints
+/// a confirmation ticket with a QR code for the admin app.
+pub fn run(args: &ProvisionArgs) -> Result<()> {
+    let text = read_input(args.input.as_deref())?;
+    let (config, errors) = match serde_json::from_str::<Value>(&text) {
+        Ok(value) => validate(&value),
+        Err(e) => (
+            new_config(),
+            vec![field_error("(file)", format!("not valid JSON: {}", e))],
+        ),
+    };
+
+    if !errors.is_empty() {
+        error!("Provisioning file is invalid:");
+        for e in &errors {
+            error!("  {}: {}", e.field, e.message);
+        }
+        if args.print_errors {
+            print(
+                &config,
+                args.mock,
+                &render_errors(&errors, printer_profile(&config))?,
+            )?;
+        }
+        return Err(anyhow::anyhow!(
+            "{} invalid provisioning field(s)",
+            errors.len()
+        ));
+    }
+
+    config::save(&args.config, &config)?;
+    info!(
+        "Device {} provisioned, config written to {}",
+        config.device_id,
+        args.config.display()
+    );
+
+    if !args.no_ticket {
+        print(
+            &config,
+            args.mock,
+            &render_confirmation(&config, &args.config, printer_profile(&config))?,
+        )?;
+    }
+    Ok(())
+}
+
+fn read_input(path: Option<&Path>) -> Result<String> {
+    match path {
+        Some(path) if path != Path::new("-") => std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read provisioning file {}", path.display())),
+        _ => {
+            let mut text = String::new();
+            std::io::stdin()
+                .read_to_string(&mut text)
+                .context("Failed to read provisioning JSON from stdin")?;
+            Ok(text)
+        }
+    }
+}
+
+/// Checks every field of a provisioning document. Invalid fields are reported
+/// and left unset in the returned config, so the printer fields can still be
+/// used to print the errors when they themselves are fine.
+pub fn validate(value: &Value) -> (DeviceConfig, Vec<FieldError>) {
+    let mut config = new_config();
+    let mut errors = Vec::new();
+
+    let Some(fields) = value.as_object() else {
+        errors.push(field_error("(file)", "must be a JSON object"));
+        return (config, errors);
+    };
+    for key in fields.keys() {
+        if !KNOWN_FIELDS.contains(&key.as_str()) {
+            errors.push(field_error(key, "unknown field"));
+        }
+    }
+
+    match string_field(fields, "url", &mut errors) {
+        Some(url) => match Url::parse(&url) {
+            Ok(url) if url.scheme() == "ws" || url.scheme() == "wss" => {
+                config.url = url.to_string()
+            }
+            Ok(url) => errors.push(field_error(
+                "url",
+                format!("must be a ws:// or wss:// URL, not {}://", url.scheme()),
+            )),
+            Err(e) => errors.push(field_error("url", format!("not a valid URL: {}", e))),
+        },
+        None if !fields.contains_key("url") => errors.push(field_error("url", "is required")),
+        None => {}
+    }
+
+    if let Some(token) = string_field(fields, "token", &mut errors)
+        && !config.url.is_empty()
+    {
+        let mut url = Url::parse(&config.url).expect("url was validated above");
+        if url.query_pairs().any(|(k, _)| k == "token") {
+            errors.push(field_error("token", "url already contains a token"));
+        } else {
+            url.query_pairs_mut().append_pair("token", &token);
+            config.url = url.to_string();
+        }
+    }
+
+    match string_field(fields, "device_id", &mut errors) {
+        Some(id) if is_valid_device_id(&id) => config.device_id = id,
+        Some(_) => errors.push(field_error(
+            "device_id",
+            "must be 1-64 letters, digits, '-' or '_'",
+        )),
+        None if !fields.contains_key("device_id") => {
+            config.device_id = hex::encode(rand::rng().random::<[u8; 6]>());
+        }
+        None => {}
+    }
+
+    match string_field(fields, "enrollment_url", &mut errors) {
+        Some(url) => match Url::parse(&url) {
+            Ok(url) if url.scheme() == "http" || url.scheme() == "https" => {
+                config.enrollment_url = url.to_string()
+            }
+            Ok(_) => errors.push(field_error(
+                "enrollment_url",
+                "must be an http:// or https:// URL",
+            )),
+            Err(e) => errors.push(field_error(
+                "enrollment_url",
+                format!("not a valid URL: {}", e),
+            )),
+        },
+        None if !fields.contains_key("enrollment_url") => {
+            errors.push(field_error("enrollment_url", "is required"))
+        }
+        None => {}
+    }
+
+    config.ip = string_field(fields, "ip", &mut errors);
+    config.serial = string_field(fields, "serial", &mut errors);
+    if config.ip.is_some() && config.serial.is_some() {
+        errors.push(field_error("serial", "cannot be combined with ip"));
+        config.serial = None;
+    }
+    config.port = integer_field(fields, "port", 1, u16::MAX as u64, &mut errors).map(|p| p as u16);
+    config.baud = integer_field(fields, "baud", 1, u32::MAX as u64, &mut errors).map(|b| b as u32);
+
+    match string_field(fields, "profile", &mut errors) {
+        Some(name) if PrinterProfile::find(&name).is_some() => config.profile = Some(name),
+        Some(name) => errors.push(field_error(
+            "profile",
+            format!(
+                "unknown profile {:?} (expected one of {})",
+                name,
+                profile::names().join(", ")
+            ),
+        )),
+        None => {}
+    }
+
+    (config, errors)
+}
+
+/// Link the admin app opens to register this device.
+pub fn enrollment_link(config: &DeviceConfig) -> String {
+    match Url::parse(&config.enrollment_url) {
+        Ok(mut url) => {
+            url.query_pairs_mut()
+                .append_pair("device_id", &config.device_id);
+            url.to_string()
+        }
+        Err(_) => config.device_id.clone(),
+    }
+}
+
+fn new_config() -> DeviceConfig {
+    DeviceConfig {
+        config_version: CONFIG_VERSION,
+        ..Default::default()
+    }
+}
+
+fn field_error(field: &str, message: impl Into<String>) -> FieldError {
+    FieldError {
+        field: field.to_owned(),
+        message: message.into(),
+    }
+}
+
+/// Returns a non-empty string field, recording an error if it has the wrong type.
+fn string_field(
+    fields: &Map<String, Value>,
+    name: &str,
+    errors: &mut Vec<FieldError>,
+) -> Option<String> {
+    match fields.get(name)? {
+        Value::String(s) if !s.trim().is_empty() => Some(s.trim().to_owned()),
+        Value::String(_) => {
+            errors.push(field_error(name, "must not be empty"));
+            None
+        }
+        other => {
+            errors.push(field_error(
+                name,
+                format!("must be a string, got {}", type_name(other)),
+            ));
+            None
+        }
+    }
+}
+
+fn integer_field(
+    fields: &Map<String, Value>,
+    name: &str,
+    min: u64,
+    max: u64,
+    errors: &mut Vec<FieldError>,
+) -> Option<u64> {
+    let value = fields.get(name)?;
+    match value.as_u64() {
+        Some(n) if (min..=max).contains(&n) => Some(n),
+        Some(_) => {
+            errors.push(field_error(
+                name,
+                format!("must be between {} and {}", min, max),
+            ));
+            None
+        }
+        None => {
+            errors.push(field_error(
+                name,
+                format!("must be an integer, got {}", value),
+            ));
+            None
+        }
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "a boolean",
+        Value::Number(_) => "a number",
+        Value::String(_) => "a string",
+        Value::Array(_) => "an array",
+        Value::Object(_) => "an object",
+    }
+}
+
+fn is_valid_device_id(id: &str) -> bool {
+    (1..=64).contains(&id.len())
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+fn render_confirmation(
+    config: &DeviceConfig,
+    path: &Path,
+    profile: &PrinterProfile,
+) -> Result<Rendered> {
+    let server = Url::parse(&config.url)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_owned))
+        .unwrap_or_default();
+
+    let mut ticket = Ticket::new(profile.commands)?;
+    ticket.printer.justify(JustifyMode::CENTER)?;
+    ticket.printer.bold(true)?;
+    ticket.line("DEVICE PROVISIONED")?;
+    ticket.printer.bold(false)?;
+    ticket.feed()?;
+    ticket.printer.qrcode(&enrollment_link(config))?;
+    ticket.feed()?;
+    ticket.line("Scan with the admin app")?;
+    ticket.line("to finish registration")?;
+    ticket.feed()?;
+    ticket.printer.justify(JustifyMode::LEFT)?;
+    ticket.line(&format!("Device: {}", config.device_id))?;
+    ticket.line(&format!("Server: {}", server))?;
+    ticket.line(&format!("Config: {}", path.display()))?;
+    ticket.finish(false)
+}
+
+fn render_errors(errors: &[FieldError], profile: &PrinterProfile) -> Result<Rendered> {
+    let mut ticket = Ticket::new(profile.commands)?;
+    ticket.printer.bold(true)?;
+    ticket.line("PROVISIONING FAILED")?;
+    ticket.printer.bold(false)?;
+    ticket.feed()?;
+    for e in errors {
+        ticket.line(&format!("{}: {}", e.field, e.message))?;
+    }
+    ticket.finish(false)
+}
+
+/// Profile named in the (possibly partial) config, or the default.
+pub fn printer_profile(config: &DeviceConfig) -> &'static PrinterProfile {
+    PrinterProfile::find(config.profile.as_deref().unwrap_or("default"))
+        .expect("profile was validated")
+}
+
+/// Prints a ticket on the printer described by the (possibly partial) config.
+pub fn print(config: &DeviceConfig, mock: bool, ticket: &Rendered) -> Result<()> {
+    let profile = printer_profile(config);
+    if mock {
+        write_once(&ConsoleDriver::open(true), ticket, profile)
+    } else if let Some(ip) = &config.ip {
+        let port = config.port.unwrap_or(9100);
+        write_once(
+            &NetworkDriver::open(ip, port, Some(Duration::from_secs(1)))?,
+            ticket,
+            profile,
+        )
+    } else if let Some(path) = &config.serial {
+        write_once(
+            &SerialDriver::open(path, config.baud.unwrap_or(9600), false)?,
+            ticket,
+            profile,
+        )
+    } else {
+        write_once(
+            &NativeUsbDriver::open(USB_VENDOR_ID, USB_PRODUCT_ID)?,
+            ticket,
+            profile,
+        )
+    }
+}
+
+fn write_once<D: Driver>(driver: &D, ticket: &Rendered, profile: &PrinterProfile) -> Result<()> {
+    driver::write_job(driver, ticket, profile)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::tempdir::TempDir;
+
+    fn complaints(value: &Value) -> Vec<(String, String)> {
+        validate(value)
+            .1
+            .into_iter()
+            .map(|e| (e.field, e.message))
+            .collect()
+    }
+
+    fn fields_wrong(value: &Value) -> Vec<String> {
+        complaints(value)
+            .into_iter()
+            .map(|(field, _)| field)
+            .collect()
+    }
+
+    fn valid() -> Value {
+        json!({
+            "url": "wss://print.example.com/ws",
+            "token": "abc 123",
+            "device_id": "kiosk-7",
+            "enrollment_url": "https://admin.example.com/enroll",
+            "ip": " 10.0.0.9 ",
+            "port": 9100,
+            "profile": "serial-58mm",
+        })
+    }
+
+    #[test]
+    fn a_valid_file_gives_a_complete_config() {
+        let (config, errors) = validate(&valid());
+        assert!(errors.is_empty());
+        assert_eq!(config.config_version, CONFIG_VERSION);
+        assert_eq!(config.url, "wss://print.example.com/ws?token=abc+123");
+        assert_eq!(config.device_id, "kiosk-7");
+        assert_eq!(config.ip.as_deref(), Some("10.0.0.9"));
+        assert_eq!(config.port, Some(9100));
+        assert_eq!(printer_profile(&config).name, "serial-58mm");
+        assert_eq!(
+            enrollment_link(&config),
+            "https://admin.example.com/enroll?device_id=kiosk-7"
+        );
+    }
+
+    #[test]
+    fn a_missing_device_id_is_generated() {
+        let mut value = valid();
+        value.as_object_mut().unwrap().remove("device_id");
+        let (config, errors) = validate(&value);
+        assert!(errors.is_empty());
+        assert_eq!(config.device_id.len(), 12);
+        assert!(is_valid_device_id(&config.device_id));
+    }
+
+    #[test]
+    fn every_invalid_field_is_reported() {
+        assert_eq!(
+            fields_wrong(&json!({"colour": "red"})),
+            ["colour", "url", "enrollment_url"]
+        );
+        assert_eq!(fields_wrong(&json!([])), ["(file)"]);
+
+        let cases = [
+            (
+                "url",
+                json!("https://print.example.com"),
+                "must be a ws:// or wss://",
+            ),
+            ("url", json!("not a url"), "not a valid URL"),
+            ("url", json!(42), "must be a string, got a number"),
+            ("device_id", json!("kiosk 7"), "must be 1-64"),
+            ("device_id", json!("a".repeat(65)), "must be 1-64"),
+            (
+                "enrollment_url",
+                json!("ftp://admin.example.com"),
+                "http:// or https://",
+            ),
+            ("enrollment_url", json!("  "), "must not be empty"),
+            ("port", json!(0), "between 1 and 65535"),
+            ("port", json!("9100"), "must be an integer"),
+            ("baud", json!(-1), "must be an integer"),
+            ("profile", json!("tm-u220"), "expected one of default"),
+        ];
+        for (field, bad, complaint) in cases {
+            let mut value = valid();
+            value[field] = bad.clone();
+            let found = complaints(&value);
+            assert_eq!(found.len(), 1, "{}: {:?}", field, found);
+            assert_eq!(found[0].0, field);
+            assert!(found[0].1.contains(complaint), "{}: {}", field, found[0].1);
+        }
+    }
+
+    #[test]
+    fn conflicting_fields_are_reported() {
+        let mut value = valid();
+        value["url"] = json!("wss://print.example.com/ws?token=old");
+        assert_eq!(fields_wrong(&value), ["token"]);
+
+        let mut value = valid();
+        value["serial"] = json!("/dev/ttyUSB0");
+        let (config, errors) = validate(&value);
+        assert_eq!(errors[0].field, "serial");
+        assert_eq!(config.serial, None);
+        assert!(config.ip.is_some());
+    }
+
+    #[test]
+    fn only_a_valid_file_is_written() {
+        let dir = TempDir::new("provision");
+        let input = dir.path().join("provision.json");
+        let args = ProvisionArgs {
+            input: Some(input.clone()),
+            config: dir.path().join("config.json"),
+            mock: true,
+            print_errors: false,
+            no_ticket: true,
+        };
+        std::fs::write(&input, "{not json").unwrap();
+        assert!(run(&args).is_err());
+        let mut invalid = valid();
+        invalid["port"] = json!(0);
+        std::fs::write(&input, invalid.to_string()).unwrap();
+        assert!(run(&args).is_err());
+        assert!(!args.config.exists());
+
+        std::fs::write(&input, valid().to_string()).unwrap();
+        run(&args).unwrap();
+        assert_eq!(config::load(&args.config).unwrap().device_id, "kiosk-7");
+    }
+
+    #[test]
+    fn the_tickets_show_what_was_set_and_what_was_wrong() {
+        let dir = TempDir::new("provision");
+        let (config, _) = validate(&valid());
+        let path = dir.path().join("config.json");
+        let rendered = render_confirmation(&config, &path, printer_profile(&config)).unwrap();
+        let contains = |rendered: &Rendered, text: &str| {
+            rendered
+                .bytes
+                .windows(text.len())
+                .any(|w| w == text.as_bytes())
+        };
+        assert!(contains(&rendered, "Device: kiosk-7"));
+        assert!(contains(&rendered, "Server: print.example.com"));
+        assert!(contains(&rendered, &enrollment_link(&config)));
+
+        let errors = [field_error("port", "must be between 1 and 65535")];
+        let rendered = render_errors(&errors, printer_profile(&config)).unwrap();
+        assert!(contains(&rendered, "PROVISIONING FAILED"));
+        assert!(contains(&rendered, "port: must be between 1 and 65535"));
+    }
+}
@@ -0,0 +1,167 @@
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// Token-bucket limit on how fast jobs reach the printer, plus a minimum gap
+/// between consecutive jobs. Used to share a printer with other systems that
+/// time out when we burst.
+#[derive(Clone)]
+pub struct RateLimiter {
+    /// Tokens regained per second (0 = no per-minute limit)
+    refill_per_sec: f64,
+    capacity: f64,
+    tokens: f64,
+    refilled_at: Instant,
+    min_gap: Duration,
+    last_job: Option<Instant>,
+}
+
+impl RateLimiter {
+    /// `max_per_minute` of 0 disables the bucket; `burst` is how many jobs may go
+    /// out back-to-back before the per-minute rate applies.
+    pub fn new(max_per_minute: u32, burst: u32, min_gap: Duration) -> Self {
+        let capacity = burst.max(1) as f64;
+        Self {
+            refill_per_sec: max_per_minute as f64 / 60.0,
+            capacity,
+            tokens: capacity,
+            refilled_at: Instant::now(),
+            min_gap,
+            last_job: None,
+        }
+    }
+
+    /// Changes the limits in place, keeping the tokens already spent and the
+    /// time of the last job.
+    pub fn reconfigure(&mut self, max_per_minute: u32, burst: u32, min_gap: Duration) {
+        self.refill(Instant::now());
+        self.refill_per_sec = max_per_minute as f64 / 60.0;
+        self.capacity = burst.max(1) as f64;
+        self.tokens = self.tokens.min(self.capacity);
+        self.min_gap = min_gap;
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.refill_per_sec > 0.0 || !self.min_gap.is_zero()
+    }
+
+    /// Earliest time the next job may start.
+    pub fn next_allowed(&mut self, now: Instant) -> Instant {
+        self.refill(now);
+        let mut at = now;
+
+        if self.refill_per_sec > 0.0 && self.tokens < 1.0 {
+            let wait = (1.0 - self.tokens) / self.refill_per_sec;
+            at = at.max(now + Duration::from_secs_f64(wait));
+        }
+        if let Some(last) = self.last_job {
+            at = at.max(last + self.min_gap);
+        }
+        at
+    }
+
+    /// How long the next job would currently have to wait.
+    pub fn delay(&mut self, now: Instant) -> Duration {
+        self.next_allowed(now).saturating_duration_since(now)
+    }
+
+    /// When a job would start if `ahead` jobs were queued in front of it, each
+    /// taking `job_time` to print and none starting before `earliest`.
+    pub fn start_after(
+        &self,
+        now: Instant,
+        earliest: Instant,
+        ahead: usize,
+        job_time: Duration,
+    ) -> Instant {
+        let mut limiter = self.clone();
+        let mut free_at = earliest.max(now);
+        for _ in 0..ahead {
+            // Jobs are recorded as they finish, as `Service::drain` does
+            free_at = limiter.next_allowed(free_at).max(free_at) + job_time;
+            limiter.record(free_at);
+        }
+        limiter.next_allowed(free_at).max(free_at)
+    }
+
+    /// Records that a job was sent to the printer.
+    pub fn record(&mut self, now: Instant) {
+        self.refill(now);
+        if self.refill_per_sec > 0.0 {
+            self.tokens = (self.tokens - 1.0).max(0.0);
+        }
+        self.last_job = Some(now);
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now
+            .saturating_duration_since(self.refilled_at)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+        self.refilled_at = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const JOB: Duration = Duration::from_millis(300);
+
+    #[test]
+    fn without_limits_the_queue_prints_back_to_back() {
+        let limiter = RateLimiter::new(0, 0, Duration::ZERO);
+        let now = Instant::now();
+        assert!(!limiter.is_enabled());
+        assert_eq!(limiter.start_after(now, now, 0, JOB), now);
+        assert_eq!(limiter.start_after(now, now, 4, JOB), now + JOB * 4);
+    }
+
+    #[test]
+    fn nothing_starts_before_the_earliest_time() {
+        let limiter = RateLimiter::new(0, 0, Duration::ZERO);
+        let now = Instant::now();
+        let resumes = now + Duration::from_secs(60);
+        assert_eq!(limiter.start_after(now, resumes, 2, JOB), resumes + JOB * 2);
+        // A time already past counts from now
+        let past = now - Duration::from_secs(60);
+        assert_eq!(limiter.start_after(now, past, 1, JOB), now + JOB);
+    }
+
+    #[test]
+    fn the_minimum_gap_follows_every_job() {
+        let gap = Duration::from_secs(2);
+        let mut limiter = RateLimiter::new(0, 0, gap);
+        let now = Instant::now();
+        assert_eq!(limiter.start_after(now, now, 0, JOB), now);
+        assert_eq!(limiter.start_after(now, now, 3, JOB), now + (JOB + gap) * 3);
+        // One that just finished holds up an empty queue too
+        limiter.record(now);
+        assert_eq!(limiter.start_after(now, now, 0, JOB), now + gap);
+    }
+
+    #[test]
+    fn the_burst_goes_first_then_the_per_minute_rate() {
+        // 6 a minute is one every 10 seconds, after a burst of 2
+        let mut limiter = RateLimiter::new(6, 2, Duration::ZERO);
+        let now = Instant::now();
+        assert_eq!(limiter.start_after(now, now, 1, JOB), now + JOB);
+        let third = limiter.start_after(now, now, 2, JOB);
+        // The tokens spent refill from when the jobs finish
+        let refilled = Duration::from_secs_f64(10.0 - JOB.as_secs_f64());
+        assert!(
+            third.duration_since(now + JOB * 2).abs_diff(refilled) < Duration::from_millis(5),
+            "{:?}",
+            third.duration_since(now)
+        );
+        // With the burst already spent, even an empty queue waits
+        limiter.record(now);
+        limiter.record(now);
+        let next = limiter.start_after(now, now, 0, JOB);
+        assert!(
+            next.duration_since(now).abs_diff(Duration::from_secs(10)) < Duration::from_millis(5)
+        );
+        // Estimating leaves the limiter as it was
+        assert_eq!(limiter.start_after(now, now, 0, JOB), next);
+    }
+}
@@ -0,0 +1,1694 @@
+use std::fmt::Write;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use escpos::driver::Driver;
+use escpos::printer::Printer;
+use escpos::utils::{JustifyMode, Protocol};
+
+use crate::clock;
+use crate::codepage::CodePage;
+use crate::commands::CommandSet;
+#[cfg(feature = "fallback-font")]
+use crate::fallback;
+use crate::footer::Footer;
+use crate::profile::{Font, PrinterProfile};
+use crate::protocol::{Accessibility, Job, ResourceRef, RuleStyle, Segment};
+
+/// A job rendered to raw ESC/POS bytes, ready to be written to a driver.
+pub struct Rendered {
+    pub bytes: Vec<u8>,
+    /// Offset where the trailing cut command starts; it must go out in the final write
+    pub cut_offset: usize,
+    /// Lines fed, for estimating paper use
+    pub lines: usize,
+}
+
+impl Rendered {
+    /// The ticket printed `copies` times over, each with its own cut.
+    pub fn repeat(self, copies: usize) -> Rendered {
+        if copies <= 1 {
+            return self;
+        }
+        let len = self.bytes.len();
+        Rendered {
+            cut_offset: len * (copies - 1) + self.cut_offset,
+            bytes: self.bytes.repeat(copies),
+            lines: self.lines * copies,
+        }
+    }
+}
+
+/// Driver that only records what is written to it, so a whole job can be built
+/// up front and sent to the real printer in as few writes as possible.
+#[derive(Clone, Default)]
+pub struct RecordingDriver {
+    buffer: Arc<Mutex<Vec<u8>>>,
+}
+
+impl RecordingDriver {
+    pub fn written(&self) -> usize {
+        self.buffer.lock().map(|b| b.len()).unwrap_or(0)
+    }
+
+    /// Returns everything recorded so far and clears the buffer.
+    pub fn take(&self) -> Vec<u8> {
+        self.buffer
+            .lock()
+            .map(|mut b| std::mem::take(&mut *b))
+            .unwrap_or_default()
+    }
+}
+
+impl Driver for RecordingDriver {
+    fn name(&self) -> String {
+        "recording".to_owned()
+    }
+
+    fn write(&self, data: &[u8]) -> escpos::errors::Result<()> {
+        self.buffer.lock()?.extend_from_slice(data);
+        Ok(())
+    }
+
+    fn read(&self, _buf: &mut [u8]) -> escpos::errors::Result<usize> {
+        Ok(0)
+    }
+
+    fn flush(&self) -> escpos::errors::Result<()> {
+        Ok(())
+    }
+}
+
+/// Bytes that put the printer back into its power-on state.
+pub fn init_sequence() -> Result<Vec<u8>> {
+    let driver = RecordingDriver::default();
+    Printer::new(driver.clone(), Protocol::default(), None)
+        .init()?
+        .print()?;
+    Ok(driver.take())
+}
+
+/// Every decision about vertical whitespace in a rendered job. Compact mode
+/// is a different policy rather than a separate code path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Spacing {
+    /// Font used unless the job or a segment picks one (`None` = the profile's)
+    pub font: Option<Font>,
+    /// Line spacing in dots unless the job or a segment sets it (`None` = the profile's)
+    pub line_spacing: Option<u8>,
+    /// Print empty lines in the text
+    pub blank_lines: bool,
+    /// Feed spacer segments
+    pub spacers: bool,
+    /// Draw rule segments
+    pub rules: bool,
+    /// Lines fed between the last line and the cut
+    pub pre_cut_feeds: u8,
+    /// Text size as (width, height) multipliers
+    pub text_size: (u8, u8),
+    /// Job and segment fonts are ignored in favour of `font`
+    pub fixed_font: bool,
+    /// Line spacing in dots is never set below this
+    pub min_line_spacing: Option<u8>,
+}
+
+impl Spacing {
+    pub const NORMAL: Spacing = Spacing {
+        font: None,
+        line_spacing: None,
+        blank_lines: true,
+        spacers: true,
+        rules: true,
+        pre_cut_feeds: 2,
+        text_size: (1, 1),
+        fixed_font: false,
+        min_line_spacing: None,
+    };
+
+    /// Saves paper: font B, tight lines, no blank lines, spacers or rules,
+    /// and a single feed before the cut.
+    pub const COMPACT: Spacing = Spacing {
+        font: Some(Font::B),
+        line_spacing: Some(20),
+        blank_lines: false,
+        spacers: false,
+        rules: false,
+        pre_cut_feeds: 1,
+        text_size: (1, 1),
+        fixed_font: false,
+        min_line_spacing: None,
+    };
+
+    /// Large print for low-vision staff: double-height font A everywhere,
+    /// with room between the lines. Wins over compact mode and font B.
+    pub const LARGE: Spacing = Spacing {
+        font: Some(Font::A),
+        line_spacing: Some(LARGE_LINE_SPACING),
+        blank_lines: true,
+        spacers: true,
+        rules: true,
+        pre_cut_feeds: 2,
+        text_size: (1, 2),
+        fixed_font: true,
+        min_line_spacing: Some(LARGE_LINE_SPACING),
+    };
+
+    /// Picks the spacing for `job`: large print, then compact, then normal.
+    pub fn for_job(job: &Job, profile: &PrinterProfile) -> Spacing {
+        let large = match job.accessibility {
+            Some(accessibility) => accessibility == Accessibility::Large,
+            None => profile.large_print,
+        };
+        if large {
+            Spacing::LARGE
+        } else if job.compact.unwrap_or(profile.compact) {
+            Spacing::COMPACT
+        } else {
+            Spacing::NORMAL
+        }
+    }
+
+    /// The style a job or segment asking for `font` and `line_spacing` gets,
+    /// falling back to `base`.
+    fn style(&self, font: Option<Font>, line_spacing: Option<u8>, base: Style) -> Style {
+        let font = if self.fixed_font {
+            base.font
+        } else {
+            font.unwrap_or(base.font)
+        };
+        let line_spacing = match (line_spacing.or(base.line_spacing), self.min_line_spacing) {
+            (Some(dots), Some(min)) => Some(dots.max(min)),
+            (dots, min) => dots.or(min),
+        };
+        Style {
+            font,
+            line_spacing,
+            text_size: self.text_size,
+        }
+    }
+}
+
+/// Line spacing of large print in dots: double-height font A is 48 dots
+/// tall, and the printer's default spacing leaves a quarter of that again
+const LARGE_LINE_SPACING: u8 = 60;
+
+/// A ticket being built in memory. Feeds, the cut and the cash drawer go
+/// through the profile's command set; everything else is written with
+/// `printer` directly.
+pub struct Ticket {
+    pub printer: Printer<RecordingDriver>,
+    driver: RecordingDriver,
+    commands: CommandSet,
+    lines: usize,
+    /// Lines past this are dropped and counted in `skipped`
+    max_lines: Option<usize>,
+    skipped: usize,
+    spacing: Spacing,
+    /// Dot rows per raster command (0 = no banding)
+    band_rows: usize,
+    code_page: CodePage,
+    /// Font in effect, for the size of fallback glyphs
+    font: Font,
+}
+
+/// Text encoded for the printer.
+#[derive(Default)]
+struct Encoded {
+    /// ESC & defining the fallback glyphs the text uses; has to go out
+    /// before the line it's on starts
+    definitions: Vec<u8>,
+    text: Vec<u8>,
+    /// Character cells it takes on paper
+    cells: usize,
+}
+
+/// The user-defined character codes fallback glyphs are given, one line at
+/// a time
+#[cfg(feature = "fallback-font")]
+const USER_CHARS: std::ops::RangeInclusive<u8> = 0x20..=0x7E;
+const SELECT_USER_CHARS: [u8; 3] = [0x1B, b'%', 1];
+const SELECT_RESIDENT_CHARS: [u8; 3] = [0x1B, b'%', 0];
+
+impl Ticket {
+    /// Starts a ticket with the printer reset to its power-on state.
+    pub fn new(commands: CommandSet) -> Result<Self> {
+        let mut ticket = Self::blank(commands);
+        ticket.printer.init()?;
+        Ok(ticket)
+    }
+
+    /// Starts a ticket that carries on from wherever the printer is.
+    fn blank(commands: CommandSet) -> Self {
+        let driver = RecordingDriver::default();
+        Self {
+            printer: Printer::new(driver.clone(), Protocol::default(), None),
+            driver,
+            commands,
+            lines: 0,
+            max_lines: None,
+            skipped: 0,
+            spacing: Spacing::NORMAL,
+            band_rows: 0,
+            code_page: CodePage::Cp437,
+            font: Font::A,
+        }
+    }
+
+    /// Encodes text in `code_page`; CP437 unless set.
+    pub fn set_code_page(&mut self, code_page: CodePage) {
+        self.code_page = code_page;
+    }
+
+    pub fn set_spacing(&mut self, spacing: Spacing) {
+        self.spacing = spacing;
+    }
+
+    pub fn set_raster_band_rows(&mut self, rows: usize) {
+        self.band_rows = rows;
+    }
+
+    /// Stops the ticket after `max_lines` lines; `finish` then prints a notice
+    /// saying how many were left out.
+    pub fn truncate_at(&mut self, max_lines: usize) {
+        self.max_lines = Some(max_lines);
+    }
+
+    fn full(&self) -> bool {
+        self.max_lines.is_some_and(|max| self.lines >= max)
+    }
+
+    /// Writes a line of text. Empty lines are dropped if the spacing says so.
+    pub fn line(&mut self, text: &str) -> Result<()> {
+        if text.is_empty() && !self.spacing.blank_lines {
+            return Ok(());
+        }
+        if self.full() {
+            self.skipped += 1;
+            return Ok(());
+        }
+        let encoded = self.encode(text);
+        self.printer.custom(&encoded.definitions)?;
+        self.printer.custom(&encoded.text)?;
+        self.feed()
+    }
+
+    /// Encodes a line of text in the code page. Characters it hasn't got are
+    /// drawn from the fallback font as user-defined characters where the
+    /// printer supports them, and printed as `?` otherwise.
+    fn encode(&self, text: &str) -> Encoded {
+        let mut encoded = Encoded::default();
+        let mut in_user_chars = false;
+        let mut fallback = self.fallback_glyphs();
+        for c in text.chars() {
+            let (bytes, user) = match self.code_page.encode(c) {
+                Some(b) => (vec![b], false),
+                None => match fallback.as_mut().and_then(|glyphs| glyphs.codes(c)) {
+                    Some(codes) => (codes, true),
+                    None => (vec![b'?'], false),
+                },
+            };
+            if user != in_user_chars {
+                encoded.text.extend_from_slice(if user {
+                    &SELECT_USER_CHARS
+                } else {
+                    &SELECT_RESIDENT_CHARS
+                });
+                in_user_chars = user;
+            }
+            encoded.cells += bytes.len();
+            encoded.text.extend_from_slice(&bytes);
+        }
+        if in_user_chars {
+            encoded.text.extend_from_slice(&SELECT_RESIDENT_CHARS);
+        }
+        if let Some(glyphs) = fallback {
+            encoded.definitions = glyphs.definitions();
+        }
+        encoded
+    }
+
+    /// Character cells `c` takes on paper: one, or as many as its fallback
+    /// glyph is wide.
+    fn cell_width(&self, c: char) -> usize {
+        if self.code_page.encode(c).is_some() {
+            return 1;
+        }
+        self.fallback_glyphs()
+            .and_then(|glyphs| glyphs.cell_count(c))
+            .unwrap_or(1)
+    }
+
+    /// Fallback glyphs for a line, if there's a fallback font and the printer
+    /// takes ESC/POS user-defined characters in CP437 mode.
+    #[cfg(feature = "fallback-font")]
+    fn fallback_glyphs(&self) -> Option<LineGlyphs> {
+        let font = fallback::font()?;
+        if self.commands != CommandSet::EscPos || self.code_page != CodePage::Cp437 {
+            return None;
+        }
+        let (cell_width, cell_height) = match self.font {
+            Font::A => (12, 24),
+            Font::B => (9, 17),
+        };
+        Some(LineGlyphs {
+            font,
+            cell_width,
+            cell_height,
+            codes: Vec::new(),
+            cells: Vec::new(),
+        })
+    }
+
+    #[cfg(not(feature = "fallback-font"))]
+    fn fallback_glyphs(&self) -> Option<LineGlyphs> {
+        None
+    }
+
+    pub fn feed(&mut self) -> Result<()> {
+        if self.full() {
+            self.skipped += 1;
+            return Ok(());
+        }
+        self.printer.custom(&self.commands.feed(1))?;
+        // A double-height line takes the paper of two
+        self.lines += self.spacing.text_size.1 as usize;
+        Ok(())
+    }
+
+    /// Feeds `dots` dot rows rather than whole lines.
+    pub fn feed_dots(&mut self, dots: u8) -> Result<()> {
+        if !self.full() {
+            self.printer.custom(&self.commands.feed_dots(dots))?;
+        }
+        Ok(())
+    }
+
+    /// Prints a 1-bit raster image `width_bytes` bytes wide and `height`
+    /// rows tall, in bands of at most `band_rows` rows. `row` fills in each
+    /// row in turn (starting from white), so only one band is ever held.
+    pub fn raster(
+        &mut self,
+        width_bytes: usize,
+        height: usize,
+        mut row: impl FnMut(&mut [u8]),
+    ) -> Result<()> {
+        if self.full() || width_bytes == 0 || height == 0 {
+            return Ok(());
+        }
+        let band_rows = match self.band_rows {
+            0 => height,
+            rows => rows.min(height),
+        };
+        let mut band = vec![0; band_rows * width_bytes];
+        let mut done = 0;
+        while done < height {
+            let band = &mut band[..band_rows.min(height - done) * width_bytes];
+            band.fill(0);
+            for line in band.chunks_mut(width_bytes) {
+                row(line);
+            }
+            self.printer
+                .custom(&self.commands.raster(width_bytes, band))?;
+            // Out of the printer's buffer, so that doesn't hold the image either
+            self.printer.print()?;
+            done += band.len() / width_bytes;
+        }
+        Ok(())
+    }
+
+    /// Feeds towards the cutter and cuts, optionally kicking the cash drawer
+    /// afterwards.
+    pub fn finish(mut self, open_drawer: bool) -> Result<Rendered> {
+        self.max_lines = None;
+        if self.skipped > 0 {
+            let notice = format!("*** {} MORE LINES CUT ***", self.skipped);
+            self.line(&notice)?;
+        }
+        for _ in 0..self.spacing.pre_cut_feeds {
+            self.feed()?;
+        }
+        self.printer.print()?;
+
+        let cut_offset = self.driver.written();
+        self.printer.custom(&self.commands.cut())?;
+        if open_drawer {
+            self.printer.custom(&self.commands.cash_drawer())?;
+        }
+        self.printer.print()?;
+
+        Ok(Rendered {
+            bytes: self.driver.take(),
+            cut_offset,
+            lines: self.lines,
+        })
+    }
+}
+
+/// Renders a job: init, the text and any segments, two feeds and a full cut.
+///
+/// Text is word-wrapped at the column count of whichever font is active, so
+/// lines break where they would on paper. Rules and boxes are sized the same
+/// way. Font and line spacing are reset before the cut so the next job starts
+/// from the printer's defaults.
+pub fn render_job(
+    job: &Job,
+    profile: &PrinterProfile,
+    footer: Option<&Footer>,
+) -> Result<Rendered> {
+    Ok(render(job, profile, footer, None)?.0)
+}
+
+/// Renders one copy of a job cut off after `max_lines` lines, with a notice
+/// saying how much was left out.
+pub fn render_job_truncated(
+    job: &Job,
+    profile: &PrinterProfile,
+    footer: Option<&Footer>,
+    max_lines: usize,
+) -> Result<Rendered> {
+    Ok(render(job, profile, footer, Some(max_lines))?.0)
+}
+
+/// Renders a job as `render_job` does, along with a template for printing it
+/// again that only renders its volatile blocks.
+pub fn render_template(
+    job: &Job,
+    profile: &PrinterProfile,
+    footer: Option<&Footer>,
+) -> Result<(Rendered, Template)> {
+    let (rendered, blocks) = render(job, profile, footer, None)?;
+    let template = Template::new(&rendered, blocks);
+    Ok((rendered, template))
+}
+
+fn render(
+    job: &Job,
+    profile: &PrinterProfile,
+    footer: Option<&Footer>,
+    max_lines: Option<usize>,
+) -> Result<(Rendered, Vec<VolatileBlock>)> {
+    let (mut ticket, mut layout) = start(job, profile);
+    ticket.printer.init()?;
+    if let Some(max_lines) = max_lines {
+        ticket.truncate_at(max_lines);
+    }
+
+    ticket.printer.smoothing(true)?;
+    layout.style.switch(&mut ticket, layout.job_style)?;
+    if job.segments.is_empty() || !job.text.is_empty() {
+        write_wrapped(&mut ticket, &job.text, layout.columns())?;
+    }
+    let mut blocks = Vec::new();
+    for segment in &job.segments {
+        let segment = std::slice::from_ref(segment);
+        if !volatile(&segment[0]) {
+            layout.segments(&mut ticket, segment, 0)?;
+            continue;
+        }
+        // Flushed on both sides, so the block's bytes can be cut out after
+        ticket.printer.print()?;
+        let (bytes, lines, style) = (ticket.driver.written(), ticket.lines, layout.style);
+        layout.segments(&mut ticket, segment, 0)?;
+        ticket.printer.print()?;
+        blocks.push(VolatileBlock {
+            segment: segment[0].clone(),
+            style,
+            bytes: bytes..ticket.driver.written(),
+            lines: lines..ticket.lines,
+        });
+    }
+    if let Some(footer) = footer {
+        layout.footer(&mut ticket, footer)?;
+    }
+    layout.style.switch(&mut ticket, Style::default())?;
+
+    Ok((ticket.finish(job.open_drawer)?, blocks))
+}
+
+/// A ticket with nothing written yet and the layout for `job`.
+fn start<'a>(job: &Job, profile: &'a PrinterProfile) -> (Ticket, Layout<'a>) {
+    let spacing = Spacing::for_job(job, profile);
+    let mut ticket = Ticket::blank(profile.commands);
+    ticket.set_spacing(spacing);
+    if !profile.graphics {
+        ticket.set_code_page(CodePage::Ascii);
+    }
+    ticket.set_raster_band_rows(profile.raster_band_rows);
+
+    let profile_style = Style {
+        font: spacing.font.unwrap_or(profile.font),
+        line_spacing: spacing.line_spacing.or(profile.line_spacing),
+        text_size: (1, 1),
+    };
+    let layout = Layout {
+        profile,
+        spacing,
+        job_style: spacing.style(job.font, job.line_spacing, profile_style),
+        style: Style::default(),
+        glyphs: if profile.graphics { &CP437 } else { &ASCII },
+    };
+    (ticket, layout)
+}
+
+/// Whether a top-level segment prints differently from one time to the next.
+fn volatile(segment: &Segment) -> bool {
+    match segment {
+        Segment::Timestamp { .. } => true,
+        Segment::Box { segments } => segments.iter().any(volatile),
+        _ => false,
+    }
+}
+
+/// Where a volatile segment went in a rendered job.
+struct VolatileBlock {
+    segment: Segment,
+    /// Style in effect before it
+    style: Style,
+    bytes: Range<usize>,
+    /// Lines fed before and after it
+    lines: Range<usize>,
+}
+
+/// A rendered job with its volatile blocks cut out, so an identical job can
+/// be printed again by rendering only those.
+pub struct Template {
+    parts: Vec<Part>,
+    /// Bytes from the cut command on
+    tail: usize,
+}
+
+enum Part {
+    Fixed {
+        bytes: Vec<u8>,
+        lines: usize,
+    },
+    /// Rendered again every time, from the style in effect before it
+    Volatile {
+        segment: Segment,
+        style: Style,
+    },
+}
+
+impl Template {
+    fn new(rendered: &Rendered, blocks: Vec<VolatileBlock>) -> Self {
+        let mut parts = Vec::new();
+        let (mut bytes, mut lines) = (0, 0);
+        for block in blocks {
+            parts.push(Part::Fixed {
+                bytes: rendered.bytes[bytes..block.bytes.start].to_vec(),
+                lines: block.lines.start - lines,
+            });
+            parts.push(Part::Volatile {
+                segment: block.segment,
+                style: block.style,
+            });
+            (bytes, lines) = (block.bytes.end, block.lines.end);
+        }
+        parts.push(Part::Fixed {
+            bytes: rendered.bytes[bytes..].to_vec(),
+            lines: rendered.lines - lines,
+        });
+        Self {
+            parts,
+            tail: rendered.bytes.len() - rendered.cut_offset,
+        }
+    }
+
+    /// Bytes held.
+    pub fn size(&self) -> usize {
+        self.parts
+            .iter()
+            .map(|part| match part {
+                Part::Fixed { bytes, .. } => bytes.len(),
+                Part::Volatile { .. } => 0,
+            })
+            .sum()
+    }
+
+    /// The ticket for `job`, which must render the same as the job the
+    /// template was made from.
+    pub fn fill(&self, job: &Job, profile: &PrinterProfile) -> Result<Rendered> {
+        let (mut bytes, mut lines) = (Vec::new(), 0);
+        for part in &self.parts {
+            match part {
+                Part::Fixed {
+                    bytes: fixed,
+                    lines: fed,
+                } => {
+                    bytes.extend_from_slice(fixed);
+                    lines += fed;
+                }
+                Part::Volatile { segment, style } => {
+                    let (mut ticket, mut layout) = start(job, profile);
+                    ticket.font = style.font;
+                    layout.style = *style;
+                    layout.segments(&mut ticket, std::slice::from_ref(segment), 0)?;
+                    ticket.printer.print()?;
+                    bytes.extend(ticket.driver.take());
+                    lines += ticket.lines;
+                }
+            }
+        }
+        Ok(Rendered {
+            cut_offset: bytes.len() - self.tail,
+            bytes,
+            lines,
+        })
+    }
+}
+
+/// Characters for rules and box borders, as printer bytes.
+struct Glyphs {
+    horizontal: u8,
+    vertical: u8,
+    /// Top left, top right, bottom left, bottom right
+    corners: [u8; 4],
+    shade: u8,
+}
+
+/// Code page 437, the printer's default after init
+const CP437: Glyphs = Glyphs {
+    horizontal: 0xC4,
+    vertical: 0xB3,
+    corners: [0xDA, 0xBF, 0xC0, 0xD9],
+    shade: 0xB0,
+};
+
+const ASCII: Glyphs = Glyphs {
+    horizontal: b'-',
+    vertical: b'|',
+    corners: [b'+'; 4],
+    shade: b'.',
+};
+
+/// Font A is 12 dots wide, so the print width in dots is its column count times 12.
+const FONT_A_DOTS: usize = 12;
+/// Height of a solid rule in dot rows
+const SOLID_RULE_DOTS: usize = 2;
+
+/// Renders segments, keeping track of the style and how deep in boxes we are.
+struct Layout<'a> {
+    profile: &'a PrinterProfile,
+    spacing: Spacing,
+    job_style: Style,
+    style: Style,
+    glyphs: &'static Glyphs,
+}
+
+impl Layout<'_> {
+    /// Characters per line in the active font and text size.
+    fn columns(&self) -> usize {
+        (self.profile.columns_for(self.style.font) / self.style.text_size.0.max(1) as usize).max(1)
+    }
+
+    /// Renders `segments` inside `depth` boxes.
+    fn segments(&mut self, ticket: &mut Ticket, segments: &[Segment], depth: usize) -> Result<()> {
+        for segment in segments {
+            match segment {
+                Segment::Text(segment) => {
+                    let style =
+                        self.spacing
+                            .style(segment.font, segment.line_spacing, self.job_style);
+                    self.text(ticket, &segment.text, style, depth)?;
+                }
+                Segment::Timestamp { timestamp } => {
+                    self.text(ticket, &local_time(timestamp), self.job_style, depth)?
+                }
+                Segment::Rule { .. } if !self.spacing.rules => {}
+                Segment::Rule { rule } => self.rule(ticket, *rule, depth)?,
+                Segment::Spacer { .. } if !self.spacing.spacers => {}
+                // Inside a box a dot feed would break the side borders, so
+                // settle for one empty line
+                Segment::Spacer { spacer } if depth > 0 => {
+                    if *spacer > 0 {
+                        self.boxed_line(ticket, b"", 0, depth)?;
+                    }
+                }
+                Segment::Spacer { spacer } => ticket.feed_dots(*spacer)?,
+                Segment::Image { image_ref } => self.image(ticket, image_ref, depth)?,
+                Segment::Box { segments } => {
+                    let [top_left, top_right, bottom_left, bottom_right] = self.glyphs.corners;
+                    let width = self.inner_columns(depth);
+                    if width < 4 {
+                        // Too deep to fit another border
+                        self.segments(ticket, segments, depth)?;
+                        continue;
+                    }
+                    let border = |left, right| {
+                        let mut line = vec![left];
+                        line.resize(width - 1, self.glyphs.horizontal);
+                        line.push(right);
+                        line
+                    };
+                    // The bottom border has to match the top one
+                    let outer = self.style;
+                    self.boxed_line(ticket, &border(top_left, top_right), width, depth)?;
+                    self.segments(ticket, segments, depth + 1)?;
+                    self.style.switch(ticket, outer)?;
+                    self.boxed_line(ticket, &border(bottom_left, bottom_right), width, depth)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Prints `text` in `style`, wrapped to the width inside `depth` boxes.
+    fn text(&mut self, ticket: &mut Ticket, text: &str, style: Style, depth: usize) -> Result<()> {
+        self.style.switch(ticket, style)?;
+        if depth == 0 {
+            return write_wrapped(ticket, text, self.columns());
+        }
+        for line in wrap(text, self.inner_columns(depth), |c| ticket.cell_width(c)) {
+            if line.is_empty() && !self.spacing.blank_lines {
+                continue;
+            }
+            let encoded = ticket.encode(&line);
+            ticket.printer.custom(&encoded.definitions)?;
+            self.boxed_line(ticket, &encoded.text, encoded.cells, depth)?;
+        }
+        Ok(())
+    }
+
+    /// Prints `footer` centred in the job's style, with its QR code and
+    /// barcode below. Without graphics the QR data is printed as text.
+    fn footer(&mut self, ticket: &mut Ticket, footer: &Footer) -> Result<()> {
+        self.style.switch(ticket, self.job_style)?;
+        ticket.printer.justify(JustifyMode::CENTER)?;
+        for line in &footer.lines {
+            write_wrapped(ticket, line, self.columns())?;
+        }
+        if let Some(data) = &footer.qr {
+            if !self.profile.graphics {
+                write_wrapped(ticket, data, self.columns())?;
+            } else if !ticket.full() {
+                ticket.printer.qrcode(data)?;
+                ticket.feed()?;
+            }
+        }
+        if let Some(data) = &footer.barcode
+            && !ticket.full()
+        {
+            ticket.printer.code39(data)?;
+            ticket.feed()?;
+        }
+        ticket.printer.justify(JustifyMode::LEFT)?;
+        Ok(())
+    }
+
+    fn rule(&mut self, ticket: &mut Ticket, style: RuleStyle, depth: usize) -> Result<()> {
+        if style == RuleStyle::Solid && depth == 0 && self.profile.graphics {
+            let width_bytes = self.profile.columns * FONT_A_DOTS / 8;
+            return ticket.raster(width_bytes, SOLID_RULE_DOTS, |row| row.fill(0xFF));
+        }
+        let glyph = match style {
+            RuleStyle::Dash => b'-',
+            RuleStyle::Double => b'=',
+            RuleStyle::Shade => self.glyphs.shade,
+            RuleStyle::Solid => self.glyphs.horizontal,
+        };
+        let width = self.inner_columns(depth);
+        self.boxed_line(ticket, &vec![glyph; width], width, depth)
+    }
+
+    /// Prints a fetched PBM or PGM image centred across the paper, cropped
+    /// if it's wider. Inside a box, on printers without graphics, or if the
+    /// image wasn't fetched (e.g. in previews) or isn't a PBM or PGM, prints
+    /// a placeholder.
+    fn image(&mut self, ticket: &mut Ticket, image: &ResourceRef, depth: usize) -> Result<()> {
+        let width_bytes = self.profile.columns * FONT_A_DOTS / 8;
+        if depth == 0
+            && self.profile.graphics
+            && let Some(data) = &image.data
+            && let Some(image) = Image::parse(&data.0)
+        {
+            let mut rows = image.rows(width_bytes * 8);
+            return ticket.raster(width_bytes, image.height, |row| rows.next(row));
+        }
+        let width = self.inner_columns(depth);
+        let placeholder: Vec<u8> = b"[image]".iter().copied().take(width).collect();
+        self.boxed_line(ticket, &placeholder, placeholder.len(), depth)
+    }
+
+    /// Columns left inside `depth` boxes, each taking `| ` and ` |`.
+    fn inner_columns(&self, depth: usize) -> usize {
+        self.columns().saturating_sub(depth * 4).max(1)
+    }
+
+    /// Writes one line of `text` (printer bytes printing `len` characters, at
+    /// most the inner width) between the borders of `depth` boxes.
+    fn boxed_line(&self, ticket: &mut Ticket, text: &[u8], len: usize, depth: usize) -> Result<()> {
+        let vertical = self.glyphs.vertical;
+        let mut line = Vec::new();
+        for _ in 0..depth {
+            line.extend_from_slice(&[vertical, b' ']);
+        }
+        line.extend_from_slice(text);
+        if depth > 0 {
+            line.resize(
+                line.len() + self.inner_columns(depth).saturating_sub(len),
+                b' ',
+            );
+        }
+        for _ in 0..depth {
+            line.extend_from_slice(&[b' ', vertical]);
+        }
+        ticket.printer.custom(&line)?;
+        ticket.feed()
+    }
+}
+
+/// An image file, read a row at a time straight out of the file's bytes so
+/// that only a row of it is ever decoded.
+struct Image<'a> {
+    width: usize,
+    height: usize,
+    format: Format,
+    pixels: &'a [u8],
+}
+
+#[derive(Clone, Copy)]
+enum Format {
+    /// Binary PBM (P4): rows packed 8 dots to a byte with the leftmost dot
+    /// in the high bit and 1 meaning black, as in ESC/POS raster data
+    Bits,
+    /// Binary PGM (P5): a byte a dot, from 0 (black) to this (white)
+    Gray(u8),
+}
+
+impl<'a> Image<'a> {
+    /// Parses a binary PBM (P4) or PGM (P5) with at most 8 bits a dot.
+    fn parse(bytes: &'a [u8]) -> Option<Self> {
+        let (format, fields) = match bytes.get(..2)? {
+            b"P4" => (Format::Bits, 2),
+            b"P5" => (Format::Gray(0), 3),
+            _ => return None,
+        };
+        let mut pos = 2;
+        let mut header = [0usize; 3];
+        for value in &mut header[..fields] {
+            // Whitespace and comments up to the number
+            loop {
+                match *bytes.get(pos)? {
+                    b'#' => {
+                        while *bytes.get(pos)? != b'\n' {
+                            pos += 1;
+                        }
+                    }
+                    b if b.is_ascii_whitespace() => pos += 1,
+                    _ => break,
+                }
+            }
+            let start = pos;
+            while bytes.get(pos).is_some_and(u8::is_ascii_digit) {
+                pos += 1;
+            }
+            *value = std::str::from_utf8(&bytes[start..pos]).ok()?.parse().ok()?;
+        }
+        // Exactly one whitespace byte before the pixels
+        pos += 1;
+        let [width, height, max] = header;
+        let (format, row_bytes) = match format {
+            Format::Bits => (Format::Bits, width.div_ceil(8)),
+            Format::Gray(_) => (
+                Format::Gray(u8::try_from(max).ok().filter(|&max| max > 0)?),
+                width,
+            ),
+        };
+        let len = row_bytes.checked_mul(height)?;
+        let pixels = bytes.get(pos..pos.checked_add(len)?)?;
+        Some(Self {
+            width,
+            height,
+            format,
+            pixels,
+        })
+    }
+
+    /// The image's rows, top to bottom, centred in rows `width` dots wide (a
+    /// multiple of 8) and cropped equally on both sides if it's wider. Grey
+    /// dots are dithered.
+    fn rows(&self, width: usize) -> Rows<'_> {
+        let (skip, pad) = if self.width > width {
+            ((self.width - width) / 2, 0)
+        } else {
+            (0, (width - self.width) / 2)
+        };
+        let visible = self.width.min(width);
+        Rows {
+            image: self,
+            y: 0,
+            skip,
+            pad,
+            visible,
+            error: vec![0; visible + 2],
+            next_error: vec![0; visible + 2],
+        }
+    }
+}
+
+/// An [`Image`]'s rows as printer dots, one at a time.
+struct Rows<'a> {
+    image: &'a Image<'a>,
+    y: usize,
+    /// Dots cropped off the left of the image
+    skip: usize,
+    /// White dots to the left of the image
+    pad: usize,
+    /// Dots of the image on paper
+    visible: usize,
+    /// Floyd-Steinberg error carried into this row and the next, one entry
+    /// either side of the visible dots
+    error: Vec<i32>,
+    next_error: Vec<i32>,
+}
+
+impl Rows<'_> {
+    /// Sets the black dots of the next row in `out`, which starts white.
+    fn next(&mut self, out: &mut [u8]) {
+        let image = self.image;
+        if self.y >= image.height {
+            return;
+        }
+        match image.format {
+            Format::Bits => {
+                let row_bytes = image.width.div_ceil(8);
+                let row = &image.pixels[self.y * row_bytes..(self.y + 1) * row_bytes];
+                for x in 0..self.visible {
+                    let (from, to) = (x + self.skip, x + self.pad);
+                    if row[from / 8] & (0x80 >> (from % 8)) != 0 {
+                        out[to / 8] |= 0x80 >> (to % 8);
+                    }
+                }
+            }
+            Format::Gray(max) => {
+                let row = &image.pixels[self.y * image.width..(self.y + 1) * image.width];
+                self.next_error.fill(0);
+                for x in 0..self.visible {
+                    let level = row[x + self.skip].min(max) as i32 * 255 / max as i32;
+                    let wanted = level + self.error[x + 1];
+                    let printed = if wanted < 128 {
+                        let to = x + self.pad;
+                        out[to / 8] |= 0x80 >> (to % 8);
+                        0
+                    } else {
+                        255
+                    };
+                    let error = wanted - printed;
+                    self.error[x + 2] += error * 7 / 16;
+                    self.next_error[x] += error * 3 / 16;
+                    self.next_error[x + 1] += error * 5 / 16;
+                    self.next_error[x + 2] += error / 16;
+                }
+                std::mem::swap(&mut self.error, &mut self.next_error);
+            }
+        }
+        self.y += 1;
+    }
+}
+
+/// The fallback glyphs one line uses, each given the next free user-defined
+/// character codes.
+#[cfg(feature = "fallback-font")]
+struct LineGlyphs {
+    font: &'static fallback::FallbackFont,
+    cell_width: usize,
+    cell_height: usize,
+    /// Characters drawn so far and their codes
+    codes: Vec<(char, Vec<u8>)>,
+    /// ESC & data of each code from the start of `USER_CHARS`
+    cells: Vec<Vec<u8>>,
+}
+
+#[cfg(feature = "fallback-font")]
+impl LineGlyphs {
+    /// Codes printing `c`, one per character cell it takes. `None` if the
+    /// font hasn't got it or the line ran out of codes.
+    fn codes(&mut self, c: char) -> Option<Vec<u8>> {
+        if let Some((_, codes)) = self.codes.iter().find(|(drawn, _)| *drawn == c) {
+            return Some(codes.clone());
+        }
+        let cells = self.font.cells(c, self.cell_width, self.cell_height)?;
+        let first = USER_CHARS.start() + self.cells.len() as u8;
+        if first as usize + cells.len() > *USER_CHARS.end() as usize + 1 {
+            return None;
+        }
+        let codes: Vec<u8> = (first..first + cells.len() as u8).collect();
+        self.cells.extend(cells);
+        self.codes.push((c, codes.clone()));
+        Some(codes)
+    }
+
+    /// How many cells `c` takes, without handing out codes for it.
+    fn cell_count(&self, c: char) -> Option<usize> {
+        self.font.cell_count(c, self.cell_width, self.cell_height)
+    }
+
+    /// ESC & defining every code handed out, or nothing if none were.
+    fn definitions(&self) -> Vec<u8> {
+        if self.cells.is_empty() {
+            return Vec::new();
+        }
+        let first = *USER_CHARS.start();
+        let mut command = vec![0x1B, b'&', 3, first, first + self.cells.len() as u8 - 1];
+        for cell in &self.cells {
+            command.push(self.cell_width as u8);
+            command.extend_from_slice(cell);
+        }
+        command
+    }
+}
+
+/// Stands in for the fallback glyphs in builds without them.
+#[cfg(not(feature = "fallback-font"))]
+struct LineGlyphs;
+
+#[cfg(not(feature = "fallback-font"))]
+impl LineGlyphs {
+    fn codes(&mut self, _c: char) -> Option<Vec<u8>> {
+        None
+    }
+
+    fn cell_count(&self, _c: char) -> Option<usize> {
+        None
+    }
+
+    fn definitions(&self) -> Vec<u8> {
+        Vec::new()
+    }
+}
+
+/// Font, line spacing and text size in effect; the default is the printer's
+/// state after init.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Style {
+    font: Font,
+    line_spacing: Option<u8>,
+    /// (width, height) multipliers
+    text_size: (u8, u8),
+}
+
+impl Default for Style {
+    fn default() -> Self {
+        Self {
+            font: Font::A,
+            line_spacing: None,
+            text_size: (1, 1),
+        }
+    }
+}
+
+impl Style {
+    /// Emits only the commands needed to get from this style to `to`, so plain
+    /// jobs render exactly as before these options existed.
+    fn switch(&mut self, ticket: &mut Ticket, to: Style) -> Result<()> {
+        ticket.font = to.font;
+        let printer = &mut ticket.printer;
+        if to.font != self.font {
+            printer.font(match to.font {
+                Font::A => escpos::utils::Font::A,
+                Font::B => escpos::utils::Font::B,
+            })?;
+        }
+        if to.line_spacing != self.line_spacing {
+            match to.line_spacing {
+                Some(dots) => printer.line_spacing(dots)?,
+                None => printer.reset_line_spacing()?,
+            };
+        }
+        if to.text_size != self.text_size {
+            match to.text_size {
+                (1, 1) => printer.reset_size()?,
+                (width, height) => printer.size(width, height)?,
+            };
+        }
+        *self = to;
+        Ok(())
+    }
+}
+
+/// The corrected local time in strftime `format`, or the format itself if
+/// it isn't valid.
+fn local_time(format: &str) -> String {
+    let mut text = String::new();
+    match write!(text, "{}", clock::local_now().format(format)) {
+        Ok(()) => text,
+        Err(_) => format.to_string(),
+    }
+}
+
+fn write_wrapped(ticket: &mut Ticket, text: &str, columns: usize) -> Result<()> {
+    for line in wrap(text, columns, |c| ticket.cell_width(c)) {
+        ticket.line(&line)?;
+    }
+    Ok(())
+}
+
+/// Word-wraps each line of `text` to at most `columns` character cells,
+/// `width` giving the cells each character takes. Lines that already fit
+/// are kept verbatim (including any spacing used for alignment); words
+/// longer than a line are broken.
+pub fn wrap(text: &str, columns: usize, width: impl Fn(char) -> usize) -> Vec<String> {
+    let columns = columns.max(1);
+    let measure = |text: &str| text.chars().map(&width).sum::<usize>();
+    let mut lines = Vec::new();
+
+    for line in text.split('\n') {
+        if measure(line) <= columns {
+            lines.push(line.to_owned());
+            continue;
+        }
+
+        let mut current = String::new();
+        let mut current_len = 0;
+        for word in line.split(' ') {
+            if current_len > 0 && current_len + 1 + measure(word) > columns {
+                lines.push(std::mem::take(&mut current));
+                current_len = 0;
+            }
+            if current_len > 0 {
+                current.push(' ');
+                current_len += 1;
+            }
+            for c in word.chars() {
+                let cells = width(c);
+                // A character wider than the whole line still gets one
+                if current_len > 0 && current_len + cells > columns {
+                    lines.push(std::mem::take(&mut current));
+                    current_len = 0;
+                }
+                current.push(c);
+                current_len += cells;
+            }
+        }
+        lines.push(current);
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::counting;
+    use crate::protocol::{ResourceData, TextSegment};
+    use crate::transcript;
+
+    /// `中` takes two cells, everything else one.
+    fn cjk(c: char) -> usize {
+        if c == '中' { 2 } else { 1 }
+    }
+
+    #[test]
+    fn single_width_text_wraps_at_words() {
+        assert_eq!(wrap("hello world", 5, |_| 1), ["hello", "world"]);
+        assert_eq!(wrap("  fits  ", 8, |_| 1), ["  fits  "]);
+        assert_eq!(wrap("abcdefgh", 3, |_| 1), ["abc", "def", "gh"]);
+    }
+
+    #[test]
+    fn wide_characters_take_two_cells() {
+        // Four characters, but six cells
+        assert_eq!(wrap("中中 a", 5, cjk), ["中中", "a"]);
+        assert_eq!(
+            wrap("1x 中中 noodles", 6, cjk),
+            ["1x", "中中", "noodle", "s"]
+        );
+        assert_eq!(wrap("a中 b", 5, cjk), ["a中 b"]);
+        assert_eq!(wrap("a中 b", 4, cjk), ["a中", "b"]);
+    }
+
+    #[test]
+    fn wide_characters_are_never_split() {
+        // The third `中` would need a sixth cell
+        assert_eq!(wrap("中中中", 5, cjk), ["中中", "中"]);
+        assert_eq!(wrap("ab中", 3, cjk), ["ab", "中"]);
+        // Wider than the line, but it still gets one to itself
+        assert_eq!(wrap("中中", 1, cjk), ["中", "中"]);
+    }
+
+    fn profile() -> PrinterProfile {
+        PrinterProfile::find("default").unwrap().clone()
+    }
+
+    fn image_job(image: Vec<u8>) -> Job {
+        let mut job = Job::plain(String::new());
+        job.segments = vec![Segment::Image {
+            image_ref: ResourceRef {
+                url: "https://example.com/logo".to_string(),
+                sha256: String::new(),
+                data: Some(ResourceData(image.into())),
+            },
+        }];
+        job
+    }
+
+    fn pgm(width: usize, height: usize, level: impl Fn(usize, usize) -> u8) -> Vec<u8> {
+        let mut pgm = format!("P5\n# test\n{} {}\n255\n", width, height).into_bytes();
+        for y in 0..height {
+            pgm.extend((0..width).map(|x| level(x, y)));
+        }
+        pgm
+    }
+
+    /// The raster rows printed, and the number of raster commands.
+    fn printed_rows(rendered: &Rendered, profile: &PrinterProfile) -> (Vec<Vec<u8>>, usize) {
+        let rasters = transcript::rasters(&rendered.bytes, profile.commands);
+        let rows = rasters
+            .iter()
+            .flat_map(|raster| raster.rows.iter().map(|row| row.to_vec()))
+            .collect();
+        (rows, rasters.len())
+    }
+
+    fn black_dots(rows: &[Vec<u8>]) -> usize {
+        rows.iter()
+            .flatten()
+            .map(|byte| byte.count_ones() as usize)
+            .sum()
+    }
+
+    #[test]
+    fn pbm_images_are_centred_and_sent_in_bands() {
+        let mut pbm = b"P4 8 300\n".to_vec();
+        pbm.extend([0xFF; 300]);
+        for (commands, name) in [
+            (CommandSet::EscPos, "default"),
+            (CommandSet::Star, "star-tsp"),
+        ] {
+            let profile = PrinterProfile::find(name).unwrap().clone();
+            assert_eq!(profile.commands, commands);
+            let rendered = render_job(&image_job(pbm.clone()), &profile, None).unwrap();
+            let (rows, bands) = printed_rows(&rendered, &profile);
+            assert_eq!(rows.len(), 300);
+            assert_eq!(bands, 300usize.div_ceil(profile.raster_band_rows));
+            // Eight dots in the middle of the paper
+            let middle = profile.columns * FONT_A_DOTS / 16;
+            for row in &rows {
+                assert_eq!(row.len(), profile.columns * FONT_A_DOTS / 8);
+                assert_eq!(row[middle - 1..=middle], [0x0F, 0xF0]);
+                assert_eq!(row.iter().map(|b| b.count_ones()).sum::<u32>(), 8);
+            }
+        }
+    }
+
+    #[test]
+    fn images_wider_than_the_paper_are_cropped_on_both_sides() {
+        let profile = profile();
+        let width = profile.columns * FONT_A_DOTS;
+        // Black only in the 8 dots either side of the paper
+        let image = pgm(
+            width + 16,
+            1,
+            |x, _| if x < 8 || x >= width + 8 { 0 } else { 255 },
+        );
+        let rendered = render_job(&image_job(image), &profile, None).unwrap();
+        let (rows, _) = printed_rows(&rendered, &profile);
+        assert_eq!(black_dots(&rows), 0);
+    }
+
+    #[test]
+    fn grey_images_are_dithered() {
+        let profile = profile();
+        let dots = |level: u8| {
+            let image = pgm(384, 64, |_, _| level);
+            let rendered = render_job(&image_job(image), &profile, None).unwrap();
+            black_dots(&printed_rows(&rendered, &profile).0)
+        };
+        assert_eq!(dots(0), 384 * 64);
+        assert_eq!(dots(255), 0);
+        let half = dots(128) as f64 / (384 * 64) as f64;
+        assert!((0.45..0.55).contains(&half), "{}", half);
+        let light = dots(192) as f64 / (384 * 64) as f64;
+        assert!((0.2..0.3).contains(&light), "{}", light);
+
+        // Levels are scaled to the file's maximum
+        let mut image = b"P5 8 1 15\n".to_vec();
+        image.extend([15; 8]);
+        let rendered = render_job(&image_job(image), &profile, None).unwrap();
+        assert_eq!(black_dots(&printed_rows(&rendered, &profile).0), 0);
+    }
+
+    #[test]
+    fn broken_images_print_a_placeholder() {
+        let profile = profile();
+        for image in [
+            &b"P6 1 1 255\n\0\0\0"[..],
+            b"P4 8 2\n\xFF",
+            b"P5 1 1 0\n\0",
+            b"P5 8 x\n",
+        ] {
+            let rendered = render_job(&image_job(image.to_vec()), &profile, None).unwrap();
+            assert_eq!(printed_rows(&rendered, &profile).1, 0);
+            let ops = transcript::decode(&rendered.bytes, profile.commands);
+            assert!(
+                ops.contains(&transcript::Op::Text("[image]".to_string())),
+                "{:?}",
+                ops
+            );
+        }
+    }
+
+    #[test]
+    fn tall_images_are_rendered_a_band_at_a_time() {
+        // The pixels alone are 11 MB, and their dithering errors four times that
+        let profile = profile();
+        let image = image_job(pgm(576, 20_000, |x, y| ((x + y) % 256) as u8));
+        let (rendered, peak) = counting::peak(|| render_job(&image, &profile, None).unwrap());
+        let (rows, bands) = printed_rows(&rendered, &profile);
+        assert_eq!(rows.len(), 20_000);
+        assert_eq!(bands, 20_000usize.div_ceil(profile.raster_band_rows));
+        // The ticket itself, with room to grow, and a few bands
+        let budget = 2 * rendered.bytes.len() + 256 * 1024;
+        assert!(peak < budget, "{} bytes at once, over {}", peak, budget);
+    }
+
+    /// The lines of text printed, each with the font it's in.
+    fn printed_lines(rendered: &Rendered, profile: &PrinterProfile) -> Vec<(Font, String)> {
+        let mut lines = Vec::new();
+        let (mut font, mut line) = (Font::A, String::new());
+        for op in transcript::decode(&rendered.bytes, profile.commands) {
+            match op {
+                transcript::Op::Init => font = Font::A,
+                transcript::Op::Font(n) => font = if n == 1 { Font::B } else { Font::A },
+                transcript::Op::Text(text) => line.push_str(&text),
+                transcript::Op::Feed(_) if !line.is_empty() => {
+                    lines.push((font, std::mem::take(&mut line)));
+                }
+                _ => {}
+            }
+        }
+        lines
+    }
+
+    fn text(text: &str, font: Option<Font>) -> Segment {
+        Segment::Text(TextSegment {
+            text: text.to_string(),
+            font,
+            line_spacing: None,
+        })
+    }
+
+    #[test]
+    fn text_wraps_at_the_width_of_the_font_in_use() {
+        let words = "abcd ".repeat(40);
+        let words = words.trim_end();
+        for (name, a, b) in [("default", 44, 64), ("serial-58mm", 29, 39)] {
+            let profile = PrinterProfile::find(name).unwrap();
+            // Font A, switching to B and back mid-ticket
+            let mut job = Job::plain(words.to_string());
+            job.segments = vec![text(words, Some(Font::B)), text(words, None)];
+            let lines = printed_lines(&render_job(&job, profile, None).unwrap(), profile);
+            let fonts: Vec<Font> = lines.iter().map(|(font, _)| *font).collect();
+            let switches = fonts.windows(2).filter(|pair| pair[0] != pair[1]).count();
+            assert_eq!(switches, 2, "{}: {:?}", name, lines);
+            for (font, line) in &lines {
+                assert!(
+                    line.len() <= profile.columns_for(*font),
+                    "{}: {:?}",
+                    name,
+                    line
+                );
+            }
+            // Full lines are as many words as fit the font's columns
+            let longest = |wanted: Font| {
+                lines
+                    .iter()
+                    .filter(|(font, _)| *font == wanted)
+                    .map(|(_, line)| line.len())
+                    .max()
+            };
+            assert_eq!(longest(Font::A), Some(a), "{}", name);
+            assert_eq!(longest(Font::B), Some(b), "{}", name);
+        }
+    }
+
+    #[test]
+    fn a_job_in_font_b_wraps_at_font_b_columns() {
+        let profile = profile();
+        let mut job = Job::plain("abcd ".repeat(30).trim_end().to_string());
+        job.font = Some(Font::B);
+        job.segments = vec![text("efgh ".repeat(30).trim_end(), Some(Font::A))];
+        let lines = printed_lines(&render_job(&job, &profile, None).unwrap(), &profile);
+        assert!(lines.iter().all(|(font, line)| match font {
+            Font::A => line.starts_with("efgh") && line.len() <= 48,
+            Font::B => line.starts_with("abcd") && line.len() <= 64,
+        }));
+        assert_eq!(lines.iter().filter(|(font, _)| *font == Font::B).count(), 3);
+        assert_eq!(lines.iter().filter(|(font, _)| *font == Font::A).count(), 4);
+    }
+
+    /// The `--mock-pretty` transcript of a job.
+    fn snapshot(job: serde_json::Value, profile: &PrinterProfile) -> String {
+        let job: Job = serde_json::from_value(job).unwrap();
+        let rendered = render_job(&job, profile, None).unwrap();
+        transcript::render(
+            &transcript::decode(&rendered.bytes, profile.commands),
+            profile,
+        )
+    }
+
+    fn layout_job() -> serde_json::Value {
+        serde_json::json!({
+            "text": "Layout",
+            "segments": [
+                {"rule": "dash"},
+                {"box": [
+                    {"text": "Table 4"},
+                    {"rule": "double"},
+                    {"spacer": 24},
+                    {"box": [{"text": "Inner"}]},
+                ]},
+                {"spacer": 24},
+                {"rule": "shade"},
+                {"rule": "solid"},
+            ],
+        })
+    }
+
+    #[test]
+    fn rules_boxes_and_spacers_at_32_columns() {
+        // No graphics, so ASCII boxes and rules
+        let profile = PrinterProfile::find("serial-58mm").unwrap();
+        assert_eq!(profile.columns, 32);
+        assert_eq!(snapshot(layout_job(), profile), NARROW_LAYOUT);
+    }
+
+    #[test]
+    fn rules_boxes_and_spacers_at_48_columns() {
+        let profile = profile();
+        assert_eq!(profile.columns, 48);
+        assert_eq!(snapshot(layout_job(), &profile), WIDE_LAYOUT);
+    }
+
+    const NARROW_LAYOUT: &str = "\
+\x200----+----1----+----2----+----3- 
+[INIT]
+[SMOOTHING ON]
+|Layout                          |
+|--------------------------------|
+|+------------------------------+|
+|| Table 4                      ||
+|| ============================ ||
+||                              ||
+|| +--------------------------+ ||
+|| | Inner                    | ||
+|| +--------------------------+ ||
+|+------------------------------+|
+[FEED 24 DOTS]
+|................................|
+|--------------------------------|
+|                                |
+|                                |
+[CUT]
+";
+
+    const WIDE_LAYOUT: &str = "\
+\x200----+----1----+----2----+----3----+----4----+-- 
+[INIT]
+[SMOOTHING ON]
+|Layout                                          |
+|------------------------------------------------|
+|┌──────────────────────────────────────────────┐|
+|│ Table 4                                      │|
+|│ ============================================ │|
+|│                                              │|
+|│ ┌──────────────────────────────────────────┐ │|
+|│ │ Inner                                    │ │|
+|│ └──────────────────────────────────────────┘ │|
+|└──────────────────────────────────────────────┘|
+[FEED 24 DOTS]
+|░░░░░░░░░░░░░░░░░░░░░░░░░░░░░░░░░░░░░░░░░░░░░░░░|
+[IMAGE 576x2 DOTS]
+|                                                |
+|                                                |
+[CUT]
+";
+
+    fn receipt_job() -> serde_json::Value {
+        serde_json::json!({
+            "text": "Order 9001\n\n2x Flat white with oat milk and an extra shot, no sugar",
+            "segments": [
+                {"spacer": 24},
+                {"rule": "dash"},
+                {"text": "Pickup at the counter", "font": "a"},
+                {"box": [{"text": "Paid"}]},
+            ],
+        })
+    }
+
+    fn receipt(mode: serde_json::Value) -> (Rendered, String) {
+        let profile = profile();
+        let mut job = receipt_job();
+        job.as_object_mut()
+            .unwrap()
+            .extend(mode.as_object().unwrap().clone());
+        let snapshot = snapshot(job.clone(), &profile);
+        let job: Job = serde_json::from_value(job).unwrap();
+        (render_job(&job, &profile, None).unwrap(), snapshot)
+    }
+
+    #[test]
+    fn compact_mode_saves_paper() {
+        let (normal, normal_snapshot) = receipt(serde_json::json!({}));
+        let (compact, compact_snapshot) = receipt(serde_json::json!({"compact": true}));
+        assert_eq!(normal_snapshot, NORMAL_RECEIPT);
+        // Font B fits the long line on one, and the blank line, spacer, rule
+        // and one of the feeds before the cut go
+        assert_eq!(compact_snapshot, COMPACT_RECEIPT);
+        assert_eq!((normal.lines, compact.lines), (11, 7));
+    }
+
+    #[test]
+    fn large_print_is_double_height_and_wraps_the_same() {
+        let (normal, _) = receipt(serde_json::json!({}));
+        let (large, large_snapshot) = receipt(serde_json::json!({"accessibility": "large"}));
+        // Double-height font A throughout, 60 dots between lines
+        assert_eq!(large_snapshot, LARGE_RECEIPT);
+        assert_eq!(large.lines, 2 * normal.lines);
+        let profile = profile();
+        let text = |rendered: &Rendered| -> Vec<String> {
+            printed_lines(rendered, &profile)
+                .into_iter()
+                .map(|(_, line)| line)
+                .collect()
+        };
+        assert_eq!(text(&large), text(&normal));
+
+        // Compact mode gives way to large print
+        let (both, _) = receipt(serde_json::json!({"accessibility": "large", "compact": true}));
+        assert_eq!(both.bytes, large.bytes);
+    }
+
+    #[test]
+    fn vendor_commands_reach_the_driver_spelled_for_the_printer() {
+        for commands in [CommandSet::EscPos, CommandSet::Star] {
+            let mut ticket = Ticket::blank(commands);
+            ticket.feed().unwrap();
+            ticket.feed_dots(9).unwrap();
+            ticket.raster(1, 2, |row| row[0] = 0x81).unwrap();
+            let rendered = ticket.finish(true).unwrap();
+
+            let mut expected = commands.feed(1);
+            expected.extend(commands.feed_dots(9));
+            expected.extend(commands.raster(1, &[0x81, 0x81]));
+            for _ in 0..Spacing::NORMAL.pre_cut_feeds {
+                expected.extend(commands.feed(1));
+            }
+            let cut_offset = expected.len();
+            expected.extend(commands.cut());
+            expected.extend(commands.cash_drawer());
+            assert_eq!(rendered.bytes, expected, "{:?}", commands);
+            assert_eq!(rendered.cut_offset, cut_offset);
+        }
+    }
+
+    #[cfg(feature = "fallback-font")]
+    #[test]
+    fn fallback_glyphs_are_measured_in_cells() {
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/fallback.bdf");
+        fallback::install(fallback::FallbackFont::load(&path).unwrap());
+
+        let mut ticket = Ticket::blank(CommandSet::EscPos);
+        for font in [Font::A, Font::B] {
+            ticket.font = font;
+            assert_eq!(ticket.cell_width('a'), 1);
+            assert_eq!(ticket.cell_width('é'), 1);
+            assert_eq!(ticket.cell_width('ā'), 1);
+            assert_eq!(ticket.cell_width('中'), 2);
+        }
+        assert_eq!(
+            wrap("1x 中 noodles Tāmaki", 8, |c| ticket.cell_width(c)),
+            ["1x 中", "noodles", "Tāmaki"]
+        );
+
+        // There are no fallback glyphs on Star printers
+        let star = Ticket::blank(CommandSet::Star);
+        assert_eq!(star.cell_width('中'), 1);
+    }
+
+    const NORMAL_RECEIPT: &str = "\
+\x200----+----1----+----2----+----3----+----4----+-- 
+[INIT]
+[SMOOTHING ON]
+|Order 9001                                      |
+|                                                |
+|2x Flat white with oat milk and an extra shot,  |
+|no sugar                                        |
+[FEED 24 DOTS]
+|------------------------------------------------|
+|Pickup at the counter                           |
+|┌──────────────────────────────────────────────┐|
+|│ Paid                                         │|
+|└──────────────────────────────────────────────┘|
+|                                                |
+|                                                |
+[CUT]
+";
+
+    const COMPACT_RECEIPT: &str = "\
+\x200----+----1----+----2----+----3----+----4----+-- 
+[INIT]
+[SMOOTHING ON]
+[FONT B]
+\x200----+----1----+----2----+----3----+----4----+----5----+----6--- 
+[LINE SPACING 20 DOTS]
+|Order 9001                                                      |
+|2x Flat white with oat milk and an extra shot, no sugar         |
+[FONT A]
+\x200----+----1----+----2----+----3----+----4----+-- 
+|Pickup at the counter                           |
+|┌──────────────────────────────────────────────┐|
+[FONT B]
+\x200----+----1----+----2----+----3----+----4----+----5----+----6--- 
+|│ Paid                                                         │|
+[FONT A]
+\x200----+----1----+----2----+----3----+----4----+-- 
+|└──────────────────────────────────────────────┘|
+[LINE SPACING DEFAULT]
+|                                                |
+[CUT]
+";
+
+    const LARGE_RECEIPT: &str = "\
+\x200----+----1----+----2----+----3----+----4----+-- 
+[INIT]
+[SMOOTHING ON]
+[LINE SPACING 60 DOTS]
+[SIZE 1x2]
+|Order 9001                                      |
+|                                                |
+|2x Flat white with oat milk and an extra shot,  |
+|no sugar                                        |
+[FEED 24 DOTS]
+|------------------------------------------------|
+|Pickup at the counter                           |
+|┌──────────────────────────────────────────────┐|
+|│ Paid                                         │|
+|└──────────────────────────────────────────────┘|
+[LINE SPACING DEFAULT]
+[SIZE 1x1]
+|                                                |
+|                                                |
+[CUT]
+";
+}
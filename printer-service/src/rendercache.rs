@@ -0,0 +1,351 @@
+//! Rendered tickets kept in memory for jobs that come round again, like the
+//! loyalty slip a venue prints with every receipt, so they skip rendering
+//! (image dithering included) and go straight to the printer. Jobs are keyed
+//! by a hash of everything that decides what they print, leaving out the id
+//! and other fields that change from one job to the next without showing on
+//! paper. Blocks that do change, such as printed timestamps, are rendered
+//! again on every hit. The least recently used tickets are dropped once the
+//! cache holds more than `--render-cache-kb`.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use log::debug;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::footer::Footer;
+use crate::profile::{Font, PrinterProfile};
+use crate::protocol::{Accessibility, Job, Segment};
+use crate::render::{self, Rendered, Template};
+
+/// Default size limit, in KB
+pub const DEFAULT_KB: usize = 1024;
+
+/// Cache counters for `status`.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct RenderCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Share of lookups that were hits, 0 to 1
+    pub hit_rate: f64,
+    pub entries: usize,
+    pub bytes: usize,
+}
+
+/// The fields of a job that decide what it prints. The profile isn't part
+/// of it because it can't change while the service runs.
+#[derive(Serialize)]
+struct Key<'a> {
+    text: &'a str,
+    font: Option<Font>,
+    line_spacing: Option<u8>,
+    segments: &'a [Segment],
+    open_drawer: bool,
+    compact: Option<bool>,
+    accessibility: Option<Accessibility>,
+    footer: Option<&'a Footer>,
+}
+
+impl Key<'_> {
+    fn hash(job: &Job, footer: Option<&Footer>) -> [u8; 32] {
+        let key = Key {
+            text: &job.text,
+            font: job.font,
+            line_spacing: job.line_spacing,
+            segments: &job.segments,
+            open_drawer: job.open_drawer,
+            compact: job.compact,
+            accessibility: job.accessibility,
+            footer,
+        };
+        let json = serde_json::to_vec(&key).expect("cache key serializes");
+        Sha256::digest(json).into()
+    }
+}
+
+struct Entry {
+    template: Arc<Template>,
+    /// When it was last used, on the cache's own clock
+    used: u64,
+}
+
+#[derive(Default)]
+struct Entries {
+    map: HashMap<[u8; 32], Entry>,
+    bytes: usize,
+    /// Counts lookups, for the LRU order
+    clock: u64,
+}
+
+pub struct RenderCache {
+    /// 0 turns the cache off
+    max_bytes: usize,
+    entries: Mutex<Entries>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl RenderCache {
+    pub fn new(max_bytes: usize) -> Self {
+        Self {
+            max_bytes,
+            entries: Mutex::new(Entries::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Renders one copy of `job` as `render::render_job` does, from the
+    /// cache if an identical job was rendered before.
+    pub fn render(
+        &self,
+        job: &Job,
+        profile: &PrinterProfile,
+        footer: Option<&Footer>,
+    ) -> Result<Rendered> {
+        if self.max_bytes == 0 {
+            return render::render_job(job, profile, footer);
+        }
+        let key = Key::hash(job, footer);
+        let cached = {
+            let mut entries = self.entries.lock().unwrap();
+            entries.clock += 1;
+            let now = entries.clock;
+            entries.map.get_mut(&key).map(|entry| {
+                entry.used = now;
+                Arc::clone(&entry.template)
+            })
+        };
+        if let Some(template) = cached {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            debug!("Job {:?} rendered from the cache", job.id);
+            return template.fill(job, profile);
+        }
+
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let (rendered, template) = render::render_template(job, profile, footer)?;
+        self.insert(key, template);
+        Ok(rendered)
+    }
+
+    /// Adds a template, dropping the least recently used ones to make room.
+    /// One bigger than the whole cache isn't kept.
+    fn insert(&self, key: [u8; 32], template: Template) {
+        let size = template.size();
+        if size > self.max_bytes {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        while entries.bytes + size > self.max_bytes {
+            let Some(oldest) = entries
+                .map
+                .iter()
+                .min_by_key(|(_, entry)| entry.used)
+                .map(|(key, _)| *key)
+            else {
+                break;
+            };
+            if let Some(entry) = entries.map.remove(&oldest) {
+                entries.bytes -= entry.template.size();
+            }
+        }
+        let used = entries.clock;
+        let entry = Entry {
+            template: Arc::new(template),
+            used,
+        };
+        entries.bytes += size;
+        // Another thread may have rendered the same job meanwhile
+        if let Some(replaced) = entries.map.insert(key, entry) {
+            entries.bytes -= replaced.template.size();
+        }
+    }
+
+    /// Drops every ticket, returning how many there were.
+    pub fn flush(&self) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        let count = entries.map.len();
+        entries.map.clear();
+        entries.bytes = 0;
+        count
+    }
+
+    pub fn stats(&self) -> RenderCacheStats {
+        let (hits, misses) = (
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed),
+        );
+        let entries = self.entries.lock().unwrap();
+        RenderCacheStats {
+            hits,
+            misses,
+            hit_rate: match hits + misses {
+                0 => 0.0,
+                lookups => hits as f64 / lookups as f64,
+            },
+            entries: entries.map.len(),
+            bytes: entries.bytes,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::TextSegment;
+
+    fn profile() -> &'static PrinterProfile {
+        PrinterProfile::find("default").unwrap()
+    }
+
+    fn job(id: &str, text: &str) -> Job {
+        let mut job = Job::plain(text.to_string());
+        job.id = Some(id.to_string());
+        job
+    }
+
+    /// Cache space for `count` tickets like `job`.
+    fn room_for(count: usize, job: &Job) -> usize {
+        let (_, template) = render::render_template(job, profile(), None).unwrap();
+        template.size() * count
+    }
+
+    fn counts(cache: &RenderCache) -> (u64, u64, usize) {
+        let stats = cache.stats();
+        (stats.hits, stats.misses, stats.entries)
+    }
+
+    #[test]
+    fn a_repeated_job_comes_from_the_cache_unchanged() {
+        let cache = RenderCache::new(DEFAULT_KB * 1024);
+        let first = job("1", "Loyalty: 10th coffee free");
+        let mut again = job("2", "Loyalty: 10th coffee free");
+        again.ttl_secs = Some(60);
+        again.tenant = Some("cafe".to_string());
+        let rendered = cache.render(&first, profile(), None).unwrap();
+        let cached = cache.render(&again, profile(), None).unwrap();
+        assert_eq!(counts(&cache), (1, 1, 1));
+        let direct = render::render_job(&again, profile(), None).unwrap();
+        assert_eq!(cached.bytes, direct.bytes);
+        assert_eq!(cached.bytes, rendered.bytes);
+        assert_eq!(cached.lines, direct.lines);
+        let stats = cache.stats();
+        assert_eq!(stats.hit_rate, 0.5);
+        assert!(stats.bytes > 0);
+    }
+
+    #[test]
+    fn anything_that_prints_differently_misses() {
+        let cache = RenderCache::new(DEFAULT_KB * 1024);
+        let base = job("1", "Loyalty");
+        cache.render(&base, profile(), None).unwrap();
+        let mut variants = vec![job("2", "Loyalty!")];
+        let mut font = base.clone();
+        font.font = Some(Font::B);
+        variants.push(font);
+        let mut drawer = base.clone();
+        drawer.open_drawer = true;
+        variants.push(drawer);
+        let mut compact = base.clone();
+        compact.compact = Some(true);
+        variants.push(compact);
+        for variant in &variants {
+            cache.render(variant, profile(), None).unwrap();
+        }
+        assert_eq!(counts(&cache), (0, 5, 5));
+
+        let footer = Footer {
+            lines: vec!["Rate us".to_string()],
+            qr: None,
+            barcode: None,
+            kinds: Vec::new(),
+        };
+        let footered = cache.render(&base, profile(), Some(&footer)).unwrap();
+        assert_eq!(counts(&cache), (0, 6, 6));
+        assert_eq!(
+            cache.render(&base, profile(), Some(&footer)).unwrap().bytes,
+            footered.bytes
+        );
+        assert_eq!(counts(&cache), (1, 6, 6));
+    }
+
+    #[test]
+    fn timestamps_are_filled_in_on_every_hit() {
+        let cache = RenderCache::new(DEFAULT_KB * 1024);
+        let mut stamped = job("1", "");
+        stamped.segments = vec![
+            Segment::Text(TextSegment {
+                text: "Printed".to_string(),
+                font: None,
+                line_spacing: None,
+            }),
+            Segment::Timestamp {
+                timestamp: "%Y".to_string(),
+            },
+        ];
+        cache.render(&stamped, profile(), None).unwrap();
+        let cached = cache.render(&stamped, profile(), None).unwrap();
+        assert_eq!(counts(&cache), (1, 1, 1));
+        let year = crate::clock::local_now().format("%Y").to_string();
+        assert!(cached.bytes.windows(4).any(|w| w == year.as_bytes()));
+        assert!(cached.bytes.windows(7).any(|w| w == b"Printed"));
+    }
+
+    #[test]
+    fn the_least_recently_used_ticket_makes_room() {
+        let (a, b, c) = (
+            job("a", "Ticket A"),
+            job("b", "Ticket B"),
+            job("c", "Ticket C"),
+        );
+        let cache = RenderCache::new(room_for(2, &a));
+        cache.render(&a, profile(), None).unwrap();
+        cache.render(&b, profile(), None).unwrap();
+        // A is used again, so B is the one to go
+        cache.render(&a, profile(), None).unwrap();
+        cache.render(&c, profile(), None).unwrap();
+        assert_eq!(counts(&cache), (1, 3, 2));
+        cache.render(&a, profile(), None).unwrap();
+        cache.render(&c, profile(), None).unwrap();
+        assert_eq!(counts(&cache), (3, 3, 2));
+        cache.render(&b, profile(), None).unwrap();
+        assert_eq!(counts(&cache), (3, 4, 2));
+        assert!(cache.stats().bytes <= room_for(2, &a));
+    }
+
+    #[test]
+    fn tickets_bigger_than_the_cache_are_not_kept() {
+        let small = job("1", "Small");
+        let big = job("2", &"A long line of text\n".repeat(50));
+        let cache = RenderCache::new(room_for(1, &small));
+        cache.render(&small, profile(), None).unwrap();
+        cache.render(&big, profile(), None).unwrap();
+        cache.render(&big, profile(), None).unwrap();
+        // Nor does trying push the small one out
+        assert_eq!(counts(&cache), (0, 3, 1));
+    }
+
+    #[test]
+    fn an_empty_cache_is_off() {
+        let cache = RenderCache::new(0);
+        let once = job("1", "Loyalty");
+        cache.render(&once, profile(), None).unwrap();
+        cache.render(&once, profile(), None).unwrap();
+        assert_eq!(counts(&cache), (0, 0, 0));
+        assert_eq!(cache.stats().hit_rate, 0.0);
+    }
+
+    #[test]
+    fn flushing_empties_the_cache_but_keeps_the_counts() {
+        let cache = RenderCache::new(DEFAULT_KB * 1024);
+        cache.render(&job("1", "One"), profile(), None).unwrap();
+        cache.render(&job("2", "Two"), profile(), None).unwrap();
+        assert_eq!(cache.flush(), 2);
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.bytes, stats.misses), (0, 0, 2));
+        assert_eq!(cache.flush(), 0);
+    }
+}
@@ -0,0 +1,341 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use chrono::{Local, NaiveTime, TimeZone};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::clock;
+
+const REPORT_FILE: &str = "report.json";
+/// Default ESC/POS line spacing is 1/6 inch
+pub const LINE_MM: f64 = 4.23;
+/// Paper fed past the print head to reach the cutter
+pub const CUT_MM: f64 = 15.0;
+
+/// Counters for the current reporting period.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Counters {
+    /// Unix time the period started
+    pub period_start: i64,
+    pub printed: u64,
+    /// Error acks sent, by error code
+    pub failures: BTreeMap<String, u64>,
+    pub ws_reconnects: u64,
+    pub printer_reconnects: u64,
+    pub paper_mm: f64,
+    /// Jobs dropped for reaching the printer after their expiry
+    #[serde(default)]
+    pub expired: u64,
+}
+
+/// Period counters for the daily report. With a spool directory they're saved
+/// after every change, so a restart doesn't reset the period.
+pub struct Report {
+    path: Option<PathBuf>,
+    counters: Counters,
+}
+
+impl Report {
+    pub fn open(dir: Option<&Path>) -> Self {
+        let path = dir.map(|dir| dir.join(REPORT_FILE));
+        let counters = path
+            .as_deref()
+            .filter(|path| path.exists())
+            .and_then(|path| match load(path) {
+                Ok(counters) => Some(counters),
+                Err(e) => {
+                    warn!("Ignoring unreadable report counters: {:#}", e);
+                    None
+                }
+            })
+            .unwrap_or_else(|| Counters {
+                period_start: clock::now().timestamp(),
+                ..Default::default()
+            });
+        Self { path, counters }
+    }
+
+    pub fn counters(&self) -> &Counters {
+        &self.counters
+    }
+
+    pub fn record_printed(&mut self) {
+        self.counters.printed += 1;
+        self.save();
+    }
+
+    /// Records paper used by a ticket that fed `lines` lines.
+    pub fn record_paper(&mut self, lines: usize) {
+        self.counters.paper_mm += lines as f64 * LINE_MM + CUT_MM;
+        self.save();
+    }
+
+    pub fn record_expired(&mut self) {
+        self.counters.expired += 1;
+        self.save();
+    }
+
+    pub fn record_failure(&mut self, code: &str) {
+        *self.counters.failures.entry(code.to_owned()).or_default() += 1;
+        self.save();
+    }
+
+    pub fn record_ws_reconnect(&mut self) {
+        self.counters.ws_reconnects += 1;
+        self.save();
+    }
+
+    pub fn record_printer_reconnect(&mut self) {
+        self.counters.printer_reconnects += 1;
+        self.save();
+    }
+
+    /// Starts a new reporting period.
+    pub fn reset(&mut self) {
+        self.counters = Counters {
+            period_start: clock::now().timestamp(),
+            ..Default::default()
+        };
+        self.save();
+    }
+
+    /// The report as ticket text.
+    pub fn text(&self, uptime: Duration) -> String {
+        let c = &self.counters;
+        let since = Local
+            .timestamp_opt(c.period_start, 0)
+            .single()
+            .map(|t| t.format("%Y-%m-%d %H:%M").to_string())
+            .unwrap_or_default();
+        let mut lines = vec![
+            "DAILY REPORT".to_owned(),
+            format!("Since:    {}", since),
+            format!(
+                "Until:    {}{}",
+                clock::local_now().format("%Y-%m-%d %H:%M"),
+                if clock::is_corrected() {
+                    " (server time)"
+                } else {
+                    ""
+                }
+            ),
+            String::new(),
+            format!("Printed:  {}", c.printed),
+        ];
+        lines.push(format!("Expired:  {}", c.expired));
+        let failed: u64 = c.failures.values().sum();
+        lines.push(format!("Failures: {}", failed));
+        for (code, count) in &c.failures {
+            lines.push(format!("  {}: {}", code, count));
+        }
+        lines.push(format!(
+            "Reconnects: {} server, {} printer",
+            c.ws_reconnects, c.printer_reconnects
+        ));
+        lines.push(format!("Paper:    ~{:.2} m", c.paper_mm / 1000.0));
+        lines.push(format!("Uptime:   {}", format_uptime(uptime)));
+        lines.join("\n")
+    }
+
+    fn save(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        if let Err(e) = save(path, &self.counters) {
+            warn!(
+                "Failed to save report counters to {}: {:#}",
+                path.display(),
+                e
+            );
+        }
+    }
+}
+
+/// How long from now until the next local `at`.
+pub fn until_next(at: NaiveTime) -> Duration {
+    let now = clock::local_now();
+    let today = now
+        .date_naive()
+        .and_time(at)
+        .and_local_timezone(now.timezone())
+        .earliest();
+    let next = match today {
+        Some(today) if today > now => today,
+        _ => {
+            let tomorrow = now
+                .date_naive()
+                .succ_opt()
+                .unwrap_or(now.date_naive())
+                .and_time(at);
+            match tomorrow.and_local_timezone(now.timezone()).earliest() {
+                Some(tomorrow) => tomorrow,
+                None => return Duration::from_secs(24 * 60 * 60),
+            }
+        }
+    };
+    let delay = (next - now).to_std().unwrap_or(Duration::from_secs(60));
+    info!("Next daily report in {:?}", delay);
+    delay
+}
+
+/// Parses `HH:MM` for `--daily-report`.
+pub fn parse_time(s: &str) -> Result<NaiveTime, String> {
+    NaiveTime::parse_from_str(s, "%H:%M").map_err(|_| format!("expected HH:MM, got {:?}", s))
+}
+
+fn format_uptime(uptime: Duration) -> String {
+    let secs = uptime.as_secs();
+    format!(
+        "{}d {:02}:{:02}",
+        secs / 86400,
+        (secs / 3600) % 24,
+        (secs / 60) % 60
+    )
+}
+
+fn load(path: &Path) -> Result<Counters> {
+    let text = fs::read_to_string(path)?;
+    serde_json::from_str(&text).with_context(|| format!("Failed to parse {}", path.display()))
+}
+
+fn save(path: &Path, counters: &Counters) -> Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    let mut tmp = File::create(&tmp_path)?;
+    tmp.write_all(&serde_json::to_vec(counters)?)?;
+    tmp.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tempdir::TempDir;
+
+    fn record_a_day(report: &mut Report) {
+        report.record_printed();
+        report.record_printed();
+        report.record_paper(10);
+        report.record_expired();
+        report.record_failure("PRINTER_OFFLINE");
+        report.record_failure("PRINTER_OFFLINE");
+        report.record_failure("RENDER_FAILED");
+        report.record_ws_reconnect();
+        report.record_printer_reconnect();
+    }
+
+    #[test]
+    fn counters_add_up() {
+        let mut report = Report::open(None);
+        record_a_day(&mut report);
+        let c = report.counters();
+        assert_eq!((c.printed, c.expired), (2, 1));
+        assert_eq!((c.ws_reconnects, c.printer_reconnects), (1, 1));
+        assert_eq!(c.failures["PRINTER_OFFLINE"], 2);
+        assert_eq!(c.failures["RENDER_FAILED"], 1);
+        assert!((c.paper_mm - (10.0 * LINE_MM + CUT_MM)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn counters_survive_a_restart_until_reset() {
+        let dir = TempDir::new("report");
+        let mut report = Report::open(Some(dir.path()));
+        let started = report.counters().period_start;
+        record_a_day(&mut report);
+        drop(report);
+
+        let mut report = Report::open(Some(dir.path()));
+        assert_eq!(report.counters().printed, 2);
+        assert_eq!(report.counters().period_start, started);
+        report.reset();
+        drop(report);
+
+        let report = Report::open(Some(dir.path()));
+        let c = report.counters();
+        assert_eq!((c.printed, c.paper_mm), (0, 0.0));
+        assert!(c.failures.is_empty());
+        assert!(c.period_start >= started);
+        assert!(!dir.path().join("report.tmp").exists());
+    }
+
+    #[test]
+    fn unreadable_counters_start_a_new_period() {
+        let dir = TempDir::new("report");
+        fs::write(dir.path().join(REPORT_FILE), "{not json").unwrap();
+        let report = Report::open(Some(dir.path()));
+        assert_eq!(report.counters().printed, 0);
+        assert!(report.counters().period_start > 0);
+    }
+
+    #[test]
+    fn counters_from_before_expiry_was_counted_load() {
+        let dir = TempDir::new("report");
+        fs::write(
+            dir.path().join(REPORT_FILE),
+            r#"{"period_start":1,"printed":4,"failures":{},"ws_reconnects":0,
+                "printer_reconnects":0,"paper_mm":0.0}"#,
+        )
+        .unwrap();
+        let report = Report::open(Some(dir.path()));
+        assert_eq!(
+            (report.counters().printed, report.counters().expired),
+            (4, 0)
+        );
+    }
+
+    #[test]
+    fn the_ticket_lists_every_counter() {
+        let mut report = Report::open(None);
+        record_a_day(&mut report);
+        for _ in 0..60 {
+            report.record_paper(100);
+        }
+        let text = report.text(Duration::from_secs(2 * 86400 + 3 * 3600 + 4 * 60 + 5));
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines[0], "DAILY REPORT");
+        assert!(lines[1].starts_with("Since:    "));
+        assert!(lines[2].starts_with("Until:    "));
+        for line in [
+            "Printed:  2",
+            "Expired:  1",
+            "Failures: 3",
+            "  PRINTER_OFFLINE: 2",
+            "  RENDER_FAILED: 1",
+            "Reconnects: 1 server, 1 printer",
+            "Paper:    ~26.34 m",
+            "Uptime:   2d 03:04",
+        ] {
+            assert!(lines.contains(&line), "{:?} not in\n{}", line, text);
+        }
+    }
+
+    #[test]
+    fn report_times_are_hours_and_minutes() {
+        assert_eq!(
+            parse_time("07:30"),
+            Ok(NaiveTime::from_hms_opt(7, 30, 0).unwrap())
+        );
+        for bad in ["7", "25:00", "07:30:00", "noon"] {
+            assert!(parse_time(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn the_next_report_is_within_a_day() {
+        // With an hour of slack either way for a change of daylight saving
+        let in_an_hour = (clock::local_now() + chrono::Duration::hours(1)).time();
+        let delay = until_next(in_an_hour);
+        assert!(delay <= Duration::from_secs(2 * 3600), "{:?}", delay);
+        assert!(delay >= Duration::from_secs(3500), "{:?}", delay);
+
+        let an_hour_ago = (clock::local_now() - chrono::Duration::hours(1)).time();
+        let delay = until_next(an_hour_ago);
+        assert!(delay <= Duration::from_secs(24 * 3600), "{:?}", delay);
+        assert!(delay >= Duration::from_secs(21 * 3600), "{:?}", delay);
+    }
+}
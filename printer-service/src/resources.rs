@@ -0,0 +1,552 @@
+//! Resources jobs refer to by URL and SHA-256 instead of carrying them, such
+//! as a logo that goes on every receipt. They're fetched over HTTP(S) the
+//! first time, checked against their hash and kept in
+//! `<state dir>/resources/`, dropping the least recently used once the cache
+//! is over its size limit.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result, bail};
+use log::{debug, info, warn};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tokio::runtime::Handle;
+use url::{Origin, Url};
+
+use crate::protocol::{Job, ResourceRef, Segment};
+
+pub(crate) const CACHE_DIR: &str = "resources";
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+const FETCH_ATTEMPTS: u32 = 3;
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_REDIRECTS: usize = 5;
+
+/// Cache counters for `status`.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct ResourceStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub fetch_failures: u64,
+}
+
+pub struct Resources {
+    dir: Option<PathBuf>,
+    /// Also the most a single download can be
+    max_bytes: u64,
+    /// The bearer token, and the only origin it's sent to
+    token: Option<(String, Origin)>,
+    /// Plain `http://` URLs are only fetched in tests
+    allow_http: bool,
+    client: reqwest::Client,
+    handle: Handle,
+    /// One lock per resource being looked up, so jobs wanting the same
+    /// resource at once fetch it only once
+    in_flight: Mutex<HashMap<String, Arc<Mutex<()>>>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    fetch_failures: AtomicU64,
+}
+
+impl Resources {
+    /// Must be called on the runtime fetches will run on. Without a state
+    /// dir nothing is cached. `token` is only sent to `token_origin`, so
+    /// without one it isn't sent at all.
+    pub fn new(
+        state_dir: Option<&Path>,
+        max_bytes: u64,
+        token: Option<String>,
+        token_origin: Option<&str>,
+    ) -> Self {
+        let dir = state_dir.map(|dir| dir.join(CACHE_DIR));
+        let client = reqwest::Client::builder()
+            .timeout(FETCH_TIMEOUT)
+            // Redirects to another origin go without the token, and never
+            // off https
+            .redirect(reqwest::redirect::Policy::custom(|attempt| {
+                if attempt.url().scheme() != "https" {
+                    attempt.error("redirected off https")
+                } else if attempt.previous().len() >= MAX_REDIRECTS {
+                    attempt.error("too many redirects")
+                } else {
+                    attempt.follow()
+                }
+            }))
+            .build()
+            .expect("failed to build HTTP client");
+        let origin = token_origin.and_then(|origin| Url::parse(origin).ok());
+        let token = match (token, origin) {
+            (Some(token), Some(origin)) => Some((token, origin.origin())),
+            (Some(_), None) => {
+                warn!("Resource token has no origin to be sent to, fetching without it");
+                None
+            }
+            (None, _) => None,
+        };
+        Self {
+            dir,
+            max_bytes,
+            token,
+            allow_http: false,
+            client,
+            handle: Handle::current(),
+            in_flight: Mutex::new(HashMap::new()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            fetch_failures: AtomicU64::new(0),
+        }
+    }
+
+    #[cfg(test)]
+    fn allow_http(mut self) -> Self {
+        self.allow_http = true;
+        self
+    }
+
+    pub fn stats(&self) -> ResourceStats {
+        ResourceStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            fetch_failures: self.fetch_failures.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Fills in the data of every resource the job refers to. Blocks while
+    /// fetching, so call it off the async threads.
+    pub fn resolve(&self, job: &mut Job) -> Result<()> {
+        fn walk(resources: &Resources, segments: &mut [Segment]) -> Result<()> {
+            for segment in segments {
+                match segment {
+                    Segment::Image { image_ref } if image_ref.data.is_none() => {
+                        image_ref.data = Some(resources.get(image_ref)?.into());
+                    }
+                    Segment::Box { segments } => walk(resources, segments)?,
+                    _ => {}
+                }
+            }
+            Ok(())
+        }
+        walk(self, &mut job.segments)
+    }
+
+    fn get(&self, resource: &ResourceRef) -> Result<Arc<[u8]>> {
+        let sha256 = resource.sha256.to_ascii_lowercase();
+        if sha256.len() != 64 || !sha256.bytes().all(|b| b.is_ascii_hexdigit()) {
+            bail!("Invalid sha256 {:?} for {}", resource.sha256, resource.url);
+        }
+        let url = Url::parse(&resource.url)
+            .with_context(|| format!("Invalid resource URL {:?}", resource.url))?;
+        if url.scheme() != "https" && !(self.allow_http && url.scheme() == "http") {
+            bail!("Resource URL {} is not https", resource.url);
+        }
+
+        let lock = self
+            .in_flight
+            .lock()
+            .unwrap()
+            .entry(sha256.clone())
+            .or_default()
+            .clone();
+        let result = {
+            let _fetching = lock.lock().unwrap();
+            self.get_locked(&url, &sha256)
+        };
+        let mut in_flight = self.in_flight.lock().unwrap();
+        // Only we and the map hold it, so nobody is waiting
+        if Arc::strong_count(&lock) == 2 {
+            in_flight.remove(&sha256);
+        }
+        result
+    }
+
+    fn get_locked(&self, url: &Url, sha256: &str) -> Result<Arc<[u8]>> {
+        let path = self.dir.as_ref().map(|dir| dir.join(sha256));
+        if let Some(path) = &path
+            && let Ok(data) = fs::read(path)
+        {
+            if hex::encode(Sha256::digest(&data)) == sha256 {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                debug!("Resource {} from cache", sha256);
+                // The modification time is the LRU order
+                if let Err(e) = fs::File::open(path).and_then(|f| f.set_modified(SystemTime::now()))
+                {
+                    debug!("Failed to touch cached resource {}: {}", path.display(), e);
+                }
+                return Ok(data.into());
+            }
+            warn!(
+                "Cached resource {} is corrupt, fetching it again",
+                path.display()
+            );
+        }
+
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let data = match self.fetch(url, sha256) {
+            Ok(data) => data,
+            Err(e) => {
+                self.fetch_failures.fetch_add(1, Ordering::Relaxed);
+                return Err(e);
+            }
+        };
+        if let (Some(dir), Some(path)) = (&self.dir, &path) {
+            if let Err(e) = store(dir, path, &data) {
+                warn!("Failed to cache resource {}: {:#}", sha256, e);
+            }
+            prune(dir, self.max_bytes, path);
+        }
+        Ok(data.into())
+    }
+
+    /// Downloads and checks a resource, trying a few times.
+    fn fetch(&self, url: &Url, sha256: &str) -> Result<Vec<u8>> {
+        let mut attempt = 1;
+        loop {
+            match self.fetch_once(url, sha256) {
+                Ok(data) => {
+                    info!("Fetched resource {} ({} bytes)", url, data.len());
+                    return Ok(data);
+                }
+                Err(e) if attempt < FETCH_ATTEMPTS => {
+                    warn!(
+                        "Fetching {} failed (attempt {}/{}): {:#}",
+                        url, attempt, FETCH_ATTEMPTS, e
+                    );
+                    std::thread::sleep(RETRY_DELAY * attempt);
+                    attempt += 1;
+                }
+                Err(e) => return Err(e.context(format!("Failed to fetch {}", url))),
+            }
+        }
+    }
+
+    /// Downloads a resource, giving up as soon as it's bigger than the
+    /// cache could hold.
+    fn fetch_once(&self, url: &Url, sha256: &str) -> Result<Vec<u8>> {
+        let mut request = self.client.get(url.clone());
+        if let Some((token, origin)) = &self.token
+            && url.origin() == *origin
+        {
+            request = request.bearer_auth(token);
+        }
+        let too_big =
+            || anyhow::anyhow!("Resource is bigger than the {} byte cache", self.max_bytes);
+        let data = tokio::task::block_in_place(|| {
+            self.handle.block_on(async {
+                let mut response = request.send().await?.error_for_status()?;
+                if response
+                    .content_length()
+                    .is_some_and(|len| len > self.max_bytes)
+                {
+                    return Err(too_big());
+                }
+                let mut data = Vec::new();
+                while let Some(chunk) = response.chunk().await? {
+                    if (data.len() + chunk.len()) as u64 > self.max_bytes {
+                        return Err(too_big());
+                    }
+                    data.extend_from_slice(&chunk);
+                }
+                Ok(data)
+            })
+        })?;
+        let actual = hex::encode(Sha256::digest(&data));
+        if actual != sha256 {
+            bail!("Downloaded data has sha256 {}, expected {}", actual, sha256);
+        }
+        Ok(data)
+    }
+}
+
+fn store(dir: &Path, path: &Path, data: &[u8]) -> Result<()> {
+    fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, data)?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+/// Deletes the least recently used resources until the cache is within
+/// `max_bytes`, never deleting `keep`.
+fn prune(dir: &Path, max_bytes: u64, keep: &Path) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    let mut files: Vec<(PathBuf, u64, SystemTime)> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let meta = entry.metadata().ok()?;
+            Some((entry.path(), meta.len(), meta.modified().ok()?))
+        })
+        .collect();
+    let mut total: u64 = files.iter().map(|(_, len, _)| len).sum();
+    files.sort_by_key(|(_, _, modified)| *modified);
+    for (path, len, _) in files {
+        if total <= max_bytes {
+            break;
+        }
+        if path == keep {
+            continue;
+        }
+        match fs::remove_file(&path) {
+            Ok(()) => {
+                debug!("Dropped cached resource {}", path.display());
+                total -= len;
+            }
+            Err(e) => warn!("Failed to delete cached resource {}: {}", path.display(), e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::tempdir::TempDir;
+
+    /// An HTTP server answering `/<name>` with the body of that name, and
+    /// 404 otherwise; bodies whose name starts with `stream` are sent without
+    /// a length. Returns its URL and the requests it got.
+    async fn server(bodies: Vec<(&'static str, Vec<u8>)>) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&requests);
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    match stream.read(&mut buf).await {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => request.extend_from_slice(&buf[..n]),
+                    }
+                }
+                let request = String::from_utf8_lossy(&request).to_string();
+                let path = request.split_whitespace().nth(1).unwrap_or_default();
+                let body = bodies.iter().find(|(name, _)| path == format!("/{}", name));
+                let head = match body {
+                    Some((name, _)) if name.starts_with("stream") => {
+                        "HTTP/1.1 200 OK\r\nConnection: close\r\n\r\n".to_string()
+                    }
+                    Some((_, body)) => format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                        body.len()
+                    ),
+                    None => {
+                        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                            .to_string()
+                    }
+                };
+                let _ = stream.write_all(head.as_bytes()).await;
+                if let Some((_, body)) = body {
+                    let _ = stream.write_all(body).await;
+                }
+                seen.lock().unwrap().push(request);
+            }
+        });
+        (url, requests)
+    }
+
+    fn sha256(data: &[u8]) -> String {
+        hex::encode(Sha256::digest(data))
+    }
+
+    fn reference(url: String, data: &[u8]) -> ResourceRef {
+        ResourceRef {
+            url,
+            sha256: sha256(data),
+            data: None,
+        }
+    }
+
+    fn image_job(image_ref: ResourceRef) -> Job {
+        let mut job = Job::plain(String::new());
+        job.segments = vec![Segment::Box {
+            segments: vec![Segment::Image { image_ref }],
+        }];
+        job
+    }
+
+    fn resolved(job: &Job) -> &[u8] {
+        let Segment::Box { segments } = &job.segments[0] else {
+            panic!("not a box");
+        };
+        let Segment::Image { image_ref } = &segments[0] else {
+            panic!("not an image");
+        };
+        &image_ref.data.as_ref().unwrap().0
+    }
+
+    fn counts(resources: &Resources) -> (u64, u64, u64) {
+        let stats = resources.stats();
+        (stats.hits, stats.misses, stats.fetch_failures)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn resources_are_fetched_once_and_then_come_from_the_cache() {
+        let logo = b"P4 8 1 \xAA".to_vec();
+        let (url, requests) = server(vec![("logo", logo.clone())]).await;
+        let dir = TempDir::new("resources");
+        let resources = Resources::new(
+            Some(dir.path()),
+            1 << 20,
+            Some("s3cret".to_string()),
+            Some(&url),
+        )
+        .allow_http();
+        let logo_ref = reference(format!("{}/logo", url), &logo);
+
+        let mut job = image_job(logo_ref.clone());
+        resources.resolve(&mut job).unwrap();
+        assert_eq!(resolved(&job), logo);
+        assert_eq!(counts(&resources), (0, 1, 0));
+        let cached = dir.path().join(CACHE_DIR).join(&logo_ref.sha256);
+        assert_eq!(fs::read(&cached).unwrap(), logo);
+        assert!(requests.lock().unwrap()[0].contains("authorization: Bearer s3cret"));
+
+        // A later start finds it on disk
+        let restarted = Resources::new(Some(dir.path()), 1 << 20, None, None).allow_http();
+        let mut job = image_job(logo_ref.clone());
+        restarted.resolve(&mut job).unwrap();
+        assert_eq!(resolved(&job), logo);
+        assert_eq!(counts(&restarted), (1, 0, 0));
+        assert_eq!(requests.lock().unwrap().len(), 1);
+
+        // And a corrupt copy is fetched again
+        fs::write(&cached, b"garbage").unwrap();
+        let mut job = image_job(logo_ref);
+        restarted.resolve(&mut job).unwrap();
+        assert_eq!(fs::read(&cached).unwrap(), logo);
+        assert_eq!(counts(&restarted), (1, 1, 0));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn without_a_state_dir_every_lookup_fetches() {
+        let logo = b"logo".to_vec();
+        let (url, requests) = server(vec![("logo", logo.clone())]).await;
+        let resources = Resources::new(None, 1 << 20, None, None).allow_http();
+        for _ in 0..2 {
+            let mut job = image_job(reference(format!("{}/logo", url), &logo));
+            resources.resolve(&mut job).unwrap();
+        }
+        assert_eq!(counts(&resources), (0, 2, 0));
+        assert!(!requests.lock().unwrap()[0].contains("authorization"));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn jobs_wanting_the_same_resource_at_once_fetch_it_once() {
+        let logo = vec![7; 64 * 1024];
+        let (url, requests) = server(vec![("logo", logo.clone())]).await;
+        let dir = TempDir::new("resources");
+        let resources = Resources::new(Some(dir.path()), 1 << 20, None, None).allow_http();
+        let logo_ref = reference(format!("{}/logo", url), &logo);
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    let mut job = image_job(logo_ref.clone());
+                    resources.resolve(&mut job).unwrap();
+                    assert_eq!(resolved(&job), logo);
+                });
+            }
+        });
+        assert_eq!(requests.lock().unwrap().len(), 1);
+        assert_eq!(counts(&resources), (3, 1, 0));
+        assert!(resources.in_flight.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn bad_hashes_and_missing_resources_fail() {
+        let (url, requests) = server(vec![("logo", b"logo".to_vec())]).await;
+        let resources = Resources::new(None, 1 << 20, None, None).allow_http();
+
+        let mut job = image_job(ResourceRef {
+            url: format!("{}/logo", url),
+            sha256: "abc".to_string(),
+            data: None,
+        });
+        assert!(resources.resolve(&mut job).is_err());
+        assert_eq!(counts(&resources), (0, 0, 0));
+
+        // Downloaded, but not what was asked for, so tried again
+        let mut job = image_job(reference(format!("{}/logo", url), b"other"));
+        let error = resources.resolve(&mut job).err().unwrap();
+        assert!(format!("{:#}", error).contains("Downloaded data has sha256"));
+        assert_eq!(requests.lock().unwrap().len(), FETCH_ATTEMPTS as usize);
+        assert_eq!(counts(&resources), (0, 1, 1));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn the_token_only_goes_to_its_origin_and_only_over_https() {
+        let logo = b"logo".to_vec();
+        let (url, requests) = server(vec![("logo", logo.clone())]).await;
+        let token = Some("s3cret".to_string());
+        let elsewhere =
+            Resources::new(None, 1 << 20, token.clone(), Some("http://127.0.0.1:1")).allow_http();
+        let mut job = image_job(reference(format!("{}/logo", url), &logo));
+        elsewhere.resolve(&mut job).unwrap();
+        assert!(!requests.lock().unwrap()[0].contains("authorization"));
+        let nowhere = Resources::new(None, 1 << 20, token.clone(), None).allow_http();
+        let mut job = image_job(reference(format!("{}/logo", url), &logo));
+        nowhere.resolve(&mut job).unwrap();
+        assert!(!requests.lock().unwrap()[1].contains("authorization"));
+
+        // Outside tests, plain http isn't fetched at all
+        let resources = Resources::new(None, 1 << 20, token, Some(&url));
+        let mut job = image_job(reference(format!("{}/logo", url), &logo));
+        let error = resources.resolve(&mut job).err().unwrap();
+        assert!(format!("{:#}", error).contains("is not https"));
+        let mut job = image_job(reference("file:///etc/passwd".to_string(), &logo));
+        assert!(resources.resolve(&mut job).is_err());
+        assert_eq!(requests.lock().unwrap().len(), 2);
+        assert_eq!(counts(&resources), (0, 0, 0));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn downloads_bigger_than_the_cache_are_cut_off() {
+        let big = vec![b'x'; 4096];
+        let (url, requests) = server(vec![("big", big.clone()), ("stream", big.clone())]).await;
+        let resources = Resources::new(None, 1024, None, None).allow_http();
+        for name in ["big", "stream"] {
+            let mut job = image_job(reference(format!("{}/{}", url, name), &big));
+            let error = resources.resolve(&mut job).err().unwrap();
+            assert!(
+                format!("{:#}", error).contains("bigger than the 1024 byte cache"),
+                "{:#}",
+                error
+            );
+        }
+        assert_eq!(requests.lock().unwrap().len(), 2 * FETCH_ATTEMPTS as usize);
+    }
+
+    #[test]
+    fn pruning_drops_the_least_recently_used() {
+        let dir = TempDir::new("resources");
+        let now = SystemTime::now();
+        for (i, name) in ["old", "newer", "newest"].iter().enumerate() {
+            let path = dir.path().join(name);
+            fs::write(&path, [0; 100]).unwrap();
+            let modified = now - Duration::from_secs(100 - i as u64 * 10);
+            fs::File::open(&path)
+                .unwrap()
+                .set_modified(modified)
+                .unwrap();
+        }
+        // Kept although it's the oldest
+        let keep = dir.path().join("old");
+        prune(dir.path(), 250, &keep);
+        let mut left: Vec<String> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        left.sort();
+        assert_eq!(left, ["newest", "old"]);
+    }
+}
@@ -0,0 +1,2231 @@
+use std::collections::{HashMap, VecDeque};
+use std::panic::AssertUnwindSafe;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Result, bail};
+use chrono::{DateTime, NaiveTime, Utc};
+use escpos::driver::Driver;
+use log::{LevelFilter, debug, error, info, warn};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
+
+use crate::archive::{Archive, ArchiveConfig};
+use crate::atrest::{AtRest, Keys};
+use crate::clock;
+use crate::commands::CommandSet;
+use crate::config::{self, DeviceConfig, RateLimitConfig};
+use crate::control::CommandPolicy;
+use crate::deadletter::DeadLetters;
+use crate::driver::{self, Readiness};
+use crate::faults::{self, FaultMonitor};
+use crate::filters::FilterChain;
+use crate::footer::{Footer, Footers};
+use crate::handle::{JobOutcome, StatusCallback};
+use crate::hooks::{HookConfig, HookEvent, Hooks};
+use crate::journal::{self, Journal};
+use crate::maintenance::Maintenance;
+use crate::memory::RssCheck;
+use crate::metrics::{JobTimings, WriteRate};
+use crate::network;
+use crate::outbox::Outbox;
+use crate::pacing::{self, Decision, Plan};
+use crate::panics::{self, PanicPolicy};
+use crate::probe::Detected;
+use crate::profile::PrinterProfile;
+use crate::protocol::{
+    AckStatus, Capability, Command, ErrorCode, Estimate, Job, Outbound, PauseState, Preflight,
+    Status, Timing, WireProtocol,
+};
+use crate::ratelimit::RateLimiter;
+use crate::render::{self, Rendered};
+use crate::rendercache::RenderCache;
+use crate::report::{self, Report};
+use crate::resources::Resources;
+use crate::signing::Signer;
+use crate::spool::Spool;
+use crate::transcript;
+
+const MAX_CONSECUTIVE_PRINT_FAILURES: u32 = 5;
+/// How many recently finished job ids are remembered, so a job the server
+/// re-sends after a reconnect is acked again instead of printed twice.
+const DEDUP_WINDOW: usize = 500;
+/// How long a woken printer gets to report itself online
+const WAKE_READY_TIMEOUT: Duration = Duration::from_secs(2);
+/// How long a `pause` without `max_secs` lasts
+const DEFAULT_PAUSE: Duration = Duration::from_secs(600);
+/// Longest a pause can last before printing resumes by itself
+const MAX_PAUSE: Duration = Duration::from_secs(3600);
+/// Minimum time between `progress` frames for one job
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How the service runs: what it prints on, the limits it keeps to, and
+/// where it keeps its state. The defaults are those of the command-line
+/// flags.
+#[derive(Clone)]
+pub struct ServiceConfig {
+    /// Spoken when the server picks none of the offered subprotocols
+    pub default_protocol: WireProtocol,
+    pub signer: Option<Signer>,
+    pub require_signed_jobs: bool,
+    /// Jobs naming another tenant are rejected
+    pub tenant: Option<String>,
+    /// With `tenant` set, also accept jobs that name none
+    pub allow_untagged_jobs: bool,
+    pub profile: PrinterProfile,
+    pub spool_dir: Option<PathBuf>,
+    pub spool_compact_threshold: usize,
+    /// Jobs per minute once the burst allowance is used up (0 = unlimited)
+    pub max_jobs_per_minute: u32,
+    pub rate_limit_burst: u32,
+    /// Minimum pause between the end of one job and the start of the next
+    pub min_gap: Duration,
+    /// Close the printer connection after every job and reopen it for the next
+    pub release_printer_between_jobs: bool,
+    /// Maximum undelivered frames kept for resending after a reconnect
+    pub outbox_size: usize,
+    /// Local time to print the daily report
+    pub daily_report: Option<NaiveTime>,
+    /// TTL for jobs that don't set one (0 = never expire)
+    pub default_job_ttl_secs: u64,
+    /// Put the printer to sleep after this long without a job (needs profile sleep commands)
+    pub sleep_after: Option<Duration>,
+    /// Rendered output budget per job, copies included (0 = unlimited)
+    pub max_job_lines: usize,
+    pub max_job_bytes: usize,
+    /// Print an over-budget job's first `max_job_lines` lines instead of failing it
+    pub truncate_oversize: bool,
+    /// Incoming messages larger than this are rejected unread
+    pub max_message_size: usize,
+    /// Reconnect when no complete message arrives for this long
+    pub ws_read_timeout: Duration,
+    /// Commands run after jobs print or fail
+    pub hooks: HookConfig,
+    /// From the device config or identity; empty with neither
+    pub device_id: String,
+    /// Device identity public key, sent in the hello frame
+    pub public_key: Option<String>,
+    /// Where state that should survive restarts (like the footer rotation) is kept
+    pub state_dir: Option<PathBuf>,
+    /// Config file re-read by the `reload` command and SIGHUP
+    pub config_path: Option<PathBuf>,
+    /// The config file as loaded at startup
+    pub loaded_config: Option<DeviceConfig>,
+    /// Rate limits were given as flags, so the config file's are ignored
+    pub rate_limit_from_cli: bool,
+    /// Log level from `RUST_LOG`, used when the config file sets none
+    pub default_log_level: LevelFilter,
+    /// What `--probe-printer` found, reported in the hello frame
+    pub printer: Option<Detected>,
+    /// Poll the printer's status this often while idle, reporting faults
+    pub status_poll: Option<Duration>,
+    /// Print a ticket for each fault episode, saying what happened and what to do
+    pub fault_tickets: bool,
+    /// Language of fault messages, e.g. "en"
+    pub locale: String,
+    /// Where copies of printed tickets go, if anywhere
+    pub archive_dir: Option<PathBuf>,
+    pub archive_url: Option<String>,
+    pub archive_max_bytes: u64,
+    pub archive_max_age: Option<Duration>,
+    /// Printed jobs that can wait to be archived
+    pub archive_queue_size: usize,
+    /// What to do after a job panics
+    pub panic_policy: PanicPolicy,
+    /// Drop the WebSocket connection this long into every session (`--chaos`)
+    pub force_disconnect_every: Option<Duration>,
+    /// Bearer token sent when fetching `image_ref` resources from `resource_origin`
+    pub resource_token: Option<String>,
+    /// The only origin `resource_token` is sent to, like `https://cdn.example.com`
+    pub resource_origin: Option<String>,
+    /// Size cap of the resource cache in the state dir
+    pub resource_cache_bytes: u64,
+    /// Size cap of the in-memory cache of rendered tickets (0 = off)
+    pub render_cache_bytes: usize,
+    /// Size cap of the job outcome journal in the state dir, rotated files included
+    pub journal_max_bytes: u64,
+    /// Rendered bytes kept for queued jobs rendered ahead of their turn (0 = unlimited)
+    pub max_rendered_bytes: usize,
+    /// Shed rendered jobs while RSS is over this many megabytes
+    pub rss_limit_mb: Option<u64>,
+    /// Control command risk levels, rate limits and which need authorizing
+    pub command_policy: CommandPolicy,
+    /// Log a network summary whenever the connection to the server drops
+    pub net_diagnostics: bool,
+    /// Whether the spool and journal are encrypted on disk
+    pub at_rest: AtRest,
+}
+
+impl Default for ServiceConfig {
+    fn default() -> Self {
+        Self {
+            default_protocol: WireProtocol::V2,
+            signer: None,
+            require_signed_jobs: false,
+            tenant: None,
+            allow_untagged_jobs: false,
+            profile: PrinterProfile::find("default")
+                .expect("the default profile is built in")
+                .clone(),
+            spool_dir: None,
+            spool_compact_threshold: 1000,
+            max_jobs_per_minute: 0,
+            rate_limit_burst: 1,
+            min_gap: Duration::ZERO,
+            release_printer_between_jobs: false,
+            outbox_size: 256,
+            daily_report: None,
+            default_job_ttl_secs: 0,
+            sleep_after: None,
+            max_job_lines: 1000,
+            max_job_bytes: 1024 * 1024,
+            truncate_oversize: false,
+            max_message_size: 4 * 1024 * 1024,
+            ws_read_timeout: Duration::from_secs(90),
+            hooks: HookConfig::default(),
+            device_id: String::new(),
+            public_key: None,
+            state_dir: None,
+            config_path: None,
+            loaded_config: None,
+            rate_limit_from_cli: false,
+            default_log_level: LevelFilter::Info,
+            printer: None,
+            status_poll: None,
+            fault_tickets: false,
+            locale: "en".to_string(),
+            archive_dir: None,
+            archive_url: None,
+            archive_max_bytes: 100 * 1024 * 1024,
+            archive_max_age: None,
+            archive_queue_size: crate::archive::CHANNEL_SIZE,
+            panic_policy: PanicPolicy::default(),
+            force_disconnect_every: None,
+            resource_token: None,
+            resource_origin: None,
+            resource_cache_bytes: 50 * 1024 * 1024,
+            render_cache_bytes: crate::rendercache::DEFAULT_KB * 1024,
+            journal_max_bytes: 4096 * 1024,
+            max_rendered_bytes: 0,
+            rss_limit_mb: None,
+            command_policy: CommandPolicy::default(),
+            net_diagnostics: false,
+            at_rest: AtRest::Plain,
+        }
+    }
+}
+
+/// What the transport and the `PrinterService` handle ask of the printer's
+/// task. Each is handled in turn, then the queue is drained.
+pub(crate) enum Request {
+    /// A job from the server
+    Job(Job),
+    /// A job from `PrinterService::submit`, answered with how it ended
+    Submit {
+        job: Job,
+        done: oneshot::Sender<JobOutcome>,
+    },
+    Preview(Job),
+    /// A control command let through by `Control`
+    Command {
+        command: Command,
+        received_at: DateTime<Utc>,
+    },
+    /// A control command `Control` refused, and the reply saying why
+    Refused {
+        command: &'static str,
+        received_at: DateTime<Utc>,
+        reply: Outbound,
+    },
+    /// A message rejected before it got here, for the journal and the server
+    Rejected {
+        tenant: Option<String>,
+        ack: Outbound,
+    },
+    /// A frame for the server that needs nothing else done
+    Reply(Outbound),
+    /// The server's clock in Unix seconds, from its hello
+    ServerTime(i64),
+    /// A connection to the server came up; `reconnect` if it isn't the first
+    Connected {
+        wire: WireProtocol,
+        reconnect: bool,
+    },
+    Disconnected,
+    /// Round trip of the last ping the server answered
+    Rtt(Duration),
+    Heartbeat,
+    /// SIGHUP
+    Reload,
+    Status(oneshot::Sender<Status>),
+    /// Stop once the job being printed (if any) is done
+    Shutdown,
+}
+
+/// A job that has been accepted (and spooled) but not printed yet.
+struct Queued {
+    job: Job,
+    seq: Option<u64>,
+    /// Generated on the device (e.g. the daily report), so there's no one to ack
+    local: bool,
+    queued_at: Instant,
+    /// Rendered while the job ahead of it was being written
+    ahead: Option<Prerender>,
+}
+
+/// A job rendered ahead of its turn, and the footer it was rendered with
+/// (from `Footers::peek`), so it can be rendered again if the rotation picks
+/// another one by the time it prints.
+struct Prerender {
+    footer: Option<Footer>,
+    result: Result<Result<Rendered, String>>,
+    elapsed: Duration,
+}
+
+impl Prerender {
+    /// Size of the rendered ticket held.
+    fn bytes(&self) -> usize {
+        match &self.result {
+            Ok(Ok(rendered)) => rendered.bytes.len(),
+            _ => 0,
+        }
+    }
+
+    /// `None` if a resource it needs couldn't be fetched; that's retried,
+    /// and reported, when its turn comes.
+    fn render(
+        mut job: Job,
+        footer: Option<Footer>,
+        config: &ServiceConfig,
+        resources: &Resources,
+        cache: &RenderCache,
+    ) -> Option<Self> {
+        let _working_on = panics::working_on(job.id.as_deref());
+        let start = Instant::now();
+        if let Err(e) = resources.resolve(&mut job) {
+            debug!("Not rendering job {:?} ahead of time: {:#}", job.id, e);
+            return None;
+        }
+        let result = render_within_budget(&job, footer.as_ref(), config, cache);
+        Some(Self {
+            footer,
+            result,
+            elapsed: start.elapsed(),
+        })
+    }
+}
+
+/// The printer side of the service: the driver plus everything needed to get a
+/// job onto paper and recover when the printer goes away.
+pub(crate) struct Service<D, F> {
+    config: Arc<ServiceConfig>,
+    /// `None` while the connection is released between jobs
+    driver: Option<D>,
+    reconnect: Option<F>,
+    consecutive_failures: u32,
+    /// Printer writes tried for the job being printed, for its journal entry
+    write_attempts: u32,
+    spool: Option<Spool>,
+    queue: VecDeque<Queued>,
+    limiter: RateLimiter,
+    started: Instant,
+    /// Frames waiting to be sent; kept across reconnects until delivered
+    outbox: Arc<Outbox>,
+    /// There's a server to send frames to; not so when embedded
+    server: bool,
+    /// This printer's name, when there are several
+    target: Option<Arc<str>>,
+    /// Callers of `PrinterService::submit` waiting on each job id
+    waiters: HashMap<String, Vec<oneshot::Sender<JobOutcome>>>,
+    /// Jobs submitted without an id, for naming them
+    submitted: u64,
+    on_status: Option<StatusCallback>,
+    /// Final acks of recently finished jobs, oldest first
+    recent: VecDeque<(String, Outbound)>,
+    previews: u64,
+    report: Report,
+    /// When the next daily report is due
+    report_at: Option<Instant>,
+    /// When the last job finished, for the idle sleep timer
+    last_job_at: Instant,
+    asleep: bool,
+    sleep_cycles: u64,
+    hooks: Hooks,
+    /// Progress frames are only queued while connected to a v2 server
+    connected: bool,
+    /// What the current (or last) connection speaks
+    wire: WireProtocol,
+    timings: JobTimings,
+    /// Rolling write speed, for the time-to-print estimate
+    write_rate: WriteRate,
+    /// Config file contents currently in effect, compared against on reload
+    device_config: Option<DeviceConfig>,
+    /// Set by the `pause` command; kept across reconnects but not restarts
+    paused: Option<Pause>,
+    footers: Footers,
+    faults: FaultMonitor,
+    /// The config file's job filters
+    filters: FilterChain,
+    /// When the printer's status is next polled
+    next_poll: Option<Instant>,
+    dead_letters: DeadLetters,
+    archive: Option<Archive>,
+    maintenance: Maintenance,
+    /// Shared with the thread rendering the next job ahead of time
+    resources: Arc<Resources>,
+    /// Also shared with the thread rendering ahead
+    render_cache: Arc<RenderCache>,
+    journal: Journal,
+    rss: Option<RssCheck>,
+    /// Round trip of the last ping answered
+    rtt: Option<Duration>,
+    /// The spool and journal encryption keys, when encrypting
+    keys: Option<Keys>,
+    /// What jobs can require of this printer
+    capabilities: Vec<Capability>,
+}
+
+struct Pause {
+    reason: Option<String>,
+    until: Instant,
+}
+
+impl<D, F> Service<D, F>
+where
+    D: Driver,
+    F: Fn() -> Result<D>,
+{
+    /// Initializes the printer and opens the spool and the rest of the
+    /// service's state. Frames for the server go in `outbox` unless there's
+    /// no `server`, naming `target` when there are several printers.
+    pub fn new(
+        config: Arc<ServiceConfig>,
+        driver: D,
+        reconnect: Option<F>,
+        outbox: Arc<Outbox>,
+        server: bool,
+        target: Option<Arc<str>>,
+        on_status: Option<StatusCallback>,
+    ) -> Result<Self> {
+        match init_printer(&driver) {
+            Ok(_) => info!("Printer initialized."),
+            Err(e) => {
+                error!("Failed to initialize printer: {}", e);
+                return Err(anyhow::anyhow!("Printer init failed"));
+            }
+        }
+
+        let spool = match &config.spool_dir {
+            Some(dir) => Some(Spool::open(
+                dir,
+                config.spool_compact_threshold,
+                config.at_rest.clone(),
+            )?),
+            None => None,
+        };
+        let limiter = RateLimiter::new(
+            config.max_jobs_per_minute,
+            config.rate_limit_burst,
+            config.min_gap,
+        );
+        if limiter.is_enabled() {
+            info!(
+                "Rate limit: {} jobs/minute (burst {}), minimum gap {:?}",
+                config.max_jobs_per_minute, config.rate_limit_burst, config.min_gap
+            );
+        }
+        let service = Service {
+            config: Arc::clone(&config),
+            driver: Some(driver),
+            reconnect,
+            consecutive_failures: 0,
+            write_attempts: 0,
+            spool,
+            queue: VecDeque::new(),
+            limiter,
+            started: Instant::now(),
+            outbox,
+            server,
+            target,
+            waiters: HashMap::new(),
+            submitted: 0,
+            on_status,
+            recent: VecDeque::new(),
+            previews: 0,
+            report: Report::open(config.spool_dir.as_deref()),
+            report_at: config
+                .daily_report
+                .map(|at| Instant::now() + report::until_next(at)),
+            last_job_at: Instant::now(),
+            asleep: false,
+            sleep_cycles: 0,
+            hooks: Hooks::new(config.hooks.clone(), config.device_id.clone()),
+            connected: false,
+            wire: config.default_protocol,
+            timings: JobTimings::default(),
+            write_rate: WriteRate::default(),
+            device_config: config.loaded_config.clone(),
+            paused: None,
+            footers: Footers::new(
+                config
+                    .loaded_config
+                    .as_ref()
+                    .map(|c| c.footers.clone())
+                    .unwrap_or_default(),
+                config.state_dir.as_deref(),
+            ),
+            faults: FaultMonitor::new(
+                faults::table(
+                    config
+                        .loaded_config
+                        .as_ref()
+                        .map_or(&[], |c| c.faults.as_slice()),
+                ),
+                config.locale.clone(),
+            ),
+            filters: FilterChain::new(
+                config
+                    .loaded_config
+                    .as_ref()
+                    .map_or(&[], |c| c.filters.as_slice()),
+            )?,
+            next_poll: config.status_poll.map(|every| Instant::now() + every),
+            dead_letters: DeadLetters::open(config.spool_dir.as_deref(), config.at_rest.clone())?,
+            maintenance: Maintenance::open(
+                config.state_dir.as_deref(),
+                config
+                    .loaded_config
+                    .as_ref()
+                    .map(|c| c.maintenance.clone())
+                    .unwrap_or_default(),
+            ),
+            archive: Archive::start(
+                ArchiveConfig {
+                    dir: config.archive_dir.clone(),
+                    url: config.archive_url.clone(),
+                    max_bytes: config.archive_max_bytes,
+                    max_age: config.archive_max_age,
+                    queue_size: config.archive_queue_size,
+                },
+                config.profile.clone(),
+                config.device_id.clone(),
+            ),
+            resources: Arc::new(Resources::new(
+                config.state_dir.as_deref(),
+                config.resource_cache_bytes,
+                config.resource_token.clone(),
+                config.resource_origin.as_deref(),
+            )),
+            render_cache: Arc::new(RenderCache::new(config.render_cache_bytes)),
+            journal: Journal::open(
+                config.state_dir.as_deref(),
+                config.journal_max_bytes,
+                config.at_rest.clone(),
+            )?,
+            rss: config.rss_limit_mb.map(RssCheck::new),
+            rtt: None,
+            keys: config.at_rest.sealing().cloned(),
+            capabilities: supported_capabilities(&config.profile, config.printer.as_ref()),
+        };
+        service.filters.dry_run();
+        Ok(service)
+    }
+
+    /// The name of the printer profile this service prints with.
+    pub fn profile_name(&self) -> &'static str {
+        self.config.profile.name
+    }
+
+    /// What jobs can require of the printer.
+    pub fn capabilities(&self) -> &[Capability] {
+        &self.capabilities
+    }
+
+    /// Prints what's left in the spool, then handles requests until told
+    /// to shut down, printing queued jobs as the rate limit and any pause
+    /// allow. An error means the printer is gone for good.
+    pub async fn run(mut self, mut requests: mpsc::UnboundedReceiver<Request>) -> Result<()> {
+        self.replay_spool();
+        tokio::task::block_in_place(|| self.drain())?;
+        // Taken from the channel while gathering jobs, handled next
+        let mut held = None;
+        loop {
+            let wake_at = self.next_wake();
+            let request = match held.take() {
+                Some(request) => Some(request),
+                None => tokio::select! {
+                    request = requests.recv() => request,
+                    _ = sleep_until_some(wake_at) => {
+                        tokio::task::block_in_place(|| self.wake())?;
+                        continue;
+                    }
+                },
+            };
+            match request {
+                Some(Request::Shutdown) | None => {
+                    match &self.target {
+                        Some(name) => info!(
+                            "Printer {} stopping with {} queued jobs",
+                            name,
+                            self.queue.len()
+                        ),
+                        None => info!(
+                            "Printer service stopping with {} queued jobs",
+                            self.queue.len()
+                        ),
+                    }
+                    return Ok(());
+                }
+                Some(request) => {
+                    let job = is_job(&request);
+                    self.handle(request);
+                    // Jobs that arrived while the last one printed are queued
+                    // together, so each renders while the one before it prints
+                    while job && let Ok(next) = requests.try_recv() {
+                        if !is_job(&next) {
+                            held = Some(next);
+                            break;
+                        }
+                        self.handle(next);
+                    }
+                    tokio::task::block_in_place(|| self.drain())?;
+                }
+            }
+        }
+    }
+}
+
+fn is_job(request: &Request) -> bool {
+    matches!(request, Request::Job(_) | Request::Submit { .. })
+}
+
+/// Capabilities a printer with `profile` can honour in this build,
+/// advertised in the hello frame. `printer` is what probing it found, and
+/// can only take capabilities away.
+pub fn supported_capabilities(
+    profile: &PrinterProfile,
+    printer: Option<&Detected>,
+) -> Vec<Capability> {
+    let probed = |answer: fn(&Detected) -> Option<bool>| printer.and_then(answer) != Some(false);
+    let mut capabilities = vec![Capability::Text];
+    if profile.font_b_columns > 0 {
+        capabilities.push(Capability::Fonts);
+    }
+    capabilities.push(Capability::Layout);
+    if profile.graphics && probed(|p| p.raster) {
+        capabilities.push(Capability::Images);
+    }
+    capabilities.push(Capability::Timestamps);
+    if profile.graphics && probed(|p| p.qr) {
+        capabilities.push(Capability::Qr);
+    }
+    // User-defined characters only go with ESC/POS and CP437
+    if cfg!(feature = "fallback-font") && profile.graphics && profile.commands == CommandSet::EscPos
+    {
+        capabilities.push(Capability::Unicode);
+    }
+    capabilities
+}
+
+/// Returns a `write_job_with_progress` callback sending a `progress` frame
+/// at most every `PROGRESS_INTERVAL`, or doing nothing when `live` is `None`.
+fn progress_reporter(
+    live: Option<Arc<Outbox>>,
+    target: Option<Arc<str>>,
+    id: Option<String>,
+) -> impl FnMut(usize, usize) {
+    let mut last = Instant::now();
+    move |written, total| {
+        if let Some(live) = &live
+            && written < total
+            && last.elapsed() >= PROGRESS_INTERVAL
+        {
+            last = Instant::now();
+            let frame = Outbound::Progress {
+                id: id.clone(),
+                percent: (written * 100 / total.max(1)) as u8,
+            };
+            live.push_from(target.clone(), frame);
+        }
+    }
+}
+
+/// Sleeps until `at`, or forever when there's nothing to wait for.
+pub(crate) async fn sleep_until_some(at: Option<Instant>) {
+    match at {
+        Some(at) => tokio::time::sleep_until(at).await,
+        None => std::future::pending().await,
+    }
+}
+
+impl<D, F> Service<D, F>
+where
+    D: Driver,
+    F: Fn() -> Result<D>,
+{
+    /// Handles one request from the transport or the handle. Replies go
+    /// to the outbox, or to whoever is waiting on them.
+    fn handle(&mut self, request: Request) {
+        match request {
+            Request::Job(job) => {
+                let reply = self.handle_job(job);
+                // v1 servers aren't told a job was accepted
+                if self.wire == WireProtocol::V2 {
+                    self.send(reply);
+                }
+            }
+            Request::Submit { job, done } => self.submit(job, done),
+            Request::Preview(job) => {
+                let reply = self.preview(job);
+                self.send(reply);
+            }
+            Request::Command {
+                command,
+                received_at,
+            } => {
+                let name = command.name();
+                let reply = self.handle_command(command);
+                self.journal.record_command(name, received_at, &reply);
+                self.send(reply);
+            }
+            Request::Refused {
+                command,
+                received_at,
+                reply,
+            } => {
+                self.journal.record_command(command, received_at, &reply);
+                self.send_from(None, reply);
+            }
+            Request::Rejected { tenant, ack } => {
+                self.journal
+                    .record(tenant.as_deref(), clock::now(), 0, &ack);
+                self.send_from(None, ack);
+            }
+            Request::Reply(frame) => self.send_from(None, frame),
+            Request::ServerTime(time) => {
+                self.set_server_time(time);
+            }
+            Request::Connected { wire, reconnect } => {
+                self.wire = wire;
+                self.connected = wire == WireProtocol::V2;
+                if reconnect {
+                    self.report.record_ws_reconnect();
+                }
+            }
+            Request::Disconnected => self.connected = false,
+            Request::Rtt(rtt) => self.rtt = Some(rtt),
+            Request::Heartbeat => self.heartbeat(),
+            Request::Reload => self.reload_on_signal(),
+            Request::Status(done) => {
+                let _ = done.send(self.status());
+            }
+            Request::Shutdown => {}
+        }
+    }
+
+    /// Queues a job from `PrinterService::submit`, which hears how it ended
+    /// through `done` instead of the server. A job without an id is given one.
+    fn submit(&mut self, mut job: Job, done: oneshot::Sender<JobOutcome>) {
+        let id = match &job.id {
+            Some(id) => id.clone(),
+            None => {
+                self.submitted += 1;
+                let id = format!("local-{}", self.submitted);
+                job.id = Some(id.clone());
+                id
+            }
+        };
+        self.waiters.entry(id).or_default().push(done);
+        let reply = self.handle_job(job);
+        self.send(reply);
+    }
+
+    /// Corrects our idea of the time to the server's, and reschedules the
+    /// daily report for the corrected time of day.
+    fn set_server_time(&mut self, time: i64) -> i64 {
+        let offset = clock::set_server_time(time);
+        if let Some(at) = self.config.daily_report {
+            self.report_at = Some(Instant::now() + report::until_next(at));
+        }
+        offset
+    }
+
+    /// Validates, spools and queues a job. It's printed by the next `drain`.
+    fn handle_job(&mut self, mut job: Job) -> Outbound {
+        if let Some(tenant) = &self.config.tenant {
+            match &job.tenant {
+                Some(job_tenant) if job_tenant != tenant => {
+                    warn!(
+                        "TENANT MISMATCH: rejecting job {:?} for tenant {:?}; this device belongs to tenant {:?}. Check the server's routing.",
+                        job.id, job_tenant, tenant
+                    );
+                    let message = format!(
+                        "Job is for tenant {}, this device is tenant {}",
+                        job_tenant, tenant
+                    );
+                    return self.reject(&job, ErrorCode::TenantMismatch, message);
+                }
+                None if !self.config.allow_untagged_jobs => {
+                    warn!(
+                        "Rejecting job {:?} without a tenant; this device belongs to tenant {:?}",
+                        job.id, tenant
+                    );
+                    let message = format!("Job names no tenant, this device is tenant {}", tenant);
+                    return self.reject(&job, ErrorCode::TenantMismatch, message);
+                }
+                _ => {}
+            }
+        }
+
+        let missing = job.missing_capabilities(&self.capabilities);
+        if !missing.is_empty() {
+            warn!(
+                "Rejecting job {:?}: unsupported capabilities {:?}",
+                job.id, missing
+            );
+            let message = format!("Unsupported capabilities: {}", missing.join(", "));
+            return self.reject(&job, ErrorCode::UnsupportedFeature, message);
+        }
+
+        self.filters.apply(&mut job);
+
+        if let Some(id) = &job.id {
+            if self
+                .queue
+                .iter()
+                .any(|queued| queued.job.id.as_ref() == Some(id))
+            {
+                info!("Job {} is already queued, not queueing it again", id);
+                return Outbound::ack(job.id, AckStatus::Accepted);
+            }
+            if let Some((_, ack)) = self.recent.iter().find(|(done, _)| done == id) {
+                info!("Job {} already finished, re-sending its ack", id);
+                return ack.clone();
+            }
+        }
+
+        // Stored as an absolute time so a replayed job keeps its original deadline
+        job.apply_ttl(self.config.default_job_ttl_secs);
+        let seq = self.spool_job(&job);
+        let id = job.id.clone();
+        self.enqueue(job, seq);
+        Outbound::ack(id, AckStatus::Accepted)
+    }
+
+    /// Queues a server job behind everything of its priority or higher, so
+    /// a `high` job jumps queued `normal` ones but not other `high` ones.
+    fn enqueue(&mut self, job: Job, seq: Option<u64>) {
+        let priority = job.priority.unwrap_or_default();
+        let at = self
+            .queue
+            .iter()
+            .rposition(|queued| queued.job.priority.unwrap_or_default() >= priority)
+            .map_or(0, |last| last + 1);
+        self.queue.insert(
+            at,
+            Queued {
+                job,
+                seq,
+                local: false,
+                queued_at: Instant::now(),
+                ahead: None,
+            },
+        );
+    }
+
+    /// Rejects a job without queueing it, recording that in the journal.
+    fn reject(&mut self, job: &Job, error: ErrorCode, message: impl Into<String>) -> Outbound {
+        let ack = Outbound::error_ack(job.id.clone(), AckStatus::Rejected, error, message);
+        self.journal
+            .record(job.tenant.as_deref(), clock::now(), 0, &ack);
+        ack
+    }
+
+    /// When `wake` should next run: when the rate limit lets the next queued
+    /// job through, the daily report is due or the printer should go to sleep,
+    /// whichever is first.
+    fn next_wake(&mut self) -> Option<Instant> {
+        let drain_at = if let Some(pause) = &self.paused {
+            Some(pause.until)
+        } else if self.queue.is_empty() {
+            None
+        } else {
+            Some(self.limiter.next_allowed(Instant::now()))
+        };
+        let sleep_at = match self.config.sleep_after {
+            Some(idle) if !self.asleep && self.queue.is_empty() => Some(self.last_job_at + idle),
+            _ => None,
+        };
+        [drain_at, self.report_at, sleep_at, self.next_poll]
+            .into_iter()
+            .flatten()
+            .min()
+    }
+
+    /// Time until a job sent now would be printed: the pause, then the queue
+    /// ahead of it at the recent write speed within the rate limit, then the
+    /// job itself.
+    fn estimate(&self) -> Estimate {
+        let now = Instant::now();
+        let earliest = self.paused.as_ref().map_or(now, |pause| pause.until);
+        let job_time = self.write_rate.job_time();
+        let start = self
+            .limiter
+            .start_after(now, earliest, self.queue.len(), job_time);
+        Estimate {
+            time_to_print_ms: (start + job_time)
+                .saturating_duration_since(now)
+                .as_millis() as u64,
+            ms_per_line: (self.write_rate.ms_per_line() * 10.0).round() / 10.0,
+            samples: self.write_rate.samples(),
+        }
+    }
+
+    /// Runs whatever is due: ends an expired pause, queues the daily report,
+    /// polls the printer's status, then drains the queue.
+    fn wake(&mut self) -> Result<()> {
+        if self
+            .paused
+            .as_ref()
+            .is_some_and(|pause| pause.until <= Instant::now())
+        {
+            info!(
+                "Pause timed out, resuming printing with {} queued jobs",
+                self.queue.len()
+            );
+            self.paused = None;
+        }
+        if let (Some(at), Some(time)) = (self.report_at, self.config.daily_report)
+            && at <= Instant::now()
+        {
+            info!("Printing daily report");
+            let text = self.report.text(self.started.elapsed());
+            self.queue.push_front(Queued {
+                job: Job::plain(text),
+                seq: None,
+                local: true,
+                queued_at: Instant::now(),
+                ahead: None,
+            });
+            self.report.reset();
+            self.report_at = Some(Instant::now() + report::until_next(time));
+        }
+        if let Some(idle) = self.config.sleep_after
+            && !self.asleep
+            && self.queue.is_empty()
+            && self.last_job_at + idle <= Instant::now()
+        {
+            self.sleep_printer(idle);
+        }
+        if let (Some(at), Some(every)) = (self.next_poll, self.config.status_poll)
+            && at <= Instant::now()
+        {
+            self.poll_status();
+            self.next_poll = Some(Instant::now() + every);
+        }
+        self.drain()
+    }
+
+    /// Asks the printer for its status and reports faults that started or
+    /// cleared, queueing a fault ticket where one is due. Skipped while the
+    /// printer is released or asleep.
+    fn poll_status(&mut self) {
+        let Some(driver) = self.driver.as_ref().filter(|_| !self.asleep) else {
+            return;
+        };
+        for transition in self.faults.poll(driver) {
+            if transition.ticket && self.config.fault_tickets {
+                let at = clock::local_now().format("%Y-%m-%d %H:%M").to_string();
+                self.queue.push_front(Queued {
+                    job: Job::plain(faults::ticket_text(
+                        &transition.message,
+                        &transition.code,
+                        &at,
+                    )),
+                    seq: None,
+                    local: true,
+                    queued_at: Instant::now(),
+                    ahead: None,
+                });
+            }
+            self.send(Outbound::PrinterFault {
+                code: transition.code,
+                active: transition.active,
+                title: transition.message.title,
+                action: transition.message.action,
+            });
+        }
+    }
+
+    /// Counts power-on time and queues the daily maintenance-due notice if
+    /// one is owed.
+    fn check_maintenance(&mut self) {
+        self.maintenance.tick();
+        if let Some(text) = self.maintenance.take_notice() {
+            warn!("Maintenance due: {}", self.maintenance.due().join(", "));
+            self.queue.push_back(Queued {
+                job: Job::plain(text),
+                seq: None,
+                local: true,
+                queued_at: Instant::now(),
+                ahead: None,
+            });
+        }
+    }
+
+    /// Whether another `bytes` of rendered tickets fit in the queue's
+    /// rendered byte limit.
+    fn fits_rendered(&self, bytes: usize) -> bool {
+        let limit = self.config.max_rendered_bytes;
+        let held: usize = self
+            .queue
+            .iter()
+            .filter_map(|queued| queued.ahead.as_ref())
+            .map(Prerender::bytes)
+            .sum();
+        limit == 0 || held + bytes <= limit
+    }
+
+    /// Checks RSS against `--rss-limit-mb`. Over it, tickets rendered ahead
+    /// of their turn are dropped (they're rendered again when it comes) and
+    /// no more are rendered ahead until RSS is back under. The jobs
+    /// themselves stay queued and spooled.
+    fn check_memory(&mut self) {
+        let Some(rss) = &mut self.rss else {
+            return;
+        };
+        if !rss.check() {
+            return;
+        }
+        let shed: usize = self
+            .queue
+            .iter_mut()
+            .filter_map(|queued| queued.ahead.take())
+            .map(|ahead| ahead.bytes())
+            .sum();
+        if shed > 0 {
+            warn!("Dropped {} bytes of tickets rendered ahead of time", shed);
+        }
+        self.queue.shrink_to_fit();
+        self.recent.shrink_to_fit();
+    }
+
+    /// Sends the profile's sleep command. On failure the printer stays awake
+    /// and we try again after another idle period.
+    fn sleep_printer(&mut self, idle: Duration) {
+        let config = Arc::clone(&self.config);
+        let Some(commands) = &config.profile.sleep else {
+            return;
+        };
+        let result = self.open_printer().and_then(|driver| {
+            driver.write(commands.sleep)?;
+            driver.flush()?;
+            Ok(())
+        });
+        match result {
+            Ok(()) => {
+                info!("Printer idle for {:?}, putting it to sleep", idle);
+                self.asleep = true;
+            }
+            Err(e) => {
+                warn!("Failed to put printer to sleep: {}", e);
+                self.last_job_at = Instant::now();
+            }
+        }
+        self.release_printer();
+    }
+
+    /// Wakes the printer, re-initializes it and waits for it to report itself
+    /// online, so the next job doesn't lose its first bytes.
+    fn wake_printer(&mut self) -> Result<()> {
+        let config = Arc::clone(&self.config);
+        let Some(commands) = &config.profile.sleep else {
+            self.asleep = false;
+            return Ok(());
+        };
+        let driver = self.open_printer()?;
+        driver.write(commands.wake)?;
+        driver.flush()?;
+        std::thread::sleep(commands.settle);
+        init_printer(driver)?;
+        match driver::wait_ready(driver, WAKE_READY_TIMEOUT)? {
+            Readiness::Online => info!("Printer woke up"),
+            Readiness::NoStatus => {
+                warn!("Printer woke up but didn't answer a status request, printing anyway")
+            }
+        }
+        self.asleep = false;
+        self.sleep_cycles += 1;
+        Ok(())
+    }
+
+    /// Prints queued jobs until the queue is empty or the rate limit says to
+    /// wait. Rate-limited jobs simply stay queued. Acks go to the outbox; an
+    /// error means the printer is gone for good and the service should stop.
+    fn drain(&mut self) -> Result<()> {
+        if self.paused.is_some() {
+            return Ok(());
+        }
+        while let Some(front) = self.queue.front() {
+            // Expired jobs don't print, so they don't wait for the rate limit either
+            if front.job.is_expired() {
+                let Queued {
+                    job,
+                    seq,
+                    queued_at,
+                    ..
+                } = self.queue.pop_front().expect("queue is not empty");
+                info!(
+                    "Job {:?} expired before it could be printed, dropping it",
+                    job.id
+                );
+                self.report.record_expired();
+                let ack = Outbound::ack(job.id.clone(), AckStatus::Expired);
+                self.finish_job(&job, seq, queued_at, ack);
+                continue;
+            }
+
+            let delay = self.limiter.delay(Instant::now());
+            if !delay.is_zero() {
+                info!(
+                    "Rate limit: {} queued jobs, next in {:?}",
+                    self.queue.len(),
+                    delay
+                );
+                break;
+            }
+
+            let Queued {
+                job,
+                seq,
+                local,
+                queued_at,
+                ahead,
+            } = self.queue.pop_front().expect("queue is not empty");
+            let queued = queued_at.elapsed();
+            if self.asleep
+                && let Err(e) = self.wake_printer()
+            {
+                warn!("Failed to wake printer: {}", e);
+            }
+            let caught = {
+                let _working_on = panics::working_on(job.id.as_deref());
+                std::panic::catch_unwind(AssertUnwindSafe(|| self.print_job(&job, ahead, local)))
+            };
+            let result = match caught {
+                Ok(result) => result,
+                Err(panic) => {
+                    self.after_panic(&job, seq, local, queued_at, &panics::message(&*panic));
+                    if self.config.panic_policy == PanicPolicy::Abort {
+                        bail!("Job {:?} panicked, exiting (--panic abort)", job.id);
+                    }
+                    continue;
+                }
+            };
+            // Ask while the printer is still open, before it's released
+            let paper_out = !local
+                && matches!(result, Ok(PrintOutcome::Failed) | Err(_))
+                && self
+                    .driver
+                    .as_ref()
+                    .and_then(driver::paper_out)
+                    .unwrap_or(false);
+            self.limiter.record(Instant::now());
+            self.last_job_at = Instant::now();
+            self.release_printer();
+            self.check_memory();
+
+            if local {
+                result?;
+                continue;
+            }
+            let ms = |d: Duration| d.as_millis() as u64;
+            let ack = match result {
+                Ok(PrintOutcome::Printed {
+                    render,
+                    write,
+                    lines,
+                    preflight,
+                }) => {
+                    self.report.record_printed();
+                    self.hooks.fire(HookEvent::Printed, job.id.as_deref(), None);
+                    let total = queued_at.elapsed();
+                    self.timings.queued.record(queued);
+                    self.timings.render.record(render);
+                    self.timings.write.record(write);
+                    self.timings.total.record(total);
+                    self.write_rate.record(lines, write);
+                    self.maintenance.record_job(lines, job.copies());
+                    self.check_maintenance();
+                    Outbound::printed(
+                        job.id.clone(),
+                        Timing {
+                            queued_ms: ms(queued),
+                            render_ms: ms(render),
+                            write_ms: ms(write),
+                            total_ms: ms(total),
+                            preflight: Some(preflight),
+                        },
+                    )
+                }
+                Ok(PrintOutcome::Failed) => {
+                    self.fire_failed(job.id.as_deref(), ErrorCode::PrintFailed, paper_out);
+                    Outbound::error_ack(
+                        job.id.clone(),
+                        AckStatus::Failed,
+                        ErrorCode::PrintFailed,
+                        "Print failed",
+                    )
+                }
+                Ok(PrintOutcome::TooLarge(message)) => {
+                    self.fire_failed(job.id.as_deref(), ErrorCode::JobTooLarge, false);
+                    Outbound::error_ack(
+                        job.id.clone(),
+                        AckStatus::Failed,
+                        ErrorCode::JobTooLarge,
+                        message,
+                    )
+                }
+                Ok(PrintOutcome::Refused {
+                    render,
+                    message,
+                    preflight,
+                }) => {
+                    self.fire_failed(job.id.as_deref(), ErrorCode::JobTooLarge, false);
+                    Outbound::error_ack(
+                        job.id.clone(),
+                        AckStatus::Failed,
+                        ErrorCode::JobTooLarge,
+                        message,
+                    )
+                    .with_timing(Timing {
+                        queued_ms: ms(queued),
+                        render_ms: ms(render),
+                        write_ms: 0,
+                        total_ms: ms(queued_at.elapsed()),
+                        preflight: Some(preflight),
+                    })
+                }
+                // Not remembered for dedup, so the server sending the job
+                // again retries the fetch
+                Ok(PrintOutcome::FetchFailed(message)) => {
+                    self.fire_failed(job.id.as_deref(), ErrorCode::ResourceFetch, false);
+                    self.complete_spooled(seq);
+                    let ack = Outbound::error_ack(
+                        job.id.clone(),
+                        AckStatus::Failed,
+                        ErrorCode::ResourceFetch,
+                        message,
+                    );
+                    self.journal(&job, queued_at, &ack);
+                    self.send(ack);
+                    continue;
+                }
+                // A spooled job and everything behind it stay in the spool
+                // and are replayed after the restart, so it isn't failed
+                // here: the server rerouting it would print it twice
+                Err(e) if seq.is_some() => {
+                    warn!(
+                        "Job {:?} left in the spool to print after the restart",
+                        job.id
+                    );
+                    return Err(e);
+                }
+                // Without a spool the job is lost, so the server is told
+                Err(e) => {
+                    self.fire_failed(job.id.as_deref(), ErrorCode::PrintFailed, paper_out);
+                    let ack = Outbound::error_ack(
+                        job.id.clone(),
+                        AckStatus::Failed,
+                        ErrorCode::PrintFailed,
+                        "Printer disconnected",
+                    );
+                    self.journal(&job, queued_at, &ack);
+                    self.send(ack);
+                    return Err(e);
+                }
+            };
+            self.finish_job(&job, seq, queued_at, ack);
+        }
+        Ok(())
+    }
+
+    /// Cleans up after `job` panicked: the printer is reset in case it was
+    /// left mid-ticket, and the job is failed and set aside, so neither the
+    /// spool nor a resend from the server brings it back.
+    fn after_panic(
+        &mut self,
+        job: &Job,
+        seq: Option<u64>,
+        local: bool,
+        queued_at: Instant,
+        message: &str,
+    ) {
+        if let Err(e) = self.open_printer().and_then(|driver| init_printer(driver)) {
+            warn!("Failed to reset the printer after a panic: {}", e);
+        }
+        self.limiter.record(Instant::now());
+        self.last_job_at = Instant::now();
+        self.release_printer();
+        self.dead_letters.add(job, &format!("panic: {}", message));
+        if local {
+            return;
+        }
+        self.fire_failed(job.id.as_deref(), ErrorCode::InternalError, false);
+        let ack = Outbound::error_ack(
+            job.id.clone(),
+            AckStatus::Failed,
+            ErrorCode::InternalError,
+            format!("Internal error: {}", message),
+        );
+        self.finish_job(job, seq, queued_at, ack);
+    }
+
+    fn fire_failed(&self, id: Option<&str>, code: ErrorCode, paper_out: bool) {
+        self.hooks.fire(HookEvent::Failed, id, Some(code));
+        if paper_out {
+            warn!("Printer reports it is out of paper");
+            self.hooks.fire(HookEvent::PaperOut, id, Some(code));
+        }
+    }
+
+    /// Marks a job done in the spool, journals and remembers its final ack
+    /// for dedup, and sends it.
+    fn finish_job(&mut self, job: &Job, seq: Option<u64>, queued_at: Instant, ack: Outbound) {
+        self.complete_spooled(seq);
+        self.journal(job, queued_at, &ack);
+        if let Some(id) = job.id.clone() {
+            if self.recent.len() >= DEDUP_WINDOW {
+                self.recent.pop_front();
+            }
+            self.recent.push_back((id, ack.clone()));
+        }
+        self.send(ack);
+    }
+
+    /// Records a queued job's final ack in the journal, with the printer
+    /// writes it took.
+    fn journal(&mut self, job: &Job, queued_at: Instant, ack: &Outbound) {
+        let waited = chrono::Duration::from_std(queued_at.elapsed()).unwrap_or_default();
+        let attempts = std::mem::take(&mut self.write_attempts);
+        self.journal
+            .record(job.tenant.as_deref(), clock::now() - waited, attempts, ack);
+    }
+
+    /// Queues a frame for the server. When the outbox is full the oldest frame
+    /// is dropped, so a long disconnect never holds up printing. Acks for
+    /// submitted jobs go to whoever submitted them instead.
+    fn send(&mut self, frame: Outbound) {
+        self.send_from(self.target.clone(), frame);
+    }
+
+    /// Like `send`, naming `target` as the printer the frame is from. Replies
+    /// to messages turned away before reaching a printer name none.
+    fn send_from(&mut self, target: Option<Arc<str>>, frame: Outbound) {
+        if let Outbound::Ack {
+            error: Some(code), ..
+        } = &frame
+        {
+            self.report.record_failure(code.as_str());
+        }
+        let changed = matches!(
+            frame,
+            Outbound::Ack { status, .. } if status != AckStatus::Printing
+        ) || matches!(frame, Outbound::PrinterFault { .. });
+        if let Outbound::Ack { id: Some(id), .. } = &frame
+            && self.waiters.contains_key(id)
+        {
+            if let Some(outcome) = JobOutcome::from_ack(&frame)
+                && let Some(waiters) = self.waiters.remove(id)
+            {
+                for done in waiters {
+                    let _ = done.send(outcome.clone());
+                }
+            }
+        } else if self.server {
+            self.outbox.push_from(target, frame);
+        }
+        if changed {
+            self.status_changed();
+        }
+    }
+
+    /// Renders a job through the full pipeline and returns the transcript. The
+    /// queue, spool and printer are left alone.
+    fn preview(&mut self, mut job: Job) -> Outbound {
+        let missing = job.missing_capabilities(&self.capabilities);
+        if !missing.is_empty() {
+            return Outbound::error_ack(
+                job.id,
+                AckStatus::Rejected,
+                ErrorCode::UnsupportedFeature,
+                format!("Unsupported capabilities: {}", missing.join(", ")),
+            );
+        }
+
+        self.filters.apply(&mut job);
+        let profile = &self.config.profile;
+        match render::render_job(&job, profile, self.footers.peek(&job)) {
+            Ok(rendered) => {
+                self.previews += 1;
+                Outbound::Preview {
+                    id: job.id,
+                    columns: profile.columns,
+                    transcript: transcript::render(
+                        &transcript::decode(&rendered.bytes, profile.commands),
+                        profile,
+                    ),
+                }
+            }
+            Err(e) => Outbound::error_ack(
+                job.id,
+                AckStatus::Rejected,
+                ErrorCode::InvalidJob,
+                e.to_string(),
+            ),
+        }
+    }
+
+    fn handle_command(&mut self, command: Command) -> Outbound {
+        match command {
+            Command::Compact => match self.spool.as_mut().map(Spool::compact) {
+                Some(Ok(stats)) => {
+                    let message = format!(
+                        "Dropped {} completed records, kept {} pending",
+                        stats.dropped, stats.kept
+                    );
+                    info!("Spool compacted: {}", message);
+                    Outbound::command_result("compact", true, message)
+                }
+                Some(Err(e)) => {
+                    error!("Spool compaction failed: {}", e);
+                    Outbound::command_result("compact", false, e.to_string())
+                }
+                None => Outbound::command_result("compact", false, "Spool is not enabled"),
+            },
+            Command::FlushCache => {
+                let dropped = self.render_cache.flush();
+                info!("Render cache flushed, dropped {} tickets", dropped);
+                Outbound::command_result(
+                    "flush_cache",
+                    true,
+                    format!("Dropped {} cached tickets", dropped),
+                )
+            }
+            Command::Report => Outbound::Report {
+                text: self.report.text(self.started.elapsed()),
+                uptime_secs: self.started.elapsed().as_secs(),
+                counters: self.report.counters().clone(),
+            },
+            Command::Status => Outbound::Status(Box::new(self.status())),
+            Command::MaintenanceReset { confirm: None } => {
+                let token = self.maintenance.reset_token();
+                info!("Maintenance counter reset requested, waiting for confirmation");
+                Outbound::command_result(
+                    "maintenance_reset",
+                    false,
+                    format!(
+                        "Send maintenance_reset again with \"confirm\": \"{}\" within 5 minutes to reset the counters",
+                        token
+                    ),
+                )
+            }
+            Command::MaintenanceReset {
+                confirm: Some(token),
+            } => {
+                let before = self.maintenance.state();
+                if self.maintenance.reset(&token) {
+                    warn!(
+                        "Maintenance counters reset (were {} cuts, {} lines, {} m paper, {} h powered on)",
+                        before.cuts, before.lines, before.paper_m, before.power_on_hours
+                    );
+                    Outbound::command_result(
+                        "maintenance_reset",
+                        true,
+                        "Maintenance counters reset",
+                    )
+                } else {
+                    warn!("Refusing maintenance counter reset with a wrong or expired token");
+                    Outbound::command_result(
+                        "maintenance_reset",
+                        false,
+                        "Wrong or expired confirmation token",
+                    )
+                }
+            }
+            Command::Time { time } => {
+                let offset = self.set_server_time(time);
+                Outbound::command_result("time", true, format!("Clock offset {}s", offset))
+            }
+            Command::Pause { reason, max_secs } => {
+                let duration = max_secs
+                    .map_or(DEFAULT_PAUSE, Duration::from_secs)
+                    .min(MAX_PAUSE);
+                info!(
+                    "Printing paused for at most {:?} ({})",
+                    duration,
+                    reason.as_deref().unwrap_or("no reason given")
+                );
+                let message = format!(
+                    "Paused, resuming by itself in {} seconds",
+                    duration.as_secs()
+                );
+                self.paused = Some(Pause {
+                    reason,
+                    until: Instant::now() + duration,
+                });
+                self.status_changed();
+                Outbound::command_result("pause", true, message)
+            }
+            Command::Resume => match self.paused.take() {
+                Some(_) => {
+                    info!("Printing resumed with {} queued jobs", self.queue.len());
+                    self.status_changed();
+                    Outbound::command_result(
+                        "resume",
+                        true,
+                        format!("Resumed, {} jobs queued", self.queue.len()),
+                    )
+                }
+                None => Outbound::command_result("resume", false, "Not paused"),
+            },
+            Command::Lookup { id, since, until } => {
+                if !self.journal.enabled() {
+                    return Outbound::command_result(
+                        "lookup",
+                        false,
+                        "No journal without --state-dir",
+                    );
+                }
+                let time =
+                    |secs: Option<i64>| secs.and_then(|secs| DateTime::from_timestamp(secs, 0));
+                let query = journal::Query {
+                    id,
+                    since: time(since),
+                    until: time(until),
+                };
+                match self.journal.lookup(&query) {
+                    Ok(found) => Outbound::Lookup { found },
+                    Err(e) => {
+                        error!("Journal lookup failed: {:#}", e);
+                        Outbound::command_result("lookup", false, format!("{:#}", e))
+                    }
+                }
+            }
+            Command::Reload => match self.reload() {
+                Ok(message) => Outbound::command_result("reload", true, message),
+                Err(e) => {
+                    error!("Config reload failed, keeping the current config: {:#}", e);
+                    Outbound::command_result("reload", false, format!("{:#}", e))
+                }
+            },
+            Command::RotateKey => match self.rotate_key() {
+                Ok(message) => Outbound::command_result("rotate_key", true, message),
+                Err(e) => {
+                    error!("At-rest key rotation failed: {:#}", e);
+                    Outbound::command_result("rotate_key", false, format!("{:#}", e))
+                }
+            },
+        }
+    }
+
+    /// Moves the spool, journal and dead letters on to the next key. The new
+    /// generation is saved first, so records not yet re-encrypted when this
+    /// fails still open with the key they name.
+    fn rotate_key(&mut self) -> Result<String> {
+        let Some(keys) = &mut self.keys else {
+            bail!("Not encrypting at rest, nothing to rotate");
+        };
+        keys.rotate()?;
+        let keys = keys.clone();
+        let pending = match &mut self.spool {
+            Some(spool) => spool.rotate_key(keys.clone())?.kept,
+            None => 0,
+        };
+        self.journal.rotate_key(keys.clone())?;
+        self.dead_letters.rotate_key(keys.clone())?;
+        let message = format!(
+            "Now at key {}, re-encrypted {} spooled jobs, the journal and the dead letters",
+            keys.generation(),
+            pending
+        );
+        info!("At-rest key rotated: {}", message);
+        Ok(message)
+    }
+
+    /// Periodic checks, and the heartbeat frame for v2 servers.
+    fn heartbeat(&mut self) {
+        self.check_maintenance();
+        self.check_memory();
+        if self.connected {
+            self.outbox.push_from(
+                self.target.clone(),
+                Outbound::Heartbeat {
+                    uptime_secs: self.started.elapsed().as_secs(),
+                    paused: self.pause_state(),
+                    estimate: self.estimate(),
+                    maintenance: self.maintenance.state(),
+                    network: tokio::task::block_in_place(|| network::stats(self.rtt)),
+                },
+            );
+        }
+    }
+
+    fn status(&mut self) -> Status {
+        Status {
+            target: self.target.as_deref().map(str::to_string),
+            uptime_secs: self.started.elapsed().as_secs(),
+            queue_depth: self.queue.len(),
+            rate_limit_delay_ms: if self.queue.is_empty() {
+                0
+            } else {
+                self.limiter.delay(Instant::now()).as_millis() as u64
+            },
+            printer_connected: self.driver.is_some(),
+            undelivered_frames: self.outbox.len(),
+            dropped_frames: self.outbox.dropped(),
+            previews: self.previews,
+            printer_asleep: self.asleep,
+            sleep_cycles: self.sleep_cycles,
+            hook_failures: self.hooks.failures(),
+            timings: self.timings.clone(),
+            paused: self.pause_state(),
+            clock_offset_secs: clock::offset_secs(),
+            estimate: self.estimate(),
+            printer_faults: self.faults.active(),
+            dead_letters: self.dead_letters.recent(),
+            maintenance: self.maintenance.state(),
+            filters: self.filters.filters().to_vec(),
+            resources: self.resources.stats(),
+            render_cache: self.render_cache.stats(),
+            network: tokio::task::block_in_place(|| network::stats(self.rtt)),
+        }
+    }
+
+    /// Tells the `on_status` callback, if any, that the status changed.
+    fn status_changed(&mut self) {
+        if let Some(on_status) = self.on_status.clone() {
+            on_status(&self.status());
+        }
+    }
+
+    fn pause_state(&self) -> Option<PauseState> {
+        self.paused.as_ref().map(|pause| PauseState {
+            reason: pause.reason.clone(),
+            resumes_in_secs: pause
+                .until
+                .saturating_duration_since(Instant::now())
+                .as_secs(),
+        })
+    }
+
+    fn reload_on_signal(&mut self) {
+        info!("SIGHUP received, reloading config");
+        if let Err(e) = self.reload() {
+            error!("Config reload failed, keeping the current config: {:#}", e);
+        }
+    }
+
+    /// Re-reads the config file and applies the settings that can change while
+    /// running. The file is fully loaded and checked first, so a bad file
+    /// changes nothing.
+    fn reload(&mut self) -> Result<String> {
+        let (Some(path), Some(running)) = (&self.config.config_path, &self.device_config) else {
+            bail!("Not started with --config, nothing to reload");
+        };
+        let mut new = config::load(path)?;
+
+        // Restart-only fields keep their running values, so they're refused
+        // again on the next reload if still changed
+        let refused = new.keep_restart_only(running);
+        for field in &refused {
+            warn!("Config change to {} needs a restart, ignoring it", field);
+        }
+
+        let mut applied = Vec::new();
+        if new.hooks != running.hooks {
+            self.hooks.reconfigure(new.hooks.clone());
+            applied.push("hooks");
+        }
+        if new.footers != running.footers {
+            self.footers.reconfigure(new.footers.clone());
+            applied.push("footers");
+        }
+        if new.faults != running.faults {
+            self.faults.reconfigure(faults::table(&new.faults));
+            applied.push("faults");
+        }
+        if new.maintenance != running.maintenance {
+            self.maintenance.reconfigure(new.maintenance.clone());
+            applied.push("maintenance");
+        }
+        if new.filters != running.filters {
+            self.filters = FilterChain::new(&new.filters)?;
+            self.filters.dry_run();
+            applied.push("filters");
+        }
+        if new.rate_limit != running.rate_limit {
+            if self.config.rate_limit_from_cli {
+                warn!("Rate limits are set on the command line, ignoring the config file's");
+            } else {
+                let limits = new.rate_limit.clone().unwrap_or(RateLimitConfig {
+                    max_jobs_per_minute: self.config.max_jobs_per_minute,
+                    burst: self.config.rate_limit_burst,
+                    min_gap_ms: self.config.min_gap.as_millis() as u64,
+                });
+                self.limiter.reconfigure(
+                    limits.max_jobs_per_minute,
+                    limits.burst,
+                    Duration::from_millis(limits.min_gap_ms),
+                );
+                applied.push("rate_limit");
+            }
+        }
+        if new.log_level != running.log_level {
+            log::set_max_level(new.log_level().unwrap_or(self.config.default_log_level));
+            applied.push("log_level");
+        }
+
+        self.device_config = Some(new);
+
+        let mut message = if applied.is_empty() {
+            "No live changes".to_string()
+        } else {
+            format!("Applied {}", applied.join(", "))
+        };
+        if !refused.is_empty() {
+            message.push_str(&format!("; restart needed for {}", refused.join(", ")));
+        }
+        info!("Config reloaded: {}", message);
+        Ok(message)
+    }
+
+    /// Queues jobs left in the spool by a previous run, ahead of anything new.
+    fn replay_spool(&mut self) {
+        let Some(spool) = &self.spool else {
+            return;
+        };
+        for (seq, job) in spool.pending() {
+            info!("Replaying spooled job {:?} (seq {})", job.id, seq);
+            self.enqueue(job, Some(seq));
+        }
+    }
+
+    fn spool_job(&mut self, job: &Job) -> Option<u64> {
+        let spool = self.spool.as_mut()?;
+        match spool.append(job) {
+            Ok(seq) => Some(seq),
+            Err(e) => {
+                error!(
+                    "Failed to spool job {:?}, printing without it: {}",
+                    job.id, e
+                );
+                None
+            }
+        }
+    }
+
+    fn complete_spooled(&mut self, seq: Option<u64>) {
+        if let (Some(spool), Some(seq)) = (self.spool.as_mut(), seq)
+            && let Err(e) = spool.complete(seq)
+        {
+            error!("Failed to mark spooled job {} complete: {}", seq, e);
+        }
+    }
+
+    /// Prints a job, reconnecting the printer and retrying once if the first attempt fails.
+    /// Returns how the attempt went, or an error once the printer appears to be gone for good.
+    /// The next queued job is rendered on another thread while this one is written.
+    /// Printed jobs from the server are archived unless marked sensitive.
+    fn print_job(
+        &mut self,
+        job: &Job,
+        ahead: Option<Prerender>,
+        local: bool,
+    ) -> Result<PrintOutcome> {
+        let footer = self.footers.pick(job);
+        let (result, render) = match ahead {
+            Some(ahead) if ahead.footer == footer => {
+                debug!(
+                    "Job {:?} was rendered while the one before it printed",
+                    job.id
+                );
+                (ahead.result, ahead.elapsed)
+            }
+            _ => {
+                let render_start = Instant::now();
+                let mut resolved = job.clone();
+                if let Err(e) = self.resources.resolve(&mut resolved) {
+                    warn!("Job {:?}: {:#}", job.id, e);
+                    return Ok(PrintOutcome::FetchFailed(format!("{:#}", e)));
+                }
+                let result = render_within_budget(
+                    &resolved,
+                    footer.as_ref(),
+                    &self.config,
+                    &self.render_cache,
+                );
+                (result, render_start.elapsed())
+            }
+        };
+        let rendered = match result {
+            Ok(Ok(rendered)) => rendered,
+            Ok(Err(message)) => return Ok(PrintOutcome::TooLarge(message)),
+            Err(e) => {
+                error!("Failed to render ticket: {}", e);
+                return Ok(PrintOutcome::Failed);
+            }
+        };
+        let plan = pacing::plan(rendered.bytes.len(), &self.config.profile);
+        info!("Job {:?}: {}", job.id, plan.summary());
+        if let Decision::Refused(message) = &plan.decision {
+            return Ok(PrintOutcome::Refused {
+                render,
+                message: message.clone(),
+                preflight: plan.preflight(),
+            });
+        }
+
+        let next = self
+            .queue
+            .front()
+            .filter(|_| !self.rss.as_ref().is_some_and(RssCheck::over))
+            .filter(|next| next.ahead.is_none() && !next.job.is_expired())
+            .map(|next| (next.job.clone(), self.footers.peek(&next.job).cloned()));
+        let config = Arc::clone(&self.config);
+        let (resources, cache) = (Arc::clone(&self.resources), Arc::clone(&self.render_cache));
+        let outcome = std::thread::scope(|scope| {
+            let ahead = next.map(|(next, footer)| {
+                scope.spawn(move || Prerender::render(next, footer, &config, &resources, &cache))
+            });
+            let outcome = self.write_rendered(job, &rendered, render, &plan);
+            if let Some(ahead) = ahead {
+                match ahead.join() {
+                    Ok(None) => {}
+                    Ok(Some(ahead)) if !self.fits_rendered(ahead.bytes()) => {
+                        debug!(
+                            "Not keeping a {} byte ticket rendered ahead of time, over the rendered byte limit",
+                            ahead.bytes()
+                        );
+                    }
+                    Ok(Some(ahead)) => {
+                        if let Some(next) = self.queue.front_mut() {
+                            next.ahead = Some(ahead);
+                        }
+                    }
+                    // Rendered again when its turn comes, where the failure is its own
+                    Err(_) => warn!("Rendering the next job ahead of time panicked"),
+                }
+            }
+            outcome
+        });
+        if let (Some(archive), Ok(PrintOutcome::Printed { .. })) = (&self.archive, &outcome)
+            && !local
+            && !job.sensitive
+        {
+            archive.record(job.id.clone(), &rendered.bytes);
+        }
+        outcome
+    }
+
+    fn write_rendered(
+        &mut self,
+        job: &Job,
+        rendered: &Rendered,
+        render: Duration,
+        plan: &Plan,
+    ) -> Result<PrintOutcome> {
+        let config = Arc::clone(&self.config);
+        let profile = plan.profile(&config.profile);
+        let write_start = Instant::now();
+        let submitted = job
+            .id
+            .as_ref()
+            .is_some_and(|id| self.waiters.contains_key(id));
+        let live =
+            Some(self.outbox.clone()).filter(|_| self.connected && job.progress && !submitted);
+        if let Some(live) = &live {
+            live.push_from(
+                self.target.clone(),
+                Outbound::ack(job.id.clone(), AckStatus::Printing),
+            );
+        }
+        let progress = progress_reporter(live, self.target.clone(), job.id.clone());
+        self.write_attempts += 1;
+        match self.open_printer().and_then(|driver| {
+            driver::write_job_with_progress(driver, rendered, &profile, progress)
+        }) {
+            Ok(stats) => {
+                info!(
+                    "Printed ticket ({} bytes in {} writes, {:?}).",
+                    stats.bytes, stats.writes, stats.elapsed
+                );
+                self.consecutive_failures = 0;
+                self.report.record_paper(rendered.lines);
+                return Ok(PrintOutcome::Printed {
+                    render,
+                    write: write_start.elapsed(),
+                    lines: rendered.lines,
+                    preflight: plan.preflight(),
+                });
+            }
+            Err(e) => {
+                self.consecutive_failures += 1;
+                error!(
+                    "Print failed ({}/{}): {}",
+                    self.consecutive_failures, MAX_CONSECUTIVE_PRINT_FAILURES, e
+                );
+            }
+        }
+
+        // Attempt to reconnect the printer driver and retry the job once
+        if self.reconnect_and_retry(rendered, &profile) {
+            self.consecutive_failures = 0;
+            self.report.record_paper(rendered.lines);
+            return Ok(PrintOutcome::Printed {
+                render,
+                write: write_start.elapsed(),
+                lines: rendered.lines,
+                preflight: plan.preflight(),
+            });
+        }
+
+        if self.consecutive_failures >= MAX_CONSECUTIVE_PRINT_FAILURES {
+            error!(
+                "Printer appears disconnected after {} consecutive failures, exiting for restart",
+                self.consecutive_failures
+            );
+            return Err(anyhow::anyhow!("Printer disconnected"));
+        }
+
+        Ok(PrintOutcome::Failed)
+    }
+
+    /// Returns the printer connection, reopening it if it was released.
+    fn open_printer(&mut self) -> Result<&D> {
+        if self.driver.is_none() {
+            let Some(reconnect_fn) = &self.reconnect else {
+                return Err(anyhow::anyhow!("Printer connection is closed"));
+            };
+            let driver = reconnect_fn()?;
+            init_printer(&driver)?;
+            self.driver = Some(driver);
+        }
+        Ok(self.driver.as_ref().expect("driver was just opened"))
+    }
+
+    /// Closes the printer connection between jobs so another system sharing the
+    /// printer can use it. Only possible when we know how to reopen it.
+    fn release_printer(&mut self) {
+        if self.config.release_printer_between_jobs
+            && self.reconnect.is_some()
+            && self.driver.take().is_some()
+        {
+            debug!("Released printer connection");
+        }
+    }
+
+    /// Reconnects the printer driver and retries writing the rendered job once.
+    /// Returns true only if the retry actually printed successfully.
+    fn reconnect_and_retry(&mut self, rendered: &Rendered, profile: &PrinterProfile) -> bool {
+        let Some(reconnect_fn) = &self.reconnect else {
+            return false;
+        };
+        let new_driver = match reconnect_fn() {
+            Ok(driver) => driver,
+            Err(e) => {
+                warn!("Printer reconnect failed: {}", e);
+                return false;
+            }
+        };
+        self.report.record_printer_reconnect();
+
+        let driver = self.driver.insert(new_driver);
+        if let Err(e) = init_printer(driver) {
+            warn!("Printer reconnected but init failed: {}", e);
+            return false;
+        }
+
+        info!("Printer reconnected, retrying print...");
+        self.write_attempts += 1;
+        match driver::write_job(driver, rendered, profile) {
+            Ok(_) => {
+                info!("Printed ticket after reconnect.");
+                true
+            }
+            Err(e) => {
+                warn!("Retry after reconnect failed: {}", e);
+                false
+            }
+        }
+    }
+}
+
+/// Renders a job with all its copies, checked against the output budget.
+/// With `--truncate-oversize` an over-budget job prints as many whole
+/// copies as fit, or if not even one does, the first `max_job_lines` lines
+/// of one. Otherwise it's refused with the reason. A copy that prints whole
+/// comes from the render cache when it can.
+fn render_within_budget(
+    job: &Job,
+    footer: Option<&Footer>,
+    config: &ServiceConfig,
+    cache: &RenderCache,
+) -> Result<Result<Rendered, String>> {
+    let profile = &config.profile;
+    let (max_lines, max_bytes) = (config.max_job_lines, config.max_job_bytes);
+    let copy = cache.render(job, profile, footer)?;
+    let copies = job.copies();
+    let lines = copy.lines.saturating_mul(copies);
+    let bytes = copy.bytes.len().saturating_mul(copies);
+    let within = |lines: usize, bytes: usize| {
+        (max_lines == 0 || lines <= max_lines) && (max_bytes == 0 || bytes <= max_bytes)
+    };
+    if within(lines, bytes) {
+        return Ok(Ok(copy.repeat(copies)));
+    }
+
+    let message = format!(
+        "Job renders to {} lines and {} bytes over {} copies, over the limit of {} lines and {} bytes",
+        lines, bytes, copies, max_lines, max_bytes
+    );
+    warn!("Job {:?}: {}", job.id, message);
+    if !config.truncate_oversize {
+        return Ok(Err(message));
+    }
+    if within(copy.lines, copy.bytes.len()) {
+        let fit = (1..copies)
+            .rev()
+            .find(|&n| within(copy.lines * n, copy.bytes.len() * n))
+            .unwrap_or(1);
+        warn!(
+            "Printing only {} of {} copies of job {:?}",
+            fit, copies, job.id
+        );
+        return Ok(Ok(copy.repeat(fit)));
+    }
+    let keep = match max_lines {
+        // Only the byte limit is set; keep the share of lines that fits it
+        0 => copy.lines * max_bytes / copy.bytes.len().max(1),
+        // Leave room for the notice and the feeds before the cut
+        max => max.saturating_sub(3).max(1),
+    };
+    let truncated = render::render_job_truncated(job, profile, footer, keep)?;
+    if !within(truncated.lines, truncated.bytes.len()) {
+        return Ok(Err(message));
+    }
+    info!(
+        "Printing one copy of the first {} lines of job {:?}",
+        keep, job.id
+    );
+    Ok(Ok(truncated))
+}
+
+/// How a print attempt ended, short of the printer going away for good.
+enum PrintOutcome {
+    Printed {
+        render: Duration,
+        write: Duration,
+        lines: usize,
+        preflight: Preflight,
+    },
+    Failed,
+    /// Over the output budget, with the reason
+    TooLarge(String),
+    /// Refused by the size check before anything was sent, with the reason
+    Refused {
+        render: Duration,
+        message: String,
+        preflight: Preflight,
+    },
+    /// A resource the job refers to couldn't be fetched, with the reason
+    FetchFailed(String),
+}
+
+/// Sends the init sequence so the printer starts from a known state.
+pub fn init_printer<D: Driver>(driver: &D) -> Result<()> {
+    driver.write(&render::init_sequence()?)?;
+    driver.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{ResourceData, ResourceRef, Segment, TextSegment};
+
+    fn names(capabilities: &[Capability]) -> Vec<&'static str> {
+        capabilities.iter().map(|c| c.name()).collect()
+    }
+
+    #[test]
+    fn capabilities_follow_the_profile() {
+        let default = PrinterProfile::find("default").unwrap();
+        let expected: &[&str] = if cfg!(feature = "fallback-font") {
+            &[
+                "text",
+                "fonts",
+                "layout",
+                "images",
+                "timestamps",
+                "qr",
+                "unicode",
+            ]
+        } else {
+            &["text", "fonts", "layout", "images", "timestamps", "qr"]
+        };
+        assert_eq!(names(&supported_capabilities(default, None)), expected);
+
+        let serial = PrinterProfile::find("serial-58mm").unwrap();
+        assert_eq!(
+            names(&supported_capabilities(serial, None)),
+            ["text", "fonts", "layout", "timestamps"]
+        );
+
+        let star = PrinterProfile::find("star-tsp").unwrap();
+        assert!(!supported_capabilities(star, None).contains(&Capability::Unicode));
+
+        let mut no_font_b = default.clone();
+        no_font_b.font_b_columns = 0;
+        assert!(!supported_capabilities(&no_font_b, None).contains(&Capability::Fonts));
+    }
+
+    #[test]
+    fn probing_only_takes_capabilities_away() {
+        let default = PrinterProfile::find("default").unwrap();
+        let unsure = Detected::default();
+        assert_eq!(
+            supported_capabilities(default, Some(&unsure)),
+            supported_capabilities(default, None)
+        );
+
+        let without = Detected {
+            raster: Some(false),
+            qr: Some(false),
+            ..Detected::default()
+        };
+        let capabilities = supported_capabilities(default, Some(&without));
+        assert!(!capabilities.contains(&Capability::Images));
+        assert!(!capabilities.contains(&Capability::Qr));
+        assert!(capabilities.contains(&Capability::Layout));
+
+        let serial = PrinterProfile::find("serial-58mm").unwrap();
+        let with = Detected {
+            raster: Some(true),
+            ..Detected::default()
+        };
+        assert!(!supported_capabilities(serial, Some(&with)).contains(&Capability::Images));
+    }
+
+    /// Writes at about a megabyte a second, keeping what it's sent.
+    #[derive(Clone, Default)]
+    struct SlowPrinter(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl Driver for SlowPrinter {
+        fn name(&self) -> String {
+            "slow".to_string()
+        }
+
+        fn write(&self, data: &[u8]) -> escpos::errors::Result<()> {
+            std::thread::sleep(Duration::from_micros(data.len() as u64));
+            self.0.lock().unwrap().extend_from_slice(data);
+            Ok(())
+        }
+
+        fn read(&self, _buf: &mut [u8]) -> escpos::errors::Result<usize> {
+            Ok(0)
+        }
+
+        fn flush(&self) -> escpos::errors::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// A ticket with a full-width image that takes a while to dither.
+    fn image_ticket(n: usize) -> Job {
+        const WIDTH: usize = 576;
+        const HEIGHT: usize = 1500;
+        let mut pgm = format!("P5 {} {} 255\n", WIDTH, HEIGHT).into_bytes();
+        pgm.extend((0..WIDTH * HEIGHT).map(|i| ((i * 7 + n * 31) % 251) as u8));
+        let mut job = Job::plain(String::new());
+        job.segments = vec![
+            Segment::Text(TextSegment {
+                text: format!("Ticket {}", n),
+                font: None,
+                line_spacing: None,
+            }),
+            Segment::Image {
+                image_ref: ResourceRef {
+                    url: format!("https://example.com/{}.pgm", n),
+                    sha256: String::new(),
+                    data: Some(ResourceData(pgm.into())),
+                },
+            },
+        ];
+        job
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "a timing test; run with --ignored"]
+    async fn the_next_ticket_renders_while_one_prints() {
+        const TICKETS: usize = 12;
+        let printer = SlowPrinter::default();
+        let service = crate::PrinterService::builder()
+            .config(ServiceConfig {
+                profile: PrinterProfile::find("default").unwrap().clone(),
+                render_cache_bytes: 0,
+                ..ServiceConfig::default()
+            })
+            .driver(printer.clone())
+            .build()
+            .unwrap();
+        let service = Arc::new(service);
+
+        let start = Instant::now();
+        let mut submitted = Vec::new();
+        for n in 0..TICKETS {
+            let service = Arc::clone(&service);
+            submitted.push(tokio::spawn(async move {
+                service.submit(image_ticket(n)).await
+            }));
+            // Queued in order
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let mut serial = Duration::ZERO;
+        for (n, submitted) in submitted.into_iter().enumerate() {
+            match submitted.await.unwrap() {
+                crate::JobOutcome::Printed(timing) => {
+                    serial += Duration::from_millis(timing.render_ms + timing.write_ms);
+                }
+                outcome => panic!("ticket {} wasn't printed: {:?}", n, outcome),
+            }
+        }
+        let elapsed = start.elapsed();
+        eprintln!(
+            "{} tickets in {:?}, against {:?} rendering and writing one after another",
+            TICKETS, elapsed, serial
+        );
+
+        let written = printer.0.lock().unwrap().clone();
+        let order: Vec<usize> = (0..TICKETS)
+            .map(|n| {
+                let label = format!("Ticket {}", n);
+                written
+                    .windows(label.len())
+                    .position(|window| window == label.as_bytes())
+                    .unwrap()
+            })
+            .collect();
+        assert!(order.is_sorted(), "printed out of order: {:?}", order);
+        // Tickets queued while the first printed each render while the one
+        // before them prints
+        assert!(
+            elapsed.as_secs_f64() < serial.as_secs_f64() * 0.85,
+            "{:?} is not much less than {:?}",
+            elapsed,
+            serial
+        );
+        Arc::into_inner(service).unwrap().shutdown().await.unwrap();
+    }
+}
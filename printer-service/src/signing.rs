@@ -0,0 +1,267 @@
+use std::collections::{HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result, anyhow, bail};
+use hmac::{Hmac, KeyInit, Mac};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::clock;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Most signatures remembered to turn away replays. A replay of one older
+/// than its clock skew window is turned away by its timestamp instead.
+const REPLAY_CACHE_SIZE: usize = 4096;
+
+/// Wire envelope for a signed frame. The payload is carried as the exact JSON
+/// string that was signed so the receiver never has to re-serialize it.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Envelope {
+    pub payload: String,
+    pub sig: Signature,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Signature {
+    /// Unix timestamp (seconds) at signing time
+    pub ts: i64,
+    /// Random hex nonce, unique per frame
+    pub nonce: String,
+    /// Hex-encoded HMAC-SHA256 over `ts.nonce.payload`
+    pub mac: String,
+}
+
+/// Signs outbound frames and verifies inbound ones with a per-device HMAC key.
+/// Clones share the signatures already seen, so a frame is only accepted
+/// once whichever of them verifies it.
+#[derive(Clone)]
+pub struct Signer {
+    key: Vec<u8>,
+    max_skew_secs: i64,
+    seen: Arc<Mutex<Seen>>,
+}
+
+/// The signatures of frames accepted recently, oldest first.
+#[derive(Default)]
+struct Seen {
+    order: VecDeque<(i64, Vec<u8>)>,
+    signatures: HashSet<(i64, Vec<u8>)>,
+}
+
+impl Seen {
+    /// Remembers a signature, saying whether it's new. Signatures outside the
+    /// skew window are forgotten first, then the oldest once it's full.
+    fn insert(&mut self, ts: i64, tag: Vec<u8>, oldest: i64) -> bool {
+        while let Some((front, _)) = self.order.front()
+            && (*front < oldest || self.order.len() >= REPLAY_CACHE_SIZE)
+        {
+            let expired = self.order.pop_front().unwrap();
+            self.signatures.remove(&expired);
+        }
+        if !self.signatures.insert((ts, tag.clone())) {
+            return false;
+        }
+        self.order.push_back((ts, tag));
+        true
+    }
+}
+
+impl Signer {
+    pub fn new(key: Vec<u8>, max_skew_secs: i64) -> Result<Self> {
+        if key.is_empty() {
+            bail!("Signing key is empty");
+        }
+        Ok(Self {
+            key,
+            max_skew_secs,
+            seen: Arc::default(),
+        })
+    }
+
+    /// Loads a hex-encoded key from a file (surrounding whitespace ignored).
+    pub fn from_file(path: &str, max_skew_secs: i64) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read signing key file {}", path))?;
+        let key = hex::decode(contents.trim())
+            .with_context(|| format!("Signing key file {} is not valid hex", path))?;
+        Self::new(key, max_skew_secs)
+    }
+
+    /// Wraps a serialized frame in a signed envelope and returns the envelope JSON.
+    pub fn sign(&self, payload: String) -> String {
+        self.sign_at(payload, unix_now())
+    }
+
+    fn sign_at(&self, payload: String, ts: i64) -> String {
+        let nonce = hex::encode(rand::rng().random::<[u8; 12]>());
+        let mac = hex::encode(self.mac(ts, &nonce, &payload).finalize().into_bytes());
+        let envelope = Envelope {
+            payload,
+            sig: Signature { ts, nonce, mac },
+        };
+        serde_json::to_string(&envelope).expect("envelope serialization cannot fail")
+    }
+
+    /// Checks the envelope's MAC (in constant time), that its timestamp is
+    /// within the allowed clock skew of the local clock, and that it wasn't
+    /// accepted before.
+    pub fn verify(&self, envelope: &Envelope) -> Result<()> {
+        self.verify_at(envelope, unix_now())
+    }
+
+    fn verify_at(&self, envelope: &Envelope, now: i64) -> Result<()> {
+        let sig = &envelope.sig;
+        let skew = (now - sig.ts).abs();
+        if skew > self.max_skew_secs {
+            bail!(
+                "Signature timestamp is {}s away from local clock (max {}s)",
+                skew,
+                self.max_skew_secs
+            );
+        }
+
+        let tag = hex::decode(&sig.mac).map_err(|_| anyhow!("Signature MAC is not valid hex"))?;
+        self.mac(sig.ts, &sig.nonce, &envelope.payload)
+            .verify_slice(&tag)
+            .map_err(|_| anyhow!("Signature MAC mismatch"))?;
+        let oldest = now - self.max_skew_secs;
+        if !self.seen.lock().unwrap().insert(sig.ts, tag, oldest) {
+            bail!("Signed frame was already received");
+        }
+        Ok(())
+    }
+
+    /// Checks (in constant time) that `auth` is the hex HMAC of
+    /// `<challenge>.<command>`, authorizing one run of `command`.
+    pub fn verify_challenge(&self, challenge: &str, command: &str, auth: &str) -> bool {
+        let Ok(tag) = hex::decode(auth) else {
+            return false;
+        };
+        let mut mac =
+            HmacSha256::new_from_slice(&self.key).expect("HMAC accepts keys of any length");
+        mac.update(challenge.as_bytes());
+        mac.update(b".");
+        mac.update(command.as_bytes());
+        mac.verify_slice(&tag).is_ok()
+    }
+
+    fn mac(&self, ts: i64, nonce: &str, payload: &str) -> HmacSha256 {
+        let mut mac =
+            HmacSha256::new_from_slice(&self.key).expect("HMAC accepts keys of any length");
+        mac.update(ts.to_string().as_bytes());
+        mac.update(b".");
+        mac.update(nonce.as_bytes());
+        mac.update(b".");
+        mac.update(payload.as_bytes());
+        mac
+    }
+}
+
+fn unix_now() -> i64 {
+    clock::now().timestamp()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: i64 = 1_760_000_000;
+    const SKEW: i64 = 300;
+
+    fn signer() -> Signer {
+        Signer::new(b"device key".to_vec(), SKEW).unwrap()
+    }
+
+    fn envelope(json: &str) -> Envelope {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn a_signed_frame_is_accepted() {
+        let signer = signer();
+        let signed = envelope(&signer.sign_at(r#"{"type":"job"}"#.to_string(), NOW));
+        assert_eq!(signed.payload, r#"{"type":"job"}"#);
+        signer.verify_at(&signed, NOW).unwrap();
+    }
+
+    #[test]
+    fn a_tampered_frame_is_rejected() {
+        let signer = signer();
+        let signed = signer.sign_at(r#"{"type":"job","text":"1 coffee"}"#.to_string(), NOW);
+
+        let mut tampered = envelope(&signed);
+        tampered.payload = r#"{"type":"job","text":"9 coffees"}"#.to_string();
+        assert!(signer.verify_at(&tampered, NOW).is_err());
+
+        let mut retimed = envelope(&signed);
+        retimed.sig.ts += 1;
+        assert!(signer.verify_at(&retimed, NOW).is_err());
+
+        let other = Signer::new(b"another key".to_vec(), SKEW).unwrap();
+        assert!(other.verify_at(&envelope(&signed), NOW).is_err());
+
+        let mut garbled = envelope(&signed);
+        garbled.sig.mac = "not hex".to_string();
+        assert!(signer.verify_at(&garbled, NOW).is_err());
+    }
+
+    #[test]
+    fn the_timestamp_must_be_within_the_skew() {
+        let signer = signer();
+        for ts in [NOW - SKEW, NOW + SKEW] {
+            let signed = envelope(&signer.sign_at("{}".to_string(), ts));
+            signer.verify_at(&signed, NOW).unwrap();
+        }
+        for ts in [NOW - SKEW - 1, NOW + SKEW + 1] {
+            let signed = envelope(&signer.sign_at("{}".to_string(), ts));
+            let error = signer.verify_at(&signed, NOW).unwrap_err();
+            assert!(error.to_string().contains("301s"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn a_frame_is_only_accepted_once() {
+        let signer = signer();
+        let signed = signer.sign_at(r#"{"type":"command"}"#.to_string(), NOW);
+        signer.verify_at(&envelope(&signed), NOW).unwrap();
+        assert!(signer.verify_at(&envelope(&signed), NOW + 1).is_err());
+        // Clones share what they've seen
+        assert!(signer.clone().verify_at(&envelope(&signed), NOW).is_err());
+        // The same payload signed again is a new frame
+        let again = signer.sign_at(r#"{"type":"command"}"#.to_string(), NOW);
+        signer.verify_at(&envelope(&again), NOW).unwrap();
+    }
+
+    #[test]
+    fn the_replay_cache_is_bounded() {
+        let signer = signer();
+        for n in 0..REPLAY_CACHE_SIZE + 10 {
+            let signed = envelope(&signer.sign_at(n.to_string(), NOW));
+            signer.verify_at(&signed, NOW).unwrap();
+        }
+        let seen = signer.seen.lock().unwrap();
+        assert_eq!(seen.order.len(), REPLAY_CACHE_SIZE);
+        assert_eq!(seen.signatures.len(), REPLAY_CACHE_SIZE);
+        drop(seen);
+
+        // Signatures past the skew window are dropped as newer ones arrive
+        let later = NOW + 2 * SKEW + 1;
+        let signed = envelope(&signer.sign_at("later".to_string(), later));
+        signer.verify_at(&signed, later).unwrap();
+        assert_eq!(signer.seen.lock().unwrap().order.len(), 1);
+    }
+
+    #[test]
+    fn challenges_are_checked() {
+        let signer = signer();
+        let mut mac = HmacSha256::new_from_slice(b"device key").unwrap();
+        mac.update(b"abc123.reload");
+        let auth = hex::encode(mac.finalize().into_bytes());
+        assert!(signer.verify_challenge("abc123", "reload", &auth));
+        assert!(!signer.verify_challenge("abc123", "pause", &auth));
+        assert!(!signer.verify_challenge("abc124", "reload", &auth));
+        assert!(!signer.verify_challenge("abc123", "reload", "zz"));
+    }
+}
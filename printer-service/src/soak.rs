@@ -0,0 +1,377 @@
+//! The `soak` subcommand: feeds synthetic jobs through rendering and a
+//! discarding printer driver at a steady rate for a long time, then reports
+//! throughput, queue depth, latency and memory. Built with the `chaos`
+//! feature it can inject faults on the way (see `--chaos`). Exits non-zero
+//! if a job fails without faults being injected, so a short soak works as
+//! a CI gate.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{Result, bail};
+use escpos::driver::Driver;
+use log::{info, warn};
+use rand::Rng;
+use rand::seq::IndexedRandom;
+
+use crate::driver;
+use crate::memory::{self, rss_kb};
+use crate::profile::{self, PrinterProfile};
+use crate::protocol::{Job, ResourceData, ResourceRef, RuleStyle, Segment, TextSegment};
+use crate::render::{self, RecordingDriver};
+
+/// Latencies kept for the percentiles; a random sample beyond this keeps
+/// memory flat however long the soak runs
+const LATENCY_SAMPLES: usize = 10_000;
+
+const WORDS: &[&str] = &[
+    "flat", "white", "long", "black", "oat", "latte", "extra", "shot", "decaf", "soy", "large",
+    "small", "table", "takeaway", "no", "sugar", "hot", "iced", "muffin", "toast",
+];
+
+#[derive(clap::Args, Debug)]
+pub struct SoakArgs {
+    /// Jobs generated per second
+    #[arg(long, default_value_t = 2.0)]
+    rate: f64,
+
+    /// How long to generate jobs for
+    #[arg(long, default_value_t = 60)]
+    duration_secs: u64,
+
+    /// Printer profile to render for
+    #[arg(long, default_value = "default", value_parser = clap::builder::PossibleValuesParser::new(profile::names()))]
+    profile: String,
+
+    /// Log progress this often
+    #[arg(long, default_value_t = 60)]
+    report_every_secs: u64,
+
+    /// Render with the --low-memory raster banding, to compare peak RSS with and without it
+    #[arg(long)]
+    low_memory: bool,
+
+    /// Inject driver faults, e.g. write_errors=0.05,latency_ms=20,paper_out=0.5 (disconnect_secs has no effect here)
+    #[cfg(feature = "chaos")]
+    #[arg(long, value_parser = crate::chaos::parse)]
+    chaos: Option<crate::chaos::ChaosConfig>,
+}
+
+#[derive(Default)]
+struct Stats {
+    printed: u64,
+    failed: u64,
+    paper_out: u64,
+    bytes: u64,
+    max_queue: usize,
+    latency_sum: Duration,
+    latency_max: Duration,
+    samples: Vec<Duration>,
+    seen: u64,
+    rss_start_kb: Option<u64>,
+}
+
+impl Stats {
+    fn record_latency(&mut self, latency: Duration) {
+        self.latency_sum += latency;
+        self.latency_max = self.latency_max.max(latency);
+        self.seen += 1;
+        if self.samples.len() < LATENCY_SAMPLES {
+            self.samples.push(latency);
+        } else {
+            // Reservoir sampling: every latency so far is equally likely to be kept
+            let slot = rand::rng().random_range(0..self.seen) as usize;
+            if slot < LATENCY_SAMPLES {
+                self.samples[slot] = latency;
+            }
+        }
+    }
+
+    fn percentile(sorted: &[Duration], p: f64) -> Duration {
+        if sorted.is_empty() {
+            return Duration::ZERO;
+        }
+        let index = ((sorted.len() - 1) as f64 * p).round() as usize;
+        sorted[index]
+    }
+
+    fn summary(&self, elapsed: Duration, generated: u64) -> String {
+        let mut sorted = self.samples.clone();
+        sorted.sort();
+        let done = self.printed + self.failed;
+        let kb =
+            |value: Option<u64>| value.map_or("unknown".to_string(), |kb| format!("{} kB", kb));
+        let rss_end = rss_kb("VmRSS");
+        let growth = match (self.rss_start_kb, rss_end) {
+            (Some(start), Some(end)) => format!("{:+} kB", end as i64 - start as i64),
+            _ => "unknown".to_string(),
+        };
+        format!(
+            "Soak finished after {:.0?}\n\
+             Jobs: {} generated, {} printed, {} failed ({} paper out)\n\
+             Throughput: {:.2} jobs/s, {} bytes written\n\
+             Queue: max depth {}\n\
+             Latency: mean {:.1?}, p50 {:.1?}, p95 {:.1?}, p99 {:.1?}, max {:.1?}\n\
+             Memory: RSS {} at start, {} at end ({}), peak {}",
+            elapsed,
+            generated,
+            self.printed,
+            self.failed,
+            self.paper_out,
+            done as f64 / elapsed.as_secs_f64().max(f64::EPSILON),
+            self.bytes,
+            self.max_queue,
+            self.latency_sum / (done.max(1) as u32),
+            Self::percentile(&sorted, 0.50),
+            Self::percentile(&sorted, 0.95),
+            Self::percentile(&sorted, 0.99),
+            self.latency_max,
+            kb(self.rss_start_kb),
+            kb(rss_end),
+            growth,
+            kb(rss_kb("VmHWM")),
+        )
+    }
+}
+
+pub fn run(args: &SoakArgs) -> Result<()> {
+    if !args.rate.is_finite() || args.rate <= 0.0 {
+        bail!("--rate must be more than 0");
+    }
+    let mut profile = PrinterProfile::find(&args.profile)
+        .expect("profile names are validated by clap")
+        .clone();
+    if args.low_memory {
+        profile.raster_band_rows = memory::band_rows(profile.raster_band_rows);
+    }
+    let recorder = RecordingDriver::default();
+
+    #[cfg(feature = "chaos")]
+    if let Some(chaos) = args.chaos.clone() {
+        info!("Injecting faults: {:?}", chaos);
+        let driver = crate::chaos::ChaosDriver::new(recorder.clone(), chaos);
+        let outcome = soak(&driver, &recorder, &profile, args, true);
+        info!("Faults injected: {}", driver.injected());
+        return outcome;
+    }
+    soak(&recorder, &recorder, &profile, args, false)
+}
+
+fn soak<D: Driver>(
+    driver: &D,
+    recorder: &RecordingDriver,
+    profile: &PrinterProfile,
+    args: &SoakArgs,
+    chaos: bool,
+) -> Result<()> {
+    let duration = Duration::from_secs(args.duration_secs);
+    let interval = Duration::from_secs_f64(1.0 / args.rate);
+    info!(
+        "Soaking profile {} at {} jobs/s for {:?}",
+        profile.name, args.rate, duration
+    );
+
+    let queued = Arc::new(AtomicUsize::new(0));
+    let (tx, rx) = mpsc::channel::<(Job, Instant)>();
+    let producer = thread::spawn({
+        let queued = queued.clone();
+        move || {
+            let start = Instant::now();
+            let mut generated = 0u64;
+            while start.elapsed() < duration {
+                generated += 1;
+                queued.fetch_add(1, Ordering::Relaxed);
+                if tx.send((synthetic_job(generated), Instant::now())).is_err() {
+                    break;
+                }
+                let next = start + interval.mul_f64(generated as f64);
+                thread::sleep(next.saturating_duration_since(Instant::now()));
+            }
+            generated
+        }
+    });
+
+    let mut stats = Stats {
+        rss_start_kb: rss_kb("VmRSS"),
+        ..Stats::default()
+    };
+    let start = Instant::now();
+    let report_every = Duration::from_secs(args.report_every_secs.max(1));
+    let mut next_report = start + report_every;
+    // Ends once the producer is done and everything it sent has been printed
+    for (job, queued_at) in rx.iter() {
+        stats.max_queue = stats.max_queue.max(queued.load(Ordering::Relaxed));
+        let result = render::render_job(&job, profile, None)
+            .and_then(|rendered| driver::write_job(driver, &rendered, profile));
+        recorder.take();
+        queued.fetch_sub(1, Ordering::Relaxed);
+        match result {
+            Ok(written) => {
+                stats.printed += 1;
+                stats.bytes += written.bytes as u64;
+            }
+            Err(e) => {
+                stats.failed += 1;
+                if driver::paper_out(driver) == Some(true) {
+                    stats.paper_out += 1;
+                }
+                if !chaos {
+                    warn!("Job {} failed: {}", job.id.as_deref().unwrap_or("?"), e);
+                }
+            }
+        }
+        stats.record_latency(queued_at.elapsed());
+
+        if Instant::now() >= next_report {
+            next_report += report_every;
+            info!(
+                "Soak {:.0?}: {} printed, {} failed, {} queued, RSS {}",
+                start.elapsed(),
+                stats.printed,
+                stats.failed,
+                queued.load(Ordering::Relaxed),
+                rss_kb("VmRSS").map_or("unknown".to_string(), |kb| format!("{} kB", kb))
+            );
+        }
+    }
+    let generated = producer
+        .join()
+        .map_err(|_| anyhow::anyhow!("Job generator panicked"))?;
+
+    println!("{}", stats.summary(start.elapsed(), generated));
+    if stats.failed > 0 && !chaos {
+        bail!("{} of {} jobs failed", stats.failed, generated);
+    }
+    Ok(())
+}
+
+/// A job of random length mixing plain text with rules, spacers and a box,
+/// roughly like an order ticket. Every tenth has an image at the top, so
+/// the raster banding `--low-memory` shrinks gets some use.
+fn synthetic_job(n: u64) -> Job {
+    let mut rng = rand::rng();
+    let mut words = |count: usize| {
+        (0..count)
+            .map(|_| *WORDS.choose(&mut rng).expect("word list is not empty"))
+            .collect::<Vec<_>>()
+            .join(" ")
+    };
+    let items = (0..rand::rng().random_range(1..=12))
+        .map(|_| format!("{}x {}", rand::rng().random_range(1..=3), words(3)))
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    let mut job = Job::plain(String::new());
+    job.id = Some(format!("soak-{}", n));
+    job.segments = vec![
+        text(format!("Order {}", n)),
+        Segment::Rule {
+            rule: RuleStyle::Double,
+        },
+        text(items),
+        Segment::Spacer { spacer: 24 },
+        Segment::Box {
+            segments: vec![text(words(8))],
+        },
+    ];
+    if n.is_multiple_of(10) {
+        let image = gradient(rand::rng().random_range(200..=1200));
+        job.segments.insert(
+            0,
+            Segment::Image {
+                image_ref: ResourceRef {
+                    url: "soak:gradient".to_string(),
+                    sha256: String::new(),
+                    data: Some(ResourceData(image.into())),
+                },
+            },
+        );
+    }
+    job
+}
+
+/// A full-width PGM shading from white to black down its height.
+fn gradient(height: usize) -> Vec<u8> {
+    const WIDTH: usize = 576;
+    let mut pgm = format!("P5 {} {} 255\n", WIDTH, height).into_bytes();
+    for y in 0..height {
+        let level = 255 - (y * 255 / height) as u8;
+        pgm.extend(std::iter::repeat_n(level, WIDTH));
+    }
+    pgm
+}
+
+fn text(text: String) -> Segment {
+    Segment::Text(TextSegment {
+        text,
+        font: None,
+        line_spacing: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        soak: SoakArgs,
+    }
+
+    /// How far RSS rose above where it was before a soak, in kB, with the
+    /// peak reset first. `None` where the kernel doesn't keep one.
+    fn rss_growth_kb(args: &[&str]) -> Option<u64> {
+        std::fs::write("/proc/self/clear_refs", "5").ok()?;
+        let start = rss_kb("VmRSS")?;
+        run(&Cli::parse_from(args).soak).unwrap();
+        Some(rss_kb("VmHWM")?.saturating_sub(start))
+    }
+
+    #[test]
+    fn synthetic_jobs_render() {
+        let profile = PrinterProfile::find("default").unwrap();
+        for n in 1..=20 {
+            let job = synthetic_job(n);
+            assert_eq!(job.id.as_deref(), Some(format!("soak-{}", n).as_str()));
+            let has_image = job
+                .segments
+                .iter()
+                .any(|segment| matches!(segment, Segment::Image { .. }));
+            assert_eq!(has_image, n.is_multiple_of(10));
+            let rendered = render::render_job(&job, profile, None).unwrap();
+            // GS v 0, a raster image
+            let raster = rendered.bytes.windows(3).any(|w| w == b"\x1dv0");
+            assert_eq!(raster, has_image, "job {}", n);
+        }
+    }
+
+    #[test]
+    #[ignore = "soaks for a minute; run with --ignored"]
+    fn low_memory_peaks_no_higher() {
+        let soak = ["soak", "--rate", "20", "--duration-secs", "30"];
+        // Warm up first, so neither run pays for the allocator's first arenas
+        run(&Cli::parse_from(["soak", "--rate", "20", "--duration-secs", "2"]).soak).unwrap();
+        let Some(normal) = rss_growth_kb(&soak) else {
+            eprintln!("No peak RSS to compare on this system");
+            return;
+        };
+        let low = rss_growth_kb(&[&soak[..], &["--low-memory"]].concat()).unwrap();
+        eprintln!(
+            "Peak RSS growth: {} kB normally, {} kB with --low-memory",
+            normal, low
+        );
+        // Allow a few pages for the allocator's noise
+        assert!(low <= normal + 256, "{} kB against {} kB", low, normal);
+        assert!(
+            low < memory::LOW_MEMORY_RSS_LIMIT_MB * 1024,
+            "{} kB is over the low-memory RSS limit",
+            low
+        );
+    }
+}
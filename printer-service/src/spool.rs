@@ -0,0 +1,491 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::atrest::{AtRest, Keys, Sealed};
+use crate::protocol::Job;
+
+const SPOOL_FILE: &str = "spool.log";
+/// Each record is `[len: u32 LE][crc32: u32 LE][payload]`.
+const HEADER_LEN: usize = 8;
+/// Anything larger than this is treated as a corrupt length field.
+const MAX_RECORD_LEN: usize = 16 * 1024 * 1024;
+/// How every payload starts: a record is a JSON object tagged with its
+/// `op` first. JSON escapes the quotes in strings, so these bytes are
+/// nearly always the start of a payload, and anywhere else they're only
+/// checked and passed over.
+const PAYLOAD_START: &[u8] = br#"{"op":""#;
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Record {
+    Job {
+        seq: u64,
+        job: Box<Job>,
+    },
+    /// A job encrypted at rest; the sequence number stays readable
+    SealedJob {
+        seq: u64,
+        #[serde(flatten)]
+        sealed: Sealed,
+    },
+    Done {
+        seq: u64,
+    },
+}
+
+/// Append-only on-disk journal of accepted jobs, so a job that was received but
+/// not yet printed survives a crash or power cut.
+///
+/// Records are length-prefixed and CRC-checked. A torn tail left by a power cut
+/// mid-append is truncated on open, and corrupt records elsewhere are skipped.
+/// Encrypted at rest, each job is sealed in its own record.
+pub struct Spool {
+    path: PathBuf,
+    file: File,
+    at_rest: AtRest,
+    pending: BTreeMap<u64, Job>,
+    next_seq: u64,
+    completed: usize,
+    compact_threshold: usize,
+}
+
+pub struct CompactStats {
+    pub dropped: usize,
+    pub kept: usize,
+}
+
+impl Spool {
+    /// Opens (or creates) the spool in `dir`, recovering pending jobs from it.
+    /// The file is compacted automatically once more than `compact_threshold`
+    /// completed records have accumulated. Jobs stored the other way than
+    /// `at_rest` says are rewritten, encrypting or decrypting them.
+    pub fn open(dir: &Path, compact_threshold: usize, at_rest: AtRest) -> Result<Self> {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create spool directory {}", dir.display()))?;
+        let path = dir.join(SPOOL_FILE);
+
+        let mut bytes = Vec::new();
+        if path.exists() {
+            File::open(&path)
+                .and_then(|mut f| f.read_to_end(&mut bytes))
+                .with_context(|| format!("Failed to read spool {}", path.display()))?;
+        }
+
+        let Scan {
+            records,
+            valid_len,
+            skipped,
+        } = scan_records(&bytes);
+        if valid_len < bytes.len() {
+            warn!(
+                "Spool {} has a torn or corrupt tail, truncating {} bytes",
+                path.display(),
+                bytes.len() - valid_len
+            );
+        }
+        if skipped > 0 {
+            warn!(
+                "Spool {} has {} corrupt bytes between records, skipping them",
+                path.display(),
+                skipped
+            );
+        }
+
+        let mut pending = BTreeMap::new();
+        let mut completed = 0;
+        let mut next_seq = 0;
+        // Jobs stored the other way than they should be from now on
+        let mut migrate = false;
+        for record in records {
+            match record {
+                Record::Job { seq, job } => {
+                    migrate |= at_rest.sealing().is_some();
+                    next_seq = next_seq.max(seq + 1);
+                    pending.insert(seq, *job);
+                }
+                Record::SealedJob { seq, sealed } => {
+                    let keys = at_rest
+                        .keys()
+                        .ok_or_else(|| AtRest::locked(&format!("Spool {}", path.display())))?;
+                    let job = open_job(keys, seq, &sealed)
+                        .with_context(|| format!("Spool {} job {}", path.display(), seq))?;
+                    migrate |= at_rest.sealing().is_none();
+                    next_seq = next_seq.max(seq + 1);
+                    pending.insert(seq, job);
+                }
+                Record::Done { seq } => {
+                    pending.remove(&seq);
+                    completed += 1;
+                }
+            }
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("Failed to open spool {}", path.display()))?;
+        file.set_len(valid_len as u64)?;
+        file.sync_all()?;
+
+        info!(
+            "Spool {} opened: {} pending, {} completed records",
+            path.display(),
+            pending.len(),
+            completed
+        );
+
+        let mut spool = Self {
+            path,
+            file,
+            at_rest,
+            pending,
+            next_seq,
+            completed,
+            compact_threshold,
+        };
+        if migrate {
+            let how = match spool.at_rest.sealing() {
+                Some(_) => "Encrypting",
+                None => "Decrypting",
+            };
+            info!(
+                "{} spool {}: {} pending jobs",
+                how,
+                spool.path.display(),
+                spool.pending.len()
+            );
+        }
+        // Rewrite the file so the corrupt region doesn't have to be skipped
+        // again, or to store it the new way
+        if skipped > 0 || migrate {
+            spool.compact()?;
+        }
+        Ok(spool)
+    }
+
+    /// Jobs that were accepted but never completed, oldest first.
+    pub fn pending(&self) -> Vec<(u64, Job)> {
+        self.pending
+            .iter()
+            .map(|(seq, job)| (*seq, job.clone()))
+            .collect()
+    }
+
+    /// Durably records an accepted job and returns its spool sequence number.
+    pub fn append(&mut self, job: &Job) -> Result<u64> {
+        let seq = self.next_seq;
+        self.write_record(&self.job_record(seq, job))?;
+        self.next_seq += 1;
+        self.pending.insert(seq, job.clone());
+        Ok(seq)
+    }
+
+    /// Marks a job as finished so it won't be replayed.
+    pub fn complete(&mut self, seq: u64) -> Result<()> {
+        if self.pending.remove(&seq).is_none() {
+            return Ok(());
+        }
+        self.write_record(&Record::Done { seq })?;
+        self.completed += 1;
+
+        if self.completed > self.compact_threshold {
+            let stats = self.compact()?;
+            info!(
+                "Spool compacted automatically: dropped {} completed records, kept {} pending",
+                stats.dropped, stats.kept
+            );
+        }
+        Ok(())
+    }
+
+    /// Rewrites the spool with only the pending jobs. The new file is written
+    /// beside the old one and renamed over it, so a crash mid-compaction leaves
+    /// one complete spool or the other.
+    pub fn compact(&mut self) -> Result<CompactStats> {
+        let tmp_path = self.path.with_extension("compact");
+        let mut tmp = File::create(&tmp_path)
+            .with_context(|| format!("Failed to create {}", tmp_path.display()))?;
+        for (seq, job) in &self.pending {
+            tmp.write_all(&encode_record(&self.job_record(*seq, job)))?;
+        }
+        tmp.sync_all()?;
+        fs::rename(&tmp_path, &self.path)?;
+        if let Some(dir) = self.path.parent() {
+            File::open(dir)?.sync_all()?;
+        }
+
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        let stats = CompactStats {
+            dropped: self.completed,
+            kept: self.pending.len(),
+        };
+        self.completed = 0;
+        Ok(stats)
+    }
+
+    /// Switches to `keys` and rewrites the pending jobs with them.
+    pub fn rotate_key(&mut self, keys: Keys) -> Result<CompactStats> {
+        self.at_rest = AtRest::Encrypted(keys);
+        self.compact()
+    }
+
+    /// How a job is stored, sealed if encrypting.
+    fn job_record(&self, seq: u64, job: &Job) -> Record {
+        match self.at_rest.sealing() {
+            Some(keys) => {
+                let json = serde_json::to_vec(job).expect("job serialization cannot fail");
+                Record::SealedJob {
+                    seq,
+                    sealed: keys.seal(&json, &aad(seq)),
+                }
+            }
+            None => Record::Job {
+                seq,
+                job: Box::new(job.clone()),
+            },
+        }
+    }
+
+    fn write_record(&mut self, record: &Record) -> Result<()> {
+        self.file.write_all(&encode_record(record))?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+}
+
+/// Ties a sealed job to its sequence number, so jobs can't be swapped.
+fn aad(seq: u64) -> String {
+    format!("spool {}", seq)
+}
+
+fn open_job(keys: &Keys, seq: u64, sealed: &Sealed) -> Result<Job> {
+    let json = keys.open(sealed, &aad(seq))?;
+    serde_json::from_slice(&json).context("Decrypted job is not valid")
+}
+
+fn encode_record(record: &Record) -> Vec<u8> {
+    let payload = serde_json::to_vec(record).expect("spool record serialization cannot fail");
+    let mut buf = Vec::with_capacity(HEADER_LEN + payload.len());
+    buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    buf.extend_from_slice(&payload);
+    buf
+}
+
+/// Result of scanning a spool file.
+struct Scan {
+    records: Vec<Record>,
+    /// Length of the file up to the end of the last good record
+    valid_len: usize,
+    /// Bytes skipped between good records because they were corrupt
+    skipped: usize,
+}
+
+/// Counts the jobs pending in the spool in `dir` without opening it for
+/// writing, so it's safe while the service has it open.
+pub fn count_pending(dir: &Path) -> Result<usize> {
+    let path = dir.join(SPOOL_FILE);
+    if !path.exists() {
+        return Ok(0);
+    }
+    let bytes =
+        fs::read(&path).with_context(|| format!("Failed to read spool {}", path.display()))?;
+    let mut pending = std::collections::BTreeSet::new();
+    for record in scan_records(&bytes).records {
+        match record {
+            Record::Job { seq, .. } | Record::SealedJob { seq, .. } => pending.insert(seq),
+            Record::Done { seq } => pending.remove(&seq),
+        };
+    }
+    Ok(pending.len())
+}
+
+/// Decodes every intact record. After a corrupt record the scan picks up
+/// again at the next place a payload could start, so damage in the middle
+/// of the file only costs the damaged record itself, and a long corrupt
+/// stretch is crossed without checksumming from every offset in it.
+fn scan_records(bytes: &[u8]) -> Scan {
+    let mut records = Vec::new();
+    let mut offset = 0;
+    let mut valid_len = 0;
+    let mut skipped = 0;
+
+    while offset < bytes.len() {
+        match decode_record(&bytes[offset..]) {
+            Some((record, len)) => {
+                skipped += offset - valid_len;
+                records.push(record);
+                offset += len;
+                valid_len = offset;
+            }
+            None => match next_payload(bytes, offset + HEADER_LEN + 1) {
+                Some(payload) => offset = payload - HEADER_LEN,
+                None => break,
+            },
+        }
+    }
+
+    Scan {
+        records,
+        valid_len,
+        skipped,
+    }
+}
+
+/// The first offset from `from` on where the bytes of [`PAYLOAD_START`]
+/// are.
+fn next_payload(bytes: &[u8], from: usize) -> Option<usize> {
+    let rest = bytes.get(from..)?;
+    rest.windows(PAYLOAD_START.len())
+        .position(|window| window == PAYLOAD_START)
+        .map(|at| from + at)
+}
+
+/// Decodes one record from the start of `bytes`, returning it and its encoded length.
+fn decode_record(bytes: &[u8]) -> Option<(Record, usize)> {
+    let header = bytes.get(..HEADER_LEN)?;
+    let len = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+    let crc = u32::from_le_bytes(header[4..8].try_into().unwrap());
+    if len > MAX_RECORD_LEN {
+        return None;
+    }
+
+    let payload = bytes.get(HEADER_LEN..HEADER_LEN + len)?;
+    if !payload.starts_with(PAYLOAD_START) || payload.last() != Some(&b'}') {
+        return None;
+    }
+    if crc32fast::hash(payload) != crc {
+        return None;
+    }
+    let record = serde_json::from_slice(payload).ok()?;
+    Some((record, HEADER_LEN + len))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::*;
+    use crate::tempdir::TempDir;
+
+    fn with_spool(bytes: &[u8]) -> TempDir {
+        let dir = TempDir::new("spool");
+        fs::write(dir.path().join(SPOOL_FILE), bytes).unwrap();
+        dir
+    }
+
+    fn text(seq: u64) -> String {
+        format!("Order {} with \"quotes\" and {{\"op\":\"job\"}} in it", seq)
+    }
+
+    /// A spool of six jobs, two of them done, and where each record is.
+    fn sample() -> (Vec<u8>, Vec<(std::ops::Range<usize>, Record)>) {
+        let dir = TempDir::new("spool");
+        let mut spool = Spool::open(dir.path(), usize::MAX, AtRest::Plain).unwrap();
+        for seq in 0..6 {
+            assert_eq!(spool.append(&Job::plain(text(seq))).unwrap(), seq);
+        }
+        spool.complete(1).unwrap();
+        spool.complete(4).unwrap();
+        let bytes = fs::read(dir.path().join(SPOOL_FILE)).unwrap();
+
+        let mut records = Vec::new();
+        let mut offset = 0;
+        while offset < bytes.len() {
+            let (record, len) = decode_record(&bytes[offset..]).unwrap();
+            records.push((offset..offset + len, record));
+            offset += len;
+        }
+        (bytes, records)
+    }
+
+    /// The jobs that should be pending after reading only the records
+    /// `intact` keeps.
+    fn expected(
+        records: &[(std::ops::Range<usize>, Record)],
+        intact: impl Fn(&std::ops::Range<usize>) -> bool,
+    ) -> BTreeSet<u64> {
+        let mut pending = BTreeSet::new();
+        for (_, record) in records.iter().filter(|(range, _)| intact(range)) {
+            match record {
+                Record::Job { seq, .. } | Record::SealedJob { seq, .. } => pending.insert(*seq),
+                Record::Done { seq } => pending.remove(seq),
+            };
+        }
+        pending
+    }
+
+    fn pending_after_open(bytes: &[u8]) -> BTreeSet<u64> {
+        let dir = with_spool(bytes);
+        let spool = Spool::open(dir.path(), usize::MAX, AtRest::Plain).unwrap();
+        let pending: BTreeSet<u64> = spool
+            .pending()
+            .into_iter()
+            .map(|(seq, job)| {
+                assert_eq!(job.text, text(seq));
+                seq
+            })
+            .collect();
+        drop(spool);
+
+        // Whatever was recovered is written back so it reads the same again
+        let again = Spool::open(dir.path(), usize::MAX, AtRest::Plain).unwrap();
+        assert_eq!(again.pending().len(), pending.len());
+        let bytes = fs::read(dir.path().join(SPOOL_FILE)).unwrap();
+        assert_eq!(scan_records(&bytes).valid_len, bytes.len());
+        pending
+    }
+
+    #[test]
+    fn a_truncated_spool_keeps_every_complete_record() {
+        let (bytes, records) = sample();
+        assert_eq!(pending_after_open(&bytes), BTreeSet::from([0, 2, 3, 5]));
+        for len in 0..bytes.len() {
+            let kept = expected(&records, |range| range.end <= len);
+            assert_eq!(
+                pending_after_open(&bytes[..len]),
+                kept,
+                "truncated to {}",
+                len
+            );
+        }
+    }
+
+    #[test]
+    fn a_corrupt_byte_only_costs_its_own_record() {
+        let (bytes, records) = sample();
+        for at in 0..bytes.len() {
+            let mut corrupt = bytes.clone();
+            corrupt[at] ^= 0xFF;
+            let kept = expected(&records, |range| !range.contains(&at));
+            assert_eq!(pending_after_open(&corrupt), kept, "byte {} corrupted", at);
+        }
+    }
+
+    #[test]
+    fn a_corrupt_stretch_between_records_is_skipped() {
+        let (bytes, records) = sample();
+        let split = records[3].0.start;
+        // Every offset claims a record half as long as the stretch, which
+        // checksumming from each one would take hours to get through
+        let mut garbage = Vec::new();
+        while garbage.len() < 1 << 20 {
+            garbage.extend_from_slice(&(1u32 << 19).to_le_bytes());
+        }
+        let mut corrupt = bytes[..split].to_vec();
+        corrupt.extend_from_slice(&garbage);
+        corrupt.extend_from_slice(&bytes[split..]);
+
+        let scan = scan_records(&corrupt);
+        assert_eq!(scan.records.len(), records.len());
+        assert_eq!(scan.skipped, garbage.len());
+        assert_eq!(scan.valid_len, corrupt.len());
+    }
+}
@@ -0,0 +1,474 @@
+//! Decodes ESC/POS byte streams into something a human can check layout with.
+//!
+//! [`decode`] turns raw bytes into a list of [`Op`]s using a single command
+//! table, so any renderer (text transcript here, an image renderer later) sees
+//! the same interpretation of the stream.
+
+use std::fmt::Write as _;
+
+use crate::codepage;
+use crate::commands::CommandSet;
+use crate::profile::{Font, PrinterProfile};
+
+const ESC: u8 = 0x1B;
+const GS: u8 = 0x1D;
+const LF: u8 = 0x0A;
+const BEL: u8 = 0x07;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Align {
+    Left,
+    Center,
+    Right,
+}
+
+/// One decoded element of an ESC/POS stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Op {
+    Text(String),
+    /// Print the current line and feed `n` lines
+    Feed(u8),
+    Init,
+    Bold(bool),
+    Underline(u8),
+    DoubleStrike(bool),
+    Reverse(bool),
+    Smoothing(bool),
+    UpsideDown(bool),
+    Font(u8),
+    Align(Align),
+    /// Character width and height multipliers (1-8)
+    Size(u8, u8),
+    LineSpacing(Option<u8>),
+    FeedDots(u8),
+    CodePage(u8),
+    Cut {
+        partial: bool,
+    },
+    CashDrawer,
+    QrCode,
+    Code2d,
+    Barcode,
+    /// Raster image of the given size in dots
+    Raster {
+        width: usize,
+        height: usize,
+    },
+    /// Defines this many user-defined characters (`ESC &`)
+    DefineChars(usize),
+    /// Switches between the user-defined and resident characters (`ESC %`)
+    UserChars(bool),
+    /// A command we recognise the shape of but don't render
+    Other(&'static str),
+    /// Bytes that don't start any known command
+    Unknown(u8),
+}
+
+/// How many bytes follow a command's prefix.
+#[derive(Clone, Copy)]
+enum Len {
+    Fixed(usize),
+    /// `pL pH` little-endian length, then that many bytes (`GS ( k` and friends)
+    Prefixed16,
+    /// `m xL xH yL yH` then `x * y` bytes (`GS v 0`)
+    Raster,
+    /// `m` then either NUL-terminated data (m < 65) or `n` + n bytes (`GS k`)
+    Barcode,
+    /// `m`, plus `n` when m is 65/66 (`GS V`)
+    Cut,
+    /// `b nL nH` + n bytes per row until `ESC * r B` (Star raster mode)
+    StarRaster,
+    /// `y c1 c2`, then for each character `x` and `y * x` bytes (`ESC &`)
+    UserChars,
+}
+
+struct Spec {
+    prefix: &'static [u8],
+    len: Len,
+    op: fn(&[u8]) -> Op,
+}
+
+/// The command-interpretation table. Each spec receives its argument bytes
+/// (everything after the prefix).
+#[rustfmt::skip]
+const COMMANDS: &[Spec] = &[
+    Spec { prefix: &[ESC, b'@'], len: Len::Fixed(0), op: |_| Op::Init },
+    Spec { prefix: &[ESC, b'E'], len: Len::Fixed(1), op: |a| Op::Bold(a[0] & 1 == 1) },
+    Spec { prefix: &[ESC, b'-'], len: Len::Fixed(1), op: |a| Op::Underline(a[0] % 48) },
+    Spec { prefix: &[ESC, b'G'], len: Len::Fixed(1), op: |a| Op::DoubleStrike(a[0] & 1 == 1) },
+    Spec { prefix: &[ESC, b'M'], len: Len::Fixed(1), op: |a| Op::Font(a[0] % 48) },
+    Spec { prefix: &[ESC, b'a'], len: Len::Fixed(1), op: |a| Op::Align(match a[0] % 48 {
+        1 => Align::Center,
+        2 => Align::Right,
+        _ => Align::Left,
+    }) },
+    Spec { prefix: &[ESC, b'd'], len: Len::Fixed(1), op: |a| Op::Feed(a[0]) },
+    Spec { prefix: &[ESC, b'J'], len: Len::Fixed(1), op: |a| Op::FeedDots(a[0]) },
+    Spec { prefix: &[ESC, b'2'], len: Len::Fixed(0), op: |_| Op::LineSpacing(None) },
+    Spec { prefix: &[ESC, b'3'], len: Len::Fixed(1), op: |a| Op::LineSpacing(Some(a[0])) },
+    Spec { prefix: &[ESC, b't'], len: Len::Fixed(1), op: |a| Op::CodePage(a[0]) },
+    Spec { prefix: &[ESC, b'R'], len: Len::Fixed(1), op: |_| Op::Other("CHARSET") },
+    Spec { prefix: &[ESC, b'{'], len: Len::Fixed(1), op: |a| Op::UpsideDown(a[0] & 1 == 1) },
+    Spec { prefix: &[ESC, b'&'], len: Len::UserChars, op: |a| Op::DefineChars((a[2] as usize + 1).saturating_sub(a[1] as usize)) },
+    Spec { prefix: &[ESC, b'%'], len: Len::Fixed(1), op: |a| Op::UserChars(a[0] & 1 == 1) },
+    Spec { prefix: &[ESC, b'p'], len: Len::Fixed(3), op: |_| Op::CashDrawer },
+    Spec { prefix: &[ESC, b'!'], len: Len::Fixed(1), op: |_| Op::Other("PRINT MODE") },
+    Spec { prefix: &[GS, b'!'], len: Len::Fixed(1), op: |a| Op::Size((a[0] >> 4) + 1, (a[0] & 0x0F) + 1) },
+    Spec { prefix: &[GS, b'B'], len: Len::Fixed(1), op: |a| Op::Reverse(a[0] & 1 == 1) },
+    Spec { prefix: &[GS, b'b'], len: Len::Fixed(1), op: |a| Op::Smoothing(a[0] & 1 == 1) },
+    Spec { prefix: &[GS, b'V'], len: Len::Cut, op: |a| Op::Cut { partial: matches!(a[0], 1 | 49 | 66) } },
+    // Args start with pL pH, then cn (49 = QR) and fn (81 = print the stored symbol)
+    Spec { prefix: &[GS, b'(', b'k'], len: Len::Prefixed16, op: |a| match (a.get(2), a.get(3)) {
+        (Some(49), Some(81)) => Op::QrCode,
+        (Some(_), Some(81)) => Op::Code2d,
+        _ => Op::Other("2D CODE SETUP"),
+    } },
+    Spec { prefix: &[GS, b'(', b'L'], len: Len::Prefixed16, op: |_| Op::Other("GRAPHICS") },
+    Spec { prefix: &[GS, b'8', b'L'], len: Len::Prefixed16, op: |_| Op::Other("GRAPHICS") },
+    Spec { prefix: &[GS, b'v', b'0'], len: Len::Raster, op: |a| {
+        let width = u16::from_le_bytes([a[1], a[2]]) as usize * 8;
+        let height = u16::from_le_bytes([a[3], a[4]]) as usize;
+        Op::Raster { width, height }
+    } },
+    Spec { prefix: &[GS, b'k'], len: Len::Barcode, op: |_| Op::Barcode },
+    Spec { prefix: &[GS, b'h'], len: Len::Fixed(1), op: |_| Op::Other("BARCODE HEIGHT") },
+    Spec { prefix: &[GS, b'w'], len: Len::Fixed(1), op: |_| Op::Other("BARCODE WIDTH") },
+    Spec { prefix: &[GS, b'H'], len: Len::Fixed(1), op: |_| Op::Other("BARCODE HRI") },
+    Spec { prefix: &[GS, b'f'], len: Len::Fixed(1), op: |_| Op::Other("BARCODE HRI FONT") },
+    Spec { prefix: &[GS, b'L'], len: Len::Fixed(2), op: |_| Op::Other("LEFT MARGIN") },
+    Spec { prefix: &[GS, b'W'], len: Len::Fixed(2), op: |_| Op::Other("PRINT WIDTH") },
+    Spec { prefix: &[GS, b'P'], len: Len::Fixed(2), op: |_| Op::Other("MOTION UNITS") },
+];
+
+/// Star Line Mode commands that differ from ESC/POS, checked before
+/// [`COMMANDS`] when decoding a Star stream.
+#[rustfmt::skip]
+const STAR_COMMANDS: &[Spec] = &[
+    Spec { prefix: &[ESC, b'd'], len: Len::Fixed(1), op: |a| Op::Cut { partial: a[0] & 1 == 1 } },
+    Spec { prefix: &[BEL], len: Len::Fixed(0), op: |_| Op::CashDrawer },
+    Spec { prefix: &[ESC, b'J'], len: Len::Fixed(1), op: |a| Op::FeedDots(a[0].saturating_mul(2)) },
+    Spec { prefix: &[ESC, b'*', b'r', b'A'], len: Len::StarRaster, op: |a| {
+        let (width_bytes, rows, _) = star_raster(a).unwrap_or_default();
+        Op::Raster { width: width_bytes * 8, height: rows }
+    } },
+];
+
+/// Decodes an ESC/POS (or Star Line Mode) byte stream. Truncated commands at
+/// the end are reported as unknown bytes.
+pub fn decode(bytes: &[u8], commands: CommandSet) -> Vec<Op> {
+    let star = commands == CommandSet::Star;
+    let mut ops = Vec::new();
+    let mut text = String::new();
+    let mut user_chars = false;
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let b = bytes[i];
+        if b != ESC && b != GS && b != LF && !(star && b == BEL) {
+            // What a user-defined character looks like isn't known here
+            text.push(match b {
+                0x20..0x7F if user_chars => '▒',
+                _ => codepage::cp437_char(b).unwrap_or('·'),
+            });
+            i += 1;
+            continue;
+        }
+        if !text.is_empty() {
+            ops.push(Op::Text(std::mem::take(&mut text)));
+        }
+        if b == LF {
+            ops.push(Op::Feed(1));
+            i += 1;
+            continue;
+        }
+
+        match match_command(&bytes[i..], star) {
+            Some((op, len)) => {
+                if let Op::UserChars(on) = op {
+                    user_chars = on;
+                }
+                ops.push(op);
+                i += len;
+            }
+            None => {
+                ops.push(Op::Unknown(b));
+                i += 1;
+            }
+        }
+    }
+    if !text.is_empty() {
+        ops.push(Op::Text(text));
+    }
+    ops
+}
+
+/// A raster image command in a stream, its rows borrowed from the stream.
+pub struct Raster<'a> {
+    pub width_bytes: usize,
+    pub rows: Vec<&'a [u8]>,
+}
+
+/// The raster images in a stream, one per command (so one per band of a
+/// banded image), in the order they print.
+pub fn rasters(bytes: &[u8], commands: CommandSet) -> Vec<Raster<'_>> {
+    let star = commands == CommandSet::Star;
+    let mut rasters = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let b = bytes[i];
+        let command = (b == ESC || b == GS).then(|| match_command(&bytes[i..], star));
+        let Some(Some((op, len))) = command else {
+            i += 1;
+            continue;
+        };
+        if let Op::Raster { width, .. } = op {
+            let command = &bytes[i..i + len];
+            let width_bytes = width / 8;
+            let rows = if star {
+                // `b nL nH` and a row, until `ESC * r B`
+                let mut rows = Vec::new();
+                let mut row = &command[4..len - 4];
+                while let [b'b', low, high, rest @ ..] = row {
+                    let n = u16::from_le_bytes([*low, *high]) as usize;
+                    rows.push(&rest[..n]);
+                    row = &rest[n..];
+                }
+                rows
+            } else {
+                command[8..].chunks(width_bytes.max(1)).collect()
+            };
+            rasters.push(Raster { width_bytes, rows });
+        }
+        i += len;
+    }
+    rasters
+}
+
+/// Matches a command at the start of `bytes`, returning it and its total length.
+fn match_command(bytes: &[u8], star: bool) -> Option<(Op, usize)> {
+    let star_commands = if star { STAR_COMMANDS } else { &[] };
+    let spec = star_commands
+        .iter()
+        .chain(COMMANDS)
+        .find(|spec| bytes.starts_with(spec.prefix))?;
+    let args = &bytes[spec.prefix.len()..];
+    let arg_len = match spec.len {
+        Len::Fixed(n) => n,
+        Len::Prefixed16 => 2 + u16::from_le_bytes([*args.first()?, *args.get(1)?]) as usize,
+        Len::Raster => {
+            let header = args.get(..5)?;
+            let x = u16::from_le_bytes([header[1], header[2]]) as usize;
+            let y = u16::from_le_bytes([header[3], header[4]]) as usize;
+            5 + x * y
+        }
+        Len::Barcode => {
+            let m = *args.first()?;
+            if m < 65 {
+                1 + args[1..].iter().position(|&b| b == 0)? + 1
+            } else {
+                2 + *args.get(1)? as usize
+            }
+        }
+        Len::Cut => match *args.first()? {
+            65 | 66 => 2,
+            _ => 1,
+        },
+        Len::StarRaster => star_raster(args)?.2,
+        Len::UserChars => {
+            let (y, first, last) = (*args.first()? as usize, *args.get(1)?, *args.get(2)?);
+            let mut len = 3;
+            for _ in first..=last {
+                len += 1 + y * *args.get(len)? as usize;
+            }
+            len
+        }
+    };
+    let args = args.get(..arg_len)?;
+    Some(((spec.op)(args), spec.prefix.len() + arg_len))
+}
+
+/// Walks the rows of a Star raster block, returning its width in bytes, its
+/// row count and the length up to and including the closing `ESC * r B`.
+fn star_raster(args: &[u8]) -> Option<(usize, usize, usize)> {
+    let (mut width_bytes, mut rows, mut i) = (0, 0, 0);
+    loop {
+        let rest = args.get(i..)?;
+        if rest.starts_with(&[ESC, b'*', b'r', b'B']) {
+            return Some((width_bytes, rows, i + 4));
+        }
+        if rest.first() != Some(&b'b') {
+            return None;
+        }
+        let n = u16::from_le_bytes([*rest.get(1)?, *rest.get(2)?]) as usize;
+        width_bytes = width_bytes.max(n);
+        rows += 1;
+        i += 3 + n;
+    }
+}
+
+/// Renders decoded ops as an annotated transcript: commands as `[BOLD ON]`-style
+/// lines, text as lines of the paper's width (the profile's columns for the
+/// current font) under a column ruler, with double-width characters spaced out
+/// to their printed width. A font switch starts a new ruler at the new width.
+pub fn render(ops: &[Op], profile: &PrinterProfile) -> String {
+    let mut columns = profile.columns;
+    let mut out = String::new();
+    out.push_str(&ruler(columns));
+    out.push('\n');
+
+    let mut line = String::new();
+    let mut align = Align::Left;
+    let mut width = 1;
+    let mut font = Font::A;
+
+    for op in ops {
+        match op {
+            Op::Text(text) => line.push_str(text),
+            Op::Feed(n) => {
+                push_text_line(&mut out, &std::mem::take(&mut line), columns, width, align);
+                for _ in 1..*n {
+                    push_text_line(&mut out, "", columns, width, align);
+                }
+            }
+            Op::Init => {
+                align = Align::Left;
+                width = 1;
+                font = Font::A;
+                columns = profile.columns;
+                out.push_str("[INIT]\n");
+            }
+            Op::Font(n) => {
+                let _ = writeln!(out, "[{}]", label(op));
+                let next = if *n == 1 { Font::B } else { Font::A };
+                if next != font {
+                    font = next;
+                    columns = profile.columns_for(font);
+                    out.push_str(&ruler(columns));
+                    out.push('\n');
+                }
+            }
+            Op::Align(a) => {
+                align = *a;
+                let _ = writeln!(out, "[ALIGN {}]", format!("{:?}", a).to_uppercase());
+            }
+            Op::Size(w, h) => {
+                width = (*w).max(1) as usize;
+                let _ = writeln!(out, "[SIZE {}x{}]", w, h);
+            }
+            // Switched mid-line around fallback glyphs, which show as `▒`
+            Op::UserChars(_) => {}
+            other => {
+                let _ = writeln!(out, "[{}]", label(other));
+            }
+        }
+    }
+    if !line.is_empty() {
+        let _ = writeln!(out, "(unterminated) {}", line);
+    }
+    out
+}
+
+fn label(op: &Op) -> String {
+    let on_off = |on: &bool| if *on { "ON" } else { "OFF" };
+    match op {
+        Op::Bold(on) => format!("BOLD {}", on_off(on)),
+        Op::Underline(0) => "UNDERLINE OFF".to_owned(),
+        Op::Underline(n) => format!("UNDERLINE {}", n),
+        Op::DoubleStrike(on) => format!("DOUBLE STRIKE {}", on_off(on)),
+        Op::Reverse(on) => format!("REVERSE {}", on_off(on)),
+        Op::Smoothing(on) => format!("SMOOTHING {}", on_off(on)),
+        Op::UpsideDown(on) => format!("UPSIDE DOWN {}", on_off(on)),
+        Op::Font(0) => "FONT A".to_owned(),
+        Op::Font(1) => "FONT B".to_owned(),
+        Op::Font(n) => format!("FONT {}", n),
+        Op::LineSpacing(None) => "LINE SPACING DEFAULT".to_owned(),
+        Op::LineSpacing(Some(n)) => format!("LINE SPACING {} DOTS", n),
+        Op::FeedDots(n) => format!("FEED {} DOTS", n),
+        Op::CodePage(n) => format!("CODE PAGE {}", n),
+        Op::Cut { partial: false } => "CUT".to_owned(),
+        Op::Cut { partial: true } => "PARTIAL CUT".to_owned(),
+        Op::CashDrawer => "CASH DRAWER".to_owned(),
+        Op::QrCode => "QR CODE".to_owned(),
+        Op::Code2d => "2D CODE".to_owned(),
+        Op::Barcode => "BARCODE".to_owned(),
+        Op::Raster { width, height } => format!("IMAGE {}x{} DOTS", width, height),
+        Op::DefineChars(n) => format!("DEFINE {} CHARS", n),
+        Op::Other(name) => name.to_string(),
+        Op::Unknown(b) => format!("UNKNOWN 0x{:02X}", b),
+        Op::Text(_) | Op::Feed(_) | Op::Init | Op::Align(_) | Op::Size(..) | Op::UserChars(_) => {
+            unreachable!()
+        }
+    }
+}
+
+/// `0----+----1----+----2...` trimmed to `columns`.
+fn ruler(columns: usize) -> String {
+    let ruler: String = (0..columns)
+        .map(|i| match i % 10 {
+            0 => char::from_digit(((i / 10) % 10) as u32, 10).unwrap(),
+            5 => '+',
+            _ => '-',
+        })
+        .collect();
+    format!(" {} ", ruler)
+}
+
+/// Appends `text` as one or more `|...|` lines, wrapped at the effective width
+/// for the current character width multiplier.
+fn push_text_line(out: &mut String, text: &str, columns: usize, width: usize, align: Align) {
+    let per_line = (columns / width).max(1);
+    let chars: Vec<char> = text.chars().collect();
+    let pieces: Vec<&[char]> = if chars.is_empty() {
+        vec![&[]]
+    } else {
+        chars.chunks(per_line).collect()
+    };
+
+    for piece in pieces {
+        let mut rendered = String::new();
+        for c in piece {
+            rendered.push(*c);
+            for _ in 1..width {
+                rendered.push(' ');
+            }
+        }
+        let used = piece.len() * width;
+        let pad = columns.saturating_sub(used);
+        let left = match align {
+            Align::Left => 0,
+            Align::Center => pad / 2,
+            Align::Right => pad,
+        };
+        let _ = writeln!(
+            out,
+            "|{}{}{}|",
+            " ".repeat(left),
+            rendered,
+            " ".repeat(pad - left)
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rasters_are_found_with_their_rows() {
+        for commands in [CommandSet::EscPos, CommandSet::Star] {
+            let mut bytes = b"Logo\n".to_vec();
+            bytes.extend(commands.raster(2, &[0x80, 0x01, 0xFF, 0x00]));
+            bytes.extend(commands.cut());
+            bytes.extend(commands.raster(1, &[0x18]));
+            let rasters = rasters(&bytes, commands);
+            assert_eq!(rasters.len(), 2);
+            assert_eq!(rasters[0].width_bytes, 2);
+            assert_eq!(rasters[0].rows, [&[0x80, 0x01][..], &[0xFF, 0x00]]);
+            assert_eq!(rasters[1].rows, [&[0x18][..]]);
+            assert!(decode(&bytes, commands).contains(&Op::Raster {
+                width: 16,
+                height: 2
+            }));
+        }
+    }
+}
@@ -0,0 +1,1070 @@
+//! Where jobs come from: the server over a WebSocket, made-up orders for
+//! `--demo`, or only `PrinterService::submit` when embedded. The router
+//! here runs the transport on its own task, decoding what arrives and
+//! passing it to the printer's task, so a slow printer never holds up
+//! reading, heartbeats or reconnecting.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Result, anyhow};
+use chrono::{DateTime, Utc};
+use escpos::driver::Driver;
+use futures_util::StreamExt;
+use log::{debug, error, info, warn};
+use rand::Rng;
+use tokio::net::TcpStream;
+use tokio::signal::unix::Signal;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::{JoinError, JoinHandle};
+use tokio::time::{Instant, MissedTickBehavior};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::error::{
+    CapacityError, Error as WsError, ProtocolError, SubProtocolError,
+};
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use tokio_tungstenite::tungstenite::http::header::SEC_WEBSOCKET_PROTOCOL;
+use tokio_tungstenite::tungstenite::protocol::{Message, WebSocketConfig};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream, connect_async_with_config};
+
+use crate::clock;
+use crate::control::{Control, Refused};
+use crate::demo;
+use crate::handle::JobOutcome;
+use crate::network;
+use crate::outbox::Outbox;
+use crate::protocol::{
+    self, AckStatus, Capability, CommandFrame, Decoded, ErrorCode, Fanout, Job, Outbound, Status,
+    TargetInfo, WireProtocol,
+};
+use crate::service::{Request, Service, ServiceConfig, sleep_until_some, supported_capabilities};
+
+const WS_BACKOFF_INITIAL: Duration = Duration::from_secs(1);
+const WS_BACKOFF_MAX: Duration = Duration::from_secs(30);
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How a [`PrinterService`](crate::PrinterService) gets its jobs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Transport {
+    /// From the server at this URL, auth token included, reconnecting
+    /// whenever the connection drops
+    WebSocket(String),
+    /// A made-up order this often and no server, for showing the kiosk off
+    /// (`--demo`)
+    Demo(Duration),
+    /// Only from [`PrinterService::submit`](crate::PrinterService::submit)
+    Embedded,
+}
+
+/// Name of the printer jobs go to when they don't name one.
+pub const DEFAULT_TARGET: &str = "default";
+
+/// What the `PrinterService` handle asks of the router.
+pub(crate) enum Call {
+    Submit {
+        job: Box<Job>,
+        done: oneshot::Sender<JobOutcome>,
+    },
+    /// The named printer's status, or the default printer's
+    Status {
+        target: Option<String>,
+        done: oneshot::Sender<Status>,
+    },
+    /// Stop one printer, leaving the others running
+    Stop {
+        target: String,
+        done: oneshot::Sender<Result<()>>,
+    },
+    Shutdown,
+}
+
+/// A printer's service task and the way to reach it.
+pub(crate) struct Printer {
+    name: Arc<str>,
+    profile: &'static str,
+    capabilities: Vec<Capability>,
+    requests: mpsc::UnboundedSender<Request>,
+    task: JoinHandle<Result<()>>,
+}
+
+impl Printer {
+    pub fn spawn<D, F>(name: &str, service: Service<D, F>) -> Self
+    where
+        D: Driver + Send + 'static,
+        F: Fn() -> Result<D> + Send + 'static,
+    {
+        let (requests, inbox) = mpsc::unbounded_channel();
+        Self {
+            name: name.into(),
+            profile: service.profile_name(),
+            capabilities: service.capabilities().to_vec(),
+            requests,
+            task: tokio::spawn(service.run(inbox)),
+        }
+    }
+
+    fn send(&self, request: Request) {
+        // The task only goes before the router when it fails, which the
+        // router hears about from `task`
+        let _ = self.requests.send(request);
+    }
+
+    /// Asks the task to stop and waits for it to.
+    async fn stop(&mut self) -> Result<()> {
+        self.send(Request::Shutdown);
+        ended((&mut self.task).await)
+    }
+}
+
+/// How a printer's task ended, once it has.
+fn ended(joined: Result<Result<()>, JoinError>) -> Result<()> {
+    joined.unwrap_or_else(|e| Err(anyhow!("Printer task failed: {}", e)))
+}
+
+/// The printers jobs can be sent to, the default one first. Each runs on
+/// its own task with its own queue, so one that's jammed or gone holds up
+/// none of the others.
+pub(crate) struct Printers(Vec<Printer>);
+
+impl Printers {
+    pub fn new(printers: Vec<Printer>) -> Self {
+        Self(printers)
+    }
+
+    /// The printer called `name`, or the default one.
+    fn find(&self, name: Option<&str>) -> Option<&Printer> {
+        let name = name.unwrap_or(DEFAULT_TARGET);
+        self.0.iter().find(|printer| &*printer.name == name)
+    }
+
+    /// Sends a request to every printer.
+    fn broadcast(&self, request: impl Fn() -> Request) {
+        for printer in &self.0 {
+            printer.send(request());
+        }
+    }
+
+    /// Hands a printer something that isn't for any one of them, like the
+    /// rejection of a message that couldn't be read, for its journal.
+    fn untargeted(&self, request: Request) {
+        if let Some(printer) = self.0.first() {
+            printer.send(request);
+        }
+    }
+
+    /// The printers other than the default one, for the hello frame.
+    fn others(&self) -> Vec<TargetInfo> {
+        if self.0.len() < 2 {
+            return Vec::new();
+        }
+        self.0
+            .iter()
+            .filter(|printer| &*printer.name != DEFAULT_TARGET)
+            .map(|printer| TargetInfo {
+                name: printer.name.to_string(),
+                profile: printer.profile,
+                capabilities: printer.capabilities.clone(),
+            })
+            .collect()
+    }
+
+    /// Waits for a printer's task to end by itself, which it only does when
+    /// the printer is gone for good, and lets go of it.
+    async fn ended(&mut self) -> (Arc<str>, Result<()>) {
+        if self.0.is_empty() {
+            return std::future::pending().await;
+        }
+        let (joined, index, _) =
+            futures_util::future::select_all(self.0.iter_mut().map(|printer| &mut printer.task))
+                .await;
+        let printer = self.0.remove(index);
+        (printer.name, ended(joined))
+    }
+
+    /// Stops the printer called `name`. `None` if there's no such printer.
+    async fn stop(&mut self, name: &str) -> Option<Result<()>> {
+        let index = self.0.iter().position(|printer| &*printer.name == name)?;
+        let mut printer = self.0.remove(index);
+        Some(printer.stop().await)
+    }
+
+    /// Stops every printer, returning the first error.
+    async fn stop_all(&mut self) -> Result<()> {
+        let mut result = Ok(());
+        for mut printer in self.0.drain(..) {
+            let stopped = printer.stop().await;
+            if result.is_ok() {
+                result = stopped;
+            }
+        }
+        result
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &Printer> {
+        self.0.iter()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// Runs the transport, handing what arrives to the printers.
+pub(crate) struct Router {
+    config: Arc<ServiceConfig>,
+    printers: Printers,
+    /// Shared with the printers' tasks, which queue most frames
+    outbox: Arc<Outbox>,
+    control: Control,
+    calls: mpsc::UnboundedReceiver<Call>,
+    /// SIGHUP, except when embedded
+    hangup: Option<Signal>,
+    /// What the current (or last) connection speaks
+    wire: WireProtocol,
+    /// Payload and send time of the last WebSocket ping
+    ping: Option<(u64, Instant)>,
+    /// Round trip of the last ping answered
+    rtt: Option<Duration>,
+}
+
+impl Router {
+    pub fn new(
+        config: Arc<ServiceConfig>,
+        printers: Printers,
+        outbox: Arc<Outbox>,
+        calls: mpsc::UnboundedReceiver<Call>,
+        hangup: Option<Signal>,
+    ) -> Self {
+        Self {
+            control: Control::new(&config.command_policy, config.signer.clone()),
+            wire: config.default_protocol,
+            config,
+            printers,
+            outbox,
+            calls,
+            hangup,
+            ping: None,
+            rtt: None,
+        }
+    }
+
+    /// Runs until shut down, or until every printer's task has failed.
+    pub async fn run(mut self, transport: Transport) -> Result<()> {
+        match transport {
+            Transport::WebSocket(url) => self.run_websocket(&url).await,
+            Transport::Demo(every) => self.run_demo(every).await,
+            Transport::Embedded => self.run_embedded().await,
+        }
+    }
+
+    /// Passes on a call from the handle. Returns whether the service is
+    /// done, in which case every printer's task has finished.
+    async fn call(&mut self, call: Option<Call>) -> Result<bool> {
+        match call {
+            Some(Call::Submit { job, done }) => match self.printers.find(job.target.as_deref()) {
+                Some(printer) => printer.send(Request::Submit { job: *job, done }),
+                None => {
+                    let _ = done.send(JobOutcome::Rejected {
+                        error: ErrorCode::UnknownTarget,
+                        message: unknown_target(job.target.as_deref()),
+                    });
+                }
+            },
+            Some(Call::Status { target, done }) => {
+                // Dropping `done` tells the caller there's no such printer
+                if let Some(printer) = self.printers.find(target.as_deref()) {
+                    printer.send(Request::Status(done));
+                }
+            }
+            Some(Call::Stop { target, done }) => {
+                let stopped = match self.printers.stop(&target).await {
+                    Some(stopped) => stopped,
+                    None => Err(anyhow!(unknown_target(Some(&target)))),
+                };
+                let _ = done.send(stopped);
+                return Ok(self.printers.is_empty());
+            }
+            // `None` when the handle was dropped without shutting down
+            Some(Call::Shutdown) | None => {
+                self.printers.stop_all().await?;
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// A printer's task ended by itself. The others carry on; the router
+    /// stops with the error once there are none left.
+    fn lost(&self, name: &str, result: Result<()>) -> Option<Result<()>> {
+        if self.printers.is_empty() {
+            return Some(result);
+        }
+        match result {
+            Ok(()) => warn!("Printer {} stopped", name),
+            Err(e) => error!("Printer {} stopped: {:#}", name, e),
+        }
+        None
+    }
+
+    async fn run_websocket(&mut self, url: &str) -> Result<()> {
+        let config = Arc::clone(&self.config);
+        let mut ws_backoff = WS_BACKOFF_INITIAL;
+        let mut connected_before = false;
+        // Messages between the limit and twice it are buffered, then rejected with
+        // an ack. Beyond that tungstenite refuses them as they arrive, keeping
+        // memory bounded, but the connection can't be resumed after that.
+        let hard_limit = config.max_message_size.saturating_mul(2);
+        let ws_config = WebSocketConfig::default()
+            .max_message_size(Some(hard_limit))
+            .max_frame_size(Some(hard_limit));
+
+        loop {
+            info!("Connecting to WebSocket...");
+            match handshake(url, config.default_protocol, ws_config).await {
+                Ok((ws_stream, wire, selected)) => {
+                    self.wire = wire;
+                    info!(
+                        "Connected! Speaking {:?} ({})",
+                        self.wire,
+                        selected.map_or("server picked no subprotocol".to_string(), |s| format!(
+                            "server picked {}",
+                            s
+                        ))
+                    );
+                    ws_backoff = WS_BACKOFF_INITIAL;
+                    self.printers.broadcast(|| Request::Connected {
+                        wire,
+                        reconnect: connected_before,
+                    });
+                    connected_before = true;
+
+                    let (mut write, mut read) = ws_stream.split();
+                    let outbox = if self.wire == WireProtocol::V2 {
+                        let hello = Outbound::Hello {
+                            version: env!("CARGO_PKG_VERSION"),
+                            time: chrono::Utc::now().timestamp(),
+                            capabilities: supported_capabilities(
+                                &config.profile,
+                                config.printer.as_ref(),
+                            ),
+                            device_id: Some(config.device_id.clone()).filter(|id| !id.is_empty()),
+                            public_key: config.public_key.clone(),
+                            tenant: config.tenant.clone(),
+                            profile: config.profile.name,
+                            printer: config.printer.clone(),
+                            targets: self.printers.others(),
+                        };
+                        if !self.outbox.is_empty() {
+                            info!("Resending {} undelivered frames", self.outbox.len());
+                        }
+                        self.outbox.push_front(hello);
+                        self.outbox.clone()
+                    } else {
+                        // v1 servers are sent nothing; frames keep for the next v2 one
+                        Arc::new(Outbox::new(0))
+                    };
+                    // Its own task, so frames go out while this one waits
+                    let mut writer = AbortOnDrop(tokio::spawn({
+                        let signer = config.signer.clone();
+                        async move {
+                            if let Err(e) = outbox.write_to(&mut write, signer.as_ref()).await {
+                                error!("Failed to send frame: {}", e);
+                            }
+                        }
+                    }));
+
+                    let mut heartbeat = tokio::time::interval(HEARTBEAT_INTERVAL);
+                    heartbeat.tick().await;
+                    let mut read_deadline = Instant::now() + config.ws_read_timeout;
+                    let disconnect_at = config
+                        .force_disconnect_every
+                        .map(|every| Instant::now() + every);
+
+                    loop {
+                        let message = tokio::select! {
+                            message = read.next() => message,
+                            _ = &mut writer.0 => break,
+                            call = self.calls.recv() => {
+                                if self.call(call).await? {
+                                    return Ok(());
+                                }
+                                continue;
+                            }
+                            (name, result) = self.printers.ended() => {
+                                if let Some(result) = self.lost(&name, result) {
+                                    return result;
+                                }
+                                continue;
+                            }
+                            _ = hung_up(&mut self.hangup) => {
+                                self.printers.broadcast(|| Request::Reload);
+                                continue;
+                            }
+                            _ = heartbeat.tick() => {
+                                self.heartbeat();
+                                continue;
+                            }
+                            _ = sleep_until_some(disconnect_at) => {
+                                warn!("CHAOS: dropping the WebSocket connection");
+                                break;
+                            }
+                            _ = tokio::time::sleep_until(read_deadline) => {
+                                warn!(
+                                    "No complete WebSocket message received in {:?}, reconnecting...",
+                                    config.ws_read_timeout
+                                );
+                                break;
+                            }
+                        };
+                        read_deadline = Instant::now() + config.ws_read_timeout;
+
+                        match message {
+                            Some(Ok(Message::Text(text))) => {
+                                if text.len() > config.max_message_size {
+                                    warn!(
+                                        "Rejecting {} byte message, over the {} byte limit",
+                                        text.len(),
+                                        config.max_message_size
+                                    );
+                                    self.printers.untargeted(Request::Reply(too_large(
+                                        text.len(),
+                                        config.max_message_size,
+                                    )));
+                                } else {
+                                    info!("Received: {}", text);
+                                    self.handle_text(&text);
+                                }
+                            }
+                            Some(Ok(Message::Pong(payload))) => self.pong(&payload),
+                            Some(Ok(_)) => {}
+                            Some(Err(WsError::Capacity(CapacityError::MessageTooLong {
+                                size,
+                                max_size,
+                            }))) => {
+                                error!(
+                                    "Incoming message of {} bytes is over the {} byte hard limit, reconnecting",
+                                    size, max_size
+                                );
+                                // Sent after the reconnect if it doesn't make it out before
+                                self.printers.untargeted(Request::Reply(too_large(
+                                    size,
+                                    config.max_message_size,
+                                )));
+                                break;
+                            }
+                            Some(Err(e)) => {
+                                error!("WebSocket error: {}", e);
+                                break;
+                            }
+                            None => {
+                                info!("WebSocket stream ended.");
+                                break;
+                            }
+                        }
+                    }
+                }
+                Err(e) if is_auth_rejected(&e) => {
+                    error!(
+                        "WebSocket authentication rejected ({}) — check the token in the ws-url",
+                        e
+                    );
+                }
+                Err(e) => {
+                    error!("WebSocket connect failed: {}", e);
+                }
+            }
+            self.printers.broadcast(|| Request::Disconnected);
+            self.outbox.discard_live();
+            self.ping = None;
+            if config.net_diagnostics {
+                match tokio::task::block_in_place(|| network::stats(self.rtt)) {
+                    Some(stats) => info!("Network: {}", stats.summary()),
+                    None => info!("Network: no default route"),
+                }
+            }
+
+            let jitter = rand::rng().random_range(0..=1000);
+            let sleep_dur = ws_backoff + Duration::from_millis(jitter);
+            info!("Reconnecting in {:?}...", sleep_dur);
+            // The printer's task keeps printing queued jobs meanwhile
+            let wake = Instant::now() + sleep_dur;
+            loop {
+                tokio::select! {
+                    _ = tokio::time::sleep_until(wake) => break,
+                    call = self.calls.recv() => {
+                        if self.call(call).await? {
+                            return Ok(());
+                        }
+                    }
+                    (name, result) = self.printers.ended() => {
+                        if let Some(result) = self.lost(&name, result) {
+                            return result;
+                        }
+                    }
+                    _ = hung_up(&mut self.hangup) => self.printers.broadcast(|| Request::Reload),
+                }
+            }
+            ws_backoff = (ws_backoff * 2).min(WS_BACKOFF_MAX);
+        }
+    }
+
+    /// `--demo`: handles a made-up order every `every` as if the server had
+    /// sent it. There's no connection, but the printer acts as if connected
+    /// to a v2 server, and its frames are logged and thrown away.
+    async fn run_demo(&mut self, every: Duration) -> Result<()> {
+        warn!(
+            "DEMO MODE: printing a made-up order every {:?}, not connecting to a server",
+            every
+        );
+        self.wire = WireProtocol::V2;
+        let wire = self.wire;
+        self.printers.broadcast(|| Request::Connected {
+            wire,
+            reconnect: false,
+        });
+        let outbox = self.outbox.clone();
+        let signer = self.config.signer.clone();
+        let _writer = AbortOnDrop(tokio::spawn(async move {
+            // The outbox logs each frame as it goes
+            let _ = outbox
+                .write_to(&mut futures_util::sink::drain(), signer.as_ref())
+                .await;
+        }));
+
+        let mut orders = tokio::time::interval(every);
+        // Printing can take longer than the interval; don't catch up afterwards
+        orders.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut heartbeat = tokio::time::interval(HEARTBEAT_INTERVAL);
+        heartbeat.tick().await;
+        let mut count = 0;
+        loop {
+            tokio::select! {
+                _ = orders.tick() => {
+                    count += 1;
+                    let text = demo::order(count, self.config.profile.columns);
+                    info!("Demo order: {}", text);
+                    self.handle_text(&text);
+                }
+                call = self.calls.recv() => {
+                    if self.call(call).await? {
+                        return Ok(());
+                    }
+                }
+                (name, result) = self.printers.ended() => {
+                    if let Some(result) = self.lost(&name, result) {
+                        return result;
+                    }
+                }
+                _ = hung_up(&mut self.hangup) => self.printers.broadcast(|| Request::Reload),
+                _ = heartbeat.tick() => self.heartbeat(),
+            }
+        }
+    }
+
+    /// No server: only calls from the handle, and the periodic checks.
+    async fn run_embedded(&mut self) -> Result<()> {
+        let mut heartbeat = tokio::time::interval(HEARTBEAT_INTERVAL);
+        heartbeat.tick().await;
+        loop {
+            tokio::select! {
+                call = self.calls.recv() => {
+                    if self.call(call).await? {
+                        return Ok(());
+                    }
+                }
+                (name, result) = self.printers.ended() => {
+                    if let Some(result) = self.lost(&name, result) {
+                        return result;
+                    }
+                }
+                _ = heartbeat.tick() => self.printers.broadcast(|| Request::Heartbeat),
+            }
+        }
+    }
+
+    /// Decodes one inbound message and passes it on to the printer it's
+    /// for.
+    fn handle_text(&mut self, text: &str) {
+        let config = Arc::clone(&self.config);
+        if self.wire == WireProtocol::V1 {
+            // v1 has no envelope to sign
+            if config.require_signed_jobs {
+                warn!("Rejecting unsigned v1 job");
+                self.reject(None, None, ErrorCode::UnsignedJob, "Job is not signed");
+            } else {
+                self.route(Job::plain(text.to_string()));
+            }
+            return;
+        }
+        match protocol::decode(text, config.signer.as_ref(), config.require_signed_jobs) {
+            Decoded::Job(job) => self.route(job),
+            Decoded::Fanout(fanout) => self.fanout(fanout),
+            Decoded::Command(frame) => self.admit(frame),
+            Decoded::Preview(job) => match self.printers.find(job.target.as_deref()) {
+                Some(printer) => printer.send(Request::Preview(job)),
+                None => {
+                    let message = unknown_target(job.target.as_deref());
+                    self.reject(job.id, job.tenant, ErrorCode::UnknownTarget, message);
+                }
+            },
+            Decoded::ServerHello { time: Some(time) } => {
+                self.printers.broadcast(|| Request::ServerTime(time))
+            }
+            Decoded::ServerHello { time: None } => {}
+            Decoded::Rejected {
+                id,
+                tenant,
+                error,
+                message,
+            } => {
+                warn!("Rejecting message ({:?}): {}", error, message);
+                self.reject(id, tenant, error, message);
+            }
+            Decoded::Refused {
+                command,
+                error,
+                message,
+            } => self.refuse(
+                command,
+                clock::now(),
+                Refused {
+                    error,
+                    message,
+                    challenge: None,
+                },
+            ),
+        }
+    }
+
+    /// Queues a job on the printer it names, or the default one.
+    fn route(&self, job: Job) {
+        match self.printers.find(job.target.as_deref()) {
+            Some(printer) => printer.send(Request::Job(job)),
+            None => {
+                let message = unknown_target(job.target.as_deref());
+                warn!("Rejecting job {:?}: {}", job.id, message);
+                self.reject(job.id, job.tenant, ErrorCode::UnknownTarget, message);
+            }
+        }
+    }
+
+    /// Queues a copy of the job on each of its targets, which ack it
+    /// separately. It's rejected outright if any of them doesn't exist.
+    fn fanout(&self, fanout: Fanout) {
+        let Fanout { targets, job } = fanout;
+        let mut printers: Vec<&Printer> = Vec::new();
+        if targets.is_empty() {
+            printers.extend(self.printers.iter());
+        }
+        for name in &targets {
+            match self.printers.find(Some(name)) {
+                Some(printer) => {
+                    if !printers.iter().any(|p| p.name == printer.name) {
+                        printers.push(printer);
+                    }
+                }
+                None => {
+                    let message = unknown_target(Some(name));
+                    warn!("Rejecting fanout job {:?}: {}", job.id, message);
+                    self.reject(job.id, job.tenant, ErrorCode::UnknownTarget, message);
+                    return;
+                }
+            }
+        }
+        for printer in printers {
+            let mut copy = job.clone();
+            copy.target = Some(printer.name.to_string());
+            printer.send(Request::Job(copy));
+        }
+    }
+
+    /// Turns a message away before it reaches a printer. It's still
+    /// recorded in a journal.
+    fn reject(
+        &self,
+        id: Option<String>,
+        tenant: Option<String>,
+        error: ErrorCode,
+        message: impl Into<String>,
+    ) {
+        self.printers.untargeted(Request::Rejected {
+            tenant,
+            ack: Outbound::error_ack(id, AckStatus::Rejected, error, message),
+        });
+    }
+
+    /// Lets a command through to the printer it names, or to every printer,
+    /// if its rate limit and authorization allow. It's recorded in the
+    /// journal either way.
+    fn admit(&mut self, frame: CommandFrame) {
+        let received_at = clock::now();
+        let name = frame.command.name();
+        let refused = match self.control.admit(name, frame.auth.as_deref()) {
+            Ok(()) => match &frame.target {
+                None => {
+                    self.printers.broadcast(|| Request::Command {
+                        command: frame.command.clone(),
+                        received_at,
+                    });
+                    return;
+                }
+                Some(target) => match self.printers.find(Some(target)) {
+                    Some(printer) => {
+                        printer.send(Request::Command {
+                            command: frame.command,
+                            received_at,
+                        });
+                        return;
+                    }
+                    None => Refused {
+                        error: ErrorCode::UnknownTarget,
+                        message: unknown_target(Some(target)),
+                        challenge: None,
+                    },
+                },
+            },
+            Err(refused) => refused,
+        };
+        self.refuse(name, received_at, refused);
+    }
+
+    /// Answers a command that won't be run. It's still recorded in a
+    /// journal.
+    fn refuse(&self, command: &'static str, received_at: DateTime<Utc>, refused: Refused) {
+        warn!(
+            "Refusing {} command ({}): {}",
+            command,
+            refused.error.as_str(),
+            refused.message
+        );
+        self.printers.untargeted(Request::Refused {
+            command,
+            received_at,
+            reply: Outbound::command_refused(
+                command,
+                refused.error,
+                refused.message,
+                refused.challenge,
+            ),
+        });
+    }
+
+    /// The printer's periodic checks and heartbeat frame, and a ping for v2
+    /// servers.
+    fn heartbeat(&mut self) {
+        self.printers.broadcast(|| Request::Heartbeat);
+        if self.wire == WireProtocol::V2 {
+            self.send_ping();
+        }
+    }
+
+    /// Pings the server, for the round trip time reported with the network stats.
+    fn send_ping(&mut self) {
+        let payload = self.ping.map_or(1, |(payload, _)| payload + 1);
+        self.ping = Some((payload, Instant::now()));
+        self.outbox.ping(payload.to_be_bytes().to_vec());
+    }
+
+    fn pong(&mut self, payload: &[u8]) {
+        if let Some((expected, sent_at)) = self.ping
+            && payload == expected.to_be_bytes()
+        {
+            let rtt = sent_at.elapsed();
+            self.rtt = Some(rtt);
+            self.printers.broadcast(|| Request::Rtt(rtt));
+        }
+    }
+}
+
+fn unknown_target(name: Option<&str>) -> String {
+    format!("No printer named {}", name.unwrap_or(DEFAULT_TARGET))
+}
+
+/// Waits for SIGHUP, or forever when not listening for it.
+async fn hung_up(hangup: &mut Option<Signal>) {
+    match hangup {
+        Some(signal) => {
+            signal.recv().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Aborts a background task when dropped, so it ends with the session.
+struct AbortOnDrop(JoinHandle<()>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Rejection for a message over `--max-message-size`. It isn't parsed, so
+/// the ack carries no job id.
+fn too_large(size: usize, limit: usize) -> Outbound {
+    Outbound::error_ack(
+        None,
+        AckStatus::Rejected,
+        ErrorCode::MessageTooLarge,
+        format!("Message of {} bytes exceeds the {} byte limit", size, limit),
+    )
+}
+
+type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Opens the WebSocket connection, offering our subprotocols. A server that
+/// doesn't negotiate and refuses the handshake for being offered them is
+/// tried again without, speaking `fallback`. Returns what the connection
+/// speaks and the subprotocol the server picked, if any.
+async fn handshake(
+    url: &str,
+    fallback: WireProtocol,
+    ws_config: WebSocketConfig,
+) -> Result<(WsStream, WireProtocol, Option<String>), WsError> {
+    let offered: Vec<&str> = WireProtocol::OFFERED
+        .iter()
+        .map(|p| p.subprotocol())
+        .collect();
+    let offered = HeaderValue::from_str(&offered.join(", "))
+        .expect("subprotocol names are valid header values");
+    let mut offer = true;
+    loop {
+        let mut request = url.into_client_request()?;
+        if offer {
+            request
+                .headers_mut()
+                .insert(SEC_WEBSOCKET_PROTOCOL, offered.clone());
+        }
+        match connect_async_with_config(request, Some(ws_config), false).await {
+            Ok((ws_stream, response)) => {
+                let selected = response
+                    .headers()
+                    .get(SEC_WEBSOCKET_PROTOCOL)
+                    .and_then(|v| v.to_str().ok())
+                    .map(str::to_string);
+                let wire = selected
+                    .as_deref()
+                    .and_then(WireProtocol::from_subprotocol)
+                    .unwrap_or(fallback);
+                return Ok((ws_stream, wire, selected));
+            }
+            Err(WsError::Protocol(ProtocolError::SecWebSocketSubProtocolError(
+                SubProtocolError::NoSubProtocol,
+            ))) if offer => {
+                info!(
+                    "Server picked no subprotocol, reconnecting without offering any and speaking {:?}",
+                    fallback
+                );
+                offer = false;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Whether the server turned the handshake down as unauthorized.
+pub fn is_auth_rejected(e: &WsError) -> bool {
+    matches!(e, WsError::Http(response) if response.status() == 401 || response.status() == 403)
+}
+
+/// Connects to the server once and hangs up again, for `--check`. Returns
+/// what the connection would speak.
+pub async fn check_server(url: &str, fallback: WireProtocol) -> Result<WireProtocol, WsError> {
+    let (mut ws_stream, wire, _) = handshake(url, fallback, WebSocketConfig::default()).await?;
+    if let Err(e) = ws_stream.close(None).await {
+        debug!("Closing the check connection failed: {}", e);
+    }
+    Ok(wire)
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::SinkExt;
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::tungstenite::handshake::server::{Request, Response};
+
+    use super::*;
+
+    /// A server answering each connection with the next of `picks` (`None`
+    /// being a server that ignores subprotocols), returning its URL and the
+    /// subprotocols each connection offered.
+    async fn server(
+        picks: Vec<Option<&'static str>>,
+    ) -> (String, mpsc::UnboundedReceiver<Option<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let (offers, offered) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            for pick in picks {
+                let (stream, _) = listener.accept().await.unwrap();
+                let offers = offers.clone();
+                #[allow(clippy::result_large_err)] // tungstenite's callback signature
+                let answer = move |request: &Request, mut response: Response| {
+                    let offer = request.headers().get(SEC_WEBSOCKET_PROTOCOL);
+                    offers
+                        .send(offer.map(|v| v.to_str().unwrap().to_string()))
+                        .unwrap();
+                    if let Some(pick) = pick {
+                        response
+                            .headers_mut()
+                            .insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static(pick));
+                    }
+                    Ok(response)
+                };
+                // Held open until the client hangs up
+                if let Ok(mut ws) = tokio_tungstenite::accept_hdr_async(stream, answer).await {
+                    tokio::spawn(async move { while let Some(Ok(_)) = ws.next().await {} });
+                }
+            }
+        });
+        (url, offered)
+    }
+
+    const OFFER: &str = "flatos-print.v2, flatos-print.v1";
+
+    #[tokio::test]
+    async fn a_server_picking_v2_gets_v2() {
+        let (url, mut offered) = server(vec![Some("flatos-print.v2")]).await;
+        let (_ws, wire, selected) = handshake(&url, WireProtocol::V1, WebSocketConfig::default())
+            .await
+            .unwrap();
+        assert_eq!(wire, WireProtocol::V2);
+        assert_eq!(selected.as_deref(), Some("flatos-print.v2"));
+        assert_eq!(offered.recv().await.unwrap().as_deref(), Some(OFFER));
+    }
+
+    #[tokio::test]
+    async fn a_server_picking_v1_gets_v1() {
+        let (url, mut offered) = server(vec![Some("flatos-print.v1")]).await;
+        let (_ws, wire, selected) = handshake(&url, WireProtocol::V2, WebSocketConfig::default())
+            .await
+            .unwrap();
+        assert_eq!(wire, WireProtocol::V1);
+        assert_eq!(selected.as_deref(), Some("flatos-print.v1"));
+        assert_eq!(offered.recv().await.unwrap().as_deref(), Some(OFFER));
+    }
+
+    #[tokio::test]
+    async fn a_server_picking_none_is_retried_without_an_offer() {
+        for fallback in [WireProtocol::V1, WireProtocol::V2] {
+            let (url, mut offered) = server(vec![None, None]).await;
+            let (_ws, wire, selected) = handshake(&url, fallback, WebSocketConfig::default())
+                .await
+                .unwrap();
+            assert_eq!(wire, fallback);
+            assert_eq!(selected, None);
+            // Offered first, refused by tungstenite for the missing answer,
+            // then asked again plainly
+            assert_eq!(offered.recv().await.unwrap().as_deref(), Some(OFFER));
+            assert_eq!(offered.recv().await.unwrap(), None);
+        }
+    }
+
+    #[tokio::test]
+    async fn a_server_picking_what_wasnt_offered_is_an_error() {
+        let (url, _offered) = server(vec![Some("flatos-print.v3")]).await;
+        let error = handshake(&url, WireProtocol::V2, WebSocketConfig::default())
+            .await
+            .err()
+            .unwrap();
+        assert!(
+            matches!(
+                error,
+                WsError::Protocol(ProtocolError::SecWebSocketSubProtocolError(
+                    SubProtocolError::InvalidSubProtocol
+                ))
+            ),
+            "{:?}",
+            error
+        );
+    }
+
+    #[tokio::test]
+    async fn check_reports_what_the_connection_would_speak() {
+        let (url, _offered) = server(vec![Some("flatos-print.v1"), None, None]).await;
+        assert_eq!(
+            check_server(&url, WireProtocol::V2).await.unwrap(),
+            WireProtocol::V1
+        );
+        assert_eq!(
+            check_server(&url, WireProtocol::V2).await.unwrap(),
+            WireProtocol::V2
+        );
+    }
+
+    /// Runs a service against a server picking `pick`, sends it a plain
+    /// text ticket, and returns the frames it sent back and what it printed
+    /// once the ticket is out.
+    async fn plain_ticket_to(pick: &'static str) -> (Vec<String>, Vec<u8>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let printer = crate::render::RecordingDriver::default();
+        let service = crate::PrinterService::builder()
+            .driver(printer.clone())
+            .transport(Transport::WebSocket(url))
+            .build()
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        #[allow(clippy::result_large_err)] // tungstenite's callback signature
+        let answer = move |_: &Request, mut response: Response| {
+            response
+                .headers_mut()
+                .insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static(pick));
+            Ok(response)
+        };
+        let mut ws = tokio_tungstenite::accept_hdr_async(stream, answer)
+            .await
+            .unwrap();
+        ws.send(Message::text("Plain ticket")).await.unwrap();
+        let mut frames = Vec::new();
+        let collect = async {
+            while let Some(Ok(message)) = ws.next().await {
+                if let Message::Text(text) = message {
+                    frames.push(text.to_string());
+                }
+            }
+        };
+        // Until the ticket has printed, and a moment longer for its ack
+        let mut printed = Vec::new();
+        let print = async {
+            while !printed.windows(12).any(|w| w == b"Plain ticket") {
+                printed.extend(printer.take());
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            tokio::time::sleep(Duration::from_millis(300)).await;
+        };
+        tokio::select! {
+            _ = collect => {}
+            done = tokio::time::timeout(Duration::from_secs(10), print) => done.unwrap(),
+        }
+        service.shutdown().await.unwrap();
+        (frames, printed)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn v1_servers_get_no_frames_back() {
+        let (frames, printed) = plain_ticket_to("flatos-print.v1").await;
+        assert!(frames.is_empty(), "{:?}", frames);
+        assert!(!printed.is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn v2_servers_get_a_hello_and_acks() {
+        let (frames, printed) = plain_ticket_to("flatos-print.v2").await;
+        let types: Vec<(String, String)> = frames
+            .iter()
+            .map(|frame| {
+                let frame: serde_json::Value = serde_json::from_str(frame).unwrap();
+                let field = |name: &str| frame[name].as_str().unwrap_or_default().to_string();
+                (field("type"), field("status"))
+            })
+            .collect();
+        assert_eq!(types[0].0, "hello");
+        assert!(
+            types.contains(&("ack".to_string(), "printed".to_string())),
+            "{:?}",
+            frames
+        );
+        assert!(!printed.is_empty());
+    }
+}
@@ -0,0 +1,196 @@
+//! The library API as an embedding program sees it.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use printer_service::{
+    Accessibility, CommandSet, Driver, ErrorCode, Font, JobOutcome, PrintJob, PrinterProfile,
+    PrinterService, PrinterServiceBuilder, ResourceData, ResourceRef, RuleStyle, Segment,
+    ServiceConfig, Status, TextSegment, Timing, Transport, WireProtocol,
+};
+
+/// Keeps everything written to it.
+#[derive(Clone, Default)]
+struct Recorder(Arc<Mutex<Vec<u8>>>);
+
+impl Recorder {
+    fn contains(&self, text: &str) -> bool {
+        let bytes = self.0.lock().unwrap();
+        bytes
+            .windows(text.len())
+            .any(|window| window == text.as_bytes())
+    }
+}
+
+impl Driver for Recorder {
+    fn name(&self) -> String {
+        "recorder".to_string()
+    }
+
+    fn write(&self, data: &[u8]) -> escpos::errors::Result<()> {
+        self.0.lock().unwrap().extend_from_slice(data);
+        Ok(())
+    }
+
+    fn read(&self, _buf: &mut [u8]) -> escpos::errors::Result<usize> {
+        Ok(0)
+    }
+
+    fn flush(&self) -> escpos::errors::Result<()> {
+        Ok(())
+    }
+}
+
+fn send<T: Send>() {}
+
+fn send_and_sync<T: Send + Sync>() {}
+
+#[test]
+fn handle_and_types_can_be_shared_across_tasks() {
+    send_and_sync::<PrinterService>();
+    send::<PrinterServiceBuilder>();
+    send_and_sync::<JobOutcome>();
+    send_and_sync::<PrintJob>();
+    send_and_sync::<Status>();
+    send_and_sync::<Timing>();
+    send_and_sync::<ErrorCode>();
+    send_and_sync::<PrinterProfile>();
+    send_and_sync::<ServiceConfig>();
+    send_and_sync::<Transport>();
+    send_and_sync::<WireProtocol>();
+    send_and_sync::<Font>();
+    send_and_sync::<CommandSet>();
+    send_and_sync::<Segment>();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn submits_prints_and_reports_status() {
+    let recorder = Recorder::default();
+    let updates = Arc::new(AtomicUsize::new(0));
+    let service = PrinterService::builder()
+        .config(ServiceConfig {
+            profile: PrinterProfile::find("default").unwrap().clone(),
+            ..ServiceConfig::default()
+        })
+        .driver(recorder.clone())
+        .transport(Transport::Embedded)
+        .on_status({
+            let updates = Arc::clone(&updates);
+            move |_| {
+                updates.fetch_add(1, Ordering::SeqCst);
+            }
+        })
+        .build()
+        .unwrap();
+
+    let outcome = service
+        .submit(PrintJob::plain("Hello from the API".to_string()))
+        .await;
+    assert!(matches!(outcome, JobOutcome::Printed(_)), "{:?}", outcome);
+    assert!(recorder.contains("Hello from the API"));
+    assert!(updates.load(Ordering::SeqCst) > 0);
+
+    let mut job = PrintJob::plain("Needs holograms".to_string());
+    job.requires = vec!["holograms".to_string()];
+    match service.submit(job).await {
+        JobOutcome::Rejected { error, .. } => assert_eq!(error, ErrorCode::UnsupportedFeature),
+        outcome => panic!("expected a rejection, got {:?}", outcome),
+    }
+    assert!(!recorder.contains("Needs holograms"));
+
+    let status = service.status().await.unwrap();
+    assert_eq!(status.queue_depth, 0);
+    assert!(status.printer_connected);
+    service.shutdown().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn jobs_needing_what_the_printer_lacks_are_rejected() {
+    let recorder = Recorder::default();
+    let service = PrinterService::builder()
+        .config(ServiceConfig {
+            profile: PrinterProfile::find("serial-58mm").unwrap().clone(),
+            ..ServiceConfig::default()
+        })
+        .driver(recorder.clone())
+        .build()
+        .unwrap();
+
+    for capability in ["images", "qr", "unicode"] {
+        let mut job = PrintJob::plain(format!("Needs {}", capability));
+        job.requires = vec!["text".to_string(), capability.to_string()];
+        match service.submit(job).await {
+            JobOutcome::Rejected { error, message } => {
+                assert_eq!(error, ErrorCode::UnsupportedFeature);
+                assert!(message.contains(capability), "{}", message);
+            }
+            outcome => panic!("expected a rejection, got {:?}", outcome),
+        }
+    }
+    let mut job = PrintJob::plain("Plain layout".to_string());
+    job.requires = vec!["fonts".to_string(), "layout".to_string()];
+    let outcome = service.submit(job).await;
+    assert!(matches!(outcome, JobOutcome::Printed(_)), "{:?}", outcome);
+    assert!(recorder.contains("Plain layout"));
+    service.shutdown().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn segmented_jobs_can_be_built_from_outside() {
+    let recorder = Recorder::default();
+    let service = PrinterService::builder()
+        .config(ServiceConfig {
+            profile: PrinterProfile::find("default").unwrap().clone(),
+            ..ServiceConfig::default()
+        })
+        .driver(recorder.clone())
+        .build()
+        .unwrap();
+
+    let logo: Arc<[u8]> = Arc::from(&b"P4 8 1 \xAA"[..]);
+    let mut job = PrintJob::plain("Table 4".to_string());
+    job.accessibility = Some(Accessibility::Standard);
+    job.segments = vec![
+        Segment::Text(TextSegment {
+            text: "Soup of the day".to_string(),
+            font: Some(Font::B),
+            line_spacing: Some(40),
+        }),
+        Segment::Rule {
+            rule: RuleStyle::Double,
+        },
+        Segment::Box {
+            segments: vec![
+                Segment::Spacer { spacer: 8 },
+                Segment::Image {
+                    image_ref: ResourceRef {
+                        url: "https://example.com/logo.pbm".to_string(),
+                        sha256: "00".repeat(32),
+                        // Already resolved, so never fetched
+                        data: Some(ResourceData(logo)),
+                    },
+                },
+            ],
+        },
+        Segment::Timestamp {
+            timestamp: "%H:%M".to_string(),
+        },
+    ];
+    let outcome = service.submit(job).await;
+    assert!(matches!(outcome, JobOutcome::Printed(_)), "{:?}", outcome);
+    assert!(recorder.contains("Table 4") && recorder.contains("Soup of the day"));
+    service.shutdown().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn build_needs_a_driver() {
+    assert!(PrinterService::builder().build().is_err());
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn build_needs_a_multi_threaded_runtime() {
+    let built = PrinterService::builder()
+        .driver(Recorder::default())
+        .build();
+    assert!(built.is_err());
+}
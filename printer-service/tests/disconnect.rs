@@ -0,0 +1,173 @@
+//! A printer that goes away mid-job: the spooled job isn't failed to the
+//! server, it's printed once after the restart.
+
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures_util::{SinkExt, StreamExt};
+use printer_service::{Driver, PrinterProfile, PrinterService, ServiceConfig, Transport};
+use serde_json::{Value, json};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+
+const WAIT: Duration = Duration::from_secs(10);
+
+/// Keeps everything written to it until it's unplugged.
+#[derive(Clone, Default)]
+struct Printer {
+    bytes: Arc<Mutex<Vec<u8>>>,
+    unplugged: Arc<AtomicBool>,
+}
+
+impl Printer {
+    fn contains(&self, text: &str) -> bool {
+        let bytes = self.bytes.lock().unwrap();
+        bytes
+            .windows(text.len())
+            .any(|window| window == text.as_bytes())
+    }
+}
+
+impl Driver for Printer {
+    fn name(&self) -> String {
+        "unpluggable".to_string()
+    }
+
+    fn write(&self, data: &[u8]) -> escpos::errors::Result<()> {
+        if self.unplugged.load(Ordering::SeqCst) {
+            return Err(escpos::errors::PrinterError::Io("unplugged".to_string()));
+        }
+        self.bytes.lock().unwrap().extend_from_slice(data);
+        Ok(())
+    }
+
+    fn read(&self, _buf: &mut [u8]) -> escpos::errors::Result<usize> {
+        Ok(0)
+    }
+
+    fn flush(&self) -> escpos::errors::Result<()> {
+        Ok(())
+    }
+}
+
+/// A spool dir of its own, removed afterwards.
+struct SpoolDir(PathBuf);
+
+impl SpoolDir {
+    fn new() -> Self {
+        let path = std::env::temp_dir().join(format!(
+            "printer-service-disconnect-{}-{}",
+            std::process::id(),
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_nanos()
+        ));
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+}
+
+impl Drop for SpoolDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Picks the v2 subprotocol, like the real server.
+#[allow(clippy::result_large_err)] // tungstenite's callback signature
+fn speak_v2(_: &Request, mut response: Response) -> Result<Response, ErrorResponse> {
+    response.headers_mut().insert(
+        "Sec-WebSocket-Protocol",
+        HeaderValue::from_static("flatos-print.v2"),
+    );
+    Ok(response)
+}
+
+async fn accept(listener: &TcpListener) -> WebSocketStream<TcpStream> {
+    let (stream, _) = timeout(WAIT, listener.accept()).await.unwrap().unwrap();
+    tokio_tungstenite::accept_hdr_async(stream, speak_v2)
+        .await
+        .unwrap()
+}
+
+/// The final acks (not `accepted` or `printing`) sent until the service
+/// hangs up or goes quiet.
+async fn final_acks(ws: &mut WebSocketStream<TcpStream>) -> Vec<Value> {
+    let mut acks = Vec::new();
+    while let Ok(Some(Ok(message))) = timeout(WAIT, ws.next()).await {
+        let Message::Text(text) = message else {
+            continue;
+        };
+        let frame: Value = serde_json::from_str(&text).unwrap();
+        if frame["type"] == "ack"
+            && !matches!(frame["status"].as_str(), Some("accepted" | "printing"))
+        {
+            acks.push(frame);
+        }
+    }
+    acks
+}
+
+fn start(printer: &Printer, url: &str, spool: &SpoolDir) -> PrinterService {
+    PrinterService::builder()
+        .config(ServiceConfig {
+            profile: PrinterProfile::find("default").unwrap().clone(),
+            spool_dir: Some(spool.0.clone()),
+            ..ServiceConfig::default()
+        })
+        .driver(printer.clone())
+        .transport(Transport::WebSocket(url.to_string()))
+        .build()
+        .unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_job_left_in_the_spool_is_not_failed_and_prints_after_the_restart() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("ws://{}", listener.local_addr().unwrap());
+    let spool = SpoolDir::new();
+    let printer = Printer::default();
+    let service = start(&printer, &url, &spool);
+    let mut ws = accept(&listener).await;
+    printer.unplugged.store(true, Ordering::SeqCst);
+
+    // Each failed write counts, until the service gives up on the printer
+    // and exits for a restart partway through one
+    for n in 1..=5 {
+        let job = json!({"type": "job", "id": n.to_string(), "text": format!("Order {}", n)});
+        ws.send(Message::text(job.to_string())).await.unwrap();
+    }
+    assert!(timeout(WAIT, service.wait()).await.unwrap().is_err());
+    let acks = final_acks(&mut ws).await;
+    let ids: Vec<&str> = acks.iter().map(|ack| ack["id"].as_str().unwrap()).collect();
+    assert_eq!(ids, ["1", "2", "3", "4"], "{:?}", acks);
+    assert!(acks.iter().all(|ack| ack["status"] == "failed"));
+
+    // Started again, it prints the job it was on and acks it once
+    let printer = Printer::default();
+    let service = start(&printer, &url, &spool);
+    let mut ws = accept(&listener).await;
+    let printed = timeout(WAIT, async {
+        loop {
+            let Some(Ok(Message::Text(text))) = ws.next().await else {
+                panic!("the service hung up");
+            };
+            let frame: Value = serde_json::from_str(&text).unwrap();
+            if frame["type"] == "ack" && frame["id"] == "5" && frame["status"] != "accepted" {
+                return frame;
+            }
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(printed["status"], "printed");
+    assert!(printer.contains("Order 5"));
+    service.shutdown().await.unwrap();
+}
@@ -0,0 +1,224 @@
+//! Oversized messages from the server: rejected with an ack, and never
+//! held in memory whole however big they are.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures_util::{SinkExt, StreamExt};
+use printer_service::{Driver, PrinterProfile, PrinterService, ServiceConfig, Transport};
+use serde_json::{Value, json};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+
+const WAIT: Duration = Duration::from_secs(10);
+const LIMIT: usize = 1024 * 1024;
+
+/// Counts what the whole process has allocated; this file's one test is
+/// all that runs in it.
+struct Counting;
+
+static HELD: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+fn grew(bytes: usize) {
+    let held = HELD.fetch_add(bytes, Ordering::Relaxed) + bytes;
+    PEAK.fetch_max(held, Ordering::Relaxed);
+}
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { System.alloc(layout) };
+        if !ptr.is_null() {
+            grew(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) };
+        HELD.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new = unsafe { System.realloc(ptr, layout, new_size) };
+        if !new.is_null() {
+            if new_size > layout.size() {
+                grew(new_size - layout.size());
+            } else {
+                HELD.fetch_sub(layout.size() - new_size, Ordering::Relaxed);
+            }
+        }
+        new
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+/// Starts counting the peak from what's held now, returning that.
+fn reset_peak() -> usize {
+    let held = HELD.load(Ordering::Relaxed);
+    PEAK.store(held, Ordering::Relaxed);
+    held
+}
+
+/// Keeps everything written to it.
+#[derive(Clone, Default)]
+struct Recorder(Arc<Mutex<Vec<u8>>>);
+
+impl Recorder {
+    fn contains(&self, text: &str) -> bool {
+        let bytes = self.0.lock().unwrap();
+        bytes
+            .windows(text.len())
+            .any(|window| window == text.as_bytes())
+    }
+}
+
+impl Driver for Recorder {
+    fn name(&self) -> String {
+        "recorder".to_string()
+    }
+
+    fn write(&self, data: &[u8]) -> escpos::errors::Result<()> {
+        self.0.lock().unwrap().extend_from_slice(data);
+        Ok(())
+    }
+
+    fn read(&self, _buf: &mut [u8]) -> escpos::errors::Result<usize> {
+        Ok(0)
+    }
+
+    fn flush(&self) -> escpos::errors::Result<()> {
+        Ok(())
+    }
+}
+
+/// Picks the v2 subprotocol, like the real server.
+#[allow(clippy::result_large_err)] // tungstenite's callback signature
+fn speak_v2(_: &Request, mut response: Response) -> Result<Response, ErrorResponse> {
+    response.headers_mut().insert(
+        "Sec-WebSocket-Protocol",
+        HeaderValue::from_static("flatos-print.v2"),
+    );
+    Ok(response)
+}
+
+async fn accept(listener: &TcpListener) -> WebSocketStream<TcpStream> {
+    let (stream, _) = timeout(WAIT, listener.accept()).await.unwrap().unwrap();
+    tokio_tungstenite::accept_hdr_async(stream, speak_v2)
+        .await
+        .unwrap()
+}
+
+/// Reads frames until one matches, failing after a while.
+async fn frame_where(
+    ws: &mut WebSocketStream<TcpStream>,
+    wanted: impl Fn(&Value) -> bool,
+) -> Value {
+    timeout(WAIT, async {
+        while let Some(message) = ws.next().await {
+            if let Message::Text(text) = message.unwrap() {
+                let frame: Value = serde_json::from_str(&text).unwrap();
+                if wanted(&frame) {
+                    return frame;
+                }
+            }
+        }
+        panic!("the service hung up");
+    })
+    .await
+    .expect("no such frame")
+}
+
+fn too_large(frame: &Value) -> bool {
+    frame["type"] == "ack" && frame["error"] == "MESSAGE_TOO_LARGE"
+}
+
+/// Sends a text message of `total` bytes in `fragment` byte frames, reusing
+/// one buffer, until it's all sent or the service hangs up.
+async fn send_fragmented(ws: &mut WebSocketStream<TcpStream>, total: usize, fragment: usize) {
+    let payload = vec![b'x'; fragment];
+    let stream = ws.get_mut();
+    let mut sent = 0;
+    while sent < total {
+        let opcode = if sent == 0 { 0x01 } else { 0x00 };
+        let last = sent + fragment >= total;
+        let mut header = vec![if last { 0x80 } else { 0 } | opcode, 127];
+        header.extend_from_slice(&(fragment as u64).to_be_bytes());
+        if stream.write_all(&header).await.is_err() || stream.write_all(&payload).await.is_err() {
+            return;
+        }
+        sent += fragment;
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn oversized_messages_are_rejected_without_being_held() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("ws://{}", listener.local_addr().unwrap());
+    let recorder = Recorder::default();
+    let service = PrinterService::builder()
+        .config(ServiceConfig {
+            profile: PrinterProfile::find("default").unwrap().clone(),
+            max_message_size: LIMIT,
+            ..ServiceConfig::default()
+        })
+        .driver(recorder.clone())
+        .transport(Transport::WebSocket(url))
+        .build()
+        .unwrap();
+
+    let mut ws = accept(&listener).await;
+    frame_where(&mut ws, |frame| frame["type"] == "hello").await;
+
+    // Over the limit but under twice it: rejected, and the connection stays
+    let big = json!({"type": "job", "id": "big", "text": "x".repeat(LIMIT + LIMIT / 2)});
+    ws.send(Message::text(big.to_string())).await.unwrap();
+    let ack = frame_where(&mut ws, too_large).await;
+    assert_eq!(ack["status"], "rejected");
+    ws.send(Message::text(
+        json!({"type": "job", "id": "small", "text": "Still here"}).to_string(),
+    ))
+    .await
+    .unwrap();
+    let ack = frame_where(&mut ws, |frame| {
+        frame["id"] == "small" && frame["status"] == "printed"
+    })
+    .await;
+    assert_eq!(ack["type"], "ack");
+    assert!(recorder.contains("Still here"));
+
+    // 40 MB in 256 KB frames: the service hangs up at twice the limit, so
+    // it never holds much more than that
+    let before = reset_peak();
+    send_fragmented(&mut ws, 40 * LIMIT, 256 * 1024).await;
+    drop(ws);
+    let mut ws = accept(&listener).await;
+    let ack = frame_where(&mut ws, too_large).await;
+    assert_eq!(ack["status"], "rejected");
+    let peak = PEAK.load(Ordering::Relaxed) - before;
+    assert!(peak < 4 * LIMIT, "{} bytes held at once", peak);
+
+    // A single frame that says it's 40 MB is refused from its header
+    let before = reset_peak();
+    let stream = ws.get_mut();
+    let mut header = vec![0x81, 127];
+    header.extend_from_slice(&(40 * LIMIT as u64).to_be_bytes());
+    stream.write_all(&header).await.unwrap();
+    let _ = stream.write_all(&[b'x'; 64 * 1024]).await;
+    drop(ws);
+    let mut ws = accept(&listener).await;
+    frame_where(&mut ws, too_large).await;
+    let peak = PEAK.load(Ordering::Relaxed) - before;
+    assert!(peak < LIMIT, "{} bytes held at once", peak);
+
+    service.shutdown().await.unwrap();
+}
@@ -0,0 +1,123 @@
+//! Job priorities: jobs queued while printing is paused come out highest
+//! priority first, in arrival order within a priority.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures_util::{SinkExt, StreamExt};
+use printer_service::{Driver, PrinterProfile, PrinterService, ServiceConfig, Transport};
+use serde_json::{Value, json};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+
+const WAIT: Duration = Duration::from_secs(10);
+
+/// Keeps everything written to it.
+#[derive(Clone, Default)]
+struct Recorder(Arc<Mutex<Vec<u8>>>);
+
+impl Recorder {
+    /// Where `text` first appears in what was printed.
+    fn position(&self, text: &str) -> Option<usize> {
+        let bytes = self.0.lock().unwrap();
+        bytes
+            .windows(text.len())
+            .position(|window| window == text.as_bytes())
+    }
+}
+
+impl Driver for Recorder {
+    fn name(&self) -> String {
+        "recorder".to_string()
+    }
+
+    fn write(&self, data: &[u8]) -> escpos::errors::Result<()> {
+        self.0.lock().unwrap().extend_from_slice(data);
+        Ok(())
+    }
+
+    fn read(&self, _buf: &mut [u8]) -> escpos::errors::Result<usize> {
+        Ok(0)
+    }
+
+    fn flush(&self) -> escpos::errors::Result<()> {
+        Ok(())
+    }
+}
+
+/// Picks the v2 subprotocol, like the real server.
+#[allow(clippy::result_large_err)] // tungstenite's callback signature
+fn speak_v2(_: &Request, mut response: Response) -> Result<Response, ErrorResponse> {
+    response.headers_mut().insert(
+        "Sec-WebSocket-Protocol",
+        HeaderValue::from_static("flatos-print.v2"),
+    );
+    Ok(response)
+}
+
+async fn send(ws: &mut WebSocketStream<TcpStream>, frame: Value) {
+    ws.send(Message::text(frame.to_string())).await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn higher_priority_jobs_print_first() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("ws://{}", listener.local_addr().unwrap());
+    let recorder = Recorder::default();
+    let service = PrinterService::builder()
+        .config(ServiceConfig {
+            profile: PrinterProfile::find("default").unwrap().clone(),
+            ..ServiceConfig::default()
+        })
+        .driver(recorder.clone())
+        .transport(Transport::WebSocket(url))
+        .build()
+        .unwrap();
+    let (stream, _) = timeout(WAIT, listener.accept()).await.unwrap().unwrap();
+    let mut ws = tokio_tungstenite::accept_hdr_async(stream, speak_v2)
+        .await
+        .unwrap();
+
+    send(&mut ws, json!({"type": "command", "command": "pause"})).await;
+    for (id, priority) in [
+        ("a", Some("low")),
+        ("b", None),
+        ("c", Some("high")),
+        ("d", Some("normal")),
+        ("e", Some("high")),
+    ] {
+        let mut job = json!({"type": "job", "id": id, "text": format!("Ticket {}", id)});
+        if let Some(priority) = priority {
+            job["priority"] = json!(priority);
+        }
+        send(&mut ws, job).await;
+    }
+    send(&mut ws, json!({"type": "command", "command": "resume"})).await;
+
+    let printed = timeout(WAIT, async {
+        let mut printed = Vec::new();
+        while printed.len() < 5 {
+            let Some(Ok(Message::Text(text))) = ws.next().await else {
+                panic!("the service hung up");
+            };
+            let frame: Value = serde_json::from_str(&text).unwrap();
+            if frame["type"] == "ack" && frame["status"] == "printed" {
+                printed.push(frame["id"].as_str().unwrap().to_string());
+            }
+        }
+        printed
+    })
+    .await
+    .expect("not every job printed");
+    assert_eq!(printed, ["c", "e", "b", "d", "a"]);
+    let positions: Vec<usize> = printed
+        .iter()
+        .map(|id| recorder.position(&format!("Ticket {}", id)).unwrap())
+        .collect();
+    assert!(positions.is_sorted(), "{:?}", positions);
+    service.shutdown().await.unwrap();
+}
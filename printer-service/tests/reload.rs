@@ -0,0 +1,149 @@
+//! Reloading the config file while running: changes that need a restart are
+//! refused every time, not just the first.
+
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures_util::{SinkExt, StreamExt};
+use printer_service::config::{self, DeviceConfig};
+use printer_service::{Driver, PrinterProfile, PrinterService, ServiceConfig, Transport};
+use serde_json::{Value, json};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+
+const WAIT: Duration = Duration::from_secs(10);
+
+/// Keeps everything written to it.
+#[derive(Clone, Default)]
+struct Recorder(Arc<Mutex<Vec<u8>>>);
+
+impl Driver for Recorder {
+    fn name(&self) -> String {
+        "recorder".to_string()
+    }
+
+    fn write(&self, data: &[u8]) -> escpos::errors::Result<()> {
+        self.0.lock().unwrap().extend_from_slice(data);
+        Ok(())
+    }
+
+    fn read(&self, _buf: &mut [u8]) -> escpos::errors::Result<usize> {
+        Ok(0)
+    }
+
+    fn flush(&self) -> escpos::errors::Result<()> {
+        Ok(())
+    }
+}
+
+/// A config dir of its own, removed afterwards.
+struct ConfigDir(PathBuf);
+
+impl ConfigDir {
+    fn new() -> Self {
+        let path = std::env::temp_dir().join(format!(
+            "printer-service-reload-{}-{}",
+            std::process::id(),
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_nanos()
+        ));
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+}
+
+impl Drop for ConfigDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Picks the v2 subprotocol, like the real server.
+#[allow(clippy::result_large_err)] // tungstenite's callback signature
+fn speak_v2(_: &Request, mut response: Response) -> Result<Response, ErrorResponse> {
+    response.headers_mut().insert(
+        "Sec-WebSocket-Protocol",
+        HeaderValue::from_static("flatos-print.v2"),
+    );
+    Ok(response)
+}
+
+/// Sends `reload` and returns the message it's answered with.
+async fn reload(ws: &mut WebSocketStream<TcpStream>) -> String {
+    let command = json!({"type": "command", "command": "reload"});
+    ws.send(Message::text(command.to_string())).await.unwrap();
+    timeout(WAIT, async {
+        while let Some(message) = ws.next().await {
+            if let Message::Text(text) = message.unwrap() {
+                let frame: Value = serde_json::from_str(&text).unwrap();
+                if frame["type"] == "command_result" && frame["command"] == "reload" {
+                    assert_eq!(frame["ok"], true, "{}", frame);
+                    return frame["message"].as_str().unwrap().to_string();
+                }
+            }
+        }
+        panic!("the service hung up");
+    })
+    .await
+    .expect("no reply to reload")
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_changed_tenant_is_refused_on_every_reload() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("ws://{}", listener.local_addr().unwrap());
+    let dir = ConfigDir::new();
+    let path = dir.0.join("config.json");
+    let running = DeviceConfig {
+        url: url.clone(),
+        tenant: Some("cafe".to_string()),
+        ..DeviceConfig::default()
+    };
+    config::save(&path, &running).unwrap();
+    let service = PrinterService::builder()
+        .config(ServiceConfig {
+            profile: PrinterProfile::find("default").unwrap().clone(),
+            config_path: Some(path.clone()),
+            loaded_config: Some(config::load(&path).unwrap()),
+            ..ServiceConfig::default()
+        })
+        .driver(Recorder::default())
+        .transport(Transport::WebSocket(url.clone()))
+        .build()
+        .unwrap();
+    let (stream, _) = timeout(WAIT, listener.accept()).await.unwrap().unwrap();
+    let mut ws = tokio_tungstenite::accept_hdr_async(stream, speak_v2)
+        .await
+        .unwrap();
+
+    let moved = DeviceConfig {
+        tenant: Some("bakery".to_string()),
+        log_level: Some("debug".to_string()),
+        ..running.clone()
+    };
+    config::save(&path, &moved).unwrap();
+    let first = reload(&mut ws).await;
+    assert_eq!(first, "Applied log_level; restart needed for tenant");
+    // Still refused, and still reported, with nothing left to apply
+    let second = reload(&mut ws).await;
+    assert_eq!(second, "No live changes; restart needed for tenant");
+
+    // Putting it back leaves nothing to restart for
+    config::save(
+        &path,
+        &DeviceConfig {
+            log_level: Some("debug".to_string()),
+            ..running
+        },
+    )
+    .unwrap();
+    assert_eq!(reload(&mut ws).await, "No live changes");
+    service.shutdown().await.unwrap();
+}
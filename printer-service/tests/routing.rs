@@ -0,0 +1,269 @@
+//! Jobs for several printers: each has its own queue, so one that's stuck
+//! holds up none of the others.
+
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+use futures_util::{SinkExt, StreamExt};
+use printer_service::{
+    Driver, ErrorCode, JobOutcome, PrintJob, PrinterProfile, PrinterService, ServiceConfig, Target,
+    Transport,
+};
+use serde_json::{Value, json};
+use tokio::net::TcpListener;
+use tokio::time::timeout;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+
+const WAIT: Duration = Duration::from_secs(10);
+
+/// Keeps everything written to it, and can be jammed so writes block until
+/// it's cleared.
+#[derive(Clone, Default)]
+struct Printer {
+    bytes: Arc<Mutex<Vec<u8>>>,
+    jammed: Arc<(Mutex<bool>, Condvar)>,
+}
+
+impl Printer {
+    fn contains(&self, text: &str) -> bool {
+        let bytes = self.bytes.lock().unwrap();
+        bytes
+            .windows(text.len())
+            .any(|window| window == text.as_bytes())
+    }
+
+    fn jam(&self, jammed: bool) {
+        let (lock, cleared) = &*self.jammed;
+        *lock.lock().unwrap() = jammed;
+        cleared.notify_all();
+    }
+}
+
+impl Driver for Printer {
+    fn name(&self) -> String {
+        "jammable".to_string()
+    }
+
+    fn write(&self, data: &[u8]) -> escpos::errors::Result<()> {
+        let (lock, cleared) = &*self.jammed;
+        drop(
+            cleared
+                .wait_while(lock.lock().unwrap(), |jammed| *jammed)
+                .unwrap(),
+        );
+        self.bytes.lock().unwrap().extend_from_slice(data);
+        Ok(())
+    }
+
+    fn read(&self, _buf: &mut [u8]) -> escpos::errors::Result<usize> {
+        Ok(0)
+    }
+
+    fn flush(&self) -> escpos::errors::Result<()> {
+        Ok(())
+    }
+}
+
+fn config() -> ServiceConfig {
+    ServiceConfig {
+        profile: PrinterProfile::find("default").unwrap().clone(),
+        ..ServiceConfig::default()
+    }
+}
+
+fn job(text: &str, target: Option<&str>) -> PrintJob {
+    let mut job = PrintJob::plain(text.to_string());
+    job.target = target.map(str::to_string);
+    job
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_jammed_printer_holds_up_none_of_the_others() {
+    let front = Printer::default();
+    let kitchen = Printer::default();
+    let service = PrinterService::builder()
+        .config(config())
+        .driver(front.clone())
+        .target(Target::new("kitchen", kitchen.clone()))
+        .build()
+        .unwrap();
+
+    front.jam(true);
+    let stuck = service.submit(job("Receipt for table 4", None));
+    for n in 1..=3 {
+        let text = format!("Kitchen order {}", n);
+        let outcome = timeout(WAIT, service.submit(job(&text, Some("kitchen"))))
+            .await
+            .expect("the kitchen printer waited on the jammed one");
+        assert!(matches!(outcome, JobOutcome::Printed(_)), "{:?}", outcome);
+        assert!(kitchen.contains(&text));
+    }
+    assert!(!front.contains("Receipt for table 4"));
+    let status = timeout(WAIT, service.printer_status("kitchen"))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(status.target.as_deref(), Some("kitchen"));
+    assert_eq!(status.queue_depth, 0);
+
+    front.jam(false);
+    let outcome = timeout(WAIT, stuck).await.unwrap();
+    assert!(matches!(outcome, JobOutcome::Printed(_)), "{:?}", outcome);
+    assert!(front.contains("Receipt for table 4"));
+    assert!(!kitchen.contains("Receipt for table 4"));
+    let status = service.status().await.unwrap();
+    assert_eq!(status.target.as_deref(), Some("default"));
+    service.shutdown().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn jobs_for_unknown_or_stopped_printers_are_rejected() {
+    let service = PrinterService::builder()
+        .config(config())
+        .driver(Printer::default())
+        .target(Target::new("bar", Printer::default()))
+        .build()
+        .unwrap();
+
+    match service.submit(job("Nowhere", Some("patio"))).await {
+        JobOutcome::Rejected { error, .. } => assert_eq!(error, ErrorCode::UnknownTarget),
+        outcome => panic!("expected a rejection, got {:?}", outcome),
+    }
+
+    service.shutdown_printer("bar").await.unwrap();
+    assert!(service.printer_status("bar").await.is_err());
+    assert!(service.shutdown_printer("bar").await.is_err());
+    match service.submit(job("Drinks", Some("bar"))).await {
+        JobOutcome::Rejected { error, .. } => assert_eq!(error, ErrorCode::UnknownTarget),
+        outcome => panic!("expected a rejection, got {:?}", outcome),
+    }
+    let outcome = service.submit(job("Still printing", None)).await;
+    assert!(matches!(outcome, JobOutcome::Printed(_)), "{:?}", outcome);
+    service.shutdown().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn printer_names_are_checked() {
+    for names in [&["default"][..], &[""], &["bar", "bar"]] {
+        let mut builder = PrinterService::builder().driver(Printer::default());
+        for name in names {
+            builder = builder.target(Target::new(*name, Printer::default()));
+        }
+        assert!(builder.build().is_err(), "{:?}", names);
+    }
+}
+
+/// Reads frames until one matches, failing after a while.
+async fn frame_where<S>(ws: &mut S, wanted: impl Fn(&Value) -> bool) -> Value
+where
+    S: StreamExt<Item = Result<Message, tokio_tungstenite::tungstenite::Error>> + Unpin,
+{
+    timeout(WAIT, async {
+        while let Some(message) = ws.next().await {
+            if let Message::Text(text) = message.unwrap() {
+                let frame: Value = serde_json::from_str(&text).unwrap();
+                if wanted(&frame) {
+                    return frame;
+                }
+            }
+        }
+        panic!("the service hung up");
+    })
+    .await
+    .expect("no such frame")
+}
+
+/// Waits for job `id` to be acked as printed by each of `targets`, in any
+/// order.
+async fn printed_on<S>(ws: &mut S, id: &str, targets: &[&str])
+where
+    S: StreamExt<Item = Result<Message, tokio_tungstenite::tungstenite::Error>> + Unpin,
+{
+    let mut waiting: Vec<&str> = targets.to_vec();
+    while !waiting.is_empty() {
+        let frame = frame_where(ws, |frame| {
+            frame["type"] == "ack" && frame["id"] == id && frame["status"] != "accepted"
+        })
+        .await;
+        assert_eq!(frame["status"], "printed", "{}", frame);
+        let target = frame["target"].as_str().unwrap();
+        assert!(waiting.contains(&target), "{}", frame);
+        waiting.retain(|waiting| *waiting != target);
+    }
+}
+
+/// Picks the v2 subprotocol, like the real server.
+#[allow(clippy::result_large_err)] // tungstenite's callback signature
+fn speak_v2(_: &Request, mut response: Response) -> Result<Response, ErrorResponse> {
+    response.headers_mut().insert(
+        "Sec-WebSocket-Protocol",
+        HeaderValue::from_static("flatos-print.v2"),
+    );
+    Ok(response)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn fanout_jobs_are_acked_by_each_printer() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("ws://{}", listener.local_addr().unwrap());
+    let front = Printer::default();
+    let kitchen = Printer::default();
+    let bar = Printer::default();
+    let service = PrinterService::builder()
+        .config(config())
+        .driver(front.clone())
+        .target(Target::new("kitchen", kitchen.clone()))
+        .target(Target::new("bar", bar.clone()))
+        .transport(Transport::WebSocket(url))
+        .build()
+        .unwrap();
+
+    let (stream, _) = listener.accept().await.unwrap();
+    let mut ws = tokio_tungstenite::accept_hdr_async(stream, speak_v2)
+        .await
+        .unwrap();
+
+    let hello = frame_where(&mut ws, |frame| frame["type"] == "hello").await;
+    let targets: Vec<&str> = hello["targets"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|target| target["name"].as_str().unwrap())
+        .collect();
+    assert_eq!(targets, ["kitchen", "bar"]);
+
+    let fanout = json!({
+        "type": "fanout",
+        "targets": ["kitchen", "bar", "kitchen"],
+        "job": {"id": "order-7", "text": "Two burgers"},
+    });
+    ws.send(Message::text(fanout.to_string())).await.unwrap();
+    printed_on(&mut ws, "order-7", &["kitchen", "bar"]).await;
+    assert!(kitchen.contains("Two burgers"));
+    assert!(bar.contains("Two burgers"));
+    assert!(!front.contains("Two burgers"));
+
+    let unknown = json!({
+        "type": "fanout",
+        "targets": ["kitchen", "patio"],
+        "job": {"id": "order-8", "text": "Lost order"},
+    });
+    ws.send(Message::text(unknown.to_string())).await.unwrap();
+    let rejected = frame_where(&mut ws, |frame| frame["id"] == "order-8").await;
+    assert_eq!(rejected["status"], "rejected");
+    assert_eq!(rejected["error"], "UNKNOWN_TARGET");
+
+    let everywhere = json!({
+        "type": "fanout",
+        "job": {"id": "order-9", "text": "Closing soon"},
+    });
+    ws.send(Message::text(everywhere.to_string()))
+        .await
+        .unwrap();
+    printed_on(&mut ws, "order-9", &["default", "kitchen", "bar"]).await;
+    assert!(!kitchen.contains("Lost order"));
+
+    service.shutdown().await.unwrap();
+}